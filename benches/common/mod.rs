@@ -0,0 +1,43 @@
+use hasher::{Hasher, HasherKeccak};
+
+/// The sizes every workload is measured at.
+pub const SIZES: [usize; 2] = [1000, 10000];
+
+/// How the keys of a workload are laid out.
+#[derive(Debug, Clone, Copy)]
+pub enum Keys {
+    /// Keccak hashes of a counter, like the secure trie of Ethereum state.
+    Hashed,
+    /// A big-endian counter, like block or transaction indexes.
+    Sequential,
+}
+
+impl Keys {
+    pub const ALL: [Keys; 2] = [Keys::Hashed, Keys::Sequential];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Keys::Hashed => "hashed",
+            Keys::Sequential => "sequential",
+        }
+    }
+
+    /// Returns "n" key/value pairs, the same ones on every call. Values are
+    /// about the size of an RLP encoded account.
+    pub fn generate(self, n: usize) -> Vec<(Vec<u8>, Vec<u8>)> {
+        let hasher = HasherKeccak::new();
+        (0..n as u64)
+            .map(|i| {
+                let counter = i.to_be_bytes().to_vec();
+                let key = match self {
+                    Keys::Hashed => hasher.digest(&counter),
+                    Keys::Sequential => counter.clone(),
+                };
+                let mut value = hasher.digest(&key);
+                value.extend_from_slice(&hasher.digest(&value));
+                value.extend_from_slice(&counter);
+                (key, value)
+            })
+            .collect()
+    }
+}
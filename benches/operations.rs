@@ -0,0 +1,140 @@
+use std::rc::Rc;
+use std::sync::Arc;
+
+use criterion::{criterion_group, criterion_main, Criterion};
+
+use hasher::HasherKeccak;
+
+use cita_trie::MemoryDB;
+use cita_trie::{PatriciaTrie, Trie};
+
+mod common;
+
+use common::{Keys, SIZES};
+
+type Bench = PatriciaTrie<MemoryDB, HasherKeccak>;
+
+fn new_trie() -> Bench {
+    PatriciaTrie::new(
+        Arc::new(MemoryDB::new(false)),
+        Arc::new(HasherKeccak::new()),
+    )
+}
+
+// Returns a committed trie holding "kvs".
+fn committed_trie(kvs: &[(Vec<u8>, Vec<u8>)]) -> Bench {
+    let mut trie = new_trie();
+    for (k, v) in kvs.iter() {
+        trie.insert(k.clone(), v.clone()).unwrap();
+    }
+    trie.root().unwrap();
+    trie
+}
+
+fn insert_benchmark(c: &mut Criterion) {
+    for &keys in Keys::ALL.iter() {
+        for &n in SIZES.iter() {
+            let kvs = keys.generate(n);
+            c.bench_function(&format!("insert {} {}", keys.name(), n), move |b| {
+                b.iter_with_setup(new_trie, |mut trie| {
+                    for (k, v) in kvs.iter() {
+                        trie.insert(k.clone(), v.clone()).unwrap();
+                    }
+                    trie
+                })
+            });
+        }
+    }
+}
+
+fn insert_batch_benchmark(c: &mut Criterion) {
+    for &keys in Keys::ALL.iter() {
+        for &n in SIZES.iter() {
+            let kvs = keys.generate(n);
+            c.bench_function(&format!("insert_batch {} {}", keys.name(), n), move |b| {
+                b.iter_with_setup(new_trie, |mut trie| {
+                    trie.insert_batch(&kvs).unwrap();
+                    trie
+                })
+            });
+        }
+    }
+}
+
+fn commit_benchmark(c: &mut Criterion) {
+    for &keys in Keys::ALL.iter() {
+        for &n in SIZES.iter() {
+            let kvs = keys.generate(n);
+            c.bench_function(&format!("commit {} {}", keys.name(), n), move |b| {
+                b.iter_with_setup(
+                    || {
+                        let mut trie = new_trie();
+                        for (k, v) in kvs.iter() {
+                            trie.insert(k.clone(), v.clone()).unwrap();
+                        }
+                        trie
+                    },
+                    |mut trie| trie.root().unwrap(),
+                )
+            });
+        }
+    }
+}
+
+fn get_benchmark(c: &mut Criterion) {
+    for &keys in Keys::ALL.iter() {
+        for &n in SIZES.iter() {
+            let kvs = keys.generate(n);
+            let trie = committed_trie(&kvs);
+            c.bench_function(&format!("get {} {}", keys.name(), n), move |b| {
+                let mut i = 0;
+                b.iter(|| {
+                    i = (i + 1) % kvs.len();
+                    trie.get(&kvs[i].0).unwrap()
+                })
+            });
+        }
+    }
+}
+
+fn proof_benchmark(c: &mut Criterion) {
+    for &keys in Keys::ALL.iter() {
+        for &n in SIZES.iter() {
+            let kvs = Rc::new(keys.generate(n));
+            let mut trie = committed_trie(&kvs);
+            let root = trie.root().unwrap();
+            let proofs: Vec<_> = kvs
+                .iter()
+                .map(|(k, _)| trie.get_proof(k).unwrap())
+                .collect();
+            let trie = Rc::new(trie);
+
+            let (get_kvs, get_trie) = (Rc::clone(&kvs), Rc::clone(&trie));
+            c.bench_function(&format!("get_proof {} {}", keys.name(), n), move |b| {
+                let mut i = 0;
+                b.iter(|| {
+                    i = (i + 1) % get_kvs.len();
+                    get_trie.get_proof(&get_kvs[i].0).unwrap()
+                })
+            });
+            c.bench_function(&format!("verify_proof {} {}", keys.name(), n), move |b| {
+                let mut i = 0;
+                b.iter(|| {
+                    i = (i + 1) % kvs.len();
+                    trie.verify_proof(root.clone(), &kvs[i].0, proofs[i].clone())
+                        .unwrap()
+                })
+            });
+        }
+    }
+}
+
+criterion_group!(
+    benches,
+    insert_benchmark,
+    insert_batch_benchmark,
+    commit_benchmark,
+    get_benchmark,
+    proof_benchmark
+);
+criterion_main!(benches);
@@ -0,0 +1,95 @@
+//! Runs the workloads of the "operations" benchmark against the "trie-db"
+//! crate, as a baseline to compare cita-trie with:
+//!
+//! ```sh
+//! cargo bench --features bench-trie-db --bench trie_db
+//! ```
+//!
+//! "trie-db" commits when a mutable trie is dropped, so inserts are only
+//! measured together with the commit.
+
+use criterion::{criterion_group, criterion_main, Criterion};
+
+use keccak_hasher::KeccakHasher;
+use memory_db::{HashKey, MemoryDB};
+use reference_trie::{RefTrieDB, RefTrieDBMut, Trie, TrieMut};
+use trie_db::{DBValue, Recorder};
+
+mod common;
+
+use common::{Keys, SIZES};
+
+type BenchDB = MemoryDB<KeccakHasher, HashKey<KeccakHasher>, DBValue>;
+
+// Returns a DB holding a trie of "kvs", and the root of the trie.
+fn committed_trie(kvs: &[(Vec<u8>, Vec<u8>)]) -> (BenchDB, [u8; 32]) {
+    let mut db = BenchDB::default();
+    let mut root = Default::default();
+    {
+        let mut trie = RefTrieDBMut::new(&mut db, &mut root);
+        for (k, v) in kvs.iter() {
+            trie.insert(k, v).unwrap();
+        }
+    }
+    (db, root)
+}
+
+fn commit_benchmark(c: &mut Criterion) {
+    for &keys in Keys::ALL.iter() {
+        for &n in SIZES.iter() {
+            let kvs = keys.generate(n);
+            c.bench_function(&format!("trie-db commit {} {}", keys.name(), n), move |b| {
+                b.iter_with_setup(BenchDB::default, |mut db| {
+                    let mut root = Default::default();
+                    let mut trie = RefTrieDBMut::new(&mut db, &mut root);
+                    for (k, v) in kvs.iter() {
+                        trie.insert(k, v).unwrap();
+                    }
+                    *trie.root()
+                })
+            });
+        }
+    }
+}
+
+fn get_benchmark(c: &mut Criterion) {
+    for &keys in Keys::ALL.iter() {
+        for &n in SIZES.iter() {
+            let kvs = keys.generate(n);
+            let (db, root) = committed_trie(&kvs);
+            c.bench_function(&format!("trie-db get {} {}", keys.name(), n), move |b| {
+                let trie = RefTrieDB::new(&db, &root).unwrap();
+                let mut i = 0;
+                b.iter(|| {
+                    i = (i + 1) % kvs.len();
+                    trie.get(&kvs[i].0).unwrap()
+                })
+            });
+        }
+    }
+}
+
+fn proof_benchmark(c: &mut Criterion) {
+    for &keys in Keys::ALL.iter() {
+        for &n in SIZES.iter() {
+            let kvs = keys.generate(n);
+            let (db, root) = committed_trie(&kvs);
+            c.bench_function(
+                &format!("trie-db get_proof {} {}", keys.name(), n),
+                move |b| {
+                    let trie = RefTrieDB::new(&db, &root).unwrap();
+                    let mut i = 0;
+                    b.iter(|| {
+                        i = (i + 1) % kvs.len();
+                        let mut recorder = Recorder::new();
+                        trie.get_with(&kvs[i].0, &mut recorder).unwrap();
+                        recorder.drain()
+                    })
+                },
+            );
+        }
+    }
+}
+
+criterion_group!(benches, commit_benchmark, get_benchmark, proof_benchmark);
+criterion_main!(benches);
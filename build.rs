@@ -0,0 +1,57 @@
+// Assembles the CRYPTOGAMS Keccak of "asm" for the "asm-keccak" feature, see
+// "src/keccak_asm.rs". Needs perl and an assembler for the target.
+fn main() {
+    #[cfg(feature = "asm-keccak")]
+    keccak::build();
+}
+
+#[cfg(feature = "asm-keccak")]
+mod keccak {
+    use std::env;
+    use std::fs;
+    use std::path::Path;
+    use std::process::Command;
+
+    // The scripts write ELF assembly for x86_64.
+    const VARIANTS: [&str; 2] = ["avx2", "avx512"];
+
+    pub fn build() {
+        println!("cargo:rerun-if-changed=build.rs");
+        let arch = env::var("CARGO_CFG_TARGET_ARCH").unwrap_or_default();
+        let os = env::var("CARGO_CFG_TARGET_OS").unwrap_or_default();
+        // Other targets hash with "HasherKeccak".
+        if arch != "x86_64" || os != "linux" {
+            return;
+        }
+
+        let manifest_dir = env::var("CARGO_MANIFEST_DIR").expect("set by cargo");
+        let out_dir = env::var("OUT_DIR").expect("set by cargo");
+        let mut build = cc::Build::new();
+        // The DWARF 5 of newer assemblers crashes the panic backtraces of
+        // older Rust toolchains.
+        build.debug(false);
+        for variant in VARIANTS.iter() {
+            let script = Path::new(&manifest_dir)
+                .join("asm")
+                .join(format!("keccak1600-{}.pl", variant));
+            println!("cargo:rerun-if-changed={}", script.display());
+            let output = Command::new("perl")
+                .arg(&script)
+                .output()
+                .expect("perl is needed by the asm-keccak feature");
+            assert!(output.status.success(), "{} failed", script.display());
+
+            // Every variant exports the same functions, each gets a prefix of
+            // its own.
+            let asm = String::from_utf8(output.stdout)
+                .expect("the assembly is ASCII")
+                .replace("SHA3_", &format!("cita_trie_{}_SHA3_", variant));
+            let path = Path::new(&out_dir).join(format!("keccak1600-{}.s", variant));
+            fs::write(&path, asm + "\n.section .note.GNU-stack,\"\",@progbits\n")
+                .expect("OUT_DIR is writable");
+            build.file(path);
+        }
+        build.compile("cita_trie_keccak");
+        println!("cargo:rustc-cfg=keccak_asm");
+    }
+}
@@ -0,0 +1,559 @@
+use std::error::Error;
+use std::sync::Arc;
+
+use futures::future::{self, Future, Loop};
+#[cfg(feature = "tokio")]
+use futures::Async;
+use hasher::Hasher;
+#[cfg(feature = "tokio")]
+use tokio_threadpool::blocking;
+
+#[cfg(feature = "tokio")]
+use crate::codec::NodeCodec;
+use crate::db::DB;
+use crate::errors::TrieError;
+use crate::trie::{PatriciaTrie, TrieResult};
+
+pub type DBFuture<T, E> = Box<dyn Future<Item = T, Error = E> + Send>;
+
+/// "AsyncDB" is the non-blocking counterpart of "DB", for stores that are
+/// backed by the network or by asynchronous IO.
+pub trait AsyncDB: Send + Sync {
+    type Error: Error + Send + 'static;
+
+    fn get(&self, key: &[u8]) -> DBFuture<Option<Vec<u8>>, Self::Error>;
+
+    fn contains(&self, key: &[u8]) -> DBFuture<bool, Self::Error>;
+
+    /// Insert data into the cache.
+    fn insert(&self, key: Vec<u8>, value: Vec<u8>) -> DBFuture<(), Self::Error>;
+
+    /// Remove data from the cache.
+    fn remove(&self, key: &[u8]) -> DBFuture<(), Self::Error>;
+
+    /// Insert a batch of data into the cache.
+    fn insert_batch(&self, keys: Vec<Vec<u8>>, values: Vec<Vec<u8>>) -> DBFuture<(), Self::Error> {
+        let inserts: Vec<_> = keys
+            .into_iter()
+            .zip(values.into_iter())
+            .map(|(key, value)| self.insert(key, value))
+            .collect();
+        Box::new(future::join_all(inserts).map(|_| ()))
+    }
+
+    /// Remove a batch of data from the cache.
+    fn remove_batch(&self, keys: &[Vec<u8>]) -> DBFuture<(), Self::Error> {
+        let removes: Vec<_> = keys.iter().map(|key| self.remove(key)).collect();
+        Box::new(future::join_all(removes).map(|_| ()))
+    }
+
+    /// Flush data to the DB from the cache.
+    fn flush(&self) -> DBFuture<(), Self::Error>;
+
+    #[cfg(test)]
+    fn len(&self) -> DBFuture<usize, Self::Error>;
+    #[cfg(test)]
+    fn is_empty(&self) -> DBFuture<bool, Self::Error>;
+}
+
+/// "BlockingDB" adapts an "AsyncDB" to the "DB" trait by waiting on every future.
+/// It blocks the calling thread, so it must not be used from inside an event loop.
+/// Prefer "load_path" to fetch the nodes asynchronously ahead of a trie operation.
+#[derive(Debug)]
+pub struct BlockingDB<A: AsyncDB> {
+    db: A,
+}
+
+impl<A: AsyncDB> BlockingDB<A> {
+    pub fn new(db: A) -> Self {
+        BlockingDB { db }
+    }
+}
+
+impl<A: AsyncDB> DB for BlockingDB<A> {
+    type Error = A::Error;
+
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+        self.db.get(key).wait()
+    }
+
+    fn contains(&self, key: &[u8]) -> Result<bool, Self::Error> {
+        self.db.contains(key).wait()
+    }
+
+    fn insert(&self, key: Vec<u8>, value: Vec<u8>) -> Result<(), Self::Error> {
+        self.db.insert(key, value).wait()
+    }
+
+    fn remove(&self, key: &[u8]) -> Result<(), Self::Error> {
+        self.db.remove(key).wait()
+    }
+
+    fn insert_batch(&self, keys: Vec<Vec<u8>>, values: Vec<Vec<u8>>) -> Result<(), Self::Error> {
+        self.db.insert_batch(keys, values).wait()
+    }
+
+    fn remove_batch(&self, keys: &[Vec<u8>]) -> Result<(), Self::Error> {
+        self.db.remove_batch(keys).wait()
+    }
+
+    fn flush(&self) -> Result<(), Self::Error> {
+        self.db.flush().wait()
+    }
+
+    #[cfg(test)]
+    fn len(&self) -> Result<usize, Self::Error> {
+        self.db.len().wait()
+    }
+    #[cfg(test)]
+    fn is_empty(&self) -> Result<bool, Self::Error> {
+        self.db.is_empty().wait()
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl<A, H, C> PatriciaTrie<BlockingDB<A>, H, C>
+where
+    A: AsyncDB + 'static,
+    H: Hasher + 'static,
+    C: NodeCodec + 'static,
+{
+    /// Commits without blocking the event loop. The trie is encoded and
+    /// hashed on the blocking pool of the tokio thread pool that polls the
+    /// future, then the nodes are written through the "AsyncDB".
+    /// Resolves to the trie and the root hash. On error the trie comes back
+    /// with the error; if the writes failed, it is at the new root already,
+    /// like after a failed "commit".
+    pub fn commit_async(self) -> DBFuture<(Self, Vec<u8>), (Self, TrieError)> {
+        let mut trie = Some(self);
+        let prepare = future::poll_fn(move || {
+            let prepared = blocking(|| {
+                let mut trie = trie.take().expect("polled after completion");
+                let prepared = trie.prepare_commit().and_then(|mut prepared| {
+                    prepared.count_refs(&**trie.db())?;
+                    Ok(prepared)
+                });
+                match prepared {
+                    Ok(prepared) => Ok((trie, prepared)),
+                    Err(e) => Err((trie, e)),
+                }
+            });
+            match prepared {
+                Ok(Async::Ready(result)) => result.map(Async::Ready),
+                Ok(Async::NotReady) => Ok(Async::NotReady),
+                Err(e) => {
+                    let trie = trie.take().expect("polled after completion");
+                    Err((trie, TrieError::Runtime(e.to_string())))
+                }
+            }
+        });
+
+        Box::new(prepare.and_then(|(trie, prepared)| {
+            let db = Arc::clone(trie.db());
+            let (keys, values) = prepared.nodes.into_iter().unzip();
+            let removed = prepared.removed;
+            let root_hash = prepared.root_hash;
+            let (remove_db, flush_db) = (Arc::clone(&db), Arc::clone(&db));
+            db.db
+                .insert_batch(keys, values)
+                .and_then(move |()| remove_db.db.remove_batch(&removed))
+                .and_then(move |()| flush_db.db.flush())
+                .then(move |result| match result {
+                    Ok(()) => Ok((trie, root_hash)),
+                    Err(e) => Err((trie, TrieError::DB(e.to_string()))),
+                })
+        }))
+    }
+}
+
+/// "NodeFetcher" fetches encoded nodes by hash from elsewhere, e.g. from the
+/// peers of a light client. Resolves to None if the node is unknown.
+pub trait NodeFetcher: Send + Sync {
+    fn fetch(&self, hash: &[u8]) -> DBFuture<Option<Vec<u8>>, TrieError>;
+}
+
+/// "FetchingDB" reads a local "DB" and falls back to a "NodeFetcher" for the
+/// nodes it lacks, so that a trie opened on it, see "from_fetcher", loads
+/// the state it touches on demand. Fetched nodes are checked against their
+/// hash and written to the local DB. Only keys of the length of a hash are
+/// fetched, metadata is local. Fetches block like "BlockingDB".
+#[derive(Debug)]
+pub struct FetchingDB<D: DB, H: Hasher, F: NodeFetcher> {
+    db: Arc<D>,
+    hasher: Arc<H>,
+    fetcher: Arc<F>,
+}
+
+impl<D: DB, H: Hasher, F: NodeFetcher> FetchingDB<D, H, F> {
+    pub fn new(db: Arc<D>, hasher: Arc<H>, fetcher: Arc<F>) -> Self {
+        FetchingDB {
+            db,
+            hasher,
+            fetcher,
+        }
+    }
+
+    pub fn local(&self) -> &Arc<D> {
+        &self.db
+    }
+}
+
+impl<D: DB, H: Hasher, F: NodeFetcher> DB for FetchingDB<D, H, F> {
+    type Error = TrieError;
+
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+        if let Some(value) = self.db.get(key).map_err(|e| TrieError::DB(e.to_string()))? {
+            return Ok(Some(value));
+        }
+        if key.len() != H::LENGTH {
+            return Ok(None);
+        }
+        match self.fetcher.fetch(key).wait()? {
+            Some(data) => {
+                if self.hasher.digest(&data) != key {
+                    return Err(TrieError::InvalidData);
+                }
+                self.db
+                    .insert(key.to_vec(), data.clone())
+                    .map_err(|e| TrieError::DB(e.to_string()))?;
+                Ok(Some(data))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn contains(&self, key: &[u8]) -> Result<bool, Self::Error> {
+        Ok(self.get(key)?.is_some())
+    }
+
+    fn insert(&self, key: Vec<u8>, value: Vec<u8>) -> Result<(), Self::Error> {
+        self.db
+            .insert(key, value)
+            .map_err(|e| TrieError::DB(e.to_string()))
+    }
+
+    fn remove(&self, key: &[u8]) -> Result<(), Self::Error> {
+        self.db
+            .remove(key)
+            .map_err(|e| TrieError::DB(e.to_string()))
+    }
+
+    fn insert_batch(&self, keys: Vec<Vec<u8>>, values: Vec<Vec<u8>>) -> Result<(), Self::Error> {
+        self.db
+            .insert_batch(keys, values)
+            .map_err(|e| TrieError::DB(e.to_string()))
+    }
+
+    fn remove_batch(&self, keys: &[Vec<u8>]) -> Result<(), Self::Error> {
+        self.db
+            .remove_batch(keys)
+            .map_err(|e| TrieError::DB(e.to_string()))
+    }
+
+    fn flush(&self) -> Result<(), Self::Error> {
+        self.db.flush().map_err(|e| TrieError::DB(e.to_string()))
+    }
+
+    #[cfg(test)]
+    fn len(&self) -> Result<usize, Self::Error> {
+        self.db.len().map_err(|e| TrieError::DB(e.to_string()))
+    }
+    #[cfg(test)]
+    fn is_empty(&self) -> Result<bool, Self::Error> {
+        self.db.is_empty().map_err(|e| TrieError::DB(e.to_string()))
+    }
+}
+
+impl<D, H, F> PatriciaTrie<FetchingDB<D, H, F>, H>
+where
+    D: DB,
+    H: Hasher,
+    F: NodeFetcher,
+{
+    /// Opens the trie at "root" on "db", resolving the nodes "db" lacks with
+    /// "fetcher", the root included, see "FetchingDB".
+    pub fn from_fetcher(
+        db: Arc<D>,
+        hasher: Arc<H>,
+        fetcher: Arc<F>,
+        root: &[u8],
+    ) -> TrieResult<Self> {
+        let db = FetchingDB::new(db, Arc::clone(&hasher), fetcher);
+        PatriciaTrie::from(Arc::new(db), hasher, root)
+    }
+}
+
+/// Fetches every node on the path of "key" under "root" from the "source" store
+/// into the local "db", verifying each node against its hash.
+/// Once the future resolves, a trie opened on "db" can "get", "contains" and
+/// "get_proof" the key without touching "source".
+pub fn load_path<D, H, A>(
+    db: Arc<D>,
+    hasher: Arc<H>,
+    source: Arc<A>,
+    root: Vec<u8>,
+    key: Vec<u8>,
+) -> DBFuture<(), TrieError>
+where
+    D: DB + 'static,
+    H: Hasher + 'static,
+    A: AsyncDB + 'static,
+{
+    Box::new(future::loop_fn((), move |()| {
+        let missing = match next_missing(&db, &hasher, &root, &key) {
+            Ok(Some(hash)) => hash,
+            Ok(None) => return future::Either::A(future::ok(Loop::Break(()))),
+            Err(e) => return future::Either::A(future::err(e)),
+        };
+
+        let db = Arc::clone(&db);
+        let hasher = Arc::clone(&hasher);
+        let is_root = missing == root;
+        let fetch = source
+            .get(&missing)
+            .map_err(|e| TrieError::DB(e.to_string()))
+            .and_then(move |data| match data {
+                Some(data) => {
+                    if hasher.digest(&data) != missing {
+                        return Err(TrieError::InvalidData);
+                    }
+                    db.insert(missing, data)
+                        .map_err(|e| TrieError::DB(e.to_string()))?;
+                    Ok(Loop::Continue(()))
+                }
+                None if is_root => Err(TrieError::InvalidStateRoot),
+                None => Err(TrieError::InvalidData),
+            });
+        future::Either::B(fetch)
+    }))
+}
+
+fn next_missing<D, H>(
+    db: &Arc<D>,
+    hasher: &Arc<H>,
+    root: &[u8],
+    key: &[u8],
+) -> Result<Option<Vec<u8>>, TrieError>
+where
+    D: DB,
+    H: Hasher,
+{
+    if !db
+        .contains(root)
+        .map_err(|e| TrieError::DB(e.to_string()))?
+    {
+        return Ok(Some(root.to_vec()));
+    }
+    let trie = PatriciaTrie::from(Arc::clone(db), Arc::clone(hasher), root)?;
+    trie.missing_node(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use futures::future::{self, Future};
+    use hasher::HasherKeccak;
+
+    use super::{load_path, AsyncDB, BlockingDB, DBFuture, FetchingDB, NodeFetcher};
+    use crate::db::{MemoryDB, DB};
+    use crate::errors::{MemDBError, TrieError};
+    use crate::trie::{PatriciaTrie, Trie};
+
+    struct RemoteDB(Arc<MemoryDB>);
+
+    impl AsyncDB for RemoteDB {
+        type Error = MemDBError;
+
+        fn get(&self, key: &[u8]) -> DBFuture<Option<Vec<u8>>, Self::Error> {
+            Box::new(future::result(self.0.get(key)))
+        }
+
+        fn contains(&self, key: &[u8]) -> DBFuture<bool, Self::Error> {
+            Box::new(future::result(self.0.contains(key)))
+        }
+
+        fn insert(&self, key: Vec<u8>, value: Vec<u8>) -> DBFuture<(), Self::Error> {
+            Box::new(future::result(self.0.insert(key, value)))
+        }
+
+        fn remove(&self, key: &[u8]) -> DBFuture<(), Self::Error> {
+            Box::new(future::result(self.0.remove(key)))
+        }
+
+        fn flush(&self) -> DBFuture<(), Self::Error> {
+            Box::new(future::ok(()))
+        }
+
+        fn len(&self) -> DBFuture<usize, Self::Error> {
+            Box::new(future::result(self.0.len()))
+        }
+
+        fn is_empty(&self) -> DBFuture<bool, Self::Error> {
+            Box::new(future::result(self.0.is_empty()))
+        }
+    }
+
+    struct RemoteFetcher {
+        db: Arc<MemoryDB>,
+        // Answers with the wrong nodes if set.
+        corrupt: bool,
+    }
+
+    impl NodeFetcher for RemoteFetcher {
+        fn fetch(&self, hash: &[u8]) -> DBFuture<Option<Vec<u8>>, TrieError> {
+            match self.db.get(hash) {
+                Ok(Some(_)) if self.corrupt => Box::new(future::ok(Some(vec![0]))),
+                Ok(data) => Box::new(future::ok(data)),
+                Err(e) => Box::new(future::err(TrieError::DB(e.to_string()))),
+            }
+        }
+    }
+
+    fn remote_trie() -> (Arc<MemoryDB>, Vec<u8>) {
+        let memdb = Arc::new(MemoryDB::new(true));
+        let mut trie = PatriciaTrie::new(Arc::clone(&memdb), Arc::new(HasherKeccak::new()));
+        for i in 0..100u8 {
+            trie.insert(vec![i, i + 1, i + 2], vec![i; 40]).unwrap();
+        }
+        let root = trie.root().unwrap();
+        (memdb, root)
+    }
+
+    #[test]
+    fn test_blocking_db() {
+        let (memdb, root) = remote_trie();
+        let blocking = Arc::new(BlockingDB::new(RemoteDB(Arc::clone(&memdb))));
+        assert_eq!(blocking.len().unwrap(), memdb.len().unwrap());
+        assert!(!blocking.is_empty().unwrap());
+        let trie = PatriciaTrie::from(blocking, Arc::new(HasherKeccak::new()), &root).unwrap();
+        assert_eq!(trie.get(&[7, 8, 9]).unwrap(), Some(vec![7; 40]));
+    }
+
+    #[test]
+    #[cfg(feature = "tokio")]
+    fn test_commit_async() {
+        use tokio_threadpool::ThreadPool;
+
+        use crate::errors::TrieError;
+
+        let remote = Arc::new(MemoryDB::new(true));
+        let hasher = Arc::new(HasherKeccak::new());
+        let db = Arc::new(BlockingDB::new(RemoteDB(Arc::clone(&remote))));
+        let mut trie = PatriciaTrie::new(db, Arc::clone(&hasher));
+        let expected_db = Arc::new(MemoryDB::new(true));
+        let mut expected = PatriciaTrie::new(Arc::clone(&expected_db), Arc::clone(&hasher));
+        for i in 0..100u8 {
+            trie.insert(vec![i], vec![i; 40]).unwrap();
+            expected.insert(vec![i], vec![i; 40]).unwrap();
+        }
+
+        let pool = ThreadPool::new();
+        let (mut trie, root) = pool
+            .spawn_handle(trie.commit_async())
+            .wait()
+            .map_err(|(_, e)| e)
+            .unwrap();
+        assert_eq!(root, expected.root().unwrap());
+        assert_eq!(remote.len().unwrap(), expected_db.len().unwrap());
+
+        // The trie is loaded again from the written nodes.
+        assert_eq!(trie.get(&[7]).unwrap(), Some(vec![7; 40]));
+        trie.insert(vec![7], vec![107; 40]).unwrap();
+        expected.insert(vec![7], vec![107; 40]).unwrap();
+        let (trie, root) = pool
+            .spawn_handle(trie.commit_async())
+            .wait()
+            .map_err(|(_, e)| e)
+            .unwrap();
+        assert_eq!(root, expected.root().unwrap());
+        assert_eq!(remote.len().unwrap(), expected_db.len().unwrap());
+
+        // The blocking pool is only there inside a thread pool, the trie is
+        // kept on errors.
+        let mut trie = match trie.commit_async().wait() {
+            Err((trie, TrieError::Runtime(_))) => trie,
+            _ => panic!("committed outside a thread pool"),
+        };
+        trie.begin();
+        trie.insert(vec![8], vec![108; 40]).unwrap();
+        let mut trie = match pool.spawn_handle(trie.commit_async()).wait() {
+            Err((trie, TrieError::TransactionOpen)) => trie,
+            _ => panic!("committed with an open transaction"),
+        };
+        trie.rollback().unwrap();
+        assert_eq!(trie.get(&[8]).unwrap(), Some(vec![8; 40]));
+        let (_, root) = pool
+            .spawn_handle(trie.commit_async())
+            .wait()
+            .map_err(|(_, e)| e)
+            .unwrap();
+        assert_eq!(root, expected.root().unwrap());
+    }
+
+    #[test]
+    fn test_load_path() {
+        let (remote, root) = remote_trie();
+        let local = Arc::new(MemoryDB::new(true));
+        let hasher = Arc::new(HasherKeccak::new());
+
+        load_path(
+            Arc::clone(&local),
+            Arc::clone(&hasher),
+            Arc::new(RemoteDB(Arc::clone(&remote))),
+            root.clone(),
+            vec![7, 8, 9],
+        )
+        .wait()
+        .unwrap();
+
+        let trie = PatriciaTrie::from(Arc::clone(&local), hasher, &root).unwrap();
+        assert_eq!(trie.get(&[7, 8, 9]).unwrap(), Some(vec![7; 40]));
+        assert!(local.len().unwrap() < remote.len().unwrap());
+    }
+
+    #[test]
+    fn test_load_path_invalid_root() {
+        let (remote, _) = remote_trie();
+        let result = load_path(
+            Arc::new(MemoryDB::new(true)),
+            Arc::new(HasherKeccak::new()),
+            Arc::new(RemoteDB(remote)),
+            vec![0; 32],
+            vec![7, 8, 9],
+        )
+        .wait();
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_from_fetcher() {
+        let (remote, root) = remote_trie();
+        let local = Arc::new(MemoryDB::new(true));
+        let fetcher = Arc::new(RemoteFetcher {
+            db: Arc::clone(&remote),
+            corrupt: false,
+        });
+        let hasher = Arc::new(HasherKeccak::new());
+        let mut trie =
+            PatriciaTrie::from_fetcher(Arc::clone(&local), Arc::clone(&hasher), fetcher, &root)
+                .unwrap();
+
+        assert_eq!(trie.get(&[7, 8, 9]).unwrap(), Some(vec![7; 40]));
+        assert!(local.len().unwrap() < remote.len().unwrap());
+        trie.insert(vec![7, 8, 9], vec![1; 40]).unwrap();
+        trie.root().unwrap();
+        assert_eq!(trie.get(&[7, 8, 9]).unwrap(), Some(vec![1; 40]));
+
+        // Nodes that don't match their hash are refused.
+        let fetcher = Arc::new(RemoteFetcher {
+            db: remote,
+            corrupt: true,
+        });
+        let db = FetchingDB::new(Arc::new(MemoryDB::new(true)), hasher, fetcher);
+        match db.get(&root) {
+            Err(TrieError::InvalidData) => {}
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,156 @@
+use std::sync::Arc;
+
+use hashbrown::{HashMap, HashSet};
+use hasher::Hasher;
+
+use crate::codec::NodeCodec;
+use crate::db::DB;
+use crate::errors::TrieError;
+use crate::refcount::{merge_deltas, ref_count_writes};
+use crate::trie::{PatriciaTrie, TrieResult};
+
+/// "TrieBatch" commits several tries on the same DB, e.g. the account trie
+/// and the storage tries of a block, with a single batch write. With a DB
+/// whose batches are atomic, a crash leaves either all or none of the tries
+/// committed.
+#[derive(Debug)]
+pub struct TrieBatch<D: DB> {
+    db: Arc<D>,
+    nodes: Vec<(Vec<u8>, Vec<u8>)>,
+    removed: Vec<Vec<u8>>,
+    // The reference changes of the tries that count them, summed.
+    ref_deltas: HashMap<Vec<u8>, i32>,
+}
+
+impl<D: DB> TrieBatch<D> {
+    pub fn new(db: Arc<D>) -> Self {
+        TrieBatch {
+            db,
+            nodes: vec![],
+            removed: vec![],
+            ref_deltas: HashMap::new(),
+        }
+    }
+
+    /// Adds the changes of "trie" to the batch, returns the root hash it has
+    /// once the batch is committed. The trie must not be used until then, as
+    /// its nodes are only written by "commit".
+    pub fn add<H, C>(&mut self, trie: &mut PatriciaTrie<D, H, C>) -> TrieResult<Vec<u8>>
+    where
+        H: Hasher,
+        C: NodeCodec,
+    {
+        if !Arc::ptr_eq(trie.db(), &self.db) {
+            return Err(TrieError::DB("the trie uses another DB".to_owned()));
+        }
+        let prepared = trie.prepare_commit()?;
+        self.nodes.extend(prepared.nodes);
+        self.removed.extend(prepared.removed);
+        merge_deltas(&mut self.ref_deltas, prepared.ref_deltas);
+        Ok(prepared.root_hash)
+    }
+
+    /// Writes the changes of all added tries.
+    pub fn commit(mut self) -> TrieResult<()> {
+        let deltas: Vec<(Vec<u8>, i32)> = self.ref_deltas.drain().collect();
+        let writes = ref_count_writes(&*self.db, &deltas)?;
+        self.nodes.extend(writes.counts);
+        self.removed.extend(writes.removed);
+
+        // A node one trie made stale may be written by another.
+        let removed: Vec<Vec<u8>> = {
+            let written: HashSet<&[u8]> = self.nodes.iter().map(|(k, _)| &k[..]).collect();
+            self.removed
+                .iter()
+                .filter(|k| !written.contains(&k[..]))
+                .cloned()
+                .collect()
+        };
+
+        let (keys, values) = self.nodes.into_iter().unzip();
+        self.db
+            .insert_batch(keys, values)
+            .map_err(|e| TrieError::DB(e.to_string()))?;
+        self.db
+            .remove_batch(&removed)
+            .map_err(|e| TrieError::DB(e.to_string()))?;
+        self.db.flush().map_err(|e| TrieError::DB(e.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use hasher::HasherKeccak;
+
+    use super::TrieBatch;
+    use crate::db::{MemoryDB, DB};
+    use crate::refcount::ref_count;
+    use crate::trie::{PatriciaTrie, Trie};
+
+    #[test]
+    fn test_trie_batch() {
+        let memdb = Arc::new(MemoryDB::new(true));
+        let hasher = Arc::new(HasherKeccak::new());
+        let mut tries: Vec<_> = (0..3)
+            .map(|_| PatriciaTrie::new(Arc::clone(&memdb), Arc::clone(&hasher)))
+            .collect();
+        let mut expected = vec![];
+        for (t, trie) in tries.iter_mut().enumerate() {
+            let mut reference =
+                PatriciaTrie::new(Arc::new(MemoryDB::new(true)), Arc::clone(&hasher));
+            for i in 0..50u8 {
+                trie.insert(vec![t as u8, i], vec![i; 40]).unwrap();
+                reference.insert(vec![t as u8, i], vec![i; 40]).unwrap();
+            }
+            expected.push(reference.root().unwrap());
+        }
+
+        let mut batch = TrieBatch::new(Arc::clone(&memdb));
+        let roots: Vec<Vec<u8>> = tries.iter_mut().map(|t| batch.add(t).unwrap()).collect();
+        assert_eq!(roots, expected);
+        assert_eq!(memdb.len().unwrap(), 0);
+        batch.commit().unwrap();
+
+        for (t, trie) in tries.iter_mut().enumerate() {
+            assert_eq!(trie.get(&[t as u8, 7]).unwrap(), Some(vec![7; 40]));
+            trie.remove(&[t as u8, 7]).unwrap();
+        }
+        let mut other = PatriciaTrie::new(Arc::new(MemoryDB::new(true)), hasher);
+        let mut batch = TrieBatch::new(memdb);
+        assert!(batch.add(&mut other).is_err());
+        for trie in tries.iter_mut() {
+            batch.add(trie).unwrap();
+        }
+        batch.commit().unwrap();
+        assert_eq!(tries[1].get(&[1, 7]).unwrap(), None);
+        assert_eq!(tries[1].get(&[1, 8]).unwrap(), Some(vec![8; 40]));
+    }
+
+    #[test]
+    fn test_trie_batch_ref_counting() {
+        let memdb = Arc::new(MemoryDB::new(true));
+        let hasher = Arc::new(HasherKeccak::new());
+        let mut tries: Vec<_> = (0..2)
+            .map(|_| PatriciaTrie::new(Arc::clone(&memdb), Arc::clone(&hasher)).with_ref_counting())
+            .collect();
+        let mut batch = TrieBatch::new(Arc::clone(&memdb));
+        let mut roots = vec![];
+        for trie in tries.iter_mut() {
+            for i in 0..50u8 {
+                trie.insert(vec![i], vec![i; 40]).unwrap();
+            }
+            roots.push(batch.add(trie).unwrap());
+        }
+        batch.commit().unwrap();
+        assert_eq!(ref_count(&*memdb, &roots[0]).unwrap(), 2);
+
+        let mut batch = TrieBatch::new(Arc::clone(&memdb));
+        tries[0].clear().unwrap();
+        batch.add(&mut tries[0]).unwrap();
+        batch.commit().unwrap();
+        assert_eq!(ref_count(&*memdb, &roots[0]).unwrap(), 1);
+        assert_eq!(tries[1].get(&[7]).unwrap(), Some(vec![7; 40]));
+    }
+}
@@ -0,0 +1,435 @@
+use std::sync::Arc;
+
+use hashbrown::HashMap;
+use hasher::Hasher;
+use rlp::{Prototype, Rlp, RlpStream};
+
+use crate::db::{MemoryDB, DB};
+use crate::errors::TrieError;
+use crate::trie::{Trie, TrieResult};
+
+/// "BinaryTrie" branches on single bits of the key instead of nibbles, so a
+/// proof holds more nodes but each has two children instead of sixteen, and
+/// proofs are much smaller. Runs of bits without a branch are compressed
+/// into the nodes. Every byte of a key is preceded by a one bit and the key
+/// ends with a zero bit, so that no key's path is a prefix of another's.
+/// Replaced nodes are kept in the DB, as in archive mode.
+#[derive(Debug)]
+pub struct BinaryTrie<D: DB, H: Hasher> {
+    db: Arc<D>,
+    hasher: Arc<H>,
+    root: Vec<u8>,
+    // The nodes written since the last "root", by hash.
+    pending: HashMap<Vec<u8>, Vec<u8>>,
+}
+
+// The paths hold a bit per byte.
+#[derive(Debug)]
+enum BinaryNode {
+    Empty,
+    Leaf {
+        path: Vec<u8>,
+        value: Vec<u8>,
+    },
+    // The path is shared by both children, the next bit picks one.
+    Internal {
+        path: Vec<u8>,
+        children: [Vec<u8>; 2],
+    },
+}
+
+impl<D: DB, H: Hasher> BinaryTrie<D, H> {
+    pub fn new(db: Arc<D>, hasher: Arc<H>) -> Self {
+        let mut trie = BinaryTrie {
+            db,
+            hasher,
+            root: vec![],
+            pending: HashMap::new(),
+        };
+        trie.root = trie.store(&BinaryNode::Empty);
+        trie
+    }
+
+    pub fn from(db: Arc<D>, hasher: Arc<H>, root: &[u8]) -> TrieResult<Self> {
+        let mut trie = BinaryTrie::new(db, hasher);
+        if trie.root[..] != *root
+            && !trie
+                .db
+                .contains(root)
+                .map_err(|e| TrieError::DB(e.to_string()))?
+        {
+            return Err(TrieError::InvalidStateRoot);
+        }
+        trie.root = root.to_vec();
+        Ok(trie)
+    }
+
+    // Follows the path of "key" and passes every node on it to "visit".
+    fn walk<F>(&self, key: &[u8], mut visit: F) -> TrieResult<Option<Vec<u8>>>
+    where
+        F: FnMut(&[u8]),
+    {
+        let bits = key_bits(key);
+        let mut rest = &bits[..];
+        let mut hash = self.root.clone();
+        loop {
+            let data = self.load_data(&hash)?;
+            visit(&data);
+            match decode(&data)? {
+                BinaryNode::Empty => return Ok(None),
+                BinaryNode::Leaf { path, value } => {
+                    return Ok(if path[..] == *rest { Some(value) } else { None });
+                }
+                BinaryNode::Internal { path, children } => {
+                    if rest.len() <= path.len() || !rest.starts_with(&path) {
+                        return Ok(None);
+                    }
+                    let bit = rest[path.len()] as usize;
+                    rest = &rest[path.len() + 1..];
+                    hash = children[bit].clone();
+                }
+            }
+        }
+    }
+
+    fn insert_at(
+        &mut self,
+        node: BinaryNode,
+        bits: &[u8],
+        value: Vec<u8>,
+    ) -> TrieResult<BinaryNode> {
+        match node {
+            BinaryNode::Empty => Ok(BinaryNode::Leaf {
+                path: bits.to_vec(),
+                value,
+            }),
+            BinaryNode::Leaf { path, value: old } => {
+                if path[..] == *bits {
+                    return Ok(BinaryNode::Leaf { path, value });
+                }
+                let common = split_at(&path, bits)?;
+                let old = self.store(&BinaryNode::Leaf {
+                    path: path[common + 1..].to_vec(),
+                    value: old,
+                });
+                let new = self.store(&BinaryNode::Leaf {
+                    path: bits[common + 1..].to_vec(),
+                    value,
+                });
+                Ok(branch(&bits[..common], path[common], old, new))
+            }
+            BinaryNode::Internal { path, mut children } => {
+                if bits.len() > path.len() && bits.starts_with(&path) {
+                    let bit = bits[path.len()] as usize;
+                    let child = self.load(&children[bit])?;
+                    let child = self.insert_at(child, &bits[path.len() + 1..], value)?;
+                    children[bit] = self.store(&child);
+                    return Ok(BinaryNode::Internal { path, children });
+                }
+                let common = split_at(&path, bits)?;
+                let old = self.store(&BinaryNode::Internal {
+                    path: path[common + 1..].to_vec(),
+                    children,
+                });
+                let new = self.store(&BinaryNode::Leaf {
+                    path: bits[common + 1..].to_vec(),
+                    value,
+                });
+                Ok(branch(&bits[..common], path[common], old, new))
+            }
+        }
+    }
+
+    // Returns the node without the key, or None if the key is absent.
+    fn remove_at(&mut self, node: BinaryNode, bits: &[u8]) -> TrieResult<Option<BinaryNode>> {
+        match node {
+            BinaryNode::Empty => Ok(None),
+            BinaryNode::Leaf { path, .. } => Ok(if path[..] == *bits {
+                Some(BinaryNode::Empty)
+            } else {
+                None
+            }),
+            BinaryNode::Internal { path, mut children } => {
+                if bits.len() <= path.len() || !bits.starts_with(&path) {
+                    return Ok(None);
+                }
+                let bit = bits[path.len()] as usize;
+                let child = self.load(&children[bit])?;
+                match self.remove_at(child, &bits[path.len() + 1..])? {
+                    None => Ok(None),
+                    // The sibling takes the place of the node.
+                    Some(BinaryNode::Empty) => {
+                        let mut joined = path;
+                        joined.push(1 - bit as u8);
+                        match self.load(&children[1 - bit])? {
+                            BinaryNode::Empty => Err(TrieError::InvalidData),
+                            BinaryNode::Leaf { path, value } => {
+                                joined.extend(path);
+                                Ok(Some(BinaryNode::Leaf {
+                                    path: joined,
+                                    value,
+                                }))
+                            }
+                            BinaryNode::Internal { path, children } => {
+                                joined.extend(path);
+                                Ok(Some(BinaryNode::Internal {
+                                    path: joined,
+                                    children,
+                                }))
+                            }
+                        }
+                    }
+                    Some(child) => {
+                        children[bit] = self.store(&child);
+                        Ok(Some(BinaryNode::Internal { path, children }))
+                    }
+                }
+            }
+        }
+    }
+
+    fn load(&self, hash: &[u8]) -> TrieResult<BinaryNode> {
+        decode(&self.load_data(hash)?)
+    }
+
+    fn load_data(&self, hash: &[u8]) -> TrieResult<Vec<u8>> {
+        if let Some(data) = self.pending.get(hash) {
+            return Ok(data.clone());
+        }
+        self.db
+            .get(hash)
+            .map_err(|e| TrieError::DB(e.to_string()))?
+            .ok_or_else(|| TrieError::MissingNode {
+                hash: hash.to_vec(),
+            })
+    }
+
+    fn store(&mut self, node: &BinaryNode) -> Vec<u8> {
+        let data = encode(node);
+        let hash = self.hasher.digest(&data);
+        self.pending.insert(hash.clone(), data);
+        hash
+    }
+}
+
+impl<D: DB, H: Hasher> Trie<D, H> for BinaryTrie<D, H> {
+    fn get<K: AsRef<[u8]>>(&self, key: K) -> TrieResult<Option<Vec<u8>>> {
+        self.walk(key.as_ref(), |_| {})
+    }
+
+    fn contains<K: AsRef<[u8]>>(&self, key: K) -> TrieResult<bool> {
+        Ok(self.get(key)?.is_some())
+    }
+
+    /// An empty value removes the key, as in "PatriciaTrie".
+    fn insert<K: AsRef<[u8]>>(&mut self, key: K, value: Vec<u8>) -> TrieResult<()> {
+        if value.is_empty() {
+            self.remove(key)?;
+            return Ok(());
+        }
+        let root = self.load(&self.root)?;
+        let root = self.insert_at(root, &key_bits(key.as_ref()), value)?;
+        self.root = self.store(&root);
+        Ok(())
+    }
+
+    fn remove<K: AsRef<[u8]>>(&mut self, key: K) -> TrieResult<bool> {
+        let root = self.load(&self.root)?;
+        match self.remove_at(root, &key_bits(key.as_ref()))? {
+            Some(root) => {
+                self.root = self.store(&root);
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    fn root(&mut self) -> TrieResult<Vec<u8>> {
+        let (keys, values) = self.pending.drain().unzip();
+        self.db
+            .insert_batch(keys, values)
+            .map_err(|e| TrieError::DB(e.to_string()))?;
+        self.db.flush().map_err(|e| TrieError::DB(e.to_string()))?;
+        Ok(self.root.clone())
+    }
+
+    fn get_proof<K: AsRef<[u8]>>(&self, key: K) -> TrieResult<Vec<Vec<u8>>> {
+        let mut proof = vec![];
+        self.walk(key.as_ref(), |data| proof.push(data.to_vec()))?;
+        Ok(proof)
+    }
+
+    fn verify_proof<K: AsRef<[u8]>>(
+        &self,
+        root_hash: Vec<u8>,
+        key: K,
+        proof: Vec<Vec<u8>>,
+    ) -> TrieResult<Option<Vec<u8>>> {
+        let memdb = Arc::new(MemoryDB::new(true));
+        for node in proof.into_iter() {
+            memdb
+                .insert(self.hasher.digest(&node), node)
+                .map_err(|e| TrieError::DB(e.to_string()))?;
+        }
+        let trie = BinaryTrie::from(memdb, Arc::clone(&self.hasher), &root_hash)
+            .or(Err(TrieError::InvalidProof))?;
+        trie.get(key).or(Err(TrieError::InvalidProof))
+    }
+}
+
+// A node with "prefix" and two children, "old" on the side of "bit".
+fn branch(prefix: &[u8], bit: u8, old: Vec<u8>, new: Vec<u8>) -> BinaryNode {
+    let children = if bit == 0 { [old, new] } else { [new, old] };
+    BinaryNode::Internal {
+        path: prefix.to_vec(),
+        children,
+    }
+}
+
+// The length of the common prefix of two paths that must part after it,
+// which the paths of different keys always do.
+fn split_at(a: &[u8], b: &[u8]) -> TrieResult<usize> {
+    let common = a.iter().zip(b.iter()).take_while(|(x, y)| x == y).count();
+    if common >= a.len() || common >= b.len() {
+        return Err(TrieError::InvalidData);
+    }
+    Ok(common)
+}
+
+fn key_bits(key: &[u8]) -> Vec<u8> {
+    let mut bits = Vec::with_capacity(key.len() * 9 + 1);
+    for byte in key.iter() {
+        bits.push(1);
+        for i in (0..8).rev() {
+            bits.push((*byte >> i) & 1);
+        }
+    }
+    bits.push(0);
+    bits
+}
+
+// A path is packed in bytes after the number of bits the last byte leaves
+// unused.
+fn encode_bits(bits: &[u8]) -> Vec<u8> {
+    let mut out = vec![((8 - bits.len() % 8) % 8) as u8];
+    for chunk in bits.chunks(8) {
+        let byte = chunk
+            .iter()
+            .enumerate()
+            .fold(0u8, |byte, (i, bit)| byte | (bit << (7 - i)));
+        out.push(byte);
+    }
+    out
+}
+
+fn decode_bits(data: &[u8]) -> TrieResult<Vec<u8>> {
+    let (unused, packed) = data.split_first().ok_or(TrieError::InvalidData)?;
+    if *unused > 7 || (packed.is_empty() && *unused != 0) {
+        return Err(TrieError::InvalidData);
+    }
+    let mut bits = Vec::with_capacity(packed.len() * 8);
+    for byte in packed.iter() {
+        for i in (0..8).rev() {
+            bits.push((*byte >> i) & 1);
+        }
+    }
+    bits.truncate(bits.len() - *unused as usize);
+    Ok(bits)
+}
+
+fn encode(node: &BinaryNode) -> Vec<u8> {
+    match node {
+        BinaryNode::Empty => rlp::NULL_RLP.to_vec(),
+        BinaryNode::Leaf { path, value } => {
+            let mut stream = RlpStream::new_list(2);
+            stream.append(&encode_bits(path));
+            stream.append(value);
+            stream.out()
+        }
+        BinaryNode::Internal { path, children } => {
+            let mut stream = RlpStream::new_list(3);
+            stream.append(&encode_bits(path));
+            stream.append(&children[0]);
+            stream.append(&children[1]);
+            stream.out()
+        }
+    }
+}
+
+fn decode(data: &[u8]) -> TrieResult<BinaryNode> {
+    let r = Rlp::new(data);
+    match r.prototype()? {
+        Prototype::Data(0) => Ok(BinaryNode::Empty),
+        Prototype::List(2) => Ok(BinaryNode::Leaf {
+            path: decode_bits(r.at(0)?.data()?)?,
+            value: r.at(1)?.data()?.to_vec(),
+        }),
+        Prototype::List(3) => Ok(BinaryNode::Internal {
+            path: decode_bits(r.at(0)?.data()?)?,
+            children: [r.at(1)?.data()?.to_vec(), r.at(2)?.data()?.to_vec()],
+        }),
+        _ => Err(TrieError::InvalidData),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use hasher::{Hasher, HasherKeccak};
+
+    use super::BinaryTrie;
+    use crate::db::MemoryDB;
+    use crate::trie::{PatriciaTrie, Trie};
+
+    #[test]
+    fn test_binary_trie() {
+        let memdb = Arc::new(MemoryDB::new(true));
+        let hasher = Arc::new(HasherKeccak::new());
+        let mut trie = BinaryTrie::new(Arc::clone(&memdb), Arc::clone(&hasher));
+        let empty = trie.root().unwrap();
+        // Keys that are prefixes of each other, the empty key included.
+        let mut keys: Vec<Vec<u8>> = (0..=255u8)
+            .map(|i| vec![i; usize::from(i % 4)])
+            .chain((0..500u32).map(|i| hasher.digest(&i.to_be_bytes())))
+            .collect();
+        keys.sort();
+        keys.dedup();
+        for key in keys.iter() {
+            trie.insert(key, [&b"v"[..], key].concat()).unwrap();
+        }
+        let root = trie.root().unwrap();
+        assert_eq!(trie.get(&[]).unwrap(), Some(b"v".to_vec()));
+        assert_eq!(trie.get(&[5]).unwrap(), Some(b"v\x05".to_vec()));
+        assert_eq!(trie.get(&[4]).unwrap(), None);
+
+        // The root does not depend on the order of the inserts.
+        let mut other = BinaryTrie::new(Arc::new(MemoryDB::new(true)), Arc::clone(&hasher));
+        for key in keys.iter().rev() {
+            other.insert(key, [&b"v"[..], key].concat()).unwrap();
+        }
+        assert_eq!(other.root().unwrap(), root);
+
+        // Proofs of present and absent keys, smaller than with nibbles.
+        let mut patricia = PatriciaTrie::new(Arc::new(MemoryDB::new(true)), Arc::clone(&hasher));
+        for key in keys.iter() {
+            patricia.insert(key, [&b"v"[..], key].concat()).unwrap();
+        }
+        patricia.root().unwrap();
+        let size = |proof: &[Vec<u8>]| proof.iter().map(Vec::len).sum::<usize>();
+        let proof = trie.get_proof(&keys[300]).unwrap();
+        assert!(size(&proof) < size(&patricia.get_proof(&keys[300]).unwrap()));
+        let value = trie.verify_proof(root.clone(), &keys[300], proof).unwrap();
+        assert_eq!(value, Some([&b"v"[..], &keys[300]].concat()));
+        let proof = trie.get_proof(&[4]).unwrap();
+        assert_eq!(trie.verify_proof(root.clone(), &[4], proof).unwrap(), None);
+        assert!(trie.verify_proof(root.clone(), &keys[300], vec![]).is_err());
+
+        let mut trie = BinaryTrie::from(memdb, hasher, &root).unwrap();
+        assert!(!trie.remove(&[4]).unwrap());
+        for key in keys.iter() {
+            assert!(trie.remove(key).unwrap());
+        }
+        assert_eq!(trie.root().unwrap(), empty);
+    }
+}
@@ -0,0 +1,103 @@
+use hasher::Hasher;
+
+use crate::codec::NodeCodec;
+use crate::db::{BloomFilter, DB};
+use crate::errors::TrieError;
+use crate::trie::{PatriciaTrie, TrieResult};
+
+// The false positive rate of "with_bloom_filter" at the expected number of keys.
+const BLOOM_FALSE_POSITIVE_RATE: f64 = 0.01;
+
+// An empty filter over the keys of a trie, sized for "expected_keys".
+pub(crate) fn key_filter(expected_keys: usize) -> TrieResult<BloomFilter> {
+    BloomFilter::new(expected_keys, BLOOM_FALSE_POSITIVE_RATE).ok_or(TrieError::InvalidData)
+}
+
+impl<D, H, C> PatriciaTrie<D, H, C>
+where
+    D: DB,
+    H: Hasher,
+    C: NodeCodec,
+{
+    // Empties "bloom" and adds the keys of the trie.
+    pub(crate) fn fill_bloom(&self, bloom: &mut BloomFilter) -> TrieResult<()> {
+        bloom.clear();
+        let mut keys = self.iter();
+        while let Some((key, _)) = keys.try_next()? {
+            bloom.insert(&key);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use hasher::HasherKeccak;
+
+    use crate::db::{MemoryDB, MeteredDB};
+    use crate::trie::{PatriciaTrie, Trie};
+
+    #[test]
+    fn test_bloom_filter() {
+        let memdb = Arc::new(MeteredDB::new(MemoryDB::new(true)));
+        let hasher = Arc::new(HasherKeccak::new());
+        let mut trie = PatriciaTrie::new(Arc::clone(&memdb), Arc::clone(&hasher));
+        for i in 0..1000u32 {
+            trie.insert(i.to_be_bytes().to_vec(), vec![1; 40]).unwrap();
+        }
+        // A key that is also a prefix of others is stored in a branch.
+        trie.insert(vec![0, 0], b"branch value".to_vec()).unwrap();
+        let root = trie.root().unwrap();
+
+        let mut trie = PatriciaTrie::from(Arc::clone(&memdb), hasher, &root)
+            .unwrap()
+            .with_bloom_filter(2000)
+            .unwrap();
+        assert!(trie.memory_usage().bloom > 0);
+        for i in 0..1000u32 {
+            assert!(trie.contains(&i.to_be_bytes()).unwrap());
+        }
+        assert!(trie.contains(&[0, 0]).unwrap());
+
+        let gets = memdb.metrics().gets;
+        let found = (1000..2000u32)
+            .filter(|i| trie.contains(&i.to_be_bytes()).unwrap())
+            .count();
+        assert_eq!(found, 0);
+        assert!(memdb.metrics().gets - gets < 100);
+
+        trie.insert(b"new key".to_vec(), b"value".to_vec()).unwrap();
+        assert!(trie.contains(b"new key").unwrap());
+    }
+
+    #[test]
+    fn test_rewind_bloom_filter() {
+        let memdb = Arc::new(MemoryDB::new(true));
+        let hasher = Arc::new(HasherKeccak::new());
+        let mut trie =
+            PatriciaTrie::new(Arc::clone(&memdb), Arc::clone(&hasher)).with_reverse_diffs();
+        for i in 0..50u8 {
+            trie.insert(vec![i], vec![i; 40]).unwrap();
+        }
+        let r1 = trie.root().unwrap();
+        trie.remove(&[20]).unwrap();
+        let r2 = trie.root().unwrap();
+
+        // The filter of a trie opened at "r2" lacks the key "r1" has again.
+        let mut trie = PatriciaTrie::from(Arc::clone(&memdb), hasher, &r2)
+            .unwrap()
+            .with_reverse_diffs()
+            .with_bloom_filter(100)
+            .unwrap();
+        assert!(!trie.contains(&[20]).unwrap());
+        trie.rewind(&r1).unwrap();
+        assert!(trie.contains(&[20]).unwrap());
+
+        trie.clear().unwrap();
+        assert!(!trie.contains(&[7]).unwrap());
+        trie.discard().unwrap();
+        assert!(trie.contains(&[7]).unwrap());
+    }
+}
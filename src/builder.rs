@@ -0,0 +1,294 @@
+use std::mem;
+
+use hasher::Hasher;
+
+use crate::codec::{ChildReference, NodeCodec, NodeData, ShortItem};
+use crate::db::DB;
+use crate::errors::TrieError;
+use crate::nibbles::Nibbles;
+use crate::trie::TrieResult;
+
+// Finished nodes are written to the DB in batches of this size.
+const BATCH_SIZE: usize = 1024;
+
+// A branch that may still get children, "depth" nibbles below the root.
+struct OpenBranch {
+    depth: usize,
+    children: [Option<ChildReference>; 16],
+    value: Option<Vec<u8>>,
+}
+
+impl OpenBranch {
+    fn new(depth: usize) -> Self {
+        OpenBranch {
+            depth,
+            children: Default::default(),
+            value: None,
+        }
+    }
+}
+
+/// Builds a trie bottom-up from keys in ascending order. A node is encoded
+/// and written as soon as no later key can end up below it, so the trie is
+/// never held in memory.
+pub(crate) struct SortedBuilder<'a, D, H, C> {
+    db: &'a D,
+    hasher: &'a H,
+    codec: &'a C,
+    // The last key and its value, placed once the next key is known.
+    last: Option<(Nibbles, Vec<u8>)>,
+    // The open branches on the path of the last key, the deepest one last.
+    branches: Vec<OpenBranch>,
+    keys: Vec<Vec<u8>>,
+    values: Vec<Vec<u8>>,
+    // Every node is encoded here, then copied out once at its final size.
+    buffer: Vec<u8>,
+}
+
+impl<'a, D, H, C> SortedBuilder<'a, D, H, C>
+where
+    D: DB,
+    H: Hasher,
+    C: NodeCodec,
+{
+    pub fn new(db: &'a D, hasher: &'a H, codec: &'a C) -> Self {
+        SortedBuilder {
+            db,
+            hasher,
+            codec,
+            last: None,
+            branches: vec![],
+            keys: vec![],
+            values: vec![],
+            buffer: vec![],
+        }
+    }
+
+    /// Adds a key, which must be greater than all keys added before.
+    /// Empty values are skipped, as "insert" treats them as removals.
+    pub fn push(&mut self, key: Vec<u8>, value: Vec<u8>) -> TrieResult<()> {
+        let path = Nibbles::from_raw(key, true);
+        let (last_path, last_value) = match self.last.take() {
+            Some(last) => last,
+            None => {
+                if !value.is_empty() {
+                    self.last = Some((path, value));
+                }
+                return Ok(());
+            }
+        };
+        // Without the terminators nibbles sort like the raw keys.
+        let (a, b) = (last_path.get_data(), path.get_data());
+        if a[..a.len() - 1] >= b[..b.len() - 1] {
+            return Err(TrieError::UnsortedKeys);
+        }
+        if value.is_empty() {
+            self.last = Some((last_path, last_value));
+            return Ok(());
+        }
+
+        // The two keys part at "split", which needs a branch.
+        let split = last_path.common_prefix(&path);
+        match self.branches.last() {
+            Some(branch) if branch.depth >= split => {}
+            _ => self.branches.push(OpenBranch::new(split)),
+        }
+        self.add_leaf(&last_path, last_value)?;
+
+        // Branches below the split are complete.
+        while self.branches.last().map_or(false, |b| b.depth > split) {
+            let branch = self.branches.pop().unwrap();
+            match self.branches.last() {
+                Some(parent) if parent.depth >= split => {}
+                _ => self.branches.push(OpenBranch::new(split)),
+            }
+            self.add_branch(&last_path, branch)?;
+        }
+
+        self.last = Some((path, value));
+        Ok(())
+    }
+
+    /// Closes all open nodes, then writes the root and flushes the DB.
+    /// Returns the root hash.
+    pub fn finish(mut self) -> TrieResult<Vec<u8>> {
+        let root = match self.last.take() {
+            None => NodeData::Empty,
+            Some((path, value)) => {
+                if self.branches.is_empty() {
+                    leaf(&path, 0, value)
+                } else {
+                    self.add_leaf(&path, value)?;
+                    loop {
+                        let branch = self.branches.pop().unwrap();
+                        if self.branches.is_empty() {
+                            break self.close(&path, 0, branch)?;
+                        }
+                        self.add_branch(&path, branch)?;
+                    }
+                }
+            }
+        };
+
+        let data = self.codec.encode(&root);
+        let hash = self.hasher.digest(&data);
+        self.write(hash.clone(), data)?;
+        self.write_batch()?;
+        self.db.flush().map_err(|e| TrieError::DB(e.to_string()))?;
+        Ok(hash)
+    }
+
+    // Puts the leaf for "path" below the deepest open branch.
+    fn add_leaf(&mut self, path: &Nibbles, value: Vec<u8>) -> TrieResult<()> {
+        let depth = self.branches.last().unwrap().depth;
+        if path.at(depth) == 16 {
+            self.branches.last_mut().unwrap().value = Some(value);
+            return Ok(());
+        }
+        let reference = self.reference(leaf(path, depth + 1, value))?;
+        self.branches.last_mut().unwrap().children[path.at(depth)] = Some(reference);
+        Ok(())
+    }
+
+    // Puts a complete branch below the deepest open branch.
+    fn add_branch(&mut self, path: &Nibbles, branch: OpenBranch) -> TrieResult<()> {
+        let depth = self.branches.last().unwrap().depth;
+        let node = self.close(path, depth + 1, branch)?;
+        let reference = self.reference(node)?;
+        self.branches.last_mut().unwrap().children[path.at(depth)] = Some(reference);
+        Ok(())
+    }
+
+    // Returns the node for a complete branch whose path starts at "start",
+    // an extension covers the nibbles up to the branch.
+    fn close(&mut self, path: &Nibbles, start: usize, branch: OpenBranch) -> TrieResult<NodeData> {
+        let node = NodeData::Branch {
+            children: branch.children,
+            value: branch.value,
+        };
+        if branch.depth == start {
+            return Ok(node);
+        }
+        Ok(NodeData::Short {
+            path: path.get_data()[start..branch.depth].to_vec(),
+            item: ShortItem::Child(self.reference(node)?),
+        })
+    }
+
+    // Nodes shorter than a hash are stored inside their parent, all other
+    // nodes are written and referenced by their hash.
+    fn reference(&mut self, node: NodeData) -> TrieResult<ChildReference> {
+        self.buffer.clear();
+        self.codec.encode_into(&node, &mut self.buffer);
+        if self.buffer.len() < H::LENGTH {
+            return Ok(ChildReference::Inline(self.buffer.clone()));
+        }
+        let hash = self.hasher.digest(&self.buffer);
+        self.write(hash.clone(), self.buffer.clone())?;
+        Ok(ChildReference::Hash(hash))
+    }
+
+    fn write(&mut self, key: Vec<u8>, value: Vec<u8>) -> TrieResult<()> {
+        self.keys.push(key);
+        self.values.push(value);
+        if self.keys.len() >= BATCH_SIZE {
+            self.write_batch()?;
+        }
+        Ok(())
+    }
+
+    fn write_batch(&mut self) -> TrieResult<()> {
+        let keys = mem::replace(&mut self.keys, vec![]);
+        let values = mem::replace(&mut self.values, vec![]);
+        self.db
+            .insert_batch(keys, values)
+            .map_err(|e| TrieError::DB(e.to_string()))
+    }
+}
+
+// The leaf for "path" below the nibble at "start", without the terminator.
+fn leaf(path: &Nibbles, start: usize, value: Vec<u8>) -> NodeData {
+    let hex = path.get_data();
+    NodeData::Short {
+        path: hex[start..hex.len() - 1].to_vec(),
+        item: ShortItem::Value(value),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::sync::Arc;
+
+    use hasher::HasherKeccak;
+    use rand::Rng;
+
+    use crate::db::MemoryDB;
+    use crate::errors::TrieError;
+    use crate::trie::{PatriciaTrie, Trie};
+
+    #[test]
+    fn test_from_sorted_iter() {
+        let mut rng = rand::thread_rng();
+        let mut kvs = BTreeMap::new();
+        for _ in 0..1000 {
+            // Short keys from a small alphabet share prefixes and contain each other.
+            let key: Vec<u8> = (0..rng.gen_range(1, 5))
+                .map(|_| rng.gen_range(0, 4) * 17)
+                .collect();
+            let value: Vec<u8> = vec![rng.gen(); rng.gen_range(1, 40)];
+            kvs.insert(key, value);
+        }
+
+        let hasher = Arc::new(HasherKeccak::new());
+        let mut expected = PatriciaTrie::new(Arc::new(MemoryDB::new(true)), Arc::clone(&hasher));
+        for (k, v) in kvs.iter() {
+            expected.insert(k.clone(), v.clone()).unwrap();
+        }
+
+        let mut trie =
+            PatriciaTrie::from_sorted_iter(Arc::new(MemoryDB::new(true)), hasher, kvs.clone())
+                .unwrap();
+        assert_eq!(trie.root().unwrap(), expected.root().unwrap());
+        for (k, v) in kvs.into_iter() {
+            assert_eq!(trie.get(&k).unwrap(), Some(v));
+        }
+    }
+
+    #[test]
+    fn test_from_sorted_iter_small() {
+        let hasher = Arc::new(HasherKeccak::new());
+        let cases: Vec<Vec<(Vec<u8>, Vec<u8>)>> = vec![
+            vec![],
+            vec![(b"key".to_vec(), b"value".to_vec())],
+            vec![
+                (b"a".to_vec(), b"1".to_vec()),
+                (b"ab".to_vec(), b"2".to_vec()),
+            ],
+            vec![(b"a".to_vec(), vec![]), (b"b".to_vec(), b"2".to_vec())],
+        ];
+        for kvs in cases.into_iter() {
+            let mut expected =
+                PatriciaTrie::new(Arc::new(MemoryDB::new(true)), Arc::clone(&hasher));
+            for (k, v) in kvs.iter() {
+                expected.insert(k.clone(), v.clone()).unwrap();
+            }
+            let mut trie = PatriciaTrie::from_sorted_iter(
+                Arc::new(MemoryDB::new(true)),
+                Arc::clone(&hasher),
+                kvs,
+            )
+            .unwrap();
+            assert_eq!(trie.root().unwrap(), expected.root().unwrap());
+        }
+
+        let unsorted = vec![
+            (b"b".to_vec(), b"1".to_vec()),
+            (b"a".to_vec(), b"2".to_vec()),
+        ];
+        match PatriciaTrie::from_sorted_iter(Arc::new(MemoryDB::new(true)), hasher, unsorted) {
+            Err(TrieError::UnsortedKeys) => {}
+            other => panic!("unexpected {:?}", other.map(|_| ())),
+        }
+    }
+}
@@ -0,0 +1,33 @@
+use parking_lot::Mutex;
+
+use crate::codec::NodeData;
+use crate::lru::LRUCache;
+
+/// A node cache that several tries share, e.g. the storage tries of many
+/// contracts, which hold many identical nodes. Nodes are keyed by hash, so
+/// only tries with the same hasher and codec should share a cache.
+#[derive(Debug)]
+pub struct SharedNodeCache {
+    nodes: Mutex<LRUCache<Vec<u8>, NodeData>>,
+}
+
+impl SharedNodeCache {
+    /// Keeps up to "capacity" decoded nodes.
+    pub fn new(capacity: usize) -> Self {
+        SharedNodeCache {
+            nodes: Mutex::new(LRUCache::new(capacity)),
+        }
+    }
+
+    pub(crate) fn get(&self, key: &[u8]) -> Option<NodeData> {
+        self.nodes.lock().get(key).cloned()
+    }
+
+    pub(crate) fn insert(&self, key: Vec<u8>, node: NodeData) {
+        self.nodes.lock().insert(key, node);
+    }
+
+    pub(crate) fn remove(&self, key: &[u8]) {
+        self.nodes.lock().remove(key);
+    }
+}
@@ -0,0 +1,477 @@
+use rlp::{Prototype, Rlp};
+
+use crate::errors::TrieError;
+use crate::nibbles::{self, Nibbles};
+
+mod binary;
+
+pub use binary::BinaryNodeCodec;
+
+/// How a node refers to one of its children: by hash, or by embedding the
+/// encoded child directly when it is shorter than a hash.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChildReference {
+    Hash(Vec<u8>),
+    Inline(Vec<u8>),
+}
+
+/// What a "NodeData::Short" leads to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ShortItem {
+    Value(Vec<u8>),
+    Child(ChildReference),
+}
+
+/// A neutral description of a node, paths are nibbles, one per byte.
+/// The trie decides what a description means: a short node holding a value is
+/// a leaf, one holding a child an extension.
+// Only ever built to be encoded, or returned by "decode".
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NodeData {
+    Empty,
+    Short {
+        path: Vec<u8>,
+        item: ShortItem,
+    },
+    Branch {
+        children: [Option<ChildReference>; 16],
+        value: Option<Vec<u8>>,
+    },
+}
+
+/// "NodeCodec" defines how trie nodes are serialized, and so the root hash.
+/// Hashing is left to "Hasher", so any codec can be combined with any hash.
+pub trait NodeCodec: Send + Sync {
+    /// Appends the encoding of "node" to "out", so that callers can reuse
+    /// one buffer for many nodes.
+    fn encode_into(&self, node: &NodeData, out: &mut Vec<u8>);
+
+    fn encode(&self, node: &NodeData) -> Vec<u8> {
+        let mut out = vec![];
+        self.encode_into(node, &mut out);
+        out
+    }
+
+    /// Must return an error, never panic, on malformed data.
+    fn decode(&self, data: &[u8]) -> Result<NodeData, TrieError>;
+
+    /// Decodes what a lookup passing through the node needs: a short node in
+    /// full, but of a branch only the child at "index", or the value if
+    /// "index" is 16. The default decodes the whole node.
+    fn decode_at(&self, data: &[u8], _index: usize) -> Result<NodeData, TrieError> {
+        self.decode(data)
+    }
+
+    /// Whether an empty value of a branch decodes as such rather than as no
+    /// value, see "with_empty_values". RLP encodes both the same way.
+    fn keeps_empty_values(&self) -> bool {
+        false
+    }
+}
+
+/// The Ethereum encoding: nodes are RLP lists and keys are hex-prefix encoded.
+#[derive(Default, Debug, Clone, Copy)]
+pub struct RLPNodeCodec;
+
+impl RLPNodeCodec {
+    pub fn new() -> Self {
+        RLPNodeCodec
+    }
+}
+
+// The RLP encoding is written by hand, the nodes are simple enough and
+// "RlpStream" always allocates a buffer of its own.
+
+// The number of bytes needed for "n" in big endian, without leading zeros.
+fn length_of_length(n: usize) -> usize {
+    (64 - (n as u64).leading_zeros() as usize + 7) / 8
+}
+
+// The size of a string or list header for a payload of "len" bytes.
+fn header_size(len: usize) -> usize {
+    if len < 56 {
+        1
+    } else {
+        1 + length_of_length(len)
+    }
+}
+
+// "offset" is 0x80 for strings and 0xc0 for lists.
+fn write_header(out: &mut Vec<u8>, len: usize, offset: u8) {
+    if len < 56 {
+        out.push(offset + len as u8);
+    } else {
+        let size = length_of_length(len);
+        out.push(offset + 55 + size as u8);
+        out.extend_from_slice(&(len as u64).to_be_bytes()[8 - size..]);
+    }
+}
+
+// A single byte below 0x80 is its own encoding.
+fn string_size(bytes: &[u8]) -> usize {
+    match bytes {
+        [b] if *b < 0x80 => 1,
+        _ => header_size(bytes.len()) + bytes.len(),
+    }
+}
+
+fn write_string(out: &mut Vec<u8>, bytes: &[u8]) {
+    match bytes {
+        [b] if *b < 0x80 => out.push(*b),
+        _ => {
+            write_header(out, bytes.len(), 0x80);
+            out.extend_from_slice(bytes);
+        }
+    }
+}
+
+// The hex-prefix encoding of "path" is one flag byte, which also holds the
+// first nibble of odd paths, followed by the remaining nibbles in pairs.
+fn compact_size(path: &[u8]) -> usize {
+    let len = path.len() / 2 + 1;
+    // The flag byte is below 0x80, so a path of one byte is its own encoding.
+    if len == 1 {
+        1
+    } else {
+        header_size(len) + len
+    }
+}
+
+fn write_compact_string(out: &mut Vec<u8>, path: &[u8], is_leaf: bool) {
+    let len = path.len() / 2 + 1;
+    if len > 1 {
+        write_header(out, len, 0x80);
+    }
+    nibbles::write_compact(path, is_leaf, out);
+}
+
+fn child_size(child: &ChildReference) -> usize {
+    match child {
+        ChildReference::Hash(hash) => string_size(hash),
+        ChildReference::Inline(data) => data.len(),
+    }
+}
+
+fn write_child(out: &mut Vec<u8>, child: &ChildReference) {
+    match child {
+        ChildReference::Hash(hash) => write_string(out, hash),
+        // Inline nodes are encoded lists already.
+        ChildReference::Inline(data) => out.extend_from_slice(data),
+    }
+}
+
+fn decode_child(r: &Rlp) -> Result<Option<ChildReference>, TrieError> {
+    match r.prototype()? {
+        Prototype::Data(0) => Ok(None),
+        Prototype::Data(_) => Ok(Some(ChildReference::Hash(r.data()?.to_vec()))),
+        Prototype::List(_) => Ok(Some(ChildReference::Inline(r.as_raw().to_vec()))),
+        _ => Err(TrieError::InvalidData),
+    }
+}
+
+fn decode_value(r: &Rlp) -> Result<Option<Vec<u8>>, TrieError> {
+    if r.is_empty() {
+        Ok(None)
+    } else {
+        Ok(Some(r.data()?.to_vec()))
+    }
+}
+
+impl NodeCodec for RLPNodeCodec {
+    fn encode_into(&self, node: &NodeData, out: &mut Vec<u8>) {
+        match node {
+            NodeData::Empty => out.extend_from_slice(&rlp::NULL_RLP),
+            NodeData::Short { path, item } => {
+                // The hex-prefix flag tells leaves from extensions.
+                let (payload, is_leaf) = match item {
+                    ShortItem::Value(value) => (string_size(value), true),
+                    ShortItem::Child(child) => (child_size(child), false),
+                };
+                write_header(out, compact_size(path) + payload, 0xc0);
+                write_compact_string(out, path, is_leaf);
+                match item {
+                    ShortItem::Value(value) => write_string(out, value),
+                    ShortItem::Child(child) => write_child(out, child),
+                }
+            }
+            NodeData::Branch { children, value } => {
+                let value_size = value.as_ref().map_or(1, |v| string_size(v));
+                let payload = children
+                    .iter()
+                    .map(|child| child.as_ref().map_or(1, child_size))
+                    .sum::<usize>()
+                    + value_size;
+                write_header(out, payload, 0xc0);
+                for child in children.iter() {
+                    match child {
+                        Some(child) => write_child(out, child),
+                        None => out.push(0x80),
+                    }
+                }
+                match value {
+                    Some(v) => write_string(out, v),
+                    None => out.push(0x80),
+                }
+            }
+        }
+    }
+
+    fn decode(&self, data: &[u8]) -> Result<NodeData, TrieError> {
+        let r = Rlp::new(data);
+
+        match r.prototype()? {
+            Prototype::Data(0) => Ok(NodeData::Empty),
+            Prototype::List(2) => {
+                let key = r.at(0)?.data()?;
+                let key = Nibbles::from_compact(key.to_vec())?;
+
+                let hex = key.get_data();
+                if key.is_leaf() {
+                    Ok(NodeData::Short {
+                        path: hex[..hex.len() - 1].to_vec(),
+                        item: ShortItem::Value(r.at(1)?.data()?.to_vec()),
+                    })
+                } else {
+                    let child = decode_child(&r.at(1)?)?.ok_or(TrieError::InvalidData)?;
+                    Ok(NodeData::Short {
+                        path: hex.to_vec(),
+                        item: ShortItem::Child(child),
+                    })
+                }
+            }
+            Prototype::List(17) => {
+                let mut children: [Option<ChildReference>; 16] = Default::default();
+                for (i, child) in children.iter_mut().enumerate() {
+                    *child = decode_child(&r.at(i)?)?;
+                }
+
+                // The last element is a value node.
+                let value = decode_value(&r.at(16)?)?;
+
+                Ok(NodeData::Branch { children, value })
+            }
+            _ => Err(TrieError::InvalidData),
+        }
+    }
+
+    // RLP lists can be indexed without decoding the other elements.
+    fn decode_at(&self, data: &[u8], index: usize) -> Result<NodeData, TrieError> {
+        let r = Rlp::new(data);
+        match r.prototype()? {
+            Prototype::List(17) => {
+                let mut children: [Option<ChildReference>; 16] = Default::default();
+                let mut value = None;
+                if index < 16 {
+                    children[index] = decode_child(&r.at(index)?)?;
+                } else {
+                    value = decode_value(&r.at(16)?)?;
+                }
+                Ok(NodeData::Branch { children, value })
+            }
+            _ => self.decode(data),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::Rng;
+
+    use super::*;
+
+    #[test]
+    fn test_rlp_short_nodes() {
+        let codec = RLPNodeCodec::new();
+        let leaf = NodeData::Short {
+            path: vec![1, 2, 3],
+            item: ShortItem::Value(vec![0xaa; 32]),
+        };
+        let extension = NodeData::Short {
+            path: vec![1, 2, 3],
+            item: ShortItem::Child(ChildReference::Hash(vec![0xaa; 32])),
+        };
+
+        // Only the hex-prefix flag tells the two apart.
+        let (leaf_data, extension_data) = (codec.encode(&leaf), codec.encode(&extension));
+        assert_eq!(leaf_data.len(), extension_data.len());
+        assert_ne!(leaf_data, extension_data);
+        assert_eq!(codec.decode(&leaf_data).unwrap(), leaf);
+        assert_eq!(codec.decode(&extension_data).unwrap(), extension);
+    }
+
+    // The encoding "RLPNodeCodec" used to build with "RlpStream".
+    fn rlp_stream_encode(node: &NodeData) -> Vec<u8> {
+        use rlp::RlpStream;
+
+        fn append_child(stream: &mut RlpStream, child: &ChildReference) {
+            match child {
+                ChildReference::Hash(hash) => stream.append(&hash.as_slice()),
+                ChildReference::Inline(data) => stream.append_raw(data, 1),
+            };
+        }
+
+        let mut stream = RlpStream::new();
+        match node {
+            NodeData::Empty => return rlp::NULL_RLP.to_vec(),
+            NodeData::Short { path, item } => {
+                stream.begin_list(2);
+                match item {
+                    ShortItem::Value(value) => {
+                        let mut hex = path.clone();
+                        hex.push(16);
+                        stream.append(&Nibbles::from_hex(hex).encode_compact());
+                        stream.append(value);
+                    }
+                    ShortItem::Child(child) => {
+                        stream.append(&Nibbles::from_hex(path.clone()).encode_compact());
+                        append_child(&mut stream, child);
+                    }
+                }
+            }
+            NodeData::Branch { children, value } => {
+                stream.begin_list(17);
+                for child in children.iter() {
+                    match child {
+                        Some(child) => append_child(&mut stream, child),
+                        None => {
+                            stream.append_empty_data();
+                        }
+                    }
+                }
+                match value {
+                    Some(v) => stream.append(v),
+                    None => stream.append_empty_data(),
+                };
+            }
+        }
+        stream.out()
+    }
+
+    #[test]
+    fn test_encode_into() {
+        let mut rng = rand::thread_rng();
+        let mut random_bytes = |max: usize| -> Vec<u8> {
+            let len = rng.gen_range(0, max);
+            (0..len).map(|_| rng.gen()).collect()
+        };
+        let mut nodes = vec![NodeData::Empty];
+        for _ in 0..200 {
+            let path: Vec<u8> = random_bytes(80).into_iter().map(|b| b % 16).collect();
+            let value = random_bytes(300);
+            nodes.push(NodeData::Short {
+                path: path.clone(),
+                item: ShortItem::Value(value.clone()),
+            });
+            nodes.push(NodeData::Short {
+                path,
+                item: ShortItem::Child(ChildReference::Hash(vec![
+                    random_bytes(256).len() as u8;
+                    32
+                ])),
+            });
+            let mut children: [Option<ChildReference>; 16] = Default::default();
+            for child in children.iter_mut() {
+                *child = match random_bytes(3).len() {
+                    0 => None,
+                    1 => Some(ChildReference::Hash(vec![
+                        random_bytes(256).len() as u8;
+                        32
+                    ])),
+                    _ => Some(ChildReference::Inline(RLPNodeCodec.encode(
+                        &NodeData::Short {
+                            path: vec![1],
+                            item: ShortItem::Value(random_bytes(20)),
+                        },
+                    ))),
+                };
+            }
+            let value = if value.is_empty() { None } else { Some(value) };
+            nodes.push(NodeData::Branch { children, value });
+        }
+
+        let codecs: Vec<Box<dyn NodeCodec>> =
+            vec![Box::new(RLPNodeCodec), Box::new(BinaryNodeCodec)];
+        for node in nodes.iter() {
+            assert_eq!(RLPNodeCodec.encode(node), rlp_stream_encode(node));
+            for codec in codecs.iter() {
+                // The encoding is appended to what the buffer holds.
+                let mut out = b"prefix".to_vec();
+                codec.encode_into(node, &mut out);
+                assert_eq!(&out[..6], b"prefix");
+                assert_eq!(out[6..], codec.encode(node)[..]);
+                assert_eq!(&codec.decode(&out[6..]).unwrap(), node);
+            }
+        }
+    }
+
+    fn assert_decode_never_panics<C: NodeCodec>(codec: &C, valid: &[u8]) {
+        for len in 0..valid.len() {
+            assert!(codec.decode(&valid[..len]).is_err());
+        }
+
+        let mut rng = rand::thread_rng();
+        for _ in 0..1000 {
+            let mut data = valid.to_vec();
+            for _ in 0..rng.gen_range(1, 4) {
+                let i = rng.gen_range(0, data.len());
+                data[i] = rng.gen();
+            }
+            let _ = codec.decode(&data);
+            let _ = codec.decode_at(&data, rng.gen_range(0, 17));
+
+            let random: Vec<u8> = (0..rng.gen_range(0, 64)).map(|_| rng.gen()).collect();
+            let _ = codec.decode(&random);
+        }
+    }
+
+    #[test]
+    fn test_decode_malformed_data() {
+        let mut children: [Option<ChildReference>; 16] = Default::default();
+        children[3] = Some(ChildReference::Hash(vec![0xaa; 32]));
+        children[9] = Some(ChildReference::Inline(RLPNodeCodec.encode(
+            &NodeData::Short {
+                path: vec![1],
+                item: ShortItem::Value(b"v".to_vec()),
+            },
+        )));
+        let branch = NodeData::Branch {
+            children,
+            value: Some(b"value".to_vec()),
+        };
+
+        assert_decode_never_panics(&RLPNodeCodec, &RLPNodeCodec.encode(&branch));
+        assert_decode_never_panics(&BinaryNodeCodec, &BinaryNodeCodec.encode(&branch));
+    }
+
+    #[test]
+    fn test_decode_at() {
+        let mut children: [Option<ChildReference>; 16] = Default::default();
+        children[3] = Some(ChildReference::Hash(vec![0xaa; 32]));
+        children[9] = Some(ChildReference::Hash(vec![0xbb; 32]));
+        let branch = NodeData::Branch {
+            children: children.clone(),
+            value: Some(b"value".to_vec()),
+        };
+
+        let codecs: Vec<Box<dyn NodeCodec>> =
+            vec![Box::new(RLPNodeCodec), Box::new(BinaryNodeCodec)];
+        for codec in codecs.iter() {
+            let data = codec.encode(&branch);
+            for index in 0..=16 {
+                let mut expected: [Option<ChildReference>; 16] = Default::default();
+                let mut value = None;
+                if index < 16 {
+                    expected[index] = children[index].clone();
+                } else {
+                    value = Some(b"value".to_vec());
+                }
+                let node = NodeData::Branch {
+                    children: expected,
+                    value,
+                };
+                assert_eq!(codec.decode_at(&data, index).unwrap(), node);
+            }
+        }
+    }
+}
@@ -0,0 +1,364 @@
+use crate::codec::{ChildReference, NodeCodec, NodeData, ShortItem};
+use crate::errors::TrieError;
+
+const TAG_EMPTY: u8 = 0;
+const TAG_LEAF: u8 = 1;
+const TAG_EXTENSION: u8 = 2;
+const TAG_BRANCH: u8 = 3;
+const TAG_BRANCH_WITH_VALUE: u8 = 4;
+
+const CHILD_HASH: u8 = 0;
+const CHILD_INLINE: u8 = 1;
+
+/// A compact length-prefixed binary encoding, for users outside the Ethereum
+/// ecosystem. Lengths are LEB128 varints and nibbles are packed two per byte.
+///
+/// Layout:
+/// empty:     tag
+/// leaf:      tag | nibbles | value
+/// extension: tag | nibbles | child
+/// branch:    tag | children bitmap (u16 LE) | present children [| value]
+/// where nibbles are "count | packed", values "len | bytes" and children
+/// "kind | len | bytes".
+#[derive(Default, Debug, Clone, Copy)]
+pub struct BinaryNodeCodec;
+
+impl BinaryNodeCodec {
+    pub fn new() -> Self {
+        BinaryNodeCodec
+    }
+}
+
+impl NodeCodec for BinaryNodeCodec {
+    fn encode_into(&self, node: &NodeData, out: &mut Vec<u8>) {
+        match node {
+            NodeData::Empty => out.push(TAG_EMPTY),
+            NodeData::Short { path, item } => {
+                let tag = match item {
+                    ShortItem::Value(_) => TAG_LEAF,
+                    ShortItem::Child(_) => TAG_EXTENSION,
+                };
+                out.push(tag);
+                write_nibbles(out, path);
+                match item {
+                    ShortItem::Value(value) => write_bytes(out, value),
+                    ShortItem::Child(child) => write_child(out, child),
+                }
+            }
+            NodeData::Branch { children, value } => {
+                let tag = if value.is_some() {
+                    TAG_BRANCH_WITH_VALUE
+                } else {
+                    TAG_BRANCH
+                };
+                let bitmap = children
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, child)| child.is_some())
+                    .fold(0u16, |bitmap, (i, _)| bitmap | 1 << i);
+
+                out.push(tag);
+                out.extend_from_slice(&bitmap.to_le_bytes());
+                for child in children.iter() {
+                    if let Some(child) = child {
+                        write_child(out, child);
+                    }
+                }
+                if let Some(value) = value {
+                    write_bytes(out, value);
+                }
+            }
+        }
+    }
+
+    fn decode(&self, data: &[u8]) -> Result<NodeData, TrieError> {
+        let (&tag, mut rest) = data.split_first().ok_or(TrieError::InvalidData)?;
+        let node = match tag {
+            TAG_EMPTY => NodeData::Empty,
+            TAG_LEAF => NodeData::Short {
+                path: read_nibbles(&mut rest)?,
+                item: ShortItem::Value(read_bytes(&mut rest)?),
+            },
+            TAG_EXTENSION => NodeData::Short {
+                path: read_nibbles(&mut rest)?,
+                item: ShortItem::Child(read_child(&mut rest)?),
+            },
+            TAG_BRANCH | TAG_BRANCH_WITH_VALUE => {
+                let bitmap = read_bitmap(&mut rest)?;
+                let mut children: [Option<ChildReference>; 16] = Default::default();
+                for (i, child) in children.iter_mut().enumerate() {
+                    if bitmap & (1 << i) != 0 {
+                        *child = Some(read_child(&mut rest)?);
+                    }
+                }
+                let value = if tag == TAG_BRANCH_WITH_VALUE {
+                    Some(read_bytes(&mut rest)?)
+                } else {
+                    None
+                };
+                NodeData::Branch { children, value }
+            }
+            _ => return Err(TrieError::InvalidData),
+        };
+
+        // Trailing bytes would give a node several encodings.
+        if !rest.is_empty() {
+            return Err(TrieError::InvalidData);
+        }
+        Ok(node)
+    }
+
+    // The children before "index" are skipped, the ones after it not read.
+    fn decode_at(&self, data: &[u8], index: usize) -> Result<NodeData, TrieError> {
+        let (&tag, mut rest) = data.split_first().ok_or(TrieError::InvalidData)?;
+        if tag != TAG_BRANCH && tag != TAG_BRANCH_WITH_VALUE {
+            return self.decode(data);
+        }
+
+        let bitmap = read_bitmap(&mut rest)?;
+        let mut children: [Option<ChildReference>; 16] = Default::default();
+        for i in 0..16 {
+            if bitmap & (1 << i) == 0 {
+                continue;
+            }
+            if i == index {
+                children[i] = Some(read_child(&mut rest)?);
+                return Ok(NodeData::Branch {
+                    children,
+                    value: None,
+                });
+            }
+            skip_child(&mut rest)?;
+        }
+        let value = if tag == TAG_BRANCH_WITH_VALUE && index >= 16 {
+            Some(read_bytes(&mut rest)?)
+        } else {
+            None
+        };
+        Ok(NodeData::Branch { children, value })
+    }
+
+    fn keeps_empty_values(&self) -> bool {
+        true
+    }
+}
+
+fn read_bitmap(rest: &mut &[u8]) -> Result<u16, TrieError> {
+    if rest.len() < 2 {
+        return Err(TrieError::InvalidData);
+    }
+    let bitmap = u16::from(rest[0]) | u16::from(rest[1]) << 8;
+    *rest = &rest[2..];
+    Ok(bitmap)
+}
+
+fn write_varint(out: &mut Vec<u8>, mut n: usize) {
+    while n >= 0x80 {
+        out.push((n as u8) | 0x80);
+        n >>= 7;
+    }
+    out.push(n as u8);
+}
+
+fn read_varint(rest: &mut &[u8]) -> Result<usize, TrieError> {
+    let mut n = 0usize;
+    for shift in (0..64).step_by(7) {
+        let (&byte, tail) = rest.split_first().ok_or(TrieError::InvalidData)?;
+        *rest = tail;
+        n |= ((byte & 0x7f) as usize) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(n);
+        }
+    }
+    Err(TrieError::InvalidData)
+}
+
+fn write_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    write_varint(out, bytes.len());
+    out.extend_from_slice(bytes);
+}
+
+fn read_bytes(rest: &mut &[u8]) -> Result<Vec<u8>, TrieError> {
+    let len = read_varint(rest)?;
+    if rest.len() < len {
+        return Err(TrieError::InvalidData);
+    }
+    let bytes = rest[..len].to_vec();
+    *rest = &rest[len..];
+    Ok(bytes)
+}
+
+fn write_nibbles(out: &mut Vec<u8>, nibbles: &[u8]) {
+    write_varint(out, nibbles.len());
+    for pair in nibbles.chunks(2) {
+        let low = pair.get(1).cloned().unwrap_or(0);
+        out.push(pair[0] << 4 | low);
+    }
+}
+
+fn read_nibbles(rest: &mut &[u8]) -> Result<Vec<u8>, TrieError> {
+    let count = read_varint(rest)?;
+    // Not "(count + 1) / 2", which overflows on a malicious count.
+    let len = count / 2 + count % 2;
+    if rest.len() < len {
+        return Err(TrieError::InvalidData);
+    }
+    let mut nibbles = Vec::with_capacity(count);
+    for byte in rest[..len].iter() {
+        nibbles.push(byte >> 4);
+        nibbles.push(byte & 0x0f);
+    }
+    // An odd count leaves a padding nibble, which must be zero.
+    if nibbles.len() > count && nibbles.pop() != Some(0) {
+        return Err(TrieError::InvalidData);
+    }
+    *rest = &rest[len..];
+    Ok(nibbles)
+}
+
+fn write_child(out: &mut Vec<u8>, child: &ChildReference) {
+    match child {
+        ChildReference::Hash(hash) => {
+            out.push(CHILD_HASH);
+            write_bytes(out, hash);
+        }
+        ChildReference::Inline(data) => {
+            out.push(CHILD_INLINE);
+            write_bytes(out, data);
+        }
+    }
+}
+
+fn read_child(rest: &mut &[u8]) -> Result<ChildReference, TrieError> {
+    let (&kind, tail) = rest.split_first().ok_or(TrieError::InvalidData)?;
+    *rest = tail;
+    match kind {
+        CHILD_HASH => Ok(ChildReference::Hash(read_bytes(rest)?)),
+        CHILD_INLINE => Ok(ChildReference::Inline(read_bytes(rest)?)),
+        _ => Err(TrieError::InvalidData),
+    }
+}
+
+fn skip_child(rest: &mut &[u8]) -> Result<(), TrieError> {
+    match rest.split_first() {
+        Some((&kind, tail)) if kind == CHILD_HASH || kind == CHILD_INLINE => *rest = tail,
+        _ => return Err(TrieError::InvalidData),
+    }
+    let len = read_varint(rest)?;
+    if rest.len() < len {
+        return Err(TrieError::InvalidData);
+    }
+    *rest = &rest[len..];
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use hasher::HasherKeccak;
+    use rand::seq::SliceRandom;
+
+    use super::*;
+    use crate::db::MemoryDB;
+    use crate::trie::{PatriciaTrie, Trie};
+
+    #[test]
+    fn test_binary_round_trip() {
+        let codec = BinaryNodeCodec::new();
+        let leaf = NodeData::Short {
+            path: vec![1],
+            item: ShortItem::Value(b"v".to_vec()),
+        };
+        let mut children: [Option<ChildReference>; 16] = Default::default();
+        children[0] = Some(ChildReference::Hash(vec![0xaa; 32]));
+        children[15] = Some(ChildReference::Inline(codec.encode(&leaf)));
+
+        let nodes = vec![
+            NodeData::Empty,
+            NodeData::Short {
+                path: vec![],
+                item: ShortItem::Value(vec![0; 200]),
+            },
+            NodeData::Short {
+                path: vec![1, 2, 3],
+                item: ShortItem::Value(b"value".to_vec()),
+            },
+            NodeData::Short {
+                path: vec![15, 0],
+                item: ShortItem::Child(ChildReference::Hash(vec![0xbb; 32])),
+            },
+            NodeData::Branch {
+                children: children.clone(),
+                value: None,
+            },
+            NodeData::Branch {
+                children,
+                value: Some(vec![]),
+            },
+        ];
+        for node in nodes.iter() {
+            let data = codec.encode(node);
+            assert_eq!(&codec.decode(&data).unwrap(), node);
+            assert!(codec.decode(&data[..data.len() - 1]).is_err() || data.len() == 1);
+        }
+        assert!(codec.decode(&[TAG_LEAF, 1, 0x11, 0]).is_err());
+
+        // A nibble count of "usize::MAX".
+        let mut data = vec![TAG_LEAF];
+        write_varint(&mut data, usize::max_value());
+        assert!(codec.decode(&data).is_err());
+    }
+
+    #[test]
+    fn test_binary_root_stability() {
+        let mut kvs: Vec<(Vec<u8>, Vec<u8>)> = (0..100u8)
+            .map(|i| (vec![i / 10, i], vec![i; (i % 40) as usize + 1]))
+            .collect();
+        let root = |kvs: &[(Vec<u8>, Vec<u8>)]| {
+            let mut trie = PatriciaTrie::new_with_codec(
+                Arc::new(MemoryDB::new(true)),
+                Arc::new(HasherKeccak::new()),
+                Arc::new(BinaryNodeCodec::new()),
+            );
+            for (k, v) in kvs.iter() {
+                trie.insert(k.clone(), v.clone()).unwrap();
+            }
+            trie.root().unwrap()
+        };
+
+        let expected = root(&kvs);
+        kvs.shuffle(&mut rand::thread_rng());
+        assert_eq!(root(&kvs), expected);
+        assert_eq!(
+            hex::encode(expected),
+            "4d505d89d647cb957d39b06929478dcf1728e2e27b38ff9b3e8d63a1998cd84a"
+        );
+    }
+
+    #[test]
+    fn test_binary_trie() {
+        let memdb = Arc::new(MemoryDB::new(true));
+        let hasher = Arc::new(HasherKeccak::new());
+        let codec = Arc::new(BinaryNodeCodec::new());
+        let mut trie = PatriciaTrie::new_with_codec(
+            Arc::clone(&memdb),
+            Arc::clone(&hasher),
+            Arc::clone(&codec),
+        );
+        for i in 0..100u8 {
+            trie.insert(vec![i], vec![i; 40]).unwrap();
+        }
+        trie.remove(&[0]).unwrap();
+        let root = trie.root().unwrap();
+
+        let trie = PatriciaTrie::from_with_codec(memdb, hasher, codec, &root).unwrap();
+        assert_eq!(trie.get(&[0]).unwrap(), None);
+        for i in 1..100u8 {
+            assert_eq!(trie.get(&[i]).unwrap(), Some(vec![i; 40]));
+            let proof = trie.get_proof(&[i]).unwrap();
+            let value = trie.verify_proof(root.clone(), &[i], proof).unwrap();
+            assert_eq!(value, Some(vec![i; 40]));
+        }
+    }
+}
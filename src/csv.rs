@@ -0,0 +1,137 @@
+use std::io::{BufRead, Write};
+
+use hasher::Hasher;
+
+use crate::codec::NodeCodec;
+use crate::db::DB;
+use crate::errors::TrieError;
+use crate::records::{from_hex, to_hex, Record};
+use crate::trie::{PatriciaTrie, TrieResult};
+
+// The first line of "dump_csv".
+const HEADER: &str = "key,value,preimage";
+
+impl<D, H, C> PatriciaTrie<D, H, C>
+where
+    D: DB,
+    H: Hasher,
+    C: NodeCodec,
+{
+    /// Like "dump_json", but writes a line of CSV per key, after a
+    /// "key,value,preimage" header, e.g. "0x01,0x0203,0x04". The preimage is
+    /// left empty if unknown.
+    pub fn dump_csv<W: Write>(
+        &self,
+        root: &[u8],
+        mut writer: W,
+        preimages: bool,
+    ) -> TrieResult<u64> {
+        writeln!(writer, "{}", HEADER)?;
+        let count = self.dump_records(root, preimages, |key, value, preimage| {
+            let preimage = preimage.map(to_hex).unwrap_or_default();
+            writeln!(writer, "{},{},{}", to_hex(key), to_hex(value), preimage)?;
+            Ok(())
+        })?;
+        writer.flush()?;
+        Ok(count)
+    }
+
+    /// Like "load_json", but reads the lines of "dump_csv". The header is
+    /// optional.
+    pub fn load_csv<R: BufRead>(&self, reader: R) -> TrieResult<Self> {
+        self.load_records(reader.lines().filter_map(|line| match line {
+            Ok(ref line) if line.trim().is_empty() || line.trim() == HEADER => None,
+            Ok(line) => Some(read_record(&line)),
+            Err(e) => Some(Err(e.into())),
+        }))
+    }
+}
+
+// Parses a line of "dump_csv".
+fn read_record(line: &str) -> TrieResult<Record> {
+    let fields: Vec<&str> = line.trim().split(',').collect();
+    let (key, value, preimage) = match fields[..] {
+        [key, value] => (key, value, ""),
+        [key, value, preimage] => (key, value, preimage),
+        _ => return Err(TrieError::InvalidData),
+    };
+    let preimage = if preimage.is_empty() {
+        None
+    } else {
+        Some(from_hex(preimage)?)
+    };
+    Ok((from_hex(key)?, from_hex(value)?, preimage))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use hasher::{Hasher, HasherKeccak};
+
+    use crate::db::{MemoryDB, DB};
+    use crate::errors::TrieError;
+    use crate::gc::preimage_key;
+    use crate::trie::{PatriciaTrie, Trie};
+
+    #[test]
+    fn test_csv_roundtrip() {
+        let hasher = Arc::new(HasherKeccak::new());
+        let memdb = Arc::new(MemoryDB::new(true));
+        let mut trie = PatriciaTrie::new(Arc::clone(&memdb), Arc::clone(&hasher));
+        for i in 0..100u8 {
+            let key = hasher.digest(&[i]);
+            trie.insert(&key, vec![i; 3]).unwrap();
+            if i % 2 == 0 {
+                memdb.insert(preimage_key(&key), vec![i]).unwrap();
+            }
+        }
+        let root = trie.root().unwrap();
+
+        let mut csv = vec![];
+        assert_eq!(trie.dump_csv(&root, &mut csv, true).unwrap(), 100);
+        let text = String::from_utf8(csv.clone()).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), 101);
+        assert_eq!(lines[0], "key,value,preimage");
+        assert!(lines[1..].iter().any(|line| line.ends_with(',')));
+        assert!(lines[1..].iter().any(|line| line.ends_with(",0x00")));
+
+        let other = Arc::new(MemoryDB::new(true));
+        let empty = PatriciaTrie::new(Arc::clone(&other), Arc::clone(&hasher));
+        let mut loaded = empty.load_csv(&csv[..]).unwrap();
+        assert_eq!(loaded.root().unwrap(), root);
+        let key = preimage_key(&hasher.digest(&[8]));
+        assert_eq!(other.get(&key).unwrap(), Some(vec![8]));
+        let key = preimage_key(&hasher.digest(&[7]));
+        assert_eq!(other.get(&key).unwrap(), None);
+
+        // Lines out of order, and malformed lines.
+        let swapped = [lines[2], lines[1]].join("\n");
+        match empty.load_csv(swapped.as_bytes()) {
+            Err(TrieError::UnsortedKeys) => {}
+            other => panic!("unexpected {:?}", other.map(|_| ())),
+        }
+        assert!(empty.load_csv(&b"0x01"[..]).is_err());
+        assert!(empty.load_csv(&b"0x01,0x02,0x03,0x04"[..]).is_err());
+        assert!(empty.load_csv(&b"0x0,0x02"[..]).is_err());
+    }
+
+    #[test]
+    fn test_csv_missing_node() {
+        let hasher = Arc::new(HasherKeccak::new());
+        let memdb = Arc::new(MemoryDB::new(true));
+        let mut trie = PatriciaTrie::new(Arc::clone(&memdb), Arc::clone(&hasher));
+        for i in 0..=255u8 {
+            trie.insert(vec![i, i], vec![i; 40]).unwrap();
+        }
+        let root = trie.root().unwrap();
+        let missing = hasher.digest(&trie.get_proof(&[7, 7]).unwrap()[1]);
+        memdb.remove(&missing).unwrap();
+
+        match trie.dump_csv(&root, vec![], false) {
+            Err(TrieError::MissingNode { hash }) => assert_eq!(hash, missing),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+}
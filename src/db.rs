@@ -1,10 +1,37 @@
 use std::collections::HashMap;
 use std::error::Error;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::Path;
 use std::sync::Arc;
 
-use parking_lot::RwLock;
+use parking_lot::{Mutex, RwLock};
 
 use crate::errors::MemDBError;
+use crate::lru::LRUCache;
+
+mod bloom;
+mod compressed;
+mod encrypted;
+mod metered;
+#[cfg(feature = "mmap")]
+mod mmap;
+mod tiered;
+mod wal;
+
+pub use bloom::BloomDB;
+pub(crate) use bloom::BloomFilter;
+#[cfg(feature = "snappy")]
+pub use compressed::SnappyCompressor;
+pub use compressed::{CompressedDB, Compressor, RleCompressor};
+#[cfg(feature = "chacha20poly1305")]
+pub use encrypted::ChaCha20Poly1305Cipher;
+pub use encrypted::{Cipher, EncryptedDB};
+pub use metered::{DBMetrics, MeteredDB};
+#[cfg(feature = "mmap")]
+pub use mmap::MmapDB;
+pub use tiered::TieredDB;
+pub use wal::WalDB;
 
 /// "DB" defines the "trait" of trie and database interaction.
 /// You should first write the data to the cache and write the data
@@ -14,6 +41,12 @@ pub trait DB: Send + Sync {
 
     fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Self::Error>;
 
+    /// Get a batch of data, in the order of "keys".
+    /// Backends with a native multi get should override it.
+    fn get_batch(&self, keys: &[Vec<u8>]) -> Result<Vec<Option<Vec<u8>>>, Self::Error> {
+        keys.iter().map(|key| self.get(key)).collect()
+    }
+
     fn contains(&self, key: &[u8]) -> Result<bool, Self::Error>;
 
     /// Insert data into the cache.
@@ -43,51 +76,129 @@ pub trait DB: Send + Sync {
     /// Flush data to the DB from the cache.
     fn flush(&self) -> Result<(), Self::Error>;
 
+    /// Writes a consistent copy of the flushed data to "path", e.g. a RocksDB checkpoint.
+    /// Returns false if the backend doesn't support checkpoints.
+    fn checkpoint(&self, _path: &Path) -> Result<bool, Self::Error> {
+        Ok(false)
+    }
+
+    /// Replaces the data with a copy written by "checkpoint".
+    /// Returns false if the backend doesn't support checkpoints.
+    fn restore(&self, _path: &Path) -> Result<bool, Self::Error> {
+        Ok(false)
+    }
+
+    /// Lists every key, including pending writes, e.g. to collect garbage.
+    /// Returns None if the backend can't list its keys.
+    fn keys(&self) -> Result<Option<Vec<Vec<u8>>>, Self::Error> {
+        Ok(None)
+    }
+
     #[cfg(test)]
     fn len(&self) -> Result<usize, Self::Error>;
     #[cfg(test)]
     fn is_empty(&self) -> Result<bool, Self::Error>;
 }
 
-#[derive(Default, Debug)]
+// The number of independently locked shards of a "MemoryDB".
+const MEMDB_SHARDS: usize = 16;
+
+type Shard = RwLock<HashMap<Vec<u8>, Vec<u8>>>;
+
+// The keys of a bounded "MemoryDB" in use order.
+type LruKeys = Mutex<LRUCache<Vec<u8>, ()>>;
+
+/// "MemoryDB" splits its keys over several locks by key hash, so tries sharing
+/// one "Arc<MemoryDB>" across threads don't serialize on a single lock.
+#[derive(Debug)]
 pub struct MemoryDB {
     // If "light" is true, the data is deleted from the database at the time of submission.
     light: bool,
-    storage: Arc<RwLock<HashMap<Vec<u8>, Vec<u8>>>>,
+    shards: Arc<Vec<Shard>>,
+    // Only set for a bounded database, locked before any shard.
+    lru: Option<Arc<LruKeys>>,
 }
 
 impl MemoryDB {
     pub fn new(light: bool) -> Self {
         MemoryDB {
             light,
-            storage: Arc::new(RwLock::new(HashMap::new())),
+            shards: Self::new_shards(),
+            lru: None,
+        }
+    }
+
+    /// Creates a database holding at most "capacity" entries, the least recently
+    /// used entries are evicted first. Use it as a cache tier only, since evicted
+    /// data is lost regardless of "light".
+    pub fn bounded(light: bool, capacity: usize) -> Self {
+        MemoryDB {
+            light,
+            shards: Self::new_shards(),
+            lru: Some(Arc::new(Mutex::new(LRUCache::new(capacity)))),
         }
     }
+
+    fn new_shards() -> Arc<Vec<Shard>> {
+        Arc::new((0..MEMDB_SHARDS).map(|_| Shard::default()).collect())
+    }
+
+    fn shard(&self, key: &[u8]) -> &Shard {
+        &self.shards[fnv64(key) as usize % self.shards.len()]
+    }
+}
+
+impl Default for MemoryDB {
+    fn default() -> Self {
+        MemoryDB::new(false)
+    }
 }
 
 impl DB for MemoryDB {
     type Error = MemDBError;
 
     fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
-        if let Some(value) = self.storage.read().get(key) {
-            Ok(Some(value.clone()))
-        } else {
-            Ok(None)
+        // Keep the LRU order locked while reading, so a concurrent remove or
+        // eviction can't come between the read and the touch.
+        if let Some(lru) = &self.lru {
+            let mut lru = lru.lock();
+            let value = self.shard(key).read().get(key).cloned();
+            if value.is_some() {
+                lru.get(key);
+            }
+            return Ok(value);
         }
+        Ok(self.shard(key).read().get(key).cloned())
     }
 
     fn insert(&self, key: Vec<u8>, value: Vec<u8>) -> Result<(), Self::Error> {
-        self.storage.write().insert(key, value);
+        // Keep the LRU order locked until the shards agree with it.
+        if let Some(lru) = &self.lru {
+            let mut lru = lru.lock();
+            if lru.capacity() == 0 {
+                return Ok(());
+            }
+            if let Some((evicted, _)) = lru.insert(key.clone(), ()) {
+                self.shard(&evicted).write().remove(&evicted);
+            }
+        }
+        self.shard(&key).write().insert(key, value);
         Ok(())
     }
 
     fn contains(&self, key: &[u8]) -> Result<bool, Self::Error> {
-        Ok(self.storage.read().contains_key(key))
+        Ok(self.shard(key).read().contains_key(key))
     }
 
     fn remove(&self, key: &[u8]) -> Result<(), Self::Error> {
         if self.light {
-            self.storage.write().remove(key);
+            if let Some(lru) = &self.lru {
+                let mut lru = lru.lock();
+                lru.remove(key);
+                self.shard(key).write().remove(key);
+            } else {
+                self.shard(key).write().remove(key);
+            }
         }
         Ok(())
     }
@@ -96,14 +207,99 @@ impl DB for MemoryDB {
         Ok(())
     }
 
+    /// Writes every entry to a single file at "path".
+    fn checkpoint(&self, path: &Path) -> Result<bool, Self::Error> {
+        // All shards are locked before copying, so the copy is of one point in
+        // time. Writers hold a single shard at a time, so this can't deadlock.
+        let shards: Vec<_> = self.shards.iter().map(|shard| shard.read()).collect();
+        let mut data = vec![];
+        for shard in shards.iter() {
+            for (key, value) in shard.iter() {
+                write_bytes(&mut data, key);
+                write_bytes(&mut data, value);
+            }
+        }
+        drop(shards);
+        let mut file = File::create(path)?;
+        file.write_all(&data)?;
+        file.sync_all()?;
+        Ok(true)
+    }
+
+    fn restore(&self, path: &Path) -> Result<bool, Self::Error> {
+        let data = fs::read(path)?;
+        let mut entries = vec![];
+        let mut rest = &data[..];
+        while !rest.is_empty() {
+            match (read_bytes(&mut rest), read_bytes(&mut rest)) {
+                (Some(key), Some(value)) => entries.push((key, value)),
+                _ => {
+                    return Err(MemDBError::IO(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "truncated checkpoint",
+                    )))
+                }
+            }
+        }
+
+        if let Some(lru) = &self.lru {
+            let mut lru = lru.lock();
+            *lru = LRUCache::new(lru.capacity());
+        }
+        for shard in self.shards.iter() {
+            shard.write().clear();
+        }
+        for (key, value) in entries.into_iter() {
+            self.insert(key, value)?;
+        }
+        Ok(true)
+    }
+
+    fn keys(&self) -> Result<Option<Vec<Vec<u8>>>, Self::Error> {
+        let mut keys = vec![];
+        for shard in self.shards.iter() {
+            keys.extend(shard.read().keys().cloned());
+        }
+        Ok(Some(keys))
+    }
+
     #[cfg(test)]
     fn len(&self) -> Result<usize, Self::Error> {
-        Ok(self.storage.try_read().unwrap().len())
+        Ok(self.shards.iter().map(|shard| shard.read().len()).sum())
     }
     #[cfg(test)]
     fn is_empty(&self) -> Result<bool, Self::Error> {
-        Ok(self.storage.try_read().unwrap().is_empty())
+        Ok(self.shards.iter().all(|shard| shard.read().is_empty()))
+    }
+}
+
+// FNV-1a, used for checksums and filter indexes where a cryptographic hash isn't needed.
+pub(crate) fn fnv64(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf2_9ce4_8422_2325u64, |hash, b| {
+        (hash ^ u64::from(*b)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+// Length prefixed byte strings, used by the on-disk formats.
+pub(crate) fn write_bytes(data: &mut Vec<u8>, bytes: &[u8]) {
+    data.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+    data.extend_from_slice(bytes);
+}
+
+pub(crate) fn read_bytes(rest: &mut &[u8]) -> Option<Vec<u8>> {
+    if rest.len() < 4 {
+        return None;
+    }
+    let len = rest[..4]
+        .iter()
+        .rev()
+        .fold(0usize, |acc, b| (acc << 8) | *b as usize);
+    if rest.len() < 4 + len {
+        return None;
     }
+    let bytes = rest[4..4 + len].to_vec();
+    *rest = &rest[4 + len..];
+    Some(bytes)
 }
 
 #[cfg(test)]
@@ -121,6 +317,18 @@ mod tests {
         assert_eq!(v, b"test-value")
     }
 
+    #[test]
+    fn test_memdb_get_batch() {
+        let memdb = MemoryDB::new(true);
+        memdb.insert(b"a".to_vec(), b"a".to_vec()).unwrap();
+        memdb.insert(b"b".to_vec(), b"b".to_vec()).unwrap();
+
+        let values = memdb
+            .get_batch(&[b"b".to_vec(), b"c".to_vec(), b"a".to_vec()])
+            .unwrap();
+        assert_eq!(values, vec![Some(b"b".to_vec()), None, Some(b"a".to_vec())]);
+    }
+
     #[test]
     fn test_memdb_contains() {
         let memdb = MemoryDB::new(true);
@@ -139,4 +347,42 @@ mod tests {
         let contains = memdb.contains(b"test").unwrap();
         assert_eq!(contains, false)
     }
+
+    #[test]
+    fn test_memdb_bounded() {
+        let memdb = MemoryDB::bounded(true, 2);
+        memdb.insert(b"a".to_vec(), b"a".to_vec()).unwrap();
+        memdb.insert(b"b".to_vec(), b"b".to_vec()).unwrap();
+        memdb.get(b"a").unwrap();
+        memdb.insert(b"c".to_vec(), b"c".to_vec()).unwrap();
+
+        assert_eq!(memdb.len().unwrap(), 2);
+        assert_eq!(memdb.contains(b"a").unwrap(), true);
+        assert_eq!(memdb.contains(b"b").unwrap(), false);
+        assert_eq!(memdb.contains(b"c").unwrap(), true);
+
+        memdb.remove(b"a").unwrap();
+        memdb.insert(b"d".to_vec(), b"d".to_vec()).unwrap();
+        assert_eq!(memdb.contains(b"c").unwrap(), true);
+    }
+
+    #[test]
+    fn test_memdb_concurrent() {
+        let memdb = Arc::new(MemoryDB::new(true));
+        let handles: Vec<_> = (0..4u8)
+            .map(|t| {
+                let memdb = Arc::clone(&memdb);
+                std::thread::spawn(move || {
+                    for i in 0..100u8 {
+                        memdb.insert(vec![t, i], vec![i]).unwrap();
+                        assert_eq!(memdb.get(&[t, i]).unwrap(), Some(vec![i]));
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        assert_eq!(memdb.len().unwrap(), 400);
+    }
 }
@@ -0,0 +1,436 @@
+use std::collections::BTreeSet;
+use std::path::Path;
+
+use parking_lot::RwLock;
+
+use crate::db::{fnv64, DB};
+use crate::errors::BloomDBError;
+
+/// The key under which the filter is persisted in the wrapped DB: its number
+/// of hashes and of 64-bit words. The words are kept in chunks, under this key
+/// followed by ":" and the chunk index, so a flush only writes the chunks that
+/// changed. Node keys are hashes, so keys of these lengths never collide with
+/// them.
+pub const BLOOM_FILTER_KEY: &[u8] = b"cita-trie:bloom-filter";
+
+// The number of words per persisted chunk, 4 KiB.
+const CHUNK_WORDS: usize = 512;
+
+/// "BloomDB" keeps a bloom filter over every key written through it, so lookups
+/// of keys that were never written are answered without reading the wrapped DB.
+/// This saves round trips against slow backends during sync and healing, where
+/// most probes are for nodes that are not stored yet.
+/// The chunks of the filter written to since the last "flush" (i.e. commit)
+/// are persisted to the wrapped DB by it.
+#[derive(Debug)]
+pub struct BloomDB<D: DB> {
+    db: D,
+    capacity: usize,
+    false_positive_rate: f64,
+    filter: RwLock<PersistedFilter>,
+}
+
+#[derive(Debug)]
+struct PersistedFilter {
+    filter: BloomFilter,
+    // The chunks changed since the last flush.
+    dirty: BTreeSet<usize>,
+    // Whether the size of the filter must be written as well.
+    new: bool,
+}
+
+impl PersistedFilter {
+    // A filter that is not persisted yet.
+    fn new(filter: BloomFilter) -> Self {
+        let chunks = (filter.bits.len() + CHUNK_WORDS - 1) / CHUNK_WORDS;
+        PersistedFilter {
+            filter,
+            dirty: (0..chunks).collect(),
+            new: true,
+        }
+    }
+
+    fn insert(&mut self, key: &[u8]) {
+        let chunks: Vec<usize> = self
+            .filter
+            .indexes(key)
+            .map(|i| i / 64 / CHUNK_WORDS)
+            .collect();
+        self.dirty.extend(chunks);
+        self.filter.insert(key);
+    }
+}
+
+impl<D: DB> BloomDB<D> {
+    /// Creates a filter sized for "capacity" keys at the given false positive
+    /// rate, which must be between 0 and 1.
+    /// "db" must not contain any node yet, otherwise lookups of those nodes fail.
+    pub fn new(
+        db: D,
+        capacity: usize,
+        false_positive_rate: f64,
+    ) -> Result<Self, BloomDBError<D::Error>> {
+        let filter =
+            BloomFilter::new(capacity, false_positive_rate).ok_or(BloomDBError::InvalidRate)?;
+        Ok(BloomDB {
+            db,
+            capacity,
+            false_positive_rate,
+            filter: RwLock::new(PersistedFilter::new(filter)),
+        })
+    }
+
+    /// Restores the filter persisted in "db". If "db" has none, or it is
+    /// corrupt, a filter is built from the keys of "db" as "new" would size it,
+    /// for at least as many keys as "db" holds. Fails if "db" can't list them.
+    pub fn open(
+        db: D,
+        capacity: usize,
+        false_positive_rate: f64,
+    ) -> Result<Self, BloomDBError<D::Error>> {
+        let mut bloom = BloomDB::new(db, capacity, false_positive_rate)?;
+        bloom.filter = RwLock::new(bloom.load()?);
+        Ok(bloom)
+    }
+
+    /// Returns false if "key" was definitely never written to the DB.
+    pub fn may_contain(&self, key: &[u8]) -> bool {
+        self.filter.read().filter.may_contain(key)
+    }
+
+    /// Returns the wrapped DB.
+    pub fn into_inner(self) -> D {
+        self.db
+    }
+
+    fn load(&self) -> Result<PersistedFilter, BloomDBError<D::Error>> {
+        if let Some(filter) = self.read_filter()? {
+            return Ok(PersistedFilter {
+                filter,
+                dirty: BTreeSet::new(),
+                new: false,
+            });
+        }
+        let keys = self
+            .db
+            .keys()
+            .map_err(BloomDBError::DB)?
+            .ok_or(BloomDBError::MissingFilter)?;
+        let mut filter = PersistedFilter::new(
+            BloomFilter::new(self.capacity.max(keys.len()), self.false_positive_rate)
+                .ok_or(BloomDBError::InvalidRate)?,
+        );
+        for key in keys.iter() {
+            filter.insert(key);
+        }
+        Ok(filter)
+    }
+
+    // Reads the persisted filter, None if it is missing or corrupt.
+    fn read_filter(&self) -> Result<Option<BloomFilter>, BloomDBError<D::Error>> {
+        let header = match self.db.get(BLOOM_FILTER_KEY).map_err(BloomDBError::DB)? {
+            Some(header) => header,
+            None => return Ok(None),
+        };
+        if header.len() != 12 {
+            return Ok(None);
+        }
+        let hashes = read_le(&header[..4]) as u32;
+        let words = read_le(&header[4..]) as usize;
+        if hashes == 0 || words == 0 {
+            return Ok(None);
+        }
+        let keys: Vec<Vec<u8>> = (0..(words + CHUNK_WORDS - 1) / CHUNK_WORDS)
+            .map(chunk_key)
+            .collect();
+        let chunks = self.db.get_batch(&keys).map_err(BloomDBError::DB)?;
+        let mut bits = Vec::with_capacity(words);
+        for chunk in chunks.into_iter() {
+            match chunk {
+                Some(ref data) if data.len() % 8 == 0 => {
+                    bits.extend(data.chunks(8).map(read_le));
+                }
+                _ => return Ok(None),
+            }
+        }
+        if bits.len() != words {
+            return Ok(None);
+        }
+        Ok(Some(BloomFilter { bits, hashes }))
+    }
+}
+
+impl<D: DB> DB for BloomDB<D> {
+    type Error = BloomDBError<D::Error>;
+
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+        if !self.may_contain(key) {
+            return Ok(None);
+        }
+        self.db.get(key).map_err(BloomDBError::DB)
+    }
+
+    fn get_batch(&self, keys: &[Vec<u8>]) -> Result<Vec<Option<Vec<u8>>>, Self::Error> {
+        let candidates: Vec<Vec<u8>> = keys
+            .iter()
+            .filter(|key| self.may_contain(key))
+            .cloned()
+            .collect();
+        let mut values = self
+            .db
+            .get_batch(&candidates)
+            .map_err(BloomDBError::DB)?
+            .into_iter();
+        Ok(keys
+            .iter()
+            .map(|key| {
+                if self.may_contain(key) {
+                    values.next().and_then(|value| value)
+                } else {
+                    None
+                }
+            })
+            .collect())
+    }
+
+    fn contains(&self, key: &[u8]) -> Result<bool, Self::Error> {
+        if !self.may_contain(key) {
+            return Ok(false);
+        }
+        self.db.contains(key).map_err(BloomDBError::DB)
+    }
+
+    fn insert(&self, key: Vec<u8>, value: Vec<u8>) -> Result<(), Self::Error> {
+        self.filter.write().insert(&key);
+        self.db.insert(key, value).map_err(BloomDBError::DB)
+    }
+
+    fn remove(&self, key: &[u8]) -> Result<(), Self::Error> {
+        self.db.remove(key).map_err(BloomDBError::DB)
+    }
+
+    fn insert_batch(&self, keys: Vec<Vec<u8>>, values: Vec<Vec<u8>>) -> Result<(), Self::Error> {
+        {
+            let mut filter = self.filter.write();
+            for key in keys.iter() {
+                filter.insert(key);
+            }
+        }
+        self.db.insert_batch(keys, values).map_err(BloomDBError::DB)
+    }
+
+    fn remove_batch(&self, keys: &[Vec<u8>]) -> Result<(), Self::Error> {
+        self.db.remove_batch(keys).map_err(BloomDBError::DB)
+    }
+
+    fn flush(&self) -> Result<(), Self::Error> {
+        let (keys, values) = {
+            let mut persisted = self.filter.write();
+            let PersistedFilter { filter, dirty, new } = &mut *persisted;
+            let mut keys = Vec::with_capacity(dirty.len() + 1);
+            let mut values = Vec::with_capacity(dirty.len() + 1);
+            if *new {
+                let mut header = filter.hashes.to_le_bytes().to_vec();
+                header.extend_from_slice(&(filter.bits.len() as u64).to_le_bytes());
+                keys.push(BLOOM_FILTER_KEY.to_vec());
+                values.push(header);
+            }
+            for chunk in dirty.iter() {
+                let words = filter.bits.chunks(CHUNK_WORDS).nth(*chunk).unwrap_or(&[]);
+                keys.push(chunk_key(*chunk));
+                values.push(
+                    words
+                        .iter()
+                        .flat_map(|word| word.to_le_bytes().to_vec())
+                        .collect(),
+                );
+            }
+            dirty.clear();
+            *new = false;
+            (keys, values)
+        };
+        self.db
+            .insert_batch(keys, values)
+            .map_err(BloomDBError::DB)?;
+        self.db.flush().map_err(BloomDBError::DB)
+    }
+
+    /// Persists the filter first, so the checkpoint includes it.
+    fn checkpoint(&self, path: &Path) -> Result<bool, Self::Error> {
+        self.flush()?;
+        self.db.checkpoint(path).map_err(BloomDBError::DB)
+    }
+
+    /// Reloads the filter from the restored data.
+    fn restore(&self, path: &Path) -> Result<bool, Self::Error> {
+        if !self.db.restore(path).map_err(BloomDBError::DB)? {
+            return Ok(false);
+        }
+        *self.filter.write() = self.load()?;
+        Ok(true)
+    }
+
+    fn keys(&self) -> Result<Option<Vec<Vec<u8>>>, Self::Error> {
+        self.db.keys().map_err(BloomDBError::DB)
+    }
+
+    #[cfg(test)]
+    fn len(&self) -> Result<usize, Self::Error> {
+        self.db.len().map_err(BloomDBError::DB)
+    }
+    #[cfg(test)]
+    fn is_empty(&self) -> Result<bool, Self::Error> {
+        self.db.is_empty().map_err(BloomDBError::DB)
+    }
+}
+
+fn chunk_key(chunk: usize) -> Vec<u8> {
+    [BLOOM_FILTER_KEY, b":", &(chunk as u32).to_be_bytes()].concat()
+}
+
+fn read_le(bytes: &[u8]) -> u64 {
+    bytes
+        .iter()
+        .rev()
+        .fold(0u64, |acc, b| (acc << 8) | u64::from(*b))
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct BloomFilter {
+    bits: Vec<u64>,
+    hashes: u32,
+}
+
+impl BloomFilter {
+    /// Returns None unless "false_positive_rate" is between 0 and 1.
+    pub(crate) fn new(capacity: usize, false_positive_rate: f64) -> Option<Self> {
+        if !(false_positive_rate > 0.0 && false_positive_rate < 1.0) {
+            return None;
+        }
+        let capacity = capacity.max(1) as f64;
+        let ln2 = std::f64::consts::LN_2;
+        let num_bits = (-capacity * false_positive_rate.ln() / (ln2 * ln2)).ceil();
+        let num_bits = num_bits.max(64.0) as usize;
+        let hashes = ((num_bits as f64 / capacity) * ln2).round().max(1.0) as u32;
+
+        Some(BloomFilter {
+            bits: vec![0; (num_bits + 63) / 64],
+            hashes,
+        })
+    }
+
+    fn indexes<'a>(&'a self, key: &[u8]) -> impl Iterator<Item = usize> + 'a {
+        let num_bits = (self.bits.len() * 64) as u64;
+        let h1 = fnv64(key);
+        let h2 = fnv64(&h1.to_le_bytes()) | 1;
+        (0..u64::from(self.hashes))
+            .map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % num_bits) as usize)
+    }
+
+    pub(crate) fn insert(&mut self, key: &[u8]) {
+        let indexes: Vec<usize> = self.indexes(key).collect();
+        for i in indexes {
+            self.bits[i / 64] |= 1 << (i % 64);
+        }
+    }
+
+    pub(crate) fn may_contain(&self, key: &[u8]) -> bool {
+        self.indexes(key)
+            .all(|i| self.bits[i / 64] & (1 << (i % 64)) != 0)
+    }
+
+    pub(crate) fn clear(&mut self) {
+        for word in self.bits.iter_mut() {
+            *word = 0;
+        }
+    }
+
+    pub(crate) fn memory_usage(&self) -> usize {
+        self.bits.capacity() * 8
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{MemoryDB, MeteredDB};
+
+    #[test]
+    fn test_bloomdb_skips_unknown_keys() {
+        let bloom = BloomDB::new(MemoryDB::new(true), 1000, 0.01).unwrap();
+        bloom
+            .insert(b"test-key".to_vec(), b"test-value".to_vec())
+            .unwrap();
+        // Written behind the filter's back, so the filter rules it out.
+        bloom
+            .db
+            .insert(b"hidden".to_vec(), b"hidden".to_vec())
+            .unwrap();
+
+        assert_eq!(
+            bloom.get(b"test-key").unwrap(),
+            Some(b"test-value".to_vec())
+        );
+        assert_eq!(bloom.get(b"hidden").unwrap(), None);
+        assert_eq!(bloom.contains(b"hidden").unwrap(), false);
+    }
+
+    #[test]
+    fn test_bloomdb_persist() {
+        let bloom = BloomDB::new(MemoryDB::new(true), 1000, 0.01).unwrap();
+        for i in 0..100u8 {
+            bloom.insert(vec![i; 32], vec![i]).unwrap();
+        }
+        bloom.flush().unwrap();
+
+        let bloom = BloomDB::open(bloom.into_inner(), 1000, 0.01).unwrap();
+        for i in 0..100u8 {
+            assert!(bloom.may_contain(&[i; 32]));
+        }
+        let false_positives = (100..=255u8)
+            .filter(|i| bloom.may_contain(&[*i; 32]))
+            .count();
+        assert!(false_positives < 10);
+    }
+
+    #[test]
+    fn test_bloomdb_flush_changed_chunks() {
+        let bloom = BloomDB::new(MeteredDB::new(MemoryDB::new(true)), 100_000, 0.01).unwrap();
+        bloom.flush().unwrap();
+        let written = bloom.db.reset().inserted_batch_keys;
+        assert!(written > 10);
+
+        bloom.insert(vec![1; 32], vec![1]).unwrap();
+        bloom.db.reset();
+        bloom.flush().unwrap();
+        let written = bloom.db.metrics().inserted_batch_keys;
+        assert!(written >= 1 && written <= 7);
+        bloom.flush().unwrap();
+        assert_eq!(bloom.db.metrics().inserted_batch_keys, written);
+    }
+
+    #[test]
+    fn test_bloomdb_open_without_filter() {
+        let memdb = MemoryDB::new(true);
+        memdb.insert(b"test".to_vec(), b"test".to_vec()).unwrap();
+
+        // The filter is built from the keys in the DB.
+        let bloom = BloomDB::open(memdb, 1000, 0.01).unwrap();
+        assert_eq!(bloom.get(b"test").unwrap(), Some(b"test".to_vec()));
+        assert!(!bloom.may_contain(b"absent"));
+        bloom.flush().unwrap();
+        let bloom = BloomDB::open(bloom.into_inner(), 1000, 0.01).unwrap();
+        assert!(bloom.may_contain(b"test"));
+        assert!(!bloom.may_contain(b"absent"));
+    }
+
+    #[test]
+    fn test_bloomdb_invalid_rate() {
+        for rate in [0.0, 1.0, -0.5, std::f64::NAN].iter() {
+            match BloomDB::new(MemoryDB::new(true), 1000, *rate) {
+                Err(BloomDBError::InvalidRate) => {}
+                other => panic!("unexpected {:?}", other),
+            }
+        }
+    }
+}
@@ -0,0 +1,303 @@
+use std::path::Path;
+
+use crate::db::DB;
+use crate::errors::CompressedDBError;
+
+// Every stored value starts with one of these frame tags.
+const FRAME_RAW: u8 = 0;
+const FRAME_COMPRESSED: u8 = 1;
+
+/// "Compressor" is the algorithm used by "CompressedDB".
+/// Plug zstd, snappy, lz4 etc. in by implementing it.
+pub trait Compressor: Send + Sync {
+    fn compress(&self, data: &[u8]) -> Vec<u8>;
+
+    /// Returns None if "data" was not produced by "compress".
+    fn decompress(&self, data: &[u8]) -> Option<Vec<u8>>;
+}
+
+/// A dependency-free run-length compressor (PackBits style).
+/// RLP nodes are full of repeated bytes (e.g. the empty children of sparse
+/// branch nodes), which this handles well; hashes are stored as literals.
+#[derive(Default, Debug, Clone, Copy)]
+pub struct RleCompressor;
+
+const MIN_RUN: usize = 3;
+const MAX_RUN: usize = 128 + MIN_RUN - 1;
+const MAX_LITERAL: usize = 128;
+
+impl Compressor for RleCompressor {
+    // A control byte below 128 is followed by "control + 1" literal bytes,
+    // otherwise the next byte is repeated "control - 128 + MIN_RUN" times.
+    fn compress(&self, data: &[u8]) -> Vec<u8> {
+        let mut out = Vec::with_capacity(data.len());
+        let mut literal_start = 0;
+        let mut i = 0;
+        while i < data.len() {
+            let mut run = 1;
+            while i + run < data.len() && data[i + run] == data[i] && run < MAX_RUN {
+                run += 1;
+            }
+
+            if run >= MIN_RUN {
+                push_literals(&mut out, &data[literal_start..i]);
+                out.push((run - MIN_RUN + 128) as u8);
+                out.push(data[i]);
+                i += run;
+                literal_start = i;
+            } else {
+                i += run;
+            }
+        }
+        push_literals(&mut out, &data[literal_start..]);
+        out
+    }
+
+    fn decompress(&self, data: &[u8]) -> Option<Vec<u8>> {
+        let mut out = Vec::with_capacity(data.len() * 2);
+        let mut i = 0;
+        while i < data.len() {
+            let control = data[i] as usize;
+            i += 1;
+            if control < 128 {
+                let end = i + control + 1;
+                if end > data.len() {
+                    return None;
+                }
+                out.extend_from_slice(&data[i..end]);
+                i = end;
+            } else {
+                let byte = *data.get(i)?;
+                out.resize(out.len() + control - 128 + MIN_RUN, byte);
+                i += 1;
+            }
+        }
+        Some(out)
+    }
+}
+
+fn push_literals(out: &mut Vec<u8>, literals: &[u8]) {
+    for chunk in literals.chunks(MAX_LITERAL) {
+        out.push((chunk.len() - 1) as u8);
+        out.extend_from_slice(chunk);
+    }
+}
+
+/// Snappy, via the "snap" crate. It also finds repeats that are apart, e.g.
+/// the shared prefixes of values in a leaf, at a few times the cost of
+/// "RleCompressor".
+#[cfg(feature = "snappy")]
+#[derive(Default, Debug, Clone, Copy)]
+pub struct SnappyCompressor;
+
+#[cfg(feature = "snappy")]
+impl Compressor for SnappyCompressor {
+    // Only inputs over 4 GiB fail, they are then stored as is.
+    fn compress(&self, data: &[u8]) -> Vec<u8> {
+        snap::Encoder::new()
+            .compress_vec(data)
+            .unwrap_or_else(|_| data.to_vec())
+    }
+
+    fn decompress(&self, data: &[u8]) -> Option<Vec<u8>> {
+        snap::Decoder::new().decompress_vec(data).ok()
+    }
+}
+
+/// "CompressedDB" compresses values on write and decompresses them on read.
+/// Each value is framed with a tag byte, values that do not shrink are stored
+/// as is, so the overhead on incompressible nodes is a single byte.
+#[derive(Debug)]
+pub struct CompressedDB<D: DB, C: Compressor = RleCompressor> {
+    db: D,
+    compressor: C,
+}
+
+impl<D: DB> CompressedDB<D, RleCompressor> {
+    pub fn new(db: D) -> Self {
+        CompressedDB {
+            db,
+            compressor: RleCompressor,
+        }
+    }
+}
+
+impl<D: DB, C: Compressor> CompressedDB<D, C> {
+    pub fn with_compressor(db: D, compressor: C) -> Self {
+        CompressedDB { db, compressor }
+    }
+
+    /// Returns the wrapped DB.
+    pub fn inner(&self) -> &D {
+        &self.db
+    }
+
+    fn encode(&self, value: Vec<u8>) -> Vec<u8> {
+        let compressed = self.compressor.compress(&value);
+        let (tag, data) = if compressed.len() < value.len() {
+            (FRAME_COMPRESSED, compressed)
+        } else {
+            (FRAME_RAW, value)
+        };
+
+        let mut framed = Vec::with_capacity(data.len() + 1);
+        framed.push(tag);
+        framed.extend_from_slice(&data);
+        framed
+    }
+
+    fn decode(&self, framed: &[u8]) -> Result<Vec<u8>, CompressedDBError<D::Error>> {
+        match framed.split_first() {
+            Some((&FRAME_RAW, data)) => Ok(data.to_vec()),
+            Some((&FRAME_COMPRESSED, data)) => self
+                .compressor
+                .decompress(data)
+                .ok_or(CompressedDBError::InvalidData),
+            _ => Err(CompressedDBError::InvalidData),
+        }
+    }
+}
+
+impl<D: DB, C: Compressor> DB for CompressedDB<D, C> {
+    type Error = CompressedDBError<D::Error>;
+
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+        match self.db.get(key).map_err(CompressedDBError::DB)? {
+            Some(framed) => Ok(Some(self.decode(&framed)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn get_batch(&self, keys: &[Vec<u8>]) -> Result<Vec<Option<Vec<u8>>>, Self::Error> {
+        self.db
+            .get_batch(keys)
+            .map_err(CompressedDBError::DB)?
+            .into_iter()
+            .map(|framed| match framed {
+                Some(framed) => Ok(Some(self.decode(&framed)?)),
+                None => Ok(None),
+            })
+            .collect()
+    }
+
+    fn contains(&self, key: &[u8]) -> Result<bool, Self::Error> {
+        self.db.contains(key).map_err(CompressedDBError::DB)
+    }
+
+    fn insert(&self, key: Vec<u8>, value: Vec<u8>) -> Result<(), Self::Error> {
+        self.db
+            .insert(key, self.encode(value))
+            .map_err(CompressedDBError::DB)
+    }
+
+    fn remove(&self, key: &[u8]) -> Result<(), Self::Error> {
+        self.db.remove(key).map_err(CompressedDBError::DB)
+    }
+
+    fn insert_batch(&self, keys: Vec<Vec<u8>>, values: Vec<Vec<u8>>) -> Result<(), Self::Error> {
+        let values = values.into_iter().map(|v| self.encode(v)).collect();
+        self.db
+            .insert_batch(keys, values)
+            .map_err(CompressedDBError::DB)
+    }
+
+    fn remove_batch(&self, keys: &[Vec<u8>]) -> Result<(), Self::Error> {
+        self.db.remove_batch(keys).map_err(CompressedDBError::DB)
+    }
+
+    fn flush(&self) -> Result<(), Self::Error> {
+        self.db.flush().map_err(CompressedDBError::DB)
+    }
+
+    fn checkpoint(&self, path: &Path) -> Result<bool, Self::Error> {
+        self.db.checkpoint(path).map_err(CompressedDBError::DB)
+    }
+
+    fn restore(&self, path: &Path) -> Result<bool, Self::Error> {
+        self.db.restore(path).map_err(CompressedDBError::DB)
+    }
+
+    fn keys(&self) -> Result<Option<Vec<Vec<u8>>>, Self::Error> {
+        self.db.keys().map_err(CompressedDBError::DB)
+    }
+
+    #[cfg(test)]
+    fn len(&self) -> Result<usize, Self::Error> {
+        self.db.len().map_err(CompressedDBError::DB)
+    }
+    #[cfg(test)]
+    fn is_empty(&self) -> Result<bool, Self::Error> {
+        self.db.is_empty().map_err(CompressedDBError::DB)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use hasher::HasherKeccak;
+
+    use super::*;
+    use crate::db::MemoryDB;
+    use crate::trie::{PatriciaTrie, Trie};
+
+    #[test]
+    fn test_rle_round_trip() {
+        let cases: Vec<Vec<u8>> = vec![
+            vec![],
+            vec![1],
+            vec![0x80; 300],
+            (0..=255u8).collect(),
+            vec![1, 1, 2, 2, 2, 3, 3, 3, 3, 4],
+        ];
+        for data in cases.into_iter() {
+            let compressed = RleCompressor.compress(&data);
+            assert_eq!(RleCompressor.decompress(&compressed), Some(data));
+        }
+        assert_eq!(RleCompressor.decompress(&[5, 1]), None);
+    }
+
+    #[test]
+    #[cfg(feature = "snappy")]
+    fn test_snappy_round_trip() {
+        let value: Vec<u8> = (0..200u8).chain(0..200u8).collect();
+        let compressed = SnappyCompressor.compress(&value);
+        assert!(compressed.len() < value.len());
+        assert_eq!(
+            SnappyCompressor.decompress(&compressed),
+            Some(value.clone())
+        );
+        assert_eq!(SnappyCompressor.decompress(&[5, 1]), None);
+
+        let db = CompressedDB::with_compressor(MemoryDB::new(true), SnappyCompressor);
+        db.insert(b"key".to_vec(), value.clone()).unwrap();
+        assert!(db.inner().get(b"key").unwrap().unwrap().len() < value.len());
+        assert_eq!(db.get(b"key").unwrap(), Some(value));
+    }
+
+    #[test]
+    fn test_compresseddb_get() {
+        let db = CompressedDB::new(MemoryDB::new(true));
+        db.insert(b"sparse".to_vec(), vec![0x80; 100]).unwrap();
+        db.insert(b"dense".to_vec(), b"dense".to_vec()).unwrap();
+
+        assert_eq!(db.get(b"sparse").unwrap(), Some(vec![0x80; 100]));
+        assert_eq!(db.get(b"dense").unwrap(), Some(b"dense".to_vec()));
+        assert!(db.inner().get(b"sparse").unwrap().unwrap().len() < 10);
+    }
+
+    #[test]
+    fn test_compresseddb_trie() {
+        let db = Arc::new(CompressedDB::new(MemoryDB::new(true)));
+        let mut trie = PatriciaTrie::new(Arc::clone(&db), Arc::new(HasherKeccak::new()));
+        for i in 0..100u8 {
+            trie.insert(vec![i], vec![i; 40]).unwrap();
+        }
+        let root = trie.root().unwrap();
+
+        let trie = PatriciaTrie::from(db, Arc::new(HasherKeccak::new()), &root).unwrap();
+        for i in 0..100u8 {
+            assert_eq!(trie.get(&[i]).unwrap(), Some(vec![i; 40]));
+        }
+    }
+}
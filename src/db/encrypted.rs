@@ -0,0 +1,310 @@
+use std::path::Path;
+
+use crate::db::DB;
+use crate::errors::EncryptedDBError;
+
+#[cfg(feature = "chacha20poly1305")]
+const NONCE_LEN: usize = 12;
+#[cfg(feature = "chacha20poly1305")]
+const TAG_LEN: usize = 16;
+
+/// "Cipher" is the authenticated encryption scheme used by "EncryptedDB",
+/// e.g. AES-GCM or ChaCha20-Poly1305 holding the user's key.
+/// Implementations are responsible for generating a fresh nonce per call and
+/// storing it alongside the ciphertext.
+pub trait Cipher: Send + Sync {
+    /// Encrypts "plaintext" and authenticates it together with "aad".
+    fn encrypt(&self, aad: &[u8], plaintext: &[u8]) -> Vec<u8>;
+
+    /// Returns None if "ciphertext" or "aad" fail authentication.
+    fn decrypt(&self, aad: &[u8], ciphertext: &[u8]) -> Option<Vec<u8>>;
+}
+
+/// "ChaCha20Poly1305Cipher" is the ChaCha20-Poly1305 AEAD of RFC 8439.
+/// Every value is stored as a random 96-bit nonce, the ciphertext and the
+/// 16-byte tag, so a key should encrypt well below 2^32 values.
+#[cfg(feature = "chacha20poly1305")]
+pub struct ChaCha20Poly1305Cipher {
+    key: [u8; 32],
+}
+
+#[cfg(feature = "chacha20poly1305")]
+impl ChaCha20Poly1305Cipher {
+    pub fn new(key: [u8; 32]) -> Self {
+        ChaCha20Poly1305Cipher { key }
+    }
+
+    fn seal(&self, nonce: [u8; NONCE_LEN], aad: &[u8], plaintext: &[u8]) -> Vec<u8> {
+        let mut out = nonce.to_vec();
+        let tag = chacha20_poly1305_aead::encrypt(&self.key, &nonce, aad, plaintext, &mut out)
+            .expect("writing to a Vec does not fail");
+        out.extend_from_slice(&tag);
+        out
+    }
+}
+
+#[cfg(feature = "chacha20poly1305")]
+impl Cipher for ChaCha20Poly1305Cipher {
+    fn encrypt(&self, aad: &[u8], plaintext: &[u8]) -> Vec<u8> {
+        self.seal(rand::random(), aad, plaintext)
+    }
+
+    fn decrypt(&self, aad: &[u8], ciphertext: &[u8]) -> Option<Vec<u8>> {
+        if ciphertext.len() < NONCE_LEN + TAG_LEN {
+            return None;
+        }
+        let (nonce, rest) = ciphertext.split_at(NONCE_LEN);
+        let (body, tag) = rest.split_at(rest.len() - TAG_LEN);
+        let mut plaintext = Vec::with_capacity(body.len());
+        chacha20_poly1305_aead::decrypt(&self.key, nonce, aad, body, tag, &mut plaintext).ok()?;
+        Some(plaintext)
+    }
+}
+
+// The key is left out, so that it does not end up in logs.
+#[cfg(feature = "chacha20poly1305")]
+impl std::fmt::Debug for ChaCha20Poly1305Cipher {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str("ChaCha20Poly1305Cipher")
+    }
+}
+
+/// "EncryptedDB" encrypts values at rest.
+/// Keys (node hashes) are stored in the clear, so lookups and trie semantics are
+/// unchanged. Each key is passed as associated data, which prevents an attacker
+/// with write access from swapping encrypted nodes between keys.
+#[derive(Debug)]
+pub struct EncryptedDB<D: DB, C: Cipher> {
+    db: D,
+    cipher: C,
+}
+
+impl<D: DB, C: Cipher> EncryptedDB<D, C> {
+    pub fn new(db: D, cipher: C) -> Self {
+        EncryptedDB { db, cipher }
+    }
+
+    /// Returns the wrapped DB.
+    pub fn inner(&self) -> &D {
+        &self.db
+    }
+}
+
+impl<D: DB, C: Cipher> DB for EncryptedDB<D, C> {
+    type Error = EncryptedDBError<D::Error>;
+
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+        match self.db.get(key).map_err(EncryptedDBError::DB)? {
+            Some(ciphertext) => self
+                .cipher
+                .decrypt(key, &ciphertext)
+                .map(Some)
+                .ok_or(EncryptedDBError::Decryption),
+            None => Ok(None),
+        }
+    }
+
+    fn get_batch(&self, keys: &[Vec<u8>]) -> Result<Vec<Option<Vec<u8>>>, Self::Error> {
+        let values = self.db.get_batch(keys).map_err(EncryptedDBError::DB)?;
+        keys.iter()
+            .zip(values.into_iter())
+            .map(|(key, ciphertext)| match ciphertext {
+                Some(ciphertext) => self
+                    .cipher
+                    .decrypt(key, &ciphertext)
+                    .map(Some)
+                    .ok_or(EncryptedDBError::Decryption),
+                None => Ok(None),
+            })
+            .collect()
+    }
+
+    fn contains(&self, key: &[u8]) -> Result<bool, Self::Error> {
+        self.db.contains(key).map_err(EncryptedDBError::DB)
+    }
+
+    fn insert(&self, key: Vec<u8>, value: Vec<u8>) -> Result<(), Self::Error> {
+        let ciphertext = self.cipher.encrypt(&key, &value);
+        self.db
+            .insert(key, ciphertext)
+            .map_err(EncryptedDBError::DB)
+    }
+
+    fn remove(&self, key: &[u8]) -> Result<(), Self::Error> {
+        self.db.remove(key).map_err(EncryptedDBError::DB)
+    }
+
+    fn insert_batch(&self, keys: Vec<Vec<u8>>, values: Vec<Vec<u8>>) -> Result<(), Self::Error> {
+        let values = keys
+            .iter()
+            .zip(values.iter())
+            .map(|(key, value)| self.cipher.encrypt(key, value))
+            .collect();
+        self.db
+            .insert_batch(keys, values)
+            .map_err(EncryptedDBError::DB)
+    }
+
+    fn remove_batch(&self, keys: &[Vec<u8>]) -> Result<(), Self::Error> {
+        self.db.remove_batch(keys).map_err(EncryptedDBError::DB)
+    }
+
+    fn flush(&self) -> Result<(), Self::Error> {
+        self.db.flush().map_err(EncryptedDBError::DB)
+    }
+
+    fn checkpoint(&self, path: &Path) -> Result<bool, Self::Error> {
+        self.db.checkpoint(path).map_err(EncryptedDBError::DB)
+    }
+
+    fn restore(&self, path: &Path) -> Result<bool, Self::Error> {
+        self.db.restore(path).map_err(EncryptedDBError::DB)
+    }
+
+    fn keys(&self) -> Result<Option<Vec<Vec<u8>>>, Self::Error> {
+        self.db.keys().map_err(EncryptedDBError::DB)
+    }
+
+    #[cfg(test)]
+    fn len(&self) -> Result<usize, Self::Error> {
+        self.db.len().map_err(EncryptedDBError::DB)
+    }
+    #[cfg(test)]
+    fn is_empty(&self) -> Result<bool, Self::Error> {
+        self.db.is_empty().map_err(EncryptedDBError::DB)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use hasher::HasherKeccak;
+
+    use super::*;
+    use crate::db::{fnv64, MemoryDB};
+    use crate::trie::{PatriciaTrie, Trie};
+
+    // Not secure, only exercises the framing: XOR "encryption" plus a checksum tag.
+    struct XorCipher(u8);
+
+    impl XorCipher {
+        fn tag(&self, aad: &[u8], plaintext: &[u8]) -> [u8; 8] {
+            let mut data = aad.to_vec();
+            data.extend_from_slice(plaintext);
+            fnv64(&data).to_le_bytes()
+        }
+    }
+
+    impl Cipher for XorCipher {
+        fn encrypt(&self, aad: &[u8], plaintext: &[u8]) -> Vec<u8> {
+            let mut out: Vec<u8> = plaintext.iter().map(|b| b ^ self.0).collect();
+            out.extend_from_slice(&self.tag(aad, plaintext));
+            out
+        }
+
+        fn decrypt(&self, aad: &[u8], ciphertext: &[u8]) -> Option<Vec<u8>> {
+            if ciphertext.len() < 8 {
+                return None;
+            }
+            let (body, tag) = ciphertext.split_at(ciphertext.len() - 8);
+            let plaintext: Vec<u8> = body.iter().map(|b| b ^ self.0).collect();
+            if self.tag(aad, &plaintext)[..] != tag[..] {
+                return None;
+            }
+            Some(plaintext)
+        }
+    }
+
+    #[test]
+    fn test_encrypteddb_get() {
+        let db = EncryptedDB::new(MemoryDB::new(true), XorCipher(0x5a));
+        db.insert(b"test-key".to_vec(), b"test-value".to_vec())
+            .unwrap();
+
+        assert_eq!(db.get(b"test-key").unwrap(), Some(b"test-value".to_vec()));
+        assert_ne!(
+            db.inner().get(b"test-key").unwrap(),
+            Some(b"test-value".to_vec())
+        );
+    }
+
+    #[test]
+    fn test_encrypteddb_rejects_moved_values() {
+        let db = EncryptedDB::new(MemoryDB::new(true), XorCipher(0x5a));
+        db.insert(b"a".to_vec(), b"value".to_vec()).unwrap();
+        let ciphertext = db.inner().get(b"a").unwrap().unwrap();
+        db.inner().insert(b"b".to_vec(), ciphertext).unwrap();
+
+        assert!(db.get(b"b").is_err());
+    }
+
+    // The AEAD test vector of RFC 8439, section 2.8.2.
+    #[cfg(feature = "chacha20poly1305")]
+    #[test]
+    fn test_chacha20poly1305_test_vector() {
+        let mut key = [0u8; 32];
+        for (i, b) in key.iter_mut().enumerate() {
+            *b = 0x80 + i as u8;
+        }
+        let cipher = ChaCha20Poly1305Cipher::new(key);
+        let nonce = [
+            0x07, 0x00, 0x00, 0x00, 0x40, 0x41, 0x42, 0x43, 0x44, 0x45, 0x46, 0x47,
+        ];
+        let aad = hex::decode("50515253c0c1c2c3c4c5c6c7").unwrap();
+        let plaintext = b"Ladies and Gentlemen of the class of '99: If I could offer you \
+                          only one tip for the future, sunscreen would be it.";
+        let expected = hex::decode(
+            "d31a8d34648e60db7b86afbc53ef7ec2a4aded51296e08fea9e2b5a736ee62d6\
+             3dbea45e8ca9671282fafb69da92728b1a71de0a9e060b2905d6a5b67ecd3b36\
+             92ddbd7f2d778b8c9803aee328091b58fab324e4fad675945585808b4831d7bc\
+             3ff4def08e4b7a9de576d26586cec64b6116\
+             1ae10b594f09e26a7e902ecbd0600691",
+        )
+        .unwrap();
+
+        let sealed = cipher.seal(nonce, &aad, plaintext);
+        assert_eq!(&sealed[..12], &nonce[..]);
+        assert_eq!(&sealed[12..], &expected[..]);
+        assert_eq!(cipher.decrypt(&aad, &sealed), Some(plaintext.to_vec()));
+
+        let mut tampered = sealed.clone();
+        tampered[20] ^= 1;
+        assert_eq!(cipher.decrypt(&aad, &tampered), None);
+        assert_eq!(cipher.decrypt(b"other", &sealed), None);
+        assert_eq!(cipher.decrypt(&aad, &sealed[..27]), None);
+
+        // Fresh nonces, so equal values do not encrypt alike.
+        assert_ne!(
+            cipher.encrypt(&aad, plaintext),
+            cipher.encrypt(&aad, plaintext)
+        );
+    }
+
+    #[cfg(feature = "chacha20poly1305")]
+    #[test]
+    fn test_encrypteddb_chacha20poly1305() {
+        let db = EncryptedDB::new(MemoryDB::new(true), ChaCha20Poly1305Cipher::new([7; 32]));
+        db.insert(b"a".to_vec(), b"value".to_vec()).unwrap();
+        assert_eq!(db.get(b"a").unwrap(), Some(b"value".to_vec()));
+        let ciphertext = db.inner().get(b"a").unwrap().unwrap();
+        assert_eq!(ciphertext.len(), 12 + 5 + 16);
+        db.inner().insert(b"b".to_vec(), ciphertext).unwrap();
+        assert!(db.get(b"b").is_err());
+    }
+
+    #[test]
+    fn test_encrypteddb_trie() {
+        let db = Arc::new(EncryptedDB::new(MemoryDB::new(true), XorCipher(0x5a)));
+        let mut trie = PatriciaTrie::new(Arc::clone(&db), Arc::new(HasherKeccak::new()));
+        for i in 0..100u8 {
+            trie.insert(vec![i], vec![i; 40]).unwrap();
+        }
+        let root = trie.root().unwrap();
+
+        let trie = PatriciaTrie::from(db, Arc::new(HasherKeccak::new()), &root).unwrap();
+        for i in 0..100u8 {
+            assert_eq!(trie.get(&[i]).unwrap(), Some(vec![i; 40]));
+        }
+    }
+}
@@ -0,0 +1,248 @@
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use crate::db::DB;
+
+/// A point in time copy of the counters of a "MeteredDB".
+/// Times are the total spent in the wrapped DB for each kind of operation.
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
+pub struct DBMetrics {
+    pub gets: u64,
+    pub get_batches: u64,
+    pub hits: u64,
+    pub misses: u64,
+    pub contains: u64,
+    pub inserts: u64,
+    pub removes: u64,
+    pub insert_batches: u64,
+    pub inserted_batch_keys: u64,
+    pub remove_batches: u64,
+    pub removed_batch_keys: u64,
+    pub flushes: u64,
+    pub get_time: Duration,
+    pub write_time: Duration,
+    pub flush_time: Duration,
+}
+
+#[derive(Default, Debug)]
+struct Counters {
+    gets: AtomicU64,
+    get_batches: AtomicU64,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    contains: AtomicU64,
+    inserts: AtomicU64,
+    removes: AtomicU64,
+    insert_batches: AtomicU64,
+    inserted_batch_keys: AtomicU64,
+    remove_batches: AtomicU64,
+    removed_batch_keys: AtomicU64,
+    flushes: AtomicU64,
+    // In nanoseconds.
+    get_time: AtomicU64,
+    write_time: AtomicU64,
+    flush_time: AtomicU64,
+}
+
+/// "MeteredDB" counts the operations issued to the wrapped DB and measures how
+/// long they take, e.g. to see how many reads a single trie "get" triggers.
+#[derive(Debug)]
+pub struct MeteredDB<D: DB> {
+    db: D,
+    counters: Counters,
+}
+
+impl<D: DB> MeteredDB<D> {
+    pub fn new(db: D) -> Self {
+        MeteredDB {
+            db,
+            counters: Counters::default(),
+        }
+    }
+
+    /// Returns the wrapped DB.
+    pub fn inner(&self) -> &D {
+        &self.db
+    }
+
+    pub fn metrics(&self) -> DBMetrics {
+        self.counters
+            .snapshot(|counter| counter.load(Ordering::Relaxed))
+    }
+
+    /// Resets every counter to zero and returns their previous values.
+    pub fn reset(&self) -> DBMetrics {
+        self.counters
+            .snapshot(|counter| counter.swap(0, Ordering::Relaxed))
+    }
+}
+
+impl Counters {
+    fn snapshot(&self, load: impl Fn(&AtomicU64) -> u64) -> DBMetrics {
+        let c = self;
+        DBMetrics {
+            gets: load(&c.gets),
+            get_batches: load(&c.get_batches),
+            hits: load(&c.hits),
+            misses: load(&c.misses),
+            contains: load(&c.contains),
+            inserts: load(&c.inserts),
+            removes: load(&c.removes),
+            insert_batches: load(&c.insert_batches),
+            inserted_batch_keys: load(&c.inserted_batch_keys),
+            remove_batches: load(&c.remove_batches),
+            removed_batch_keys: load(&c.removed_batch_keys),
+            flushes: load(&c.flushes),
+            get_time: Duration::from_nanos(load(&c.get_time)),
+            write_time: Duration::from_nanos(load(&c.write_time)),
+            flush_time: Duration::from_nanos(load(&c.flush_time)),
+        }
+    }
+}
+
+fn incr(counter: &AtomicU64, n: u64) {
+    counter.fetch_add(n, Ordering::Relaxed);
+}
+
+fn timed<T>(counter: &AtomicU64, f: impl FnOnce() -> T) -> T {
+    let start = Instant::now();
+    let result = f();
+    let elapsed = start.elapsed();
+    let nanos = elapsed.as_secs() * 1_000_000_000 + u64::from(elapsed.subsec_nanos());
+    incr(counter, nanos);
+    result
+}
+
+impl<D: DB> DB for MeteredDB<D> {
+    type Error = D::Error;
+
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+        let c = &self.counters;
+        incr(&c.gets, 1);
+        let value = timed(&c.get_time, || self.db.get(key))?;
+        if value.is_some() {
+            incr(&c.hits, 1);
+        } else {
+            incr(&c.misses, 1);
+        }
+        Ok(value)
+    }
+
+    /// Every key of the batch is counted as a get.
+    fn get_batch(&self, keys: &[Vec<u8>]) -> Result<Vec<Option<Vec<u8>>>, Self::Error> {
+        let c = &self.counters;
+        incr(&c.get_batches, 1);
+        incr(&c.gets, keys.len() as u64);
+        let values = timed(&c.get_time, || self.db.get_batch(keys))?;
+        let hits = values.iter().filter(|value| value.is_some()).count() as u64;
+        incr(&c.hits, hits);
+        incr(&c.misses, values.len() as u64 - hits);
+        Ok(values)
+    }
+
+    fn contains(&self, key: &[u8]) -> Result<bool, Self::Error> {
+        incr(&self.counters.contains, 1);
+        timed(&self.counters.get_time, || self.db.contains(key))
+    }
+
+    fn insert(&self, key: Vec<u8>, value: Vec<u8>) -> Result<(), Self::Error> {
+        incr(&self.counters.inserts, 1);
+        timed(&self.counters.write_time, || self.db.insert(key, value))
+    }
+
+    fn remove(&self, key: &[u8]) -> Result<(), Self::Error> {
+        incr(&self.counters.removes, 1);
+        timed(&self.counters.write_time, || self.db.remove(key))
+    }
+
+    fn insert_batch(&self, keys: Vec<Vec<u8>>, values: Vec<Vec<u8>>) -> Result<(), Self::Error> {
+        let c = &self.counters;
+        incr(&c.insert_batches, 1);
+        incr(&c.inserted_batch_keys, keys.len() as u64);
+        timed(&c.write_time, || self.db.insert_batch(keys, values))
+    }
+
+    fn remove_batch(&self, keys: &[Vec<u8>]) -> Result<(), Self::Error> {
+        let c = &self.counters;
+        incr(&c.remove_batches, 1);
+        incr(&c.removed_batch_keys, keys.len() as u64);
+        timed(&c.write_time, || self.db.remove_batch(keys))
+    }
+
+    fn flush(&self) -> Result<(), Self::Error> {
+        incr(&self.counters.flushes, 1);
+        timed(&self.counters.flush_time, || self.db.flush())
+    }
+
+    fn checkpoint(&self, path: &Path) -> Result<bool, Self::Error> {
+        self.db.checkpoint(path)
+    }
+
+    fn restore(&self, path: &Path) -> Result<bool, Self::Error> {
+        self.db.restore(path)
+    }
+
+    fn keys(&self) -> Result<Option<Vec<Vec<u8>>>, Self::Error> {
+        self.db.keys()
+    }
+
+    #[cfg(test)]
+    fn len(&self) -> Result<usize, Self::Error> {
+        self.db.len()
+    }
+    #[cfg(test)]
+    fn is_empty(&self) -> Result<bool, Self::Error> {
+        self.db.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use hasher::HasherKeccak;
+
+    use super::*;
+    use crate::db::MemoryDB;
+    use crate::trie::{PatriciaTrie, Trie};
+
+    #[test]
+    fn test_metereddb_counts() {
+        let db = MeteredDB::new(MemoryDB::new(true));
+        db.insert(b"test".to_vec(), b"test".to_vec()).unwrap();
+        db.get(b"test").unwrap();
+        db.get(b"missing").unwrap();
+        db.insert_batch(vec![vec![1], vec![2]], vec![vec![1], vec![2]])
+            .unwrap();
+        db.flush().unwrap();
+
+        let metrics = db.reset();
+        assert_eq!(metrics.gets, 2);
+        assert_eq!(metrics.hits, 1);
+        assert_eq!(metrics.misses, 1);
+        assert_eq!(metrics.inserts, 1);
+        assert_eq!(metrics.insert_batches, 1);
+        assert_eq!(metrics.inserted_batch_keys, 2);
+        assert_eq!(metrics.flushes, 1);
+        assert_eq!(db.metrics(), DBMetrics::default());
+    }
+
+    #[test]
+    fn test_metereddb_trie_reads() {
+        let db = Arc::new(MeteredDB::new(MemoryDB::new(true)));
+        let mut trie = PatriciaTrie::new(Arc::clone(&db), Arc::new(HasherKeccak::new()));
+        for i in 0..100u8 {
+            trie.insert(vec![i], vec![i; 40]).unwrap();
+        }
+        let root = trie.root().unwrap();
+
+        let trie =
+            PatriciaTrie::from(Arc::clone(&db), Arc::new(HasherKeccak::new()), &root).unwrap();
+        db.reset();
+        trie.get(&[7]).unwrap();
+        let metrics = db.metrics();
+        assert!(metrics.gets >= 1);
+        assert_eq!(metrics.misses, 0);
+    }
+}
@@ -0,0 +1,349 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use hashbrown::HashMap;
+use memmap::Mmap;
+use parking_lot::{Mutex, RwLock};
+
+use crate::db::{fnv64, DB};
+use crate::errors::MmapDBError;
+
+// key length (u32) + value length (u32) + checksum (u64)
+const HEADER_LEN: usize = 16;
+
+/// "MmapDB" stores nodes in a single append-only file, read through a memory map.
+/// Nodes are content addressed and never rewritten, so "flush" only appends the
+/// new nodes and an in-memory index maps every key to the offset of its value.
+/// A key written again with another value, e.g. metadata, gets a new record,
+/// the last record of a key wins.
+/// "remove" is a no-op, which suits archival nodes that keep every historical
+/// state and avoids the write amplification of an LSM tree.
+///
+/// The file must not be modified by anyone else while it is open.
+/// A record torn by a crash during "flush" is truncated on "open".
+#[derive(Debug)]
+pub struct MmapDB {
+    path: PathBuf,
+    file: Mutex<File>,
+    state: RwLock<State>,
+    pending: RwLock<HashMap<Vec<u8>, Vec<u8>>>,
+}
+
+#[derive(Debug)]
+struct State {
+    // None while the file is empty, an empty file can't be mapped.
+    map: Option<Mmap>,
+    // key -> (offset, length) of the value
+    index: HashMap<Vec<u8>, (usize, usize)>,
+    len: usize,
+}
+
+impl State {
+    fn get(&self, key: &[u8]) -> Option<&[u8]> {
+        let (offset, len) = *self.index.get(key)?;
+        self.map.as_ref().map(|map| &map[offset..offset + len])
+    }
+}
+
+impl MmapDB {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, MmapDBError> {
+        let path = path.as_ref().to_path_buf();
+        let (file, state) = load(&path)?;
+        Ok(MmapDB {
+            path,
+            file: Mutex::new(file),
+            state: RwLock::new(state),
+            pending: RwLock::new(HashMap::new()),
+        })
+    }
+}
+
+// Opens the file at "path", indexes every complete record and drops a torn tail.
+fn load(path: &Path) -> Result<(File, State), MmapDBError> {
+    let file = OpenOptions::new()
+        .read(true)
+        .append(true)
+        .create(true)
+        .open(path)?;
+
+    let mut index = HashMap::new();
+    let mut len = 0;
+    if let Some(map) = map(&file)? {
+        while let Some((key, value_offset, value_len)) = read_record(&map, len) {
+            index.insert(key.to_vec(), (value_offset, value_len));
+            len = value_offset + value_len;
+        }
+    }
+    if file.metadata()?.len() != len as u64 {
+        file.set_len(len as u64)?;
+    }
+
+    let state = State {
+        map: map(&file)?,
+        index,
+        len,
+    };
+    Ok((file, state))
+}
+
+fn map(file: &File) -> Result<Option<Mmap>, MmapDBError> {
+    if file.metadata()?.len() == 0 {
+        return Ok(None);
+    }
+    // The file is only ever appended to, and only by this process,
+    // so the mapped bytes never change underneath us.
+    let map = unsafe { Mmap::map(file)? };
+    Ok(Some(map))
+}
+
+// Returns the key, value offset and value length of the record at "offset",
+// or None if there is no complete and valid record there.
+fn read_record(data: &[u8], offset: usize) -> Option<(&[u8], usize, usize)> {
+    let header = data.get(offset..offset + HEADER_LEN)?;
+    let key_len = read_u32(&header[..4]);
+    let value_len = read_u32(&header[4..8]);
+    let checksum = header[8..]
+        .iter()
+        .rev()
+        .fold(0u64, |acc, b| (acc << 8) | u64::from(*b));
+
+    let key_offset = offset + HEADER_LEN;
+    let value_offset = key_offset + key_len;
+    let record = data.get(key_offset..value_offset + value_len)?;
+    if fnv64(record) != checksum {
+        return None;
+    }
+    Some((&record[..key_len], value_offset, value_len))
+}
+
+fn read_u32(bytes: &[u8]) -> usize {
+    bytes
+        .iter()
+        .rev()
+        .fold(0usize, |acc, b| (acc << 8) | *b as usize)
+}
+
+fn write_record(data: &mut Vec<u8>, key: &[u8], value: &[u8]) {
+    let mut record = Vec::with_capacity(key.len() + value.len());
+    record.extend_from_slice(key);
+    record.extend_from_slice(value);
+
+    data.extend_from_slice(&(key.len() as u32).to_le_bytes());
+    data.extend_from_slice(&(value.len() as u32).to_le_bytes());
+    data.extend_from_slice(&fnv64(&record).to_le_bytes());
+    data.extend_from_slice(&record);
+}
+
+impl DB for MmapDB {
+    type Error = MmapDBError;
+
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+        if let Some(value) = self.pending.read().get(key) {
+            return Ok(Some(value.clone()));
+        }
+        Ok(self.state.read().get(key).map(|value| value.to_vec()))
+    }
+
+    fn contains(&self, key: &[u8]) -> Result<bool, Self::Error> {
+        if self.pending.read().contains_key(key) {
+            return Ok(true);
+        }
+        Ok(self.state.read().index.contains_key(key))
+    }
+
+    fn insert(&self, key: Vec<u8>, value: Vec<u8>) -> Result<(), Self::Error> {
+        self.pending.write().insert(key, value);
+        Ok(())
+    }
+
+    /// Nodes are never deleted from the file.
+    fn remove(&self, _key: &[u8]) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    /// Appends the pending nodes to the file and syncs it.
+    fn flush(&self) -> Result<(), Self::Error> {
+        let mut file = self.file.lock();
+        let mut pending = self.pending.write();
+        if pending.is_empty() {
+            return Ok(());
+        }
+
+        let base = self.state.read().len;
+        let mut data = vec![];
+        let mut locations = Vec::with_capacity(pending.len());
+        {
+            let state = self.state.read();
+            for (key, value) in pending.iter() {
+                if state.get(key) == Some(&value[..]) {
+                    continue;
+                }
+                let value_offset = base + data.len() + HEADER_LEN + key.len();
+                locations.push((key.clone(), (value_offset, value.len())));
+                write_record(&mut data, key, value);
+            }
+        }
+        file.write_all(&data)?;
+        file.sync_data()?;
+
+        let mut state = self.state.write();
+        state.map = map(&file)?;
+        state.len += data.len();
+        state.index.extend(locations);
+        pending.clear();
+        Ok(())
+    }
+
+    /// Copies the file to "path".
+    fn checkpoint(&self, path: &Path) -> Result<bool, Self::Error> {
+        self.flush()?;
+        let _file = self.file.lock();
+        fs::copy(&self.path, path)?;
+        Ok(true)
+    }
+
+    /// Replaces the file with the one at "path" and drops pending nodes.
+    fn restore(&self, path: &Path) -> Result<bool, Self::Error> {
+        let mut file = self.file.lock();
+        let mut pending = self.pending.write();
+        let mut state = self.state.write();
+        // Unmap before the file is overwritten.
+        state.map = None;
+        fs::copy(path, &self.path)?;
+
+        let (new_file, new_state) = load(&self.path)?;
+        *file = new_file;
+        *state = new_state;
+        pending.clear();
+        Ok(true)
+    }
+
+    fn keys(&self) -> Result<Option<Vec<Vec<u8>>>, Self::Error> {
+        let state = self.state.read();
+        let mut keys: Vec<Vec<u8>> = state.index.keys().cloned().collect();
+        keys.extend(
+            self.pending
+                .read()
+                .keys()
+                .filter(|key| !state.index.contains_key(*key))
+                .cloned(),
+        );
+        Ok(Some(keys))
+    }
+
+    #[cfg(test)]
+    fn len(&self) -> Result<usize, Self::Error> {
+        let state = self.state.read();
+        let pending = self
+            .pending
+            .read()
+            .keys()
+            .filter(|key| !state.index.contains_key(*key))
+            .count();
+        Ok(state.index.len() + pending)
+    }
+    #[cfg(test)]
+    fn is_empty(&self) -> Result<bool, Self::Error> {
+        Ok(self.len()? == 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use hasher::HasherKeccak;
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::trie::{PatriciaTrie, Trie};
+
+    fn temp_path() -> PathBuf {
+        std::env::temp_dir().join(format!("cita-trie-mmap-{}", Uuid::new_v4()))
+    }
+
+    #[test]
+    fn test_mmapdb_reopen() {
+        let path = temp_path();
+        {
+            let db = MmapDB::open(&path).unwrap();
+            db.insert(b"test-key".to_vec(), b"test-value".to_vec())
+                .unwrap();
+            assert_eq!(db.get(b"test-key").unwrap(), Some(b"test-value".to_vec()));
+            db.flush().unwrap();
+            db.insert(b"unflushed".to_vec(), b"unflushed".to_vec())
+                .unwrap();
+        }
+
+        let db = MmapDB::open(&path).unwrap();
+        assert_eq!(db.get(b"test-key").unwrap(), Some(b"test-value".to_vec()));
+        assert_eq!(db.contains(b"unflushed").unwrap(), false);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_mmapdb_overwrite() {
+        let path = temp_path();
+        {
+            let db = MmapDB::open(&path).unwrap();
+            db.insert(b"key".to_vec(), b"old".to_vec()).unwrap();
+            db.flush().unwrap();
+            db.insert(b"key".to_vec(), b"new".to_vec()).unwrap();
+            db.flush().unwrap();
+            assert_eq!(db.get(b"key").unwrap(), Some(b"new".to_vec()));
+            assert_eq!(db.len().unwrap(), 1);
+        }
+
+        let db = MmapDB::open(&path).unwrap();
+        assert_eq!(db.get(b"key").unwrap(), Some(b"new".to_vec()));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_mmapdb_torn_tail() {
+        let path = temp_path();
+        {
+            let db = MmapDB::open(&path).unwrap();
+            db.insert(b"a".to_vec(), b"a".to_vec()).unwrap();
+            db.flush().unwrap();
+        }
+        let len = fs::metadata(&path).unwrap().len();
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        let mut torn = vec![];
+        write_record(&mut torn, b"b", b"b");
+        file.write_all(&torn[..torn.len() - 1]).unwrap();
+        drop(file);
+
+        let db = MmapDB::open(&path).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().len(), len);
+        assert_eq!(db.get(b"a").unwrap(), Some(b"a".to_vec()));
+        assert_eq!(db.get(b"b").unwrap(), None);
+
+        db.insert(b"c".to_vec(), b"c".to_vec()).unwrap();
+        db.flush().unwrap();
+        let db = MmapDB::open(&path).unwrap();
+        assert_eq!(db.get(b"c").unwrap(), Some(b"c".to_vec()));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_mmapdb_trie() {
+        let path = temp_path();
+        let root = {
+            let db = Arc::new(MmapDB::open(&path).unwrap());
+            let mut trie = PatriciaTrie::new(db, Arc::new(HasherKeccak::new()));
+            for i in 0..100u8 {
+                trie.insert(vec![i], vec![i; 40]).unwrap();
+            }
+            trie.root().unwrap()
+        };
+
+        let db = Arc::new(MmapDB::open(&path).unwrap());
+        let trie = PatriciaTrie::from(db, Arc::new(HasherKeccak::new()), &root).unwrap();
+        for i in 0..100u8 {
+            assert_eq!(trie.get(&[i]).unwrap(), Some(vec![i; 40]));
+        }
+        fs::remove_file(&path).unwrap();
+    }
+}
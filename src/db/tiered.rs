@@ -0,0 +1,249 @@
+use hashbrown::{HashMap, HashSet};
+use parking_lot::Mutex;
+
+use crate::db::DB;
+use crate::errors::TieredDBError;
+
+/// "TieredDB" keeps recently used nodes in a fast "hot" store and moves nodes
+/// that were not read or written for "max_idle" commits to a "cold" store.
+/// Reads fall back to the cold store transparently.
+///
+/// The hot store must actually delete data on "remove" (e.g. a light "MemoryDB"),
+/// otherwise migrated nodes are never evicted from it.
+/// Access times are only tracked in memory, so nodes that were in the hot store
+/// before the "TieredDB" was created stay there until they are touched again.
+#[derive(Debug)]
+pub struct TieredDB<H: DB, C: DB> {
+    hot: H,
+    cold: C,
+    max_idle: u64,
+    // The number of flushes (i.e. commits) so far.
+    epoch: Mutex<u64>,
+    // Node key -> epoch of the last access, for every node in the hot store.
+    last_access: Mutex<HashMap<Vec<u8>, u64>>,
+}
+
+impl<H: DB, C: DB> TieredDB<H, C> {
+    pub fn new(hot: H, cold: C, max_idle: u64) -> Self {
+        TieredDB {
+            hot,
+            cold,
+            max_idle,
+            epoch: Mutex::new(0),
+            last_access: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn hot(&self) -> &H {
+        &self.hot
+    }
+
+    pub fn cold(&self) -> &C {
+        &self.cold
+    }
+
+    fn touch(&self, key: &[u8]) {
+        let epoch = *self.epoch.lock();
+        if let Some(last) = self.last_access.lock().get_mut(key) {
+            *last = epoch;
+        }
+    }
+
+    /// Moves every node idle for more than "max_idle" commits to the cold store.
+    pub fn migrate(&self) -> Result<(), TieredDBError<H::Error, C::Error>> {
+        let epoch = *self.epoch.lock();
+        let idle: Vec<Vec<u8>> = self
+            .last_access
+            .lock()
+            .iter()
+            .filter(|(_, last)| epoch - **last > self.max_idle)
+            .map(|(key, _)| key.clone())
+            .collect();
+        if idle.is_empty() {
+            return Ok(());
+        }
+
+        let mut keys = Vec::with_capacity(idle.len());
+        let mut values = Vec::with_capacity(idle.len());
+        for key in idle.iter() {
+            if let Some(value) = self.hot.get(key).map_err(TieredDBError::Hot)? {
+                keys.push(key.clone());
+                values.push(value);
+            }
+        }
+
+        // Make the cold copies durable before dropping the hot ones.
+        self.cold
+            .insert_batch(keys, values)
+            .map_err(TieredDBError::Cold)?;
+        self.cold.flush().map_err(TieredDBError::Cold)?;
+        self.hot.remove_batch(&idle).map_err(TieredDBError::Hot)?;
+
+        let mut last_access = self.last_access.lock();
+        for key in idle.iter() {
+            last_access.remove(key);
+        }
+        Ok(())
+    }
+}
+
+impl<H: DB, C: DB> DB for TieredDB<H, C> {
+    type Error = TieredDBError<H::Error, C::Error>;
+
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+        match self.hot.get(key).map_err(TieredDBError::Hot)? {
+            Some(value) => {
+                self.touch(key);
+                Ok(Some(value))
+            }
+            None => self.cold.get(key).map_err(TieredDBError::Cold),
+        }
+    }
+
+    fn get_batch(&self, keys: &[Vec<u8>]) -> Result<Vec<Option<Vec<u8>>>, Self::Error> {
+        let mut values = self.hot.get_batch(keys).map_err(TieredDBError::Hot)?;
+        let mut cold_keys = vec![];
+        for (key, value) in keys.iter().zip(values.iter()) {
+            if value.is_some() {
+                self.touch(key);
+            } else {
+                cold_keys.push(key.clone());
+            }
+        }
+        if cold_keys.is_empty() {
+            return Ok(values);
+        }
+
+        let mut cold_values = self
+            .cold
+            .get_batch(&cold_keys)
+            .map_err(TieredDBError::Cold)?
+            .into_iter();
+        for value in values.iter_mut().filter(|value| value.is_none()) {
+            *value = cold_values.next().and_then(|value| value);
+        }
+        Ok(values)
+    }
+
+    fn contains(&self, key: &[u8]) -> Result<bool, Self::Error> {
+        if self.hot.contains(key).map_err(TieredDBError::Hot)? {
+            return Ok(true);
+        }
+        self.cold.contains(key).map_err(TieredDBError::Cold)
+    }
+
+    fn insert(&self, key: Vec<u8>, value: Vec<u8>) -> Result<(), Self::Error> {
+        let epoch = *self.epoch.lock();
+        self.last_access.lock().insert(key.clone(), epoch);
+        self.hot.insert(key, value).map_err(TieredDBError::Hot)
+    }
+
+    fn remove(&self, key: &[u8]) -> Result<(), Self::Error> {
+        self.last_access.lock().remove(key);
+        self.hot.remove(key).map_err(TieredDBError::Hot)?;
+        self.cold.remove(key).map_err(TieredDBError::Cold)
+    }
+
+    fn insert_batch(&self, keys: Vec<Vec<u8>>, values: Vec<Vec<u8>>) -> Result<(), Self::Error> {
+        let epoch = *self.epoch.lock();
+        {
+            let mut last_access = self.last_access.lock();
+            for key in keys.iter() {
+                last_access.insert(key.clone(), epoch);
+            }
+        }
+        self.hot
+            .insert_batch(keys, values)
+            .map_err(TieredDBError::Hot)
+    }
+
+    fn remove_batch(&self, keys: &[Vec<u8>]) -> Result<(), Self::Error> {
+        {
+            let mut last_access = self.last_access.lock();
+            for key in keys {
+                last_access.remove(key);
+            }
+        }
+        self.hot.remove_batch(keys).map_err(TieredDBError::Hot)?;
+        self.cold.remove_batch(keys).map_err(TieredDBError::Cold)
+    }
+
+    /// Flushes both stores, then migrates idle nodes.
+    fn flush(&self) -> Result<(), Self::Error> {
+        self.hot.flush().map_err(TieredDBError::Hot)?;
+        self.cold.flush().map_err(TieredDBError::Cold)?;
+        *self.epoch.lock() += 1;
+        self.migrate()
+    }
+
+    fn keys(&self) -> Result<Option<Vec<Vec<u8>>>, Self::Error> {
+        let hot = self.hot.keys().map_err(TieredDBError::Hot)?;
+        let cold = self.cold.keys().map_err(TieredDBError::Cold)?;
+        match (hot, cold) {
+            (Some(hot), Some(cold)) => {
+                let mut keys: HashSet<Vec<u8>> = hot.into_iter().collect();
+                keys.extend(cold);
+                Ok(Some(keys.into_iter().collect()))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    #[cfg(test)]
+    fn len(&self) -> Result<usize, Self::Error> {
+        let hot = self.hot.len().map_err(TieredDBError::Hot)?;
+        let cold = self.cold.len().map_err(TieredDBError::Cold)?;
+        Ok(hot + cold)
+    }
+    #[cfg(test)]
+    fn is_empty(&self) -> Result<bool, Self::Error> {
+        Ok(self.len()? == 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use hasher::HasherKeccak;
+
+    use super::*;
+    use crate::db::MemoryDB;
+    use crate::trie::{PatriciaTrie, Trie};
+
+    #[test]
+    fn test_tiereddb_migrate() {
+        let tiered = TieredDB::new(MemoryDB::new(true), MemoryDB::new(true), 1);
+        tiered.insert(b"idle".to_vec(), b"idle".to_vec()).unwrap();
+        tiered.insert(b"busy".to_vec(), b"busy".to_vec()).unwrap();
+
+        for _ in 0..3 {
+            tiered.get(b"busy").unwrap();
+            tiered.flush().unwrap();
+        }
+
+        assert_eq!(tiered.hot().contains(b"idle").unwrap(), false);
+        assert_eq!(tiered.cold().contains(b"idle").unwrap(), true);
+        assert_eq!(tiered.hot().contains(b"busy").unwrap(), true);
+        assert_eq!(tiered.get(b"idle").unwrap(), Some(b"idle".to_vec()));
+    }
+
+    #[test]
+    fn test_tiereddb_trie() {
+        let tiered = Arc::new(TieredDB::new(MemoryDB::new(true), MemoryDB::new(true), 0));
+        let mut trie = PatriciaTrie::new(Arc::clone(&tiered), Arc::new(HasherKeccak::new()));
+        for i in 0..100u8 {
+            trie.insert(vec![i], vec![i; 40]).unwrap();
+        }
+        trie.root().unwrap();
+        trie.insert(vec![0], vec![1; 40]).unwrap();
+        let root = trie.root().unwrap();
+
+        assert!(!tiered.cold().is_empty().unwrap());
+        let trie =
+            PatriciaTrie::from(Arc::clone(&tiered), Arc::new(HasherKeccak::new()), &root).unwrap();
+        for i in 1..100u8 {
+            assert_eq!(trie.get(&[i]).unwrap(), Some(vec![i; 40]));
+        }
+    }
+}
@@ -0,0 +1,370 @@
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use hashbrown::{HashMap, HashSet};
+use parking_lot::{Mutex, RwLock};
+
+use crate::db::{fnv64, read_bytes, write_bytes, DB};
+use crate::errors::WalDBError;
+
+const OP_INSERT: u8 = 0;
+const OP_REMOVE: u8 = 1;
+// payload length (u64) + checksum (u64)
+const TRAILER_LEN: usize = 16;
+
+/// "WalDB" wraps a "DB" with a write-ahead log.
+/// Inserts and removes are buffered in memory until "flush", which first writes
+/// the whole batch to the log and syncs it, then applies it to the inner DB.
+/// If the process crashes in between, the batch is replayed on the next "open",
+/// so the inner DB never observes half of a commit.
+#[derive(Debug)]
+pub struct WalDB<D: DB> {
+    db: D,
+    log: Mutex<File>,
+    pending: RwLock<Pending>,
+}
+
+#[derive(Default, Debug)]
+struct Pending {
+    inserts: HashMap<Vec<u8>, Vec<u8>>,
+    removes: HashSet<Vec<u8>>,
+}
+
+impl Pending {
+    fn is_empty(&self) -> bool {
+        self.inserts.is_empty() && self.removes.is_empty()
+    }
+
+    fn clear(&mut self) {
+        self.inserts.clear();
+        self.removes.clear();
+    }
+
+    // Layout: repeated records "op | key len | key [| value len | value]",
+    // followed by the payload length and its checksum.
+    fn encode(&self) -> Vec<u8> {
+        let mut data = vec![];
+        for (key, value) in self.inserts.iter() {
+            data.push(OP_INSERT);
+            write_bytes(&mut data, key);
+            write_bytes(&mut data, value);
+        }
+        for key in self.removes.iter() {
+            data.push(OP_REMOVE);
+            write_bytes(&mut data, key);
+        }
+
+        let checksum = fnv64(&data);
+        data.extend_from_slice(&(data.len() as u64).to_le_bytes());
+        data.extend_from_slice(&checksum.to_le_bytes());
+        data
+    }
+
+    // Returns None if the log is empty, torn or corrupted.
+    fn decode(data: &[u8]) -> Option<Self> {
+        if data.len() < TRAILER_LEN {
+            return None;
+        }
+        let (payload, trailer) = data.split_at(data.len() - TRAILER_LEN);
+        if read_u64(&trailer[..8]) != payload.len() as u64
+            || read_u64(&trailer[8..]) != fnv64(payload)
+        {
+            return None;
+        }
+
+        let mut pending = Pending::default();
+        let mut rest = payload;
+        while !rest.is_empty() {
+            let op = rest[0];
+            rest = &rest[1..];
+            let key = read_bytes(&mut rest)?;
+            match op {
+                OP_INSERT => {
+                    let value = read_bytes(&mut rest)?;
+                    pending.inserts.insert(key, value);
+                }
+                OP_REMOVE => {
+                    pending.removes.insert(key);
+                }
+                _ => return None,
+            }
+        }
+        Some(pending)
+    }
+}
+
+impl<D: DB> WalDB<D> {
+    /// Opens the log at "path" in front of "db", replaying any batch that was
+    /// logged but not fully applied before the last shutdown.
+    pub fn open<P: AsRef<Path>>(db: D, path: P) -> Result<Self, WalDBError<D::Error>> {
+        let mut log = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(path)?;
+
+        let mut data = vec![];
+        log.read_to_end(&mut data)?;
+
+        let wal = WalDB {
+            db,
+            log: Mutex::new(log),
+            pending: RwLock::new(Pending::default()),
+        };
+
+        if let Some(pending) = Pending::decode(&data) {
+            wal.apply(&pending)?;
+        }
+        wal.reset_log(&mut wal.log.lock())?;
+        Ok(wal)
+    }
+
+    /// Returns the wrapped DB.
+    pub fn inner(&self) -> &D {
+        &self.db
+    }
+
+    fn apply(&self, pending: &Pending) -> Result<(), WalDBError<D::Error>> {
+        let mut keys = Vec::with_capacity(pending.inserts.len());
+        let mut values = Vec::with_capacity(pending.inserts.len());
+        for (k, v) in pending.inserts.iter() {
+            keys.push(k.clone());
+            values.push(v.clone());
+        }
+        self.db.insert_batch(keys, values).map_err(WalDBError::DB)?;
+
+        let removed_keys: Vec<Vec<u8>> = pending.removes.iter().cloned().collect();
+        self.db
+            .remove_batch(&removed_keys)
+            .map_err(WalDBError::DB)?;
+
+        self.db.flush().map_err(WalDBError::DB)
+    }
+
+    fn reset_log(&self, log: &mut File) -> Result<(), WalDBError<D::Error>> {
+        log.set_len(0)?;
+        log.seek(SeekFrom::Start(0))?;
+        log.sync_data()?;
+        Ok(())
+    }
+}
+
+impl<D: DB> DB for WalDB<D> {
+    type Error = WalDBError<D::Error>;
+
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+        let pending = self.pending.read();
+        if pending.removes.contains(key) {
+            return Ok(None);
+        }
+        if let Some(value) = pending.inserts.get(key) {
+            return Ok(Some(value.clone()));
+        }
+        self.db.get(key).map_err(WalDBError::DB)
+    }
+
+    fn get_batch(&self, keys: &[Vec<u8>]) -> Result<Vec<Option<Vec<u8>>>, Self::Error> {
+        let pending = self.pending.read();
+        let inner_keys: Vec<Vec<u8>> = keys
+            .iter()
+            .filter(|key| !pending.removes.contains(*key) && !pending.inserts.contains_key(*key))
+            .cloned()
+            .collect();
+        let mut inner_values = self
+            .db
+            .get_batch(&inner_keys)
+            .map_err(WalDBError::DB)?
+            .into_iter();
+        Ok(keys
+            .iter()
+            .map(|key| {
+                if pending.removes.contains(key) {
+                    None
+                } else if let Some(value) = pending.inserts.get(key) {
+                    Some(value.clone())
+                } else {
+                    inner_values.next().and_then(|value| value)
+                }
+            })
+            .collect())
+    }
+
+    fn contains(&self, key: &[u8]) -> Result<bool, Self::Error> {
+        let pending = self.pending.read();
+        if pending.removes.contains(key) {
+            return Ok(false);
+        }
+        if pending.inserts.contains_key(key) {
+            return Ok(true);
+        }
+        self.db.contains(key).map_err(WalDBError::DB)
+    }
+
+    fn insert(&self, key: Vec<u8>, value: Vec<u8>) -> Result<(), Self::Error> {
+        let mut pending = self.pending.write();
+        pending.removes.remove(&key);
+        pending.inserts.insert(key, value);
+        Ok(())
+    }
+
+    fn remove(&self, key: &[u8]) -> Result<(), Self::Error> {
+        let mut pending = self.pending.write();
+        pending.inserts.remove(key);
+        pending.removes.insert(key.to_vec());
+        Ok(())
+    }
+
+    fn insert_batch(&self, keys: Vec<Vec<u8>>, values: Vec<Vec<u8>>) -> Result<(), Self::Error> {
+        let mut pending = self.pending.write();
+        for (key, value) in keys.into_iter().zip(values.into_iter()) {
+            pending.removes.remove(&key);
+            pending.inserts.insert(key, value);
+        }
+        Ok(())
+    }
+
+    fn remove_batch(&self, keys: &[Vec<u8>]) -> Result<(), Self::Error> {
+        let mut pending = self.pending.write();
+        for key in keys {
+            pending.inserts.remove(key);
+            pending.removes.insert(key.clone());
+        }
+        Ok(())
+    }
+
+    /// Logs the pending batch, applies it to the inner DB and truncates the log.
+    fn flush(&self) -> Result<(), Self::Error> {
+        let mut log = self.log.lock();
+        let mut pending = self.pending.write();
+        if pending.is_empty() {
+            return self.db.flush().map_err(WalDBError::DB);
+        }
+
+        log.set_len(0)?;
+        log.seek(SeekFrom::Start(0))?;
+        log.write_all(&pending.encode())?;
+        log.sync_data()?;
+
+        self.apply(&pending)?;
+        self.reset_log(&mut log)?;
+        pending.clear();
+        Ok(())
+    }
+
+    /// Flushes the pending batch first, so the checkpoint includes it.
+    fn checkpoint(&self, path: &Path) -> Result<bool, Self::Error> {
+        self.flush()?;
+        self.db.checkpoint(path).map_err(WalDBError::DB)
+    }
+
+    /// Drops the pending batch.
+    fn restore(&self, path: &Path) -> Result<bool, Self::Error> {
+        let _log = self.log.lock();
+        let mut pending = self.pending.write();
+        if !self.db.restore(path).map_err(WalDBError::DB)? {
+            return Ok(false);
+        }
+        pending.clear();
+        Ok(true)
+    }
+
+    fn keys(&self) -> Result<Option<Vec<Vec<u8>>>, Self::Error> {
+        let pending = self.pending.read();
+        let keys = match self.db.keys().map_err(WalDBError::DB)? {
+            Some(keys) => keys,
+            None => return Ok(None),
+        };
+        let mut keys: Vec<Vec<u8>> = keys
+            .into_iter()
+            .filter(|key| !pending.removes.contains(key) && !pending.inserts.contains_key(key))
+            .collect();
+        keys.extend(pending.inserts.keys().cloned());
+        Ok(Some(keys))
+    }
+
+    #[cfg(test)]
+    fn len(&self) -> Result<usize, Self::Error> {
+        self.db.len().map_err(WalDBError::DB)
+    }
+    #[cfg(test)]
+    fn is_empty(&self) -> Result<bool, Self::Error> {
+        self.db.is_empty().map_err(WalDBError::DB)
+    }
+}
+
+fn read_u64(bytes: &[u8]) -> u64 {
+    bytes
+        .iter()
+        .rev()
+        .fold(0u64, |acc, b| (acc << 8) | u64::from(*b))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::PathBuf;
+
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::db::MemoryDB;
+
+    fn temp_log() -> PathBuf {
+        std::env::temp_dir().join(format!("cita-trie-wal-{}", Uuid::new_v4()))
+    }
+
+    #[test]
+    fn test_waldb_flush() {
+        let path = temp_log();
+        let wal = WalDB::open(MemoryDB::new(true), &path).unwrap();
+        wal.insert(b"test-key".to_vec(), b"test-value".to_vec())
+            .unwrap();
+
+        assert_eq!(wal.get(b"test-key").unwrap(), Some(b"test-value".to_vec()));
+        assert_eq!(wal.inner().contains(b"test-key").unwrap(), false);
+
+        wal.flush().unwrap();
+        assert_eq!(wal.inner().contains(b"test-key").unwrap(), true);
+        assert_eq!(fs::metadata(&path).unwrap().len(), 0);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_waldb_replay() {
+        let path = temp_log();
+        let mut pending = Pending::default();
+        pending
+            .inserts
+            .insert(b"test-key".to_vec(), b"test-value".to_vec());
+        pending.removes.insert(b"stale".to_vec());
+        // Simulate a crash after the log was synced but before it was applied.
+        fs::write(&path, pending.encode()).unwrap();
+
+        let memdb = MemoryDB::new(true);
+        memdb.insert(b"stale".to_vec(), b"stale".to_vec()).unwrap();
+        let wal = WalDB::open(memdb, &path).unwrap();
+
+        assert_eq!(
+            wal.inner().get(b"test-key").unwrap(),
+            Some(b"test-value".to_vec())
+        );
+        assert_eq!(wal.inner().contains(b"stale").unwrap(), false);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_waldb_discard_torn_log() {
+        let path = temp_log();
+        let mut pending = Pending::default();
+        pending
+            .inserts
+            .insert(b"test-key".to_vec(), b"test-value".to_vec());
+        let data = pending.encode();
+        fs::write(&path, &data[..data.len() - 1]).unwrap();
+
+        let wal = WalDB::open(MemoryDB::new(true), &path).unwrap();
+        assert_eq!(wal.get(b"test-key").unwrap(), None);
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,5 +1,6 @@
 use std::error::Error;
 use std::fmt;
+use std::io;
 
 use rlp::DecoderError;
 
@@ -10,6 +11,43 @@ pub enum TrieError {
     InvalidData,
     InvalidStateRoot,
     InvalidProof,
+    UnsortedKeys,
+    /// "commit_tx" or "rollback" was called without "begin".
+    NoTransaction,
+    /// The trie can not be committed while a transaction is open.
+    TransactionOpen,
+    /// The checkpoint was reverted or committed already.
+    UnknownCheckpoint,
+    /// The async runtime failed, e.g. "commit_async" ran outside a thread pool.
+    Runtime(String),
+    /// The DB can not list its keys, see "DB::keys".
+    KeysUnsupported,
+    /// No reverse diffs lead back to the root, see "rewind".
+    NoReverseDiff,
+    /// Versions must be committed in ascending order.
+    InvalidVersion,
+    /// The version was pruned or never committed.
+    UnknownVersion,
+    /// The codec can not tell an empty value from none, see "with_empty_values".
+    EmptyValuesUnsupported,
+    /// A nibble of a key given as nibbles is not below 16.
+    InvalidNibbles,
+    /// The key, of this many bytes, exceeds the limit, see "with_size_limits".
+    KeyTooLarge(usize),
+    /// The value, of this many bytes, exceeds the limit, see "with_size_limits".
+    ValueTooLarge(usize),
+    /// The DB lacks the node of this hash, see "with_strict_nodes".
+    MissingNode {
+        hash: Vec<u8>,
+    },
+    /// The node read under this hash does not hash to it, see
+    /// "with_node_checksums".
+    CorruptNode {
+        hash: Vec<u8>,
+    },
+    /// The path of the key leaves the nodes of the witness, see "PartialTrie".
+    OutsideWitness,
+    IO(io::Error),
 }
 
 impl Error for TrieError {}
@@ -22,6 +60,27 @@ impl fmt::Display for TrieError {
             TrieError::InvalidData => "trie error: invali data".to_owned(),
             TrieError::InvalidStateRoot => "trie error: invali state root".to_owned(),
             TrieError::InvalidProof => "trie error: invali proof".to_owned(),
+            TrieError::UnsortedKeys => "trie error: keys are not in ascending order".to_owned(),
+            TrieError::NoTransaction => "trie error: no transaction is open".to_owned(),
+            TrieError::TransactionOpen => "trie error: a transaction is open".to_owned(),
+            TrieError::UnknownCheckpoint => "trie error: unknown checkpoint".to_owned(),
+            TrieError::Runtime(ref err) => format!("trie error: runtime: {}", err),
+            TrieError::KeysUnsupported => "trie error: the db can not list its keys".to_owned(),
+            TrieError::NoReverseDiff => "trie error: no reverse diff leads to the root".to_owned(),
+            TrieError::InvalidVersion => "trie error: versions must ascend".to_owned(),
+            TrieError::UnknownVersion => "trie error: unknown version".to_owned(),
+            TrieError::EmptyValuesUnsupported => {
+                "trie error: the codec can not store empty values".to_owned()
+            }
+            TrieError::InvalidNibbles => "trie error: nibbles must be below 16".to_owned(),
+            TrieError::KeyTooLarge(len) => format!("trie error: key of {} bytes is too large", len),
+            TrieError::ValueTooLarge(len) => {
+                format!("trie error: value of {} bytes is too large", len)
+            }
+            TrieError::MissingNode { ref hash } => format!("trie error: missing node {:?}", hash),
+            TrieError::CorruptNode { ref hash } => format!("trie error: corrupt node {:?}", hash),
+            TrieError::OutsideWitness => "trie error: the key is outside the witness".to_owned(),
+            TrieError::IO(ref err) => format!("trie error: {:?}", err),
         };
         write!(f, "{}", printable)
     }
@@ -33,13 +92,158 @@ impl From<DecoderError> for TrieError {
     }
 }
 
+impl From<io::Error> for TrieError {
+    fn from(error: io::Error) -> Self {
+        TrieError::IO(error)
+    }
+}
+
 #[derive(Debug)]
-pub enum MemDBError {}
+pub enum MemDBError {
+    IO(io::Error),
+}
 
 impl Error for MemDBError {}
 
 impl fmt::Display for MemDBError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "error")
+        let printable = match *self {
+            MemDBError::IO(ref err) => format!("mem db error: {:?}", err),
+        };
+        write!(f, "{}", printable)
+    }
+}
+
+impl From<io::Error> for MemDBError {
+    fn from(error: io::Error) -> Self {
+        MemDBError::IO(error)
+    }
+}
+
+#[derive(Debug)]
+pub enum WalDBError<E: Error> {
+    DB(E),
+    IO(io::Error),
+}
+
+impl<E: Error> Error for WalDBError<E> {}
+
+impl<E: Error> fmt::Display for WalDBError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let printable = match *self {
+            WalDBError::DB(ref err) => format!("wal db error: {}", err),
+            WalDBError::IO(ref err) => format!("wal db error: {:?}", err),
+        };
+        write!(f, "{}", printable)
+    }
+}
+
+impl<E: Error> From<io::Error> for WalDBError<E> {
+    fn from(error: io::Error) -> Self {
+        WalDBError::IO(error)
+    }
+}
+
+#[derive(Debug)]
+pub enum TieredDBError<H: Error, C: Error> {
+    Hot(H),
+    Cold(C),
+}
+
+impl<H: Error, C: Error> Error for TieredDBError<H, C> {}
+
+impl<H: Error, C: Error> fmt::Display for TieredDBError<H, C> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let printable = match *self {
+            TieredDBError::Hot(ref err) => format!("tiered db error: hot store: {}", err),
+            TieredDBError::Cold(ref err) => format!("tiered db error: cold store: {}", err),
+        };
+        write!(f, "{}", printable)
+    }
+}
+
+#[derive(Debug)]
+pub enum CompressedDBError<E: Error> {
+    DB(E),
+    InvalidData,
+}
+
+impl<E: Error> Error for CompressedDBError<E> {}
+
+impl<E: Error> fmt::Display for CompressedDBError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let printable = match *self {
+            CompressedDBError::DB(ref err) => format!("compressed db error: {}", err),
+            CompressedDBError::InvalidData => "compressed db error: invalid data".to_owned(),
+        };
+        write!(f, "{}", printable)
+    }
+}
+
+#[derive(Debug)]
+pub enum BloomDBError<E: Error> {
+    DB(E),
+    InvalidRate,
+    MissingFilter,
+}
+
+impl<E: Error> Error for BloomDBError<E> {}
+
+impl<E: Error> fmt::Display for BloomDBError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let printable = match *self {
+            BloomDBError::DB(ref err) => format!("bloom db error: {}", err),
+            BloomDBError::InvalidRate => {
+                "bloom db error: the false positive rate must be between 0 and 1".to_owned()
+            }
+            BloomDBError::MissingFilter => {
+                "bloom db error: no filter is persisted and the keys can't be listed".to_owned()
+            }
+        };
+        write!(f, "{}", printable)
+    }
+}
+
+#[derive(Debug)]
+pub enum EncryptedDBError<E: Error> {
+    DB(E),
+    Decryption,
+}
+
+impl<E: Error> Error for EncryptedDBError<E> {}
+
+impl<E: Error> fmt::Display for EncryptedDBError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let printable = match *self {
+            EncryptedDBError::DB(ref err) => format!("encrypted db error: {}", err),
+            EncryptedDBError::Decryption => "encrypted db error: decryption failed".to_owned(),
+        };
+        write!(f, "{}", printable)
+    }
+}
+
+#[cfg(feature = "mmap")]
+#[derive(Debug)]
+pub enum MmapDBError {
+    IO(io::Error),
+}
+
+#[cfg(feature = "mmap")]
+impl Error for MmapDBError {}
+
+#[cfg(feature = "mmap")]
+impl fmt::Display for MmapDBError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let printable = match *self {
+            MmapDBError::IO(ref err) => format!("mmap db error: {:?}", err),
+        };
+        write!(f, "{}", printable)
+    }
+}
+
+#[cfg(feature = "mmap")]
+impl From<io::Error> for MmapDBError {
+    fn from(error: io::Error) -> Self {
+        MmapDBError::IO(error)
     }
 }
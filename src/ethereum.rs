@@ -0,0 +1,310 @@
+use std::sync::Arc;
+
+use ethereum_types::{H160, H256};
+use hashbrown::HashMap;
+use hasher::{Hasher, HasherKeccak};
+
+use crate::db::DB;
+use crate::errors::TrieError;
+use crate::gc::preimage_key;
+use crate::key::TrieKey;
+use crate::trie::{PatriciaTrie, Trie, TrieResult};
+
+/// "PatriciaTrie" with keccak and the RLP codec computes the same roots as
+/// Ethereum's "trie.Trie", e.g. transaction and receipt roots.
+pub type EthereumTrie<D> = PatriciaTrie<D, HasherKeccak>;
+
+/// "EthereumKeys" reads and writes any trie by the key types of Ethereum
+/// state, in their canonical layout: a hash or slot is its 32 big-endian
+/// bytes, the slot of an account is the address followed by the slot. On a
+/// "SecureTrie" the keys are hashed as well.
+pub trait EthereumKeys<D: DB, H: Hasher>: Trie<D, H> {
+    fn get_h256(&self, key: &H256) -> TrieResult<Option<Vec<u8>>> {
+        self.get(key.trie_key())
+    }
+
+    fn insert_h256(&mut self, key: &H256, value: Vec<u8>) -> TrieResult<()> {
+        self.insert(key.trie_key(), value)
+    }
+
+    fn remove_h256(&mut self, key: &H256) -> TrieResult<bool> {
+        self.remove(key.trie_key())
+    }
+
+    fn get_address_slot(&self, address: &H160, slot: &H256) -> TrieResult<Option<Vec<u8>>> {
+        self.get((*address, *slot).trie_key())
+    }
+
+    fn insert_address_slot(
+        &mut self,
+        address: &H160,
+        slot: &H256,
+        value: Vec<u8>,
+    ) -> TrieResult<()> {
+        self.insert((*address, *slot).trie_key(), value)
+    }
+
+    fn remove_address_slot(&mut self, address: &H160, slot: &H256) -> TrieResult<bool> {
+        self.remove((*address, *slot).trie_key())
+    }
+}
+
+impl<D: DB, H: Hasher, T: Trie<D, H>> EthereumKeys<D, H> for T {}
+
+/// "SecureTrie" stores every value under the keccak hash of its key, as the
+/// Ethereum state and storage tries do, so it computes the same roots.
+/// Keys are not recoverable, use "get" with the original key, unless their
+/// preimages are recorded.
+#[derive(Debug)]
+pub struct SecureTrie<D: DB> {
+    trie: EthereumTrie<D>,
+    hasher: Arc<HasherKeccak>,
+    record_preimages: bool,
+    // The preimages recorded since the last flush, by hash.
+    preimages: HashMap<Vec<u8>, Vec<u8>>,
+}
+
+impl<D: DB> SecureTrie<D> {
+    pub fn new(db: Arc<D>) -> Self {
+        let hasher = Arc::new(HasherKeccak::new());
+        SecureTrie {
+            trie: PatriciaTrie::new(db, Arc::clone(&hasher)),
+            hasher,
+            record_preimages: false,
+            preimages: HashMap::new(),
+        }
+    }
+
+    pub fn from(db: Arc<D>, root: &[u8]) -> TrieResult<Self> {
+        let hasher = Arc::new(HasherKeccak::new());
+        Ok(SecureTrie {
+            trie: PatriciaTrie::from(db, Arc::clone(&hasher), root)?,
+            hasher,
+            record_preimages: false,
+            preimages: HashMap::new(),
+        })
+    }
+
+    /// Records the key of every insert under its hash, so that the keys of a
+    /// dump can be mapped back, see "preimage". Preimages are written to the
+    /// DB by "flush_preimages" and every commit.
+    pub fn with_preimages(self) -> Self {
+        Self {
+            record_preimages: true,
+            ..self
+        }
+    }
+
+    /// Starts or stops recording preimages, those recorded are kept.
+    pub fn set_record_preimages(&mut self, enabled: bool) {
+        self.record_preimages = enabled;
+    }
+
+    /// Returns the key whose hash is "hash", if its preimage was recorded.
+    pub fn preimage(&self, hash: &[u8]) -> TrieResult<Option<Vec<u8>>> {
+        if let Some(key) = self.preimages.get(hash) {
+            return Ok(Some(key.clone()));
+        }
+        self.trie
+            .db()
+            .get(&preimage_key(hash))
+            .map_err(|e| TrieError::DB(e.to_string()))
+    }
+
+    /// Writes the preimages recorded since the last flush to the DB, returns
+    /// how many were written.
+    pub fn flush_preimages(&mut self) -> TrieResult<usize> {
+        if self.preimages.is_empty() {
+            return Ok(0);
+        }
+        let (keys, values): (Vec<Vec<u8>>, Vec<Vec<u8>>) = self
+            .preimages
+            .drain()
+            .map(|(hash, key)| (preimage_key(&hash), key))
+            .unzip();
+        let written = keys.len();
+        let db = self.trie.db();
+        db.insert_batch(keys, values)
+            .map_err(|e| TrieError::DB(e.to_string()))?;
+        db.flush().map_err(|e| TrieError::DB(e.to_string()))?;
+        Ok(written)
+    }
+
+    fn hash_key(&self, key: &[u8]) -> Vec<u8> {
+        self.hasher.digest(key)
+    }
+}
+
+impl<D: DB> Trie<D, HasherKeccak> for SecureTrie<D> {
+    fn get<K: AsRef<[u8]>>(&self, key: K) -> TrieResult<Option<Vec<u8>>> {
+        self.trie.get(self.hash_key(key.as_ref()))
+    }
+
+    fn contains<K: AsRef<[u8]>>(&self, key: K) -> TrieResult<bool> {
+        self.trie.contains(self.hash_key(key.as_ref()))
+    }
+
+    fn insert<K: AsRef<[u8]>>(&mut self, key: K, value: Vec<u8>) -> TrieResult<()> {
+        let key = key.as_ref();
+        let hash = self.hash_key(key);
+        if self.record_preimages {
+            self.preimages.insert(hash.clone(), key.to_vec());
+        }
+        self.trie.insert(hash, value)
+    }
+
+    fn remove<K: AsRef<[u8]>>(&mut self, key: K) -> TrieResult<bool> {
+        let key = self.hash_key(key.as_ref());
+        self.trie.remove(key)
+    }
+
+    fn root(&mut self) -> TrieResult<Vec<u8>> {
+        let root = self.trie.root()?;
+        self.flush_preimages()?;
+        Ok(root)
+    }
+
+    fn get_proof<K: AsRef<[u8]>>(&self, key: K) -> TrieResult<Vec<Vec<u8>>> {
+        self.trie.get_proof(self.hash_key(key.as_ref()))
+    }
+
+    fn verify_proof<K: AsRef<[u8]>>(
+        &self,
+        root_hash: Vec<u8>,
+        key: K,
+        proof: Vec<Vec<u8>>,
+    ) -> TrieResult<Option<Vec<u8>>> {
+        self.trie
+            .verify_proof(root_hash, self.hash_key(key.as_ref()), proof)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::Value;
+
+    use super::*;
+    use crate::db::MemoryDB;
+
+    // Fixtures from https://github.com/ethereum/tests/tree/develop/TrieTests.
+    const TRIE_TEST: &str = include_str!("../tests/fixtures/TrieTests/trietest.json");
+    const SECURE_TRIE_TEST: &str =
+        include_str!("../tests/fixtures/TrieTests/hex_encoded_securetrie_test.json");
+
+    // Strings starting with "0x" are hex, anything else is taken verbatim.
+    fn decode(s: &str) -> Vec<u8> {
+        if s.starts_with("0x") {
+            hex::decode(&s[2..]).unwrap()
+        } else {
+            s.as_bytes().to_vec()
+        }
+    }
+
+    fn assert_fixture<T: Trie<MemoryDB, HasherKeccak>>(
+        name: &str,
+        mut trie: T,
+        kvs: Vec<(Vec<u8>, Vec<u8>)>,
+        root: &Value,
+    ) {
+        for (k, v) in kvs.into_iter() {
+            trie.insert(k, v).unwrap();
+        }
+        let expected = decode(root.as_str().unwrap());
+        assert_eq!(trie.root().unwrap(), expected, "{}", name);
+    }
+
+    #[test]
+    fn test_ethereum_trie_fixtures() {
+        let tests: Value = serde_json::from_str(TRIE_TEST).unwrap();
+        for (name, test) in tests.as_object().unwrap().iter() {
+            // A null value deletes the key, as does inserting an empty value.
+            let kvs = test["in"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|kv| {
+                    let value = kv[1].as_str().map(decode).unwrap_or_default();
+                    (decode(kv[0].as_str().unwrap()), value)
+                })
+                .collect();
+            let trie =
+                EthereumTrie::new(Arc::new(MemoryDB::new(true)), Arc::new(HasherKeccak::new()));
+            assert_fixture(name, trie, kvs, &test["root"]);
+        }
+    }
+
+    #[test]
+    fn test_ethereum_secure_trie_fixtures() {
+        let tests: Value = serde_json::from_str(SECURE_TRIE_TEST).unwrap();
+        for (name, test) in tests.as_object().unwrap().iter() {
+            let kvs = test["in"]
+                .as_object()
+                .unwrap()
+                .iter()
+                .map(|(k, v)| (decode(k), decode(v.as_str().unwrap())))
+                .collect();
+            let trie = SecureTrie::new(Arc::new(MemoryDB::new(true)));
+            assert_fixture(name, trie, kvs, &test["root"]);
+        }
+    }
+
+    #[test]
+    fn test_secure_trie_proof() {
+        let memdb = Arc::new(MemoryDB::new(true));
+        let mut trie = SecureTrie::new(Arc::clone(&memdb));
+        for i in 0..100u8 {
+            trie.insert(vec![i], vec![i; 40]).unwrap();
+        }
+        let root = trie.root().unwrap();
+
+        let trie = SecureTrie::from(memdb, &root).unwrap();
+        assert_eq!(trie.get(&[7]).unwrap(), Some(vec![7; 40]));
+        let proof = trie.get_proof(&[7]).unwrap();
+        let value = trie.verify_proof(root, &[7], proof).unwrap();
+        assert_eq!(value, Some(vec![7; 40]));
+        assert_eq!(trie.get(&[100]).unwrap(), None);
+    }
+
+    #[test]
+    fn test_ethereum_keys() {
+        let address = H160::from([7u8; 20]);
+        let slot = H256::from(1u64);
+        let mut trie =
+            EthereumTrie::new(Arc::new(MemoryDB::new(true)), Arc::new(HasherKeccak::new()));
+        trie.insert_h256(&slot, b"hash".to_vec()).unwrap();
+        trie.insert_address_slot(&address, &slot, b"slot".to_vec())
+            .unwrap();
+        let mut key = vec![0; 31];
+        key.push(1);
+        assert_eq!(trie.get(&key).unwrap(), Some(b"hash".to_vec()));
+        assert_eq!(
+            trie.get([&[7; 20][..], &key].concat()).unwrap(),
+            Some(b"slot".to_vec())
+        );
+        assert!(trie.remove_address_slot(&address, &slot).unwrap());
+        assert_eq!(trie.get_address_slot(&address, &slot).unwrap(), None);
+
+        let mut secure = SecureTrie::new(Arc::new(MemoryDB::new(true)));
+        secure.insert_h256(&slot, b"hash".to_vec()).unwrap();
+        assert_eq!(secure.get(&key).unwrap(), Some(b"hash".to_vec()));
+        assert_eq!(secure.get_h256(&slot).unwrap(), Some(b"hash".to_vec()));
+    }
+
+    #[test]
+    fn test_secure_trie_preimages() {
+        let memdb = Arc::new(MemoryDB::new(true));
+        let hasher = HasherKeccak::new();
+        let mut trie = SecureTrie::new(Arc::clone(&memdb)).with_preimages();
+        trie.insert(b"recorded".to_vec(), vec![1; 40]).unwrap();
+        let hash = hasher.digest(b"recorded");
+        assert_eq!(trie.preimage(&hash).unwrap(), Some(b"recorded".to_vec()));
+        trie.set_record_preimages(false);
+        trie.insert(b"skipped".to_vec(), vec![2; 40]).unwrap();
+        assert_eq!(trie.preimage(&hasher.digest(b"skipped")).unwrap(), None);
+
+        let root = trie.root().unwrap();
+        assert_eq!(trie.flush_preimages().unwrap(), 0);
+        let trie = SecureTrie::from(memdb, &root).unwrap();
+        assert_eq!(trie.preimage(&hash).unwrap(), Some(b"recorded".to_vec()));
+    }
+}
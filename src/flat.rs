@@ -0,0 +1,364 @@
+use std::iter;
+
+use hasher::Hasher;
+
+use crate::codec::NodeCodec;
+use crate::db::DB;
+use crate::errors::TrieError;
+use crate::trie::{PatriciaTrie, PendingChange, TrieResult};
+
+// The values of a flat snapshot are kept under this prefix, the length of
+// the name of the table as 4 bytes big endian, the name, the generation of
+// the table and the key.
+const FLAT_PREFIX: &[u8] = b"cita-trie:flat:";
+
+// Followed by the name, the generation of the current table, 8 bytes big
+// endian. A table that fell behind is written again as a new generation, so
+// that the values of keys removed meanwhile are never read.
+const FLAT_GENERATION_KEY: &[u8] = b"cita-trie:flat-generation:";
+
+// Followed by the name, the generation and the root the current table holds
+// the values of, empty while it is written. Reads check it, so tries sharing
+// the table never read one another moved on.
+const FLAT_ROOT_KEY: &[u8] = b"cita-trie:flat-root:";
+
+// Entries start with a tag, a removed key is overwritten with the tag of
+// absence alone, as some DBs ignore removes, e.g. "MemoryDB::new(false)".
+const FLAT_PRESENT: u8 = 1;
+const FLAT_REMOVED: u8 = 0;
+
+// Regenerated values are written in batches of this size.
+const FLAT_BATCH: usize = 1024;
+
+impl<D, H, C> PatriciaTrie<D, H, C>
+where
+    D: DB,
+    H: Hasher,
+    C: NodeCodec,
+{
+    // The table "name" if it holds the values of the trie, which has no
+    // change since the last commit.
+    pub(crate) fn committed_flat(&self, name: &[u8]) -> TrieResult<Option<FlatSnapshot>> {
+        if !self.is_committed() {
+            return Ok(None);
+        }
+        FlatSnapshot::at(&**self.db(), name, self.root_hash())
+    }
+
+    // Commits and updates the table "name" with the changes if it holds the
+    // old root, or writes it again from the new root.
+    pub(crate) fn commit_flat(&mut self, name: &[u8]) -> TrieResult<Vec<u8>> {
+        let mut flat = FlatSnapshot::load(&**self.db(), name)?;
+        let changes = if flat.is_at(self.root_hash()) {
+            Some(self.pending_diff()?)
+        } else {
+            None
+        };
+        let root_hash = self.commit_nodes()?;
+        match changes {
+            Some(changes) => flat.update(&**self.db(), &root_hash, changes)?,
+            None => {
+                let mut values = self.iter();
+                let values = iter::from_fn(|| values.try_next().transpose());
+                flat.regenerate(&**self.db(), &root_hash, values)?
+            }
+        }
+        Ok(root_hash)
+    }
+}
+
+// The table of values "with_flat_snapshot" maintains.
+#[derive(Debug)]
+pub(crate) struct FlatSnapshot {
+    name: Vec<u8>,
+    generation: u64,
+    root: Option<Vec<u8>>,
+}
+
+impl FlatSnapshot {
+    fn load<D: DB>(db: &D, name: &[u8]) -> TrieResult<Self> {
+        let generation = match db
+            .get(&[FLAT_GENERATION_KEY, name].concat())
+            .map_err(|e| TrieError::DB(e.to_string()))?
+        {
+            Some(bytes) => {
+                if bytes.len() != 8 {
+                    return Err(TrieError::InvalidData);
+                }
+                read_u64(&bytes)
+            }
+            None => 0,
+        };
+        let root = read_root(db, name)?
+            .filter(|(root_generation, _)| *root_generation == generation)
+            .map(|(_, root)| root);
+        Ok(FlatSnapshot {
+            name: name.to_vec(),
+            generation,
+            root,
+        })
+    }
+
+    // Returns the table if it holds the values of "root".
+    fn at<D: DB>(db: &D, name: &[u8], root: &[u8]) -> TrieResult<Option<Self>> {
+        Ok(read_root(db, name)?.and_then(|(generation, flat_root)| {
+            if flat_root[..] == *root {
+                Some(FlatSnapshot {
+                    name: name.to_vec(),
+                    generation,
+                    root: Some(flat_root),
+                })
+            } else {
+                None
+            }
+        }))
+    }
+
+    // Whether the table holds the values of "root".
+    fn is_at(&self, root: &[u8]) -> bool {
+        self.root
+            .as_ref()
+            .map_or(false, |flat_root| flat_root[..] == *root)
+    }
+
+    pub(crate) fn get<D: DB>(&self, db: &D, key: &[u8]) -> TrieResult<Option<Vec<u8>>> {
+        let entry = db
+            .get(&self.key(key))
+            .map_err(|e| TrieError::DB(e.to_string()))?;
+        match entry {
+            Some(entry) => match entry.split_first() {
+                Some((&FLAT_PRESENT, value)) => Ok(Some(value.to_vec())),
+                Some((&FLAT_REMOVED, [])) => Ok(None),
+                _ => Err(TrieError::InvalidData),
+            },
+            None => Ok(None),
+        }
+    }
+
+    // Applies "changes", the diff from the root the table is at to "root".
+    fn update<D: DB>(
+        &mut self,
+        db: &D,
+        root: &[u8],
+        changes: Vec<PendingChange>,
+    ) -> TrieResult<()> {
+        self.invalidate(db)?;
+        let (mut keys, mut values) = (vec![], vec![]);
+        for (key, _, new) in changes.into_iter() {
+            keys.push(self.key(&key));
+            values.push(match new {
+                Some(value) => present(&value),
+                None => vec![FLAT_REMOVED],
+            });
+        }
+        db.insert_batch(keys, values)
+            .map_err(|e| TrieError::DB(e.to_string()))?;
+        self.set_root(db, root)
+    }
+
+    // Writes a new generation of the table, holding "values", the values of
+    // "root". An error of "values" fails before the table is marked as at
+    // "root". The older generations are removed if the DB lists its keys and
+    // are never read otherwise.
+    fn regenerate<D, I>(&mut self, db: &D, root: &[u8], values: I) -> TrieResult<()>
+    where
+        D: DB,
+        I: IntoIterator<Item = TrieResult<(Vec<u8>, Vec<u8>)>>,
+    {
+        self.invalidate(db)?;
+        self.generation += 1;
+        db.insert(
+            [FLAT_GENERATION_KEY, &self.name].concat(),
+            self.generation.to_be_bytes().to_vec(),
+        )
+        .map_err(|e| TrieError::DB(e.to_string()))?;
+
+        let (mut keys, mut batch) = (vec![], vec![]);
+        for entry in values.into_iter() {
+            let (key, value) = entry?;
+            keys.push(self.key(&key));
+            batch.push(present(&value));
+            if keys.len() >= FLAT_BATCH {
+                db.insert_batch(keys.split_off(0), batch.split_off(0))
+                    .map_err(|e| TrieError::DB(e.to_string()))?;
+            }
+        }
+        db.insert_batch(keys, batch)
+            .map_err(|e| TrieError::DB(e.to_string()))?;
+        self.set_root(db, root)?;
+
+        let (table, current) = (self.table_prefix(), self.key(&[]));
+        if let Some(keys) = db.keys().map_err(|e| TrieError::DB(e.to_string()))? {
+            let stale: Vec<Vec<u8>> = keys
+                .into_iter()
+                .filter(|key| key.starts_with(&table) && !key.starts_with(&current))
+                .collect();
+            db.remove_batch(&stale)
+                .map_err(|e| TrieError::DB(e.to_string()))?;
+            db.flush().map_err(|e| TrieError::DB(e.to_string()))?;
+        }
+        Ok(())
+    }
+
+    // Marks the table as being written, so that it is not read if the writes
+    // are cut short.
+    fn invalidate<D: DB>(&mut self, db: &D) -> TrieResult<()> {
+        self.root = None;
+        db.insert([FLAT_ROOT_KEY, &self.name].concat(), vec![])
+            .map_err(|e| TrieError::DB(e.to_string()))?;
+        db.flush().map_err(|e| TrieError::DB(e.to_string()))
+    }
+
+    fn set_root<D: DB>(&mut self, db: &D, root: &[u8]) -> TrieResult<()> {
+        let marker = [&self.generation.to_be_bytes()[..], root].concat();
+        db.insert([FLAT_ROOT_KEY, &self.name].concat(), marker)
+            .map_err(|e| TrieError::DB(e.to_string()))?;
+        db.flush().map_err(|e| TrieError::DB(e.to_string()))?;
+        self.root = Some(root.to_vec());
+        Ok(())
+    }
+
+    // The prefix of every generation of the table.
+    fn table_prefix(&self) -> Vec<u8> {
+        let len = (self.name.len() as u32).to_be_bytes();
+        [FLAT_PREFIX, &len, &self.name].concat()
+    }
+
+    fn key(&self, key: &[u8]) -> Vec<u8> {
+        [
+            &self.table_prefix(),
+            &self.generation.to_be_bytes()[..],
+            key,
+        ]
+        .concat()
+    }
+}
+
+fn read_root<D: DB>(db: &D, name: &[u8]) -> TrieResult<Option<(u64, Vec<u8>)>> {
+    match db
+        .get(&[FLAT_ROOT_KEY, name].concat())
+        .map_err(|e| TrieError::DB(e.to_string()))?
+    {
+        Some(ref marker) if marker.is_empty() => Ok(None),
+        Some(marker) => {
+            if marker.len() < 8 {
+                return Err(TrieError::InvalidData);
+            }
+            Ok(Some((read_u64(&marker[..8]), marker[8..].to_vec())))
+        }
+        None => Ok(None),
+    }
+}
+
+fn present(value: &[u8]) -> Vec<u8> {
+    [&[FLAT_PRESENT][..], value].concat()
+}
+
+fn read_u64(bytes: &[u8]) -> u64 {
+    let mut buf = [0u8; 8];
+    buf.copy_from_slice(bytes);
+    u64::from_be_bytes(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use hasher::{Hasher, HasherKeccak};
+
+    use super::FlatSnapshot;
+    use crate::db::{MemoryDB, MeteredDB, DB};
+    use crate::errors::TrieError;
+    use crate::trie::{PatriciaTrie, Trie};
+
+    #[test]
+    fn test_flat_snapshot() {
+        let memdb = Arc::new(MeteredDB::new(MemoryDB::new(true)));
+        let hasher = Arc::new(HasherKeccak::new());
+        let mut trie =
+            PatriciaTrie::new(Arc::clone(&memdb), Arc::clone(&hasher)).with_flat_snapshot(b"state");
+        for i in 0..=255u8 {
+            trie.insert(vec![i, i], vec![i; 40]).unwrap();
+        }
+        trie.root().unwrap();
+        trie.insert(vec![1, 1], vec![11; 40]).unwrap();
+        trie.remove(&[2, 2]).unwrap();
+        // Uncommitted changes are read from the trie.
+        assert_eq!(trie.get(&[1, 1]).unwrap(), Some(vec![11; 40]));
+        let root = trie.root().unwrap();
+
+        let trie = PatriciaTrie::from(Arc::clone(&memdb), Arc::clone(&hasher), &root)
+            .unwrap()
+            .with_flat_snapshot(b"state");
+        memdb.reset();
+        assert_eq!(trie.get(&[1, 1]).unwrap(), Some(vec![11; 40]));
+        assert_eq!(trie.get(&[2, 2]).unwrap(), None);
+        assert_eq!(memdb.metrics().gets, 4);
+
+        // A commit without the table leaves it behind, reads walk the trie
+        // until a commit with the table writes it again.
+        let mut plain = PatriciaTrie::from(Arc::clone(&memdb), Arc::clone(&hasher), &root).unwrap();
+        plain.remove(&[3, 3]).unwrap();
+        let root = plain.root().unwrap();
+        let mut trie = trie.at_root(&root).unwrap();
+        assert_eq!(trie.get(&[3, 3]).unwrap(), None);
+        trie.remove(&[4, 4]).unwrap();
+        let root = trie.root().unwrap();
+        let trie = trie.at_root(&root).unwrap();
+        memdb.reset();
+        assert_eq!(trie.get(&[3, 3]).unwrap(), None);
+        assert_eq!(trie.get(&[5, 5]).unwrap(), Some(vec![5; 40]));
+        assert_eq!(memdb.metrics().gets, 4);
+        let keys = memdb.keys().unwrap().unwrap();
+        let flat = keys.iter().filter(|k| k.starts_with(b"cita-trie:flat:"));
+        assert_eq!(flat.count(), 253);
+
+        // Tables of other names are kept apart.
+        let mut other =
+            PatriciaTrie::new(Arc::clone(&memdb), Arc::clone(&hasher)).with_flat_snapshot(b"other");
+        other.insert(vec![5, 5], vec![55; 40]).unwrap();
+        let other_root = other.root().unwrap();
+        let other = other.at_root(&other_root).unwrap();
+        memdb.reset();
+        assert_eq!(trie.get(&[5, 5]).unwrap(), Some(vec![5; 40]));
+        assert_eq!(memdb.metrics().gets, 2);
+        assert_eq!(other.get(&[5, 5]).unwrap(), Some(vec![55; 40]));
+        assert_eq!(other.get(&[6, 6]).unwrap(), None);
+
+        // Removed keys are read as absent from DBs that ignore removes.
+        let memdb = Arc::new(MemoryDB::new(false));
+        let mut trie =
+            PatriciaTrie::new(Arc::clone(&memdb), Arc::clone(&hasher)).with_flat_snapshot(b"state");
+        for i in 0..=255u8 {
+            trie.insert(vec![i, i], vec![i; 40]).unwrap();
+        }
+        trie.root().unwrap();
+        trie.remove(&[2, 2]).unwrap();
+        let root = trie.root().unwrap();
+        let trie = trie.at_root(&root).unwrap();
+        assert_eq!(trie.get(&[2, 2]).unwrap(), None);
+        assert_eq!(trie.get(&[3, 3]).unwrap(), Some(vec![3; 40]));
+
+        // A node missing from the DB of a strict trie fails the commit that
+        // writes the table again, instead of leaving it short.
+        let memdb = Arc::new(MemoryDB::new(true));
+        let mut plain = PatriciaTrie::new(Arc::clone(&memdb), Arc::clone(&hasher));
+        for i in 0..=255u8 {
+            plain.insert(vec![i, i], vec![i; 40]).unwrap();
+        }
+        let root = plain.root().unwrap();
+        let missing = hasher.digest(&plain.get_proof(&[7, 7]).unwrap()[1]);
+        memdb.remove(&missing).unwrap();
+        let mut trie = plain
+            .at_root(&root)
+            .unwrap()
+            .with_strict_nodes()
+            .with_flat_snapshot(b"state");
+        trie.insert(vec![0x80, 1], vec![8; 40]).unwrap();
+        match trie.root() {
+            Err(TrieError::MissingNode { hash }) => assert_eq!(hash, missing),
+            _ => panic!("the table was written without the missing node"),
+        }
+        assert!(!FlatSnapshot::load(&*memdb, b"state").unwrap().is_at(&root));
+    }
+}
@@ -0,0 +1,274 @@
+use hashbrown::HashSet;
+
+use crate::codec::{ChildReference, NodeCodec, NodeData, ShortItem};
+use crate::db::DB;
+use crate::errors::TrieError;
+use crate::refcount::REF_COUNT_PREFIX;
+use crate::rewind::REVERSE_DIFF_PREFIX;
+use crate::trie::TrieResult;
+
+// The keys of the metadata the crate keeps in the DB, e.g. the bloom filter,
+// start with this. They are not nodes and are kept.
+const METADATA_PREFIX: &[u8] = b"cita-trie:";
+
+/// The roots registered with "register_root" are kept in the DB under this
+/// prefix and the root hash.
+pub const ROOT_PREFIX: &[u8] = b"cita-trie:root:";
+
+/// The key a "SecureTrie" hashed is kept in the DB under this prefix and
+/// the hash, see "with_preimages".
+pub const PREIMAGE_PREFIX: &[u8] = b"cita-trie:preimage:";
+
+#[cfg(any(feature = "ethereum", feature = "json", feature = "csv"))]
+pub(crate) fn preimage_key(hash: &[u8]) -> Vec<u8> {
+    [PREIMAGE_PREFIX, hash].concat()
+}
+
+/// Removes every node of "db" that none of "live_roots" reaches, e.g. the
+/// nodes of abandoned forks, and returns the number of removed keys. The keys
+/// are listed first, so nodes written meanwhile are kept, but a trie that
+/// commits while it runs must have its roots in "live_roots".
+pub fn collect_garbage<D, C>(db: &D, codec: &C, live_roots: &[Vec<u8>]) -> TrieResult<usize>
+where
+    D: DB,
+    C: NodeCodec,
+{
+    let dead = unreachable_keys(db, codec, live_roots)?;
+    db.remove_batch(&dead)
+        .map_err(|e| TrieError::DB(e.to_string()))?;
+    db.flush().map_err(|e| TrieError::DB(e.to_string()))?;
+    Ok(dead.len())
+}
+
+/// Records "root" in "db" as a root that "find_orphans" keeps, e.g. the root
+/// of every retained block.
+pub fn register_root<D: DB>(db: &D, root: &[u8]) -> TrieResult<()> {
+    db.insert(root_key(root), vec![])
+        .map_err(|e| TrieError::DB(e.to_string()))?;
+    db.flush().map_err(|e| TrieError::DB(e.to_string()))
+}
+
+pub fn unregister_root<D: DB>(db: &D, root: &[u8]) -> TrieResult<()> {
+    db.remove(&root_key(root))
+        .map_err(|e| TrieError::DB(e.to_string()))?;
+    db.flush().map_err(|e| TrieError::DB(e.to_string()))
+}
+
+pub fn registered_roots<D: DB>(db: &D) -> TrieResult<Vec<Vec<u8>>> {
+    Ok(list_keys(db)?
+        .into_iter()
+        .filter(|key| key.starts_with(ROOT_PREFIX))
+        .map(|key| key[ROOT_PREFIX.len()..].to_vec())
+        .collect())
+}
+
+/// Returns the keys of the nodes that no registered root reaches, e.g. the
+/// partial write sets of commits interrupted by a crash. If "remove" is set
+/// they are removed as well. With no root registered, every node is an orphan.
+pub fn find_orphans<D, C>(db: &D, codec: &C, remove: bool) -> TrieResult<Vec<Vec<u8>>>
+where
+    D: DB,
+    C: NodeCodec,
+{
+    let orphans = unreachable_keys(db, codec, &registered_roots(db)?)?;
+    if remove {
+        db.remove_batch(&orphans)
+            .map_err(|e| TrieError::DB(e.to_string()))?;
+        db.flush().map_err(|e| TrieError::DB(e.to_string()))?;
+    }
+    Ok(orphans)
+}
+
+fn root_key(root: &[u8]) -> Vec<u8> {
+    [ROOT_PREFIX, root].concat()
+}
+
+fn list_keys<D: DB>(db: &D) -> TrieResult<Vec<Vec<u8>>> {
+    match db.keys().map_err(|e| TrieError::DB(e.to_string()))? {
+        Some(keys) => Ok(keys),
+        None => Err(TrieError::KeysUnsupported),
+    }
+}
+
+// Returns the keys of "db" that are neither metadata nor nodes under "roots".
+fn unreachable_keys<D, C>(db: &D, codec: &C, roots: &[Vec<u8>]) -> TrieResult<Vec<Vec<u8>>>
+where
+    D: DB,
+    C: NodeCodec,
+{
+    let keys = list_keys(db)?;
+    let live = reachable_nodes(db, codec, roots)?;
+    Ok(keys
+        .into_iter()
+        .filter(|key| !is_live(&live, key))
+        .collect())
+}
+
+// Returns the hashes of the nodes under "roots", which must all be in "db".
+// The tries are walked level by level, with one batch read per level.
+fn reachable_nodes<D, C>(db: &D, codec: &C, roots: &[Vec<u8>]) -> TrieResult<HashSet<Vec<u8>>>
+where
+    D: DB,
+    C: NodeCodec,
+{
+    let mut live = HashSet::new();
+    let mut pending: Vec<Vec<u8>> = roots
+        .iter()
+        .filter(|root| live.insert(root.to_vec()))
+        .cloned()
+        .collect();
+    let mut is_root = true;
+    while !pending.is_empty() {
+        let values = db
+            .get_batch(&pending)
+            .map_err(|e| TrieError::DB(e.to_string()))?;
+        let mut children = vec![];
+        for value in values.into_iter() {
+            match value {
+                Some(data) => child_hashes(codec, &data, &mut children)?,
+                // A missing root would make every node garbage.
+                None if is_root => return Err(TrieError::InvalidStateRoot),
+                None => {}
+            }
+        }
+        pending = children
+            .into_iter()
+            .filter(|hash| live.insert(hash.clone()))
+            .collect();
+        is_root = false;
+    }
+    Ok(live)
+}
+
+// Appends the hashes of the children of the encoded node "data", including
+// those of inline children.
+pub(crate) fn child_hashes<C: NodeCodec>(
+    codec: &C,
+    data: &[u8],
+    out: &mut Vec<Vec<u8>>,
+) -> TrieResult<()> {
+    let push = |child: ChildReference, out: &mut Vec<Vec<u8>>| match child {
+        ChildReference::Hash(hash) => {
+            out.push(hash);
+            Ok(())
+        }
+        ChildReference::Inline(data) => child_hashes(codec, &data, out),
+    };
+    match codec.decode(data)? {
+        NodeData::Short {
+            item: ShortItem::Child(child),
+            ..
+        } => push(child, out),
+        NodeData::Branch { children, .. } => {
+            for child in children.iter().cloned().flatten() {
+                push(child, out)?;
+            }
+            Ok(())
+        }
+        _ => Ok(()),
+    }
+}
+
+// The metadata of a node or root is kept as long as the node is.
+fn is_live(live: &HashSet<Vec<u8>>, key: &[u8]) -> bool {
+    for prefix in [REF_COUNT_PREFIX, REVERSE_DIFF_PREFIX].iter() {
+        if key.starts_with(prefix) {
+            return live.contains(&key[prefix.len()..]);
+        }
+    }
+    key.starts_with(METADATA_PREFIX) || live.contains(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use hasher::HasherKeccak;
+
+    use super::{collect_garbage, find_orphans, register_root, registered_roots, unregister_root};
+    use crate::codec::RLPNodeCodec;
+    use crate::db::{MemoryDB, DB};
+    use crate::errors::TrieError;
+    use crate::trie::{PatriciaTrie, Trie};
+
+    #[test]
+    fn test_collect_garbage() {
+        let memdb = Arc::new(MemoryDB::new(true));
+        let hasher = Arc::new(HasherKeccak::new());
+        let mut trie =
+            PatriciaTrie::new(Arc::clone(&memdb), Arc::clone(&hasher)).with_archive_mode();
+        let mut roots = vec![];
+        for i in 0..20u8 {
+            trie.insert(vec![i], vec![i; 40]).unwrap();
+            roots.push(trie.root().unwrap());
+        }
+        // An abandoned fork of the 10th root.
+        let mut fork = PatriciaTrie::from(Arc::clone(&memdb), Arc::clone(&hasher), &roots[9])
+            .unwrap()
+            .with_archive_mode();
+        fork.insert(vec![50], vec![50; 40]).unwrap();
+        fork.root().unwrap();
+
+        let live = vec![roots[9].clone(), roots[19].clone()];
+        let removed = collect_garbage(&*memdb, &RLPNodeCodec, &live).unwrap();
+        assert!(removed > 0);
+        assert_eq!(collect_garbage(&*memdb, &RLPNodeCodec, &live).unwrap(), 0);
+        for root in live.iter() {
+            let trie = PatriciaTrie::from(Arc::clone(&memdb), Arc::clone(&hasher), root).unwrap();
+            assert_eq!(trie.iter().count(), if *root == roots[9] { 10 } else { 20 });
+        }
+        assert!(PatriciaTrie::from(Arc::clone(&memdb), Arc::clone(&hasher), &roots[5]).is_err());
+
+        // A mistyped root removes nothing.
+        let len = memdb.len().unwrap();
+        match collect_garbage(&*memdb, &RLPNodeCodec, &[vec![0; 32]]) {
+            Err(TrieError::InvalidStateRoot) => {}
+            other => panic!("unexpected {:?}", other),
+        }
+        assert_eq!(memdb.len().unwrap(), len);
+    }
+
+    #[test]
+    fn test_find_orphans() {
+        let memdb = Arc::new(MemoryDB::new(true));
+        let hasher = Arc::new(HasherKeccak::new());
+        let mut trie = PatriciaTrie::new(Arc::clone(&memdb), Arc::clone(&hasher));
+        for i in 0..20u8 {
+            trie.insert(vec![i], vec![i; 40]).unwrap();
+        }
+        let root = trie.root().unwrap();
+        register_root(&*memdb, &root).unwrap();
+        assert_eq!(registered_roots(&*memdb).unwrap(), vec![root.clone()]);
+        assert!(find_orphans(&*memdb, &RLPNodeCodec, false)
+            .unwrap()
+            .is_empty());
+        let len = memdb.len().unwrap();
+
+        // Half of the write set of a commit that crashed.
+        let scratch = Arc::new(MemoryDB::new(true));
+        let mut crashed = PatriciaTrie::new(Arc::clone(&scratch), Arc::clone(&hasher));
+        for i in 0..20u8 {
+            crashed.insert(vec![i], vec![i + 100; 40]).unwrap();
+        }
+        crashed.root().unwrap();
+        let mut partial = scratch.keys().unwrap().unwrap();
+        partial.truncate(partial.len() / 2);
+        for key in partial.iter() {
+            memdb
+                .insert(key.clone(), scratch.get(key).unwrap().unwrap())
+                .unwrap();
+        }
+
+        let mut orphans = find_orphans(&*memdb, &RLPNodeCodec, false).unwrap();
+        orphans.sort();
+        partial.sort();
+        assert_eq!(orphans, partial);
+        find_orphans(&*memdb, &RLPNodeCodec, true).unwrap();
+        assert_eq!(memdb.len().unwrap(), len);
+        let trie = PatriciaTrie::from(Arc::clone(&memdb), hasher, &root).unwrap();
+        assert_eq!(trie.iter().count(), 20);
+
+        unregister_root(&*memdb, &root).unwrap();
+        assert!(registered_roots(&*memdb).unwrap().is_empty());
+    }
+}
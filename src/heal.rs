@@ -0,0 +1,171 @@
+use std::sync::Arc;
+
+use hashbrown::HashSet;
+use hasher::Hasher;
+
+use crate::codec::NodeCodec;
+use crate::db::DB;
+use crate::errors::TrieError;
+use crate::gc::child_hashes;
+use crate::trie::TrieResult;
+
+/// "Heal" completes a trie whose nodes are partly missing from the DB, e.g.
+/// after a sync by ranges or an interrupted download. "heal" finds the
+/// missing nodes, "add_nodes" writes them once fetched, e.g. from peers, and
+/// finds the nodes they lead to that are missing as well.
+#[derive(Debug)]
+pub struct Heal<D: DB, H: Hasher, C: NodeCodec> {
+    db: Arc<D>,
+    hasher: Arc<H>,
+    codec: Arc<C>,
+    missing: HashSet<Vec<u8>>,
+}
+
+/// Walks the trie at "root" in "db" and collects the hashes of the nodes it
+/// lacks, the root included.
+pub fn heal<D, H, C>(
+    db: Arc<D>,
+    hasher: Arc<H>,
+    codec: Arc<C>,
+    root: &[u8],
+) -> TrieResult<Heal<D, H, C>>
+where
+    D: DB,
+    H: Hasher,
+    C: NodeCodec,
+{
+    let missing = find_missing(&*db, &*codec, vec![root.to_vec()])?;
+    Ok(Heal {
+        db,
+        hasher,
+        codec,
+        missing: missing.into_iter().collect(),
+    })
+}
+
+impl<D: DB, H: Hasher, C: NodeCodec> Heal<D, H, C> {
+    /// Returns the hashes of the missing nodes known so far, sorted.
+    pub fn missing(&self) -> Vec<Vec<u8>> {
+        let mut missing: Vec<Vec<u8>> = self.missing.iter().cloned().collect();
+        missing.sort();
+        missing
+    }
+
+    /// Whether every node of the trie is in the DB.
+    pub fn is_complete(&self) -> bool {
+        self.missing.is_empty()
+    }
+
+    /// Writes those of "nodes" that are missing, checked by their hash, and
+    /// returns how many. Other nodes are ignored, a peer may send nodes that
+    /// were not asked for.
+    pub fn add_nodes(&mut self, nodes: Vec<Vec<u8>>) -> TrieResult<usize> {
+        let mut keys = vec![];
+        let mut values = vec![];
+        let mut children = vec![];
+        for node in nodes.into_iter() {
+            let hash = self.hasher.digest(&node);
+            if !self.missing.contains(&hash) {
+                continue;
+            }
+            child_hashes(&*self.codec, &node, &mut children)?;
+            self.missing.remove(&hash);
+            keys.push(hash);
+            values.push(node);
+        }
+        if keys.is_empty() {
+            return Ok(0);
+        }
+
+        let added = keys.len();
+        self.db
+            .insert_batch(keys, values)
+            .map_err(|e| TrieError::DB(e.to_string()))?;
+        self.db.flush().map_err(|e| TrieError::DB(e.to_string()))?;
+        let missing = find_missing(&*self.db, &*self.codec, children)?;
+        self.missing.extend(missing);
+        Ok(added)
+    }
+}
+
+/// Returns the nodes "db" lacks of the subtries at "hashes", a level of the
+/// nodes it holds at a time.
+pub(crate) fn find_missing<D, C>(
+    db: &D,
+    codec: &C,
+    hashes: Vec<Vec<u8>>,
+) -> TrieResult<Vec<Vec<u8>>>
+where
+    D: DB,
+    C: NodeCodec,
+{
+    let mut seen = HashSet::new();
+    let mut pending: Vec<Vec<u8>> = hashes
+        .into_iter()
+        .filter(|hash| seen.insert(hash.clone()))
+        .collect();
+    let mut missing = vec![];
+    while !pending.is_empty() {
+        let values = db
+            .get_batch(&pending)
+            .map_err(|e| TrieError::DB(e.to_string()))?;
+        let mut children = vec![];
+        for (hash, value) in pending.into_iter().zip(values) {
+            match value {
+                Some(data) => child_hashes(codec, &data, &mut children)?,
+                None => missing.push(hash),
+            }
+        }
+        pending = children
+            .into_iter()
+            .filter(|hash| seen.insert(hash.clone()))
+            .collect();
+    }
+    Ok(missing)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use hasher::HasherKeccak;
+
+    use super::heal;
+    use crate::codec::RLPNodeCodec;
+    use crate::db::{MemoryDB, DB};
+    use crate::trie::{PatriciaTrie, Trie};
+
+    #[test]
+    fn test_heal() {
+        let source = Arc::new(MemoryDB::new(true));
+        let hasher = Arc::new(HasherKeccak::new());
+        let mut trie = PatriciaTrie::new(Arc::clone(&source), Arc::clone(&hasher));
+        for i in 0..=255u8 {
+            trie.insert(vec![i, i], vec![i; 40]).unwrap();
+        }
+        let root = trie.root().unwrap();
+
+        // A partial copy, every other node is missing.
+        let memdb = Arc::new(MemoryDB::new(true));
+        let mut keys = source.keys().unwrap().unwrap();
+        keys.sort();
+        for key in keys.iter().step_by(2) {
+            memdb
+                .insert(key.clone(), source.get(key).unwrap().unwrap())
+                .unwrap();
+        }
+
+        let codec = Arc::new(RLPNodeCodec::new());
+        let mut heal = heal(Arc::clone(&memdb), hasher, codec, &root).unwrap();
+        assert!(!heal.is_complete());
+        assert_eq!(heal.add_nodes(vec![b"unasked".to_vec()]).unwrap(), 0);
+        while !heal.is_complete() {
+            let nodes = source.get_batch(&heal.missing()).unwrap();
+            let nodes: Vec<Vec<u8>> = nodes.into_iter().map(Option::unwrap).collect();
+            assert_eq!(heal.add_nodes(nodes.clone()).unwrap(), nodes.len());
+        }
+        assert_eq!(memdb.len().unwrap(), source.len().unwrap());
+        let trie = PatriciaTrie::from(memdb, Arc::new(HasherKeccak::new()), &root).unwrap();
+        assert_eq!(trie.iter().count(), 256);
+    }
+}
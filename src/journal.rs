@@ -0,0 +1,150 @@
+use std::mem;
+
+use hashbrown::HashMap;
+
+// Recorded changes are folded into the net counts once there are this many.
+const COMPACT_THRESHOLD: usize = 4096;
+
+/// The nodes a commit wrote and made stale, by hash.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ChangeSet {
+    pub inserted: Vec<Vec<u8>>,
+    pub removed: Vec<Vec<u8>>,
+}
+
+/// Records the nodes that the operations since the last commit encoded and
+/// made stale. Changes are appended as they happen and folded into a net
+/// count per hash, where a node that is made stale and encoded again cancels
+/// out, so the journal only holds the hashes that actually changed.
+#[derive(Debug, Default, Clone)]
+pub(crate) struct Journal {
+    // Changes since the last compaction, in the order they were made.
+    entries: Vec<(Vec<u8>, i32)>,
+    // Net count per hash, zero counts are dropped.
+    net: HashMap<Vec<u8>, i32>,
+}
+
+impl Journal {
+    pub fn new() -> Self {
+        Journal::default()
+    }
+
+    pub fn inserted(&mut self, hash: Vec<u8>) {
+        self.push(hash, 1);
+    }
+
+    pub fn removed(&mut self, hash: Vec<u8>) {
+        self.push(hash, -1);
+    }
+
+    fn push(&mut self, hash: Vec<u8>, change: i32) {
+        self.entries.push((hash, change));
+        if self.entries.len() >= COMPACT_THRESHOLD {
+            self.compact();
+        }
+    }
+
+    /// Appends the changes of "other", e.g. of a trie that worked on a subtrie.
+    #[cfg(feature = "parallel")]
+    pub fn append(&mut self, other: Journal) {
+        self.entries.extend(other.entries);
+        self.entries.extend(other.net);
+        self.compact();
+    }
+
+    pub fn compact(&mut self) {
+        for (hash, change) in mem::replace(&mut self.entries, vec![]).into_iter() {
+            let count = {
+                let count = self.net.entry(hash.clone()).or_insert(0);
+                *count += change;
+                *count
+            };
+            if count == 0 {
+                self.net.remove(&hash);
+            }
+        }
+    }
+
+    /// Whether more nodes under "hash" were made stale than encoded, as of
+    /// the last compaction.
+    pub fn is_removed(&self, hash: &[u8]) -> bool {
+        self.net.get(hash).map_or(false, |count| *count < 0)
+    }
+
+    /// Returns the net changes, sorted by hash.
+    pub fn changeset(&mut self) -> ChangeSet {
+        self.compact();
+        let mut changes = ChangeSet::default();
+        for (hash, count) in self.net.iter() {
+            if *count > 0 {
+                changes.inserted.push(hash.clone());
+            } else {
+                changes.removed.push(hash.clone());
+            }
+        }
+        changes.inserted.sort();
+        changes.removed.sort();
+        changes
+    }
+
+    /// Returns the net count of every changed hash, sorted by hash. A node
+    /// at several places of the trie counts once per place.
+    pub fn deltas(&mut self) -> Vec<(Vec<u8>, i32)> {
+        self.compact();
+        let mut deltas: Vec<(Vec<u8>, i32)> = self
+            .net
+            .iter()
+            .map(|(hash, count)| (hash.clone(), *count))
+            .collect();
+        deltas.sort();
+        deltas
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.net.clear();
+    }
+
+    /// Returns the approximate number of bytes held by the journal.
+    pub fn memory_usage(&self) -> usize {
+        let entry = mem::size_of::<(Vec<u8>, i32)>();
+        // The table of a hash map holds one control byte per entry.
+        self.entries.capacity() * entry
+            + self.net.capacity() * (entry + 1)
+            + self.entries.iter().map(|(h, _)| h.len()).sum::<usize>()
+            + self.net.keys().map(Vec::len).sum::<usize>()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ChangeSet, Journal};
+
+    #[test]
+    fn test_journal_compaction() {
+        let mut journal = Journal::new();
+        journal.removed(b"a".to_vec());
+        journal.inserted(b"b".to_vec());
+        journal.removed(b"c".to_vec());
+        // "c" is encoded again, e.g. after a value was changed back.
+        journal.inserted(b"c".to_vec());
+        assert!(!journal.is_removed(b"a"));
+
+        journal.compact();
+        assert!(journal.is_removed(b"a") && !journal.is_removed(b"c"));
+        assert_eq!(journal.net.len(), 2);
+
+        journal.removed(b"b".to_vec());
+        journal.inserted(b"d".to_vec());
+        assert_eq!(
+            journal.changeset(),
+            ChangeSet {
+                inserted: vec![b"d".to_vec()],
+                removed: vec![b"a".to_vec()],
+            }
+        );
+
+        journal.clear();
+        assert_eq!(journal.changeset(), ChangeSet::default());
+    }
+}
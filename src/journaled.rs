@@ -0,0 +1,156 @@
+use hasher::{Hasher, HasherKeccak};
+
+use crate::codec::{NodeCodec, RLPNodeCodec};
+use crate::db::DB;
+use crate::errors::TrieError;
+use crate::trie::{PatriciaTrie, Trie, TrieResult};
+
+/// "JournaledTrie" records the previous value of every write, so that the
+/// writes since a checkpoint can be reverted, like the state journal of an
+/// EVM when a call fails. Checkpoints nest and last until the next commit.
+#[derive(Debug)]
+pub struct JournaledTrie<D, H = HasherKeccak, C = RLPNodeCodec>
+where
+    D: DB,
+    H: Hasher,
+    C: NodeCodec,
+{
+    trie: PatriciaTrie<D, H, C>,
+    // The key and previous value of every write since the first checkpoint.
+    undo: Vec<(Vec<u8>, Option<Vec<u8>>)>,
+    // The length of "undo" at every open checkpoint, the innermost last.
+    checkpoints: Vec<usize>,
+}
+
+impl<D, H, C> JournaledTrie<D, H, C>
+where
+    D: DB,
+    H: Hasher,
+    C: NodeCodec,
+{
+    pub fn new(trie: PatriciaTrie<D, H, C>) -> Self {
+        JournaledTrie {
+            trie,
+            undo: vec![],
+            checkpoints: vec![],
+        }
+    }
+
+    pub fn trie(&self) -> &PatriciaTrie<D, H, C> {
+        &self.trie
+    }
+
+    pub fn into_inner(self) -> PatriciaTrie<D, H, C> {
+        self.trie
+    }
+
+    pub fn get(&self, key: &[u8]) -> TrieResult<Option<Vec<u8>>> {
+        self.trie.get(key)
+    }
+
+    pub fn contains(&self, key: &[u8]) -> TrieResult<bool> {
+        self.trie.contains(key)
+    }
+
+    pub fn insert(&mut self, key: Vec<u8>, value: Vec<u8>) -> TrieResult<()> {
+        // Writes are only recorded while a checkpoint is open.
+        if self.checkpoints.is_empty() {
+            return self.trie.insert(key, value);
+        }
+        let old = self.trie.insert_return_old(key.clone(), value)?;
+        self.undo.push((key, old));
+        Ok(())
+    }
+
+    pub fn remove(&mut self, key: &[u8]) -> TrieResult<bool> {
+        let old = self.trie.take(key)?;
+        let removed = old.is_some();
+        // Writes are only recorded while a checkpoint is open.
+        if removed && !self.checkpoints.is_empty() {
+            self.undo.push((key.to_vec(), old));
+        }
+        Ok(removed)
+    }
+
+    /// Opens a checkpoint inside the innermost one, returns its id.
+    pub fn checkpoint(&mut self) -> usize {
+        self.checkpoints.push(self.undo.len());
+        self.checkpoints.len() - 1
+    }
+
+    /// Reverts the writes since checkpoint "id" was opened. The checkpoint
+    /// and those opened after it are closed.
+    pub fn revert_to_checkpoint(&mut self, id: usize) -> TrieResult<()> {
+        let len = match self.checkpoints.get(id) {
+            Some(len) => *len,
+            None => return Err(TrieError::UnknownCheckpoint),
+        };
+        while self.undo.len() > len {
+            let (key, old) = self.undo.pop().unwrap();
+            match old {
+                Some(value) => self.trie.insert(key, value)?,
+                None => {
+                    self.trie.remove(&key)?;
+                }
+            }
+        }
+        self.checkpoints.truncate(id);
+        if self.checkpoints.is_empty() {
+            self.undo.clear();
+        }
+        Ok(())
+    }
+
+    /// Commits the trie, the open checkpoints are closed.
+    pub fn root(&mut self) -> TrieResult<Vec<u8>> {
+        self.undo.clear();
+        self.checkpoints.clear();
+        self.trie.root()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use hasher::HasherKeccak;
+
+    use super::JournaledTrie;
+    use crate::db::MemoryDB;
+    use crate::errors::TrieError;
+    use crate::trie::{PatriciaTrie, Trie};
+
+    #[test]
+    fn test_revert_to_checkpoint() {
+        let hasher = Arc::new(HasherKeccak::new());
+        let mut expected = PatriciaTrie::new(Arc::new(MemoryDB::new(true)), Arc::clone(&hasher));
+        let mut trie = JournaledTrie::new(PatriciaTrie::new(Arc::new(MemoryDB::new(true)), hasher));
+        for i in 0..10u8 {
+            trie.insert(vec![i], vec![i; 40]).unwrap();
+            expected.insert(vec![i], vec![i; 40]).unwrap();
+        }
+
+        // A call that writes, and makes an inner call that fails.
+        let outer = trie.checkpoint();
+        trie.insert(vec![1], b"outer".to_vec()).unwrap();
+        trie.insert(vec![20], b"outer".to_vec()).unwrap();
+        let inner = trie.checkpoint();
+        trie.remove(&[1]).unwrap();
+        trie.remove(&[2]).unwrap();
+        trie.insert(vec![20], b"inner".to_vec()).unwrap();
+        trie.insert(vec![21], b"inner".to_vec()).unwrap();
+        trie.revert_to_checkpoint(inner).unwrap();
+        assert_eq!(trie.get(&[1]).unwrap(), Some(b"outer".to_vec()));
+        assert_eq!(trie.get(&[2]).unwrap(), Some(vec![2; 40]));
+        assert_eq!(trie.get(&[20]).unwrap(), Some(b"outer".to_vec()));
+        assert_eq!(trie.get(&[21]).unwrap(), None);
+
+        // The outer call fails as well.
+        trie.revert_to_checkpoint(outer).unwrap();
+        match trie.revert_to_checkpoint(inner) {
+            Err(TrieError::UnknownCheckpoint) => {}
+            other => panic!("unexpected {:?}", other),
+        }
+        assert_eq!(trie.root().unwrap(), expected.root().unwrap());
+    }
+}
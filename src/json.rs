@@ -0,0 +1,144 @@
+use std::io::{BufRead, Write};
+
+use hasher::Hasher;
+use serde_json::{Map, Value};
+
+use crate::codec::NodeCodec;
+use crate::db::DB;
+use crate::errors::TrieError;
+use crate::records::{from_hex, to_hex, Record};
+use crate::trie::{PatriciaTrie, TrieResult};
+
+impl<D, H, C> PatriciaTrie<D, H, C>
+where
+    D: DB,
+    H: Hasher,
+    C: NodeCodec,
+{
+    /// Writes every key and value of the trie at "root" to "writer" as lines
+    /// of JSON, in key order, with hex strings, e.g. for audits or other
+    /// tools. With "preimages" the keys a "SecureTrie" hashed are written too,
+    /// if recorded. Returns the number of keys; a node missing from the DB
+    /// fails the dump.
+    pub fn dump_json<W: Write>(
+        &self,
+        root: &[u8],
+        mut writer: W,
+        preimages: bool,
+    ) -> TrieResult<u64> {
+        let count = self.dump_records(root, preimages, |key, value, preimage| {
+            write_record(&mut writer, key, value, preimage)
+        })?;
+        writer.flush()?;
+        Ok(count)
+    }
+
+    /// Builds a trie from the lines of "dump_json" in the DB, bottom-up as
+    /// "from_sorted_iter" does, so the keys must be in order, and opens it.
+    /// Preimages are recorded for "SecureTrie".
+    pub fn load_json<R: BufRead>(&self, reader: R) -> TrieResult<Self> {
+        self.load_records(reader.lines().filter_map(|line| match line {
+            Ok(ref line) if line.trim().is_empty() => None,
+            Ok(line) => Some(read_record(&line)),
+            Err(e) => Some(Err(e.into())),
+        }))
+    }
+}
+
+// Writes a record as a line of JSON, e.g.
+// {"key":"0x01","value":"0x0203","preimage":"0x04"}.
+fn write_record<W: Write>(
+    writer: &mut W,
+    key: &[u8],
+    value: &[u8],
+    preimage: Option<&[u8]>,
+) -> TrieResult<()> {
+    let mut record = Map::new();
+    record.insert("key".to_owned(), Value::String(to_hex(key)));
+    record.insert("value".to_owned(), Value::String(to_hex(value)));
+    if let Some(preimage) = preimage {
+        record.insert("preimage".to_owned(), Value::String(to_hex(preimage)));
+    }
+    serde_json::to_writer(&mut *writer, &Value::Object(record))
+        .map_err(|_| TrieError::InvalidData)?;
+    writer.write_all(b"\n")?;
+    Ok(())
+}
+
+// Parses a line of "write_record".
+fn read_record(line: &str) -> TrieResult<Record> {
+    let record: Value = serde_json::from_str(line).map_err(|_| TrieError::InvalidData)?;
+    let field = |name: &str| match record.get(name) {
+        Some(Value::String(hex)) => from_hex(hex).map(Some),
+        Some(_) => Err(TrieError::InvalidData),
+        None => Ok(None),
+    };
+    let key = field("key")?.ok_or(TrieError::InvalidData)?;
+    let value = field("value")?.ok_or(TrieError::InvalidData)?;
+    Ok((key, value, field("preimage")?))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use hasher::{Hasher, HasherKeccak};
+
+    use crate::db::{MemoryDB, DB};
+    use crate::errors::TrieError;
+    use crate::gc::preimage_key;
+    use crate::trie::{PatriciaTrie, Trie};
+
+    #[test]
+    fn test_json_roundtrip() {
+        let hasher = Arc::new(HasherKeccak::new());
+        let memdb = Arc::new(MemoryDB::new(true));
+        let mut trie = PatriciaTrie::new(Arc::clone(&memdb), Arc::clone(&hasher));
+        for i in 0..100u8 {
+            let key = hasher.digest(&[i]);
+            trie.insert(&key, vec![i; 3]).unwrap();
+            memdb.insert(preimage_key(&key), vec![i]).unwrap();
+        }
+        let root = trie.root().unwrap();
+
+        let mut json = vec![];
+        assert_eq!(trie.dump_json(&root, &mut json, true).unwrap(), 100);
+        let first = String::from_utf8(json.clone()).unwrap();
+        let first = first.lines().next().unwrap();
+        assert!(first.contains(r#""value":"0x"#) && first.contains(r#""preimage":"0x"#));
+
+        let other = Arc::new(MemoryDB::new(true));
+        let empty = PatriciaTrie::new(Arc::clone(&other), Arc::clone(&hasher));
+        let mut loaded = empty.load_json(&json[..]).unwrap();
+        assert_eq!(loaded.root().unwrap(), root);
+        let key = preimage_key(&hasher.digest(&[7]));
+        assert_eq!(other.get(&key).unwrap(), Some(vec![7]));
+
+        // Lines out of order, and a malformed line.
+        let lines: Vec<&[u8]> = json.split(|b| *b == b'\n').collect();
+        let swapped = [lines[1], lines[0]].join(&b'\n');
+        match empty.load_json(&swapped[..]) {
+            Err(TrieError::UnsortedKeys) => {}
+            other => panic!("unexpected {:?}", other.map(|_| ())),
+        }
+        assert!(empty.load_json(&br#"{"key":"0x0"}"#[..]).is_err());
+    }
+
+    #[test]
+    fn test_json_missing_node() {
+        let hasher = Arc::new(HasherKeccak::new());
+        let memdb = Arc::new(MemoryDB::new(true));
+        let mut trie = PatriciaTrie::new(Arc::clone(&memdb), Arc::clone(&hasher));
+        for i in 0..=255u8 {
+            trie.insert(vec![i, i], vec![i; 40]).unwrap();
+        }
+        let root = trie.root().unwrap();
+        let missing = hasher.digest(&trie.get_proof(&[7, 7]).unwrap()[1]);
+        memdb.remove(&missing).unwrap();
+
+        match trie.dump_json(&root, vec![], false) {
+            Err(TrieError::MissingNode { hash }) => assert_eq!(hash, missing),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+}
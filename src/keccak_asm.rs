@@ -0,0 +1,168 @@
+use hasher::{Hasher, HasherKeccak};
+
+/// Keccak-256 computed by the AVX-512 or AVX2 assembly of CRYPTOGAMS, the
+/// first the CPU supports, and by "HasherKeccak" otherwise, e.g. off x86_64
+/// Linux. Hashes are identical to "HasherKeccak", only faster, so the two can
+/// be swapped on an existing database.
+#[derive(Debug, Clone, Copy)]
+pub struct HasherKeccakAsm {
+    backend: Backend,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Backend {
+    #[cfg(keccak_asm)]
+    Avx512,
+    #[cfg(keccak_asm)]
+    Avx2,
+    Portable,
+}
+
+impl HasherKeccakAsm {
+    pub fn new() -> Self {
+        HasherKeccakAsm {
+            backend: Backend::detect(),
+        }
+    }
+}
+
+impl Default for HasherKeccakAsm {
+    fn default() -> Self {
+        HasherKeccakAsm::new()
+    }
+}
+
+impl Hasher for HasherKeccakAsm {
+    const LENGTH: usize = 32;
+
+    fn digest(&self, data: &[u8]) -> Vec<u8> {
+        match self.backend {
+            #[cfg(keccak_asm)]
+            Backend::Avx512 => unsafe {
+                asm::keccak256(
+                    data,
+                    asm::cita_trie_avx512_SHA3_absorb,
+                    asm::cita_trie_avx512_SHA3_squeeze,
+                )
+            },
+            #[cfg(keccak_asm)]
+            Backend::Avx2 => unsafe {
+                asm::keccak256(
+                    data,
+                    asm::cita_trie_avx2_SHA3_absorb,
+                    asm::cita_trie_avx2_SHA3_squeeze,
+                )
+            },
+            Backend::Portable => HasherKeccak::new().digest(data),
+        }
+    }
+}
+
+impl Backend {
+    #[cfg(keccak_asm)]
+    fn detect() -> Self {
+        if is_x86_feature_detected!("avx512f") {
+            Backend::Avx512
+        } else if is_x86_feature_detected!("avx2") {
+            Backend::Avx2
+        } else {
+            Backend::Portable
+        }
+    }
+
+    #[cfg(not(keccak_asm))]
+    fn detect() -> Self {
+        Backend::Portable
+    }
+}
+
+// The functions "build.rs" assembles, see "SHA3_absorb" and "SHA3_squeeze" of
+// OpenSSL.
+#[cfg(keccak_asm)]
+#[allow(non_snake_case)]
+mod asm {
+    // The rate of Keccak-256 in bytes.
+    const RATE: usize = 136;
+
+    type Absorb = unsafe extern "C" fn(*mut [u64; 25], *const u8, usize, usize) -> usize;
+    type Squeeze = unsafe extern "C" fn(*mut [u64; 25], *mut u8, usize, usize);
+
+    extern "C" {
+        pub fn cita_trie_avx2_SHA3_absorb(
+            state: *mut [u64; 25],
+            input: *const u8,
+            len: usize,
+            rate: usize,
+        ) -> usize;
+        pub fn cita_trie_avx2_SHA3_squeeze(
+            state: *mut [u64; 25],
+            output: *mut u8,
+            len: usize,
+            rate: usize,
+        );
+        pub fn cita_trie_avx512_SHA3_absorb(
+            state: *mut [u64; 25],
+            input: *const u8,
+            len: usize,
+            rate: usize,
+        ) -> usize;
+        pub fn cita_trie_avx512_SHA3_squeeze(
+            state: *mut [u64; 25],
+            output: *mut u8,
+            len: usize,
+            rate: usize,
+        );
+    }
+
+    // The CPU must support the instructions of "absorb" and "squeeze".
+    pub unsafe fn keccak256(data: &[u8], absorb: Absorb, squeeze: Squeeze) -> Vec<u8> {
+        let mut state = [0u64; 25];
+        // Absorbs the whole blocks and returns the length of the rest.
+        let rest = absorb(&mut state, data.as_ptr(), data.len(), RATE);
+        // The last block, padded with the 0x01 .. 0x80 of Keccak.
+        let mut block = [0u8; RATE];
+        block[..rest].copy_from_slice(&data[data.len() - rest..]);
+        block[rest] ^= 0x01;
+        block[RATE - 1] ^= 0x80;
+        absorb(&mut state, block.as_ptr(), RATE, RATE);
+        let mut hash = vec![0; 32];
+        squeeze(&mut state, hash.as_mut_ptr(), hash.len(), RATE);
+        hash
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use hasher::{Hasher, HasherKeccak};
+
+    use super::{Backend, HasherKeccakAsm};
+
+    #[test]
+    fn test_keccak_asm_matches_keccak() {
+        let expected = HasherKeccak::new();
+        let mut backends = vec![HasherKeccakAsm::new().backend, Backend::Portable];
+        #[cfg(keccak_asm)]
+        {
+            if is_x86_feature_detected!("avx2") {
+                backends.push(Backend::Avx2);
+            }
+            if is_x86_feature_detected!("avx512f") {
+                backends.push(Backend::Avx512);
+            }
+        }
+        // Lengths around the 136 byte blocks.
+        let data: Vec<u8> = (0..600u32).map(|i| (i * 7 + 3) as u8).collect();
+        for backend in backends {
+            let hasher = HasherKeccakAsm { backend };
+            for len in (0..300).chain(vec![407, 408, 409, 600]) {
+                assert_eq!(
+                    hasher.digest(&data[..len]),
+                    expected.digest(&data[..len]),
+                    "{:?} at {} bytes",
+                    backend,
+                    len
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,101 @@
+#[cfg(feature = "ethereum")]
+use ethereum_types::{H160, H256};
+
+/// "TrieKey" encodes a typed key into the bytes of a trie key, e.g.
+/// "trie.get(height.trie_key())". Numbers are big-endian, so that keys sort
+/// in numeric order, and tuples concatenate the keys of their parts.
+pub trait TrieKey {
+    fn trie_key(&self) -> Vec<u8>;
+}
+
+impl TrieKey for u64 {
+    fn trie_key(&self) -> Vec<u8> {
+        self.to_be_bytes().to_vec()
+    }
+}
+
+#[cfg(feature = "ethereum")]
+impl TrieKey for H160 {
+    fn trie_key(&self) -> Vec<u8> {
+        self.as_bytes().to_vec()
+    }
+}
+
+#[cfg(feature = "ethereum")]
+impl TrieKey for H256 {
+    fn trie_key(&self) -> Vec<u8> {
+        self.as_bytes().to_vec()
+    }
+}
+
+impl<A: TrieKey, B: TrieKey> TrieKey for (A, B) {
+    fn trie_key(&self) -> Vec<u8> {
+        let mut key = self.0.trie_key();
+        key.extend(self.1.trie_key());
+        key
+    }
+}
+
+impl<A: TrieKey, B: TrieKey, C: TrieKey> TrieKey for (A, B, C) {
+    fn trie_key(&self) -> Vec<u8> {
+        let mut key = self.0.trie_key();
+        key.extend(self.1.trie_key());
+        key.extend(self.2.trie_key());
+        key
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use hasher::HasherKeccak;
+
+    use super::TrieKey;
+    use crate::db::MemoryDB;
+    use crate::trie::{PatriciaTrie, Trie};
+
+    #[test]
+    fn test_trie_key() {
+        assert!(255u64.trie_key() < 256u64.trie_key());
+        assert_eq!(
+            (1u64, 2u64).trie_key(),
+            [1u64.trie_key(), 2u64.trie_key()].concat()
+        );
+
+        let mut trie =
+            PatriciaTrie::new(Arc::new(MemoryDB::new(true)), Arc::new(HasherKeccak::new()));
+        for height in 0..100u64 {
+            trie.insert(height.trie_key(), vec![height as u8; 40])
+                .unwrap();
+        }
+        trie.insert((7u64, 1u64, 2u64).trie_key(), b"slot".to_vec())
+            .unwrap();
+        assert_eq!(trie.get(7u64.trie_key()).unwrap(), Some(vec![7; 40]));
+        assert_eq!(
+            trie.get(&[0, 0, 0, 0, 0, 0, 0, 8]).unwrap(),
+            Some(vec![8; 40])
+        );
+        assert_eq!(
+            trie.get((7u64, 1u64, 2u64).trie_key()).unwrap(),
+            Some(b"slot".to_vec())
+        );
+        let keys: Vec<Vec<u8>> = trie.iter().map(|(k, _)| k).take(3).collect();
+        assert_eq!(
+            keys,
+            vec![0u64.trie_key(), 1u64.trie_key(), 2u64.trie_key()]
+        );
+    }
+
+    #[cfg(feature = "ethereum")]
+    #[test]
+    fn test_ethereum_trie_keys() {
+        use ethereum_types::{H160, H256};
+
+        let address = H160::from([1u8; 20]);
+        let slot = H256::from(7u64);
+        let key = (address, slot).trie_key();
+        assert_eq!(key.len(), 52);
+        assert_eq!(&key[20..], slot.as_bytes());
+    }
+}
@@ -39,10 +39,105 @@ mod nibbles;
 mod node;
 mod tests;
 
+mod batch;
+mod binary_trie;
+mod bloom;
+mod builder;
+mod cache;
+mod codec;
 mod db;
 mod errors;
+mod flat;
+mod gc;
+mod heal;
+mod journal;
+mod journaled;
+mod key;
+mod lru;
+mod manager;
+mod migrate;
+mod prefixed;
+mod provider;
+mod range;
+mod refcount;
+mod repair;
+mod rewind;
+mod root;
+mod sha256;
+mod snapshot;
+mod sync;
 mod trie;
+mod typed;
+mod window;
 
-pub use db::{MemoryDB, DB};
-pub use errors::{MemDBError, TrieError};
-pub use trie::{PatriciaTrie, Trie};
+#[cfg(feature = "async")]
+pub mod async_db;
+#[cfg(feature = "csv")]
+mod csv;
+#[cfg(feature = "ethereum")]
+mod ethereum;
+#[cfg(feature = "json")]
+mod json;
+#[cfg(feature = "asm-keccak")]
+mod keccak_asm;
+pub mod light;
+#[cfg(feature = "poseidon")]
+mod poseidon;
+#[cfg(any(feature = "json", feature = "csv"))]
+mod records;
+pub mod smt;
+
+pub use batch::TrieBatch;
+pub use binary_trie::BinaryTrie;
+pub use cache::SharedNodeCache;
+pub use codec::{BinaryNodeCodec, ChildReference, NodeCodec, NodeData, RLPNodeCodec, ShortItem};
+#[cfg(feature = "chacha20poly1305")]
+pub use db::ChaCha20Poly1305Cipher;
+#[cfg(feature = "mmap")]
+pub use db::MmapDB;
+#[cfg(feature = "snappy")]
+pub use db::SnappyCompressor;
+pub use db::{
+    BloomDB, Cipher, CompressedDB, Compressor, DBMetrics, EncryptedDB, MemoryDB, MeteredDB,
+    RleCompressor, TieredDB, WalDB, DB,
+};
+#[cfg(feature = "mmap")]
+pub use errors::MmapDBError;
+pub use errors::{
+    BloomDBError, CompressedDBError, EncryptedDBError, MemDBError, TieredDBError, TrieError,
+    WalDBError,
+};
+#[cfg(feature = "ethereum")]
+pub use ethereum::{EthereumKeys, EthereumTrie, SecureTrie};
+pub use gc::{
+    collect_garbage, find_orphans, register_root, registered_roots, unregister_root,
+    PREIMAGE_PREFIX, ROOT_PREFIX,
+};
+pub use hasher::Hasher;
+pub use heal::{heal, Heal};
+pub use journal::ChangeSet;
+pub use journaled::JournaledTrie;
+#[cfg(feature = "asm-keccak")]
+pub use keccak_asm::HasherKeccakAsm;
+pub use key::TrieKey;
+pub use manager::TrieManager;
+pub use migrate::migrate;
+#[cfg(feature = "poseidon")]
+pub use poseidon::HasherPoseidon;
+pub use prefixed::PrefixedTrie;
+pub use provider::NodeProvider;
+pub use range::{get_range, verify_range_proof, RangeProof};
+pub use refcount::{ref_count, REF_COUNT_PREFIX};
+pub use repair::{recover, LostRange, Recovery};
+pub use rewind::REVERSE_DIFF_PREFIX;
+pub use root::{
+    ordered_trie_root, ordered_trie_root_with_codec, sec_trie_root, sec_trie_root_with_codec,
+    trie_root, trie_root_with_codec,
+};
+pub use sha256::HasherSha256;
+pub use snapshot::{export_snapshot, import_snapshot};
+pub use sync::{SyncProgress, SyncScheduler};
+pub use trie::{
+    get_at, PatriciaTrie, PendingChange, PendingStats, Trie, TrieMemoryStats, ValueRef,
+};
+pub use typed::{RLPValueCodec, TypedTrie, ValueCodec};
@@ -0,0 +1,353 @@
+//! Verifies values against a trusted root, e.g. the state root of a header,
+//! from the nodes of a proof alone. Nothing here reads or writes a DB, so
+//! light clients and wallets need no backend.
+
+use hashbrown::HashMap;
+use hasher::{Hasher, HasherKeccak};
+use rlp::Rlp;
+
+use crate::codec::{ChildReference, NodeCodec, NodeData, RLPNodeCodec, ShortItem};
+use crate::errors::TrieError;
+use crate::nibbles::Nibbles;
+use crate::trie::TrieResult;
+
+/// An account of the Ethereum state.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Account {
+    pub nonce: u64,
+    /// Big-endian, without leading zeros.
+    pub balance: Vec<u8>,
+    pub storage_root: Vec<u8>,
+    pub code_hash: Vec<u8>,
+}
+
+impl Account {
+    /// Decodes an account from its RLP list, as the state trie stores it.
+    pub fn decode(data: &[u8]) -> TrieResult<Self> {
+        let rlp = Rlp::new(data);
+        Ok(Account {
+            nonce: rlp.val_at(0)?,
+            balance: rlp.val_at(1)?,
+            storage_root: rlp.val_at(2)?,
+            code_hash: rlp.val_at(3)?,
+        })
+    }
+}
+
+/// "PartialTrie" reads the part of a trie that a witness, a set of its nodes
+/// such as the proofs of some keys, covers. Keys whose path leaves the
+/// witness fail with "OutsideWitness".
+#[derive(Debug, Clone)]
+pub struct PartialTrie<C: NodeCodec = RLPNodeCodec> {
+    codec: C,
+    root: Vec<u8>,
+    // The root of the empty trie, which has no nodes to witness.
+    empty_root: Vec<u8>,
+    nodes: HashMap<Vec<u8>, Vec<u8>>,
+}
+
+impl PartialTrie {
+    /// Builds the trie at "root" from the nodes of "witness", with keccak
+    /// and RLP.
+    pub fn new(root: &[u8], witness: Vec<Vec<u8>>) -> Self {
+        PartialTrie::with_codec(&HasherKeccak::new(), RLPNodeCodec::new(), root, witness)
+    }
+}
+
+impl<C: NodeCodec> PartialTrie<C> {
+    /// Like "new", with any hasher and codec.
+    pub fn with_codec<H: Hasher>(hasher: &H, codec: C, root: &[u8], witness: Vec<Vec<u8>>) -> Self {
+        let nodes = witness
+            .into_iter()
+            .map(|node| (hasher.digest(&node), node))
+            .collect();
+        let empty_root = hasher.digest(&codec.encode(&NodeData::Empty));
+        PartialTrie {
+            codec,
+            root: root.to_vec(),
+            empty_root,
+            nodes,
+        }
+    }
+
+    pub fn root(&self) -> &[u8] {
+        &self.root
+    }
+
+    pub fn get(&self, key: &[u8]) -> TrieResult<Option<Vec<u8>>> {
+        if self.root == self.empty_root {
+            return Ok(None);
+        }
+        lookup(&self.codec, &self.root, key, |hash| self.node(hash))
+    }
+
+    pub fn contains(&self, key: &[u8]) -> TrieResult<bool> {
+        Ok(self.get(key)?.is_some())
+    }
+
+    /// Returns the proof of "key", as "get_proof" of the whole trie does.
+    pub fn get_proof(&self, key: &[u8]) -> TrieResult<Vec<Vec<u8>>> {
+        let mut proof = vec![];
+        if self.root == self.empty_root {
+            return Ok(proof);
+        }
+        lookup(&self.codec, &self.root, key, |hash| {
+            let node = self.node(hash)?;
+            proof.push(node.clone());
+            Ok(node)
+        })?;
+        Ok(proof)
+    }
+
+    fn node(&self, hash: &[u8]) -> TrieResult<Vec<u8>> {
+        self.nodes
+            .get(hash)
+            .cloned()
+            .ok_or(TrieError::OutsideWitness)
+    }
+}
+
+/// Returns the value of "key" in the trie at "root" that "proof" proves, or
+/// None if it proves the key absent, with keccak and RLP. "proof" holds the
+/// nodes "get_proof" returns, none for the empty trie. Fails with
+/// "InvalidProof" if a node of the path is not in "proof".
+pub fn verify_value(root: &[u8], key: &[u8], proof: &[Vec<u8>]) -> TrieResult<Option<Vec<u8>>> {
+    verify_value_with_codec(&HasherKeccak::new(), &RLPNodeCodec::new(), root, key, proof)
+}
+
+/// Like "verify_value", with any hasher and codec.
+pub fn verify_value_with_codec<H, C>(
+    hasher: &H,
+    codec: &C,
+    root: &[u8],
+    key: &[u8],
+    proof: &[Vec<u8>],
+) -> TrieResult<Option<Vec<u8>>>
+where
+    H: Hasher,
+    C: NodeCodec,
+{
+    if *root == hasher.digest(&codec.encode(&NodeData::Empty))[..] {
+        return Ok(None);
+    }
+    let nodes: HashMap<Vec<u8>, &Vec<u8>> = proof
+        .iter()
+        .map(|node| (hasher.digest(node), node))
+        .collect();
+    lookup(codec, root, key, |hash| {
+        nodes
+            .get(hash)
+            .map(|node| node.to_vec())
+            .ok_or(TrieError::InvalidProof)
+    })
+}
+
+/// Returns the account of "address" at "state_root" that "proof" proves, or
+/// None if it proves the account absent. The key is the keccak hash of the
+/// address, as in "SecureTrie".
+pub fn verify_account(
+    state_root: &[u8],
+    address: &[u8],
+    proof: &[Vec<u8>],
+) -> TrieResult<Option<Account>> {
+    let key = HasherKeccak::new().digest(address);
+    match verify_value(state_root, &key, proof)? {
+        Some(data) => Ok(Some(Account::decode(&data)?)),
+        None => Ok(None),
+    }
+}
+
+/// Returns the value of "slot" in the storage of "address" at "state_root",
+/// RLP encoded as stored, or None if the account or the slot is absent.
+/// "account_proof" proves the account and its storage root, which
+/// "storage_proof" proves the slot against.
+pub fn verify_storage(
+    state_root: &[u8],
+    address: &[u8],
+    account_proof: &[Vec<u8>],
+    slot: &[u8],
+    storage_proof: &[Vec<u8>],
+) -> TrieResult<Option<Vec<u8>>> {
+    match verify_account(state_root, address, account_proof)? {
+        Some(account) => {
+            let key = HasherKeccak::new().digest(slot);
+            verify_value(&account.storage_root, &key, storage_proof)
+        }
+        None => Ok(None),
+    }
+}
+
+/// Returns the value of "key" at "root", decoding only the nodes on its path.
+/// "load" returns the encoded node of a hash.
+pub(crate) fn lookup<C, F>(
+    codec: &C,
+    root: &[u8],
+    key: &[u8],
+    mut load: F,
+) -> TrieResult<Option<Vec<u8>>>
+where
+    C: NodeCodec,
+    F: FnMut(&[u8]) -> TrieResult<Vec<u8>>,
+{
+    let mut decode = |child: ChildReference| match child {
+        ChildReference::Inline(data) => codec.decode(&data),
+        ChildReference::Hash(hash) => codec.decode(&load(&hash)?),
+    };
+
+    let nibbles = Nibbles::from_raw(key.to_vec(), false);
+    let mut partial = nibbles.get_data();
+    let mut node = decode(ChildReference::Hash(root.to_vec()))?;
+    loop {
+        node = match node {
+            NodeData::Empty => return Ok(None),
+            NodeData::Short { path, item } => {
+                if !partial.starts_with(&path) {
+                    return Ok(None);
+                }
+                partial = &partial[path.len()..];
+                match item {
+                    ShortItem::Value(value) => {
+                        return Ok(if partial.is_empty() {
+                            Some(value)
+                        } else {
+                            None
+                        })
+                    }
+                    ShortItem::Child(child) => decode(child)?,
+                }
+            }
+            NodeData::Branch {
+                mut children,
+                value,
+            } => match partial.split_first() {
+                None => return Ok(value),
+                Some((first, rest)) => {
+                    partial = rest;
+                    match children.get_mut(*first as usize).and_then(Option::take) {
+                        Some(child) => decode(child)?,
+                        None => return Ok(None),
+                    }
+                }
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use hasher::{Hasher, HasherKeccak};
+    use rlp::RlpStream;
+
+    use super::{verify_account, verify_storage, verify_value, Account, PartialTrie};
+    use crate::db::MemoryDB;
+    use crate::errors::TrieError;
+    use crate::trie::{PatriciaTrie, Trie};
+
+    #[test]
+    fn test_verify_storage() {
+        let hasher = HasherKeccak::new();
+        let memdb = Arc::new(MemoryDB::new(true));
+        let mut storage = PatriciaTrie::new(Arc::clone(&memdb), Arc::new(HasherKeccak::new()));
+        for i in 0..50u8 {
+            storage
+                .insert(hasher.digest(&[i; 32]), rlp::encode(&(u64::from(i) + 1)))
+                .unwrap();
+        }
+        let storage_root = storage.root().unwrap();
+
+        let account = Account {
+            nonce: 3,
+            balance: vec![1, 0],
+            storage_root: storage_root.clone(),
+            code_hash: hasher.digest(b""),
+        };
+        let mut stream = RlpStream::new_list(4);
+        stream.append(&account.nonce);
+        stream.append(&account.balance);
+        stream.append(&account.storage_root);
+        stream.append(&account.code_hash);
+        let mut state = PatriciaTrie::new(memdb, Arc::new(HasherKeccak::new()));
+        for i in 0..50u8 {
+            state.insert(hasher.digest(&[i; 20]), vec![i; 70]).unwrap();
+        }
+        state
+            .insert(hasher.digest(&[7; 20]), stream.out().to_vec())
+            .unwrap();
+        let state_root = state.root().unwrap();
+
+        let account_proof = state.get_proof(hasher.digest(&[7; 20])).unwrap();
+        let verified = verify_account(&state_root, &[7; 20], &account_proof).unwrap();
+        assert_eq!(verified, Some(account));
+        let storage_proof = storage.get_proof(hasher.digest(&[9; 32])).unwrap();
+        let value = verify_storage(
+            &state_root,
+            &[7; 20],
+            &account_proof,
+            &[9; 32],
+            &storage_proof,
+        );
+        assert_eq!(value.unwrap(), Some(rlp::encode(&10u64)));
+
+        // Absent keys are proven, incomplete proofs are not.
+        let proof = state.get_proof(b"absent").unwrap();
+        assert_eq!(verify_value(&state_root, b"absent", &proof).unwrap(), None);
+        let value = verify_value(&state_root, &hasher.digest(&[7; 20]), &account_proof[1..]);
+        match value {
+            Err(TrieError::InvalidProof) => {}
+            other => panic!("unexpected {:?}", other),
+        }
+
+        // An account without storage has the empty root, whose proof is empty.
+        let mut empty_storage =
+            PatriciaTrie::new(Arc::new(MemoryDB::new(true)), Arc::new(HasherKeccak::new()));
+        let empty_root = empty_storage.root().unwrap();
+        let mut stream = RlpStream::new_list(4);
+        stream.append(&1u64);
+        stream.append(&vec![5u8]);
+        stream.append(&empty_root);
+        stream.append(&hasher.digest(b""));
+        state
+            .insert(hasher.digest(&[8; 20]), stream.out().to_vec())
+            .unwrap();
+        let state_root = state.root().unwrap();
+        let account_proof = state.get_proof(hasher.digest(&[8; 20])).unwrap();
+        let storage_proof = empty_storage.get_proof(hasher.digest(&[9; 32])).unwrap();
+        assert!(storage_proof.is_empty());
+        let value = verify_storage(
+            &state_root,
+            &[8; 20],
+            &account_proof,
+            &[9; 32],
+            &storage_proof,
+        );
+        assert_eq!(value.unwrap(), None);
+        let partial = PartialTrie::new(&empty_root, vec![]);
+        assert_eq!(partial.get(&[1]).unwrap(), None);
+        assert!(partial.get_proof(&[1]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_partial_trie() {
+        let mut trie =
+            PatriciaTrie::new(Arc::new(MemoryDB::new(true)), Arc::new(HasherKeccak::new()));
+        for i in 0..100u8 {
+            trie.insert(vec![i, i], vec![i; 40]).unwrap();
+        }
+        let root = trie.root().unwrap();
+        let mut witness = trie.get_proof(&[7, 7]).unwrap();
+        witness.extend(trie.get_proof(&[90, 90]).unwrap());
+        witness.extend(trie.get_proof(&[200]).unwrap());
+
+        let partial = PartialTrie::new(&root, witness);
+        assert_eq!(partial.get(&[7, 7]).unwrap(), Some(vec![7; 40]));
+        assert!(!partial.contains(&[200]).unwrap());
+        let proof = partial.get_proof(&[90, 90]).unwrap();
+        assert_eq!(proof, trie.get_proof(&[90, 90]).unwrap());
+        match partial.get(&[50, 50]) {
+            Err(TrieError::OutsideWitness) => {}
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+}
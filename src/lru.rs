@@ -0,0 +1,111 @@
+use std::borrow::Borrow;
+use std::collections::BTreeMap;
+use std::hash::Hash;
+
+use hashbrown::HashMap;
+
+/// A least recently used cache, evicting the entry that was read or written
+/// the longest time ago once "capacity" is reached.
+/// A capacity of 0 disables the cache.
+#[derive(Debug)]
+pub struct LRUCache<K, V> {
+    capacity: usize,
+    // Increases on every access, entries with a smaller tick are older.
+    tick: u64,
+    entries: HashMap<K, (V, u64)>,
+    order: BTreeMap<u64, K>,
+}
+
+impl<K: Hash + Eq + Clone, V> LRUCache<K, V> {
+    pub fn new(capacity: usize) -> Self {
+        LRUCache {
+            capacity,
+            tick: 0,
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn get<Q>(&mut self, key: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.tick += 1;
+        let tick = self.tick;
+        let entry = self.entries.get_mut(key)?;
+        let key = self
+            .order
+            .remove(&entry.1)
+            .expect("entries and order are in sync");
+        self.order.insert(tick, key);
+        entry.1 = tick;
+        Some(&entry.0)
+    }
+
+    /// Returns the entry evicted to make room, if any.
+    pub fn insert(&mut self, key: K, value: V) -> Option<(K, V)> {
+        if self.capacity == 0 {
+            return None;
+        }
+        self.tick += 1;
+        let mut evicted = None;
+        if let Some((_, tick)) = self.entries.remove(&key) {
+            self.order.remove(&tick);
+        } else if self.entries.len() >= self.capacity {
+            let oldest = *self.order.keys().next().expect("the cache is not empty");
+            let key = self
+                .order
+                .remove(&oldest)
+                .expect("entries and order are in sync");
+            evicted = self.entries.remove(&key).map(|(value, _)| (key, value));
+        }
+        self.order.insert(self.tick, key.clone());
+        self.entries.insert(key, (value, self.tick));
+        evicted
+    }
+
+    pub fn remove<Q>(&mut self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let (value, tick) = self.entries.remove(key)?;
+        self.order.remove(&tick);
+        Some(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::LRUCache;
+
+    #[test]
+    fn test_lru_eviction() {
+        let mut cache = LRUCache::new(2);
+        cache.insert(b"a".to_vec(), 1);
+        cache.insert(b"b".to_vec(), 2);
+        // "a" is now more recently used than "b".
+        assert_eq!(cache.get(&b"a"[..]), Some(&1));
+        assert_eq!(cache.insert(b"c".to_vec(), 3), Some((b"b".to_vec(), 2)));
+
+        assert_eq!(cache.get(&b"b"[..]), None);
+        assert_eq!(cache.get(&b"a"[..]), Some(&1));
+        assert_eq!(cache.get(&b"c"[..]), Some(&3));
+
+        cache.insert(b"c".to_vec(), 4);
+        assert_eq!(cache.remove(&b"c"[..]), Some(4));
+        assert_eq!(cache.get(&b"a"[..]), Some(&1));
+    }
+
+    #[test]
+    fn test_lru_disabled() {
+        let mut cache = LRUCache::new(0);
+        cache.insert(b"a".to_vec(), 1);
+        assert_eq!(cache.get(&b"a"[..]), None);
+    }
+}
@@ -0,0 +1,143 @@
+use std::collections::BTreeMap;
+
+use hasher::{Hasher, HasherKeccak};
+
+use crate::codec::{NodeCodec, RLPNodeCodec};
+use crate::db::DB;
+use crate::errors::TrieError;
+use crate::trie::{PatriciaTrie, Trie, TrieResult};
+
+/// "TrieManager" commits a trie once per version, e.g. per block height, and
+/// keeps the roots of the last "retention" versions readable. Older versions
+/// are pruned as new ones are committed.
+#[derive(Debug)]
+pub struct TrieManager<D, H = HasherKeccak, C = RLPNodeCodec>
+where
+    D: DB,
+    H: Hasher,
+    C: NodeCodec,
+{
+    trie: PatriciaTrie<D, H, C>,
+    // The root hash of every retained version.
+    versions: BTreeMap<u64, Vec<u8>>,
+    retention: u64,
+}
+
+impl<D, H, C> TrieManager<D, H, C>
+where
+    D: DB,
+    H: Hasher,
+    C: NodeCodec,
+{
+    /// Manages "trie", whose stale nodes are then kept by a pruning window of
+    /// "retention" commits, see "with_pruning_window". A retention of 0 is
+    /// taken as 1.
+    pub fn new(trie: PatriciaTrie<D, H, C>, retention: u64) -> TrieResult<Self> {
+        let retention = retention.max(1);
+        Ok(TrieManager {
+            trie: trie.with_pruning_window(retention)?,
+            versions: BTreeMap::new(),
+            retention,
+        })
+    }
+
+    /// The working trie, changes go through the manager.
+    pub fn trie(&self) -> &PatriciaTrie<D, H, C> {
+        &self.trie
+    }
+
+    pub fn get(&self, key: &[u8]) -> TrieResult<Option<Vec<u8>>> {
+        self.trie.get(key)
+    }
+
+    pub fn insert(&mut self, key: Vec<u8>, value: Vec<u8>) -> TrieResult<()> {
+        self.trie.insert(key, value)
+    }
+
+    pub fn remove(&mut self, key: &[u8]) -> TrieResult<bool> {
+        self.trie.remove(key)
+    }
+
+    /// Commits the changes as "version", which must be above the latest one,
+    /// and prunes the versions that fall out of the retention window.
+    pub fn commit(&mut self, version: u64) -> TrieResult<Vec<u8>> {
+        if self
+            .latest_version()
+            .map_or(false, |latest| version <= latest)
+        {
+            return Err(TrieError::InvalidVersion);
+        }
+        let root = self.trie.root()?;
+        self.versions.insert(version, root.clone());
+        while self.versions.len() as u64 > self.retention {
+            let oldest = *self.versions.keys().next().unwrap();
+            self.versions.remove(&oldest);
+        }
+        Ok(root)
+    }
+
+    pub fn latest_version(&self) -> Option<u64> {
+        self.versions.keys().next_back().cloned()
+    }
+
+    /// Returns the root hash of a retained "version".
+    pub fn root_at(&self, version: u64) -> Option<&[u8]> {
+        self.versions.get(&version).map(Vec::as_slice)
+    }
+
+    /// Returns the retained versions, the oldest first.
+    pub fn versions(&self) -> Vec<u64> {
+        self.versions.keys().cloned().collect()
+    }
+
+    /// Opens the trie at a retained "version" for reading. The view must not
+    /// be committed, and only stays valid while the version is retained.
+    pub fn view(&self, version: u64) -> TrieResult<PatriciaTrie<D, H, C>> {
+        match self.versions.get(&version) {
+            Some(root) => self.trie.at_root(root),
+            None => Err(TrieError::UnknownVersion),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use hasher::HasherKeccak;
+
+    use super::TrieManager;
+    use crate::db::MemoryDB;
+    use crate::errors::TrieError;
+    use crate::trie::{PatriciaTrie, Trie};
+
+    #[test]
+    fn test_trie_manager() {
+        let memdb = Arc::new(MemoryDB::new(true));
+        let trie = PatriciaTrie::new(Arc::clone(&memdb), Arc::new(HasherKeccak::new()));
+        let mut manager = TrieManager::new(trie, 3).unwrap();
+        for i in 0..50u8 {
+            manager.insert(vec![i], vec![i; 40]).unwrap();
+        }
+        for height in 100..110u64 {
+            manager.insert(vec![7], vec![height as u8; 40]).unwrap();
+            manager.commit(height).unwrap();
+        }
+        assert_eq!(manager.versions(), vec![107, 108, 109]);
+        assert_eq!(manager.latest_version(), Some(109));
+        match manager.commit(109) {
+            Err(TrieError::InvalidVersion) => {}
+            other => panic!("unexpected {:?}", other),
+        }
+
+        for height in 107..110u64 {
+            let view = manager.view(height).unwrap();
+            assert_eq!(view.get(&[7]).unwrap(), Some(vec![height as u8; 40]));
+            assert_eq!(view.iter().count(), 50);
+        }
+        match manager.view(106) {
+            Err(TrieError::UnknownVersion) => {}
+            other => panic!("unexpected {:?}", other.map(|_| ())),
+        }
+    }
+}
@@ -0,0 +1,121 @@
+use hashbrown::HashSet;
+use hasher::Hasher;
+
+use crate::codec::NodeCodec;
+use crate::db::DB;
+use crate::errors::TrieError;
+use crate::snapshot::walk_nodes;
+use crate::trie::TrieResult;
+
+// Copied nodes are written in batches of this size.
+const MIGRATE_BATCH: usize = 1024;
+
+/// Copies every node that "roots" reach in "src" to "dst", e.g. to move to
+/// another backend, and returns the number of nodes. "progress" is called
+/// with the number of nodes copied so far after every batch. Once copied,
+/// the roots are walked again in "dst", every node must be there and hash to
+/// its key, or "InvalidData" is returned. Metadata, e.g. reference counts, is
+/// not copied.
+pub fn migrate<S, T, H, C, F>(
+    src: &S,
+    dst: &T,
+    hasher: &H,
+    codec: &C,
+    roots: &[Vec<u8>],
+    mut progress: F,
+) -> TrieResult<u64>
+where
+    S: DB,
+    T: DB,
+    H: Hasher,
+    C: NodeCodec,
+    F: FnMut(u64),
+{
+    let (mut keys, mut values) = (vec![], vec![]);
+    let mut copied = 0;
+    let mut write = |keys: Vec<Vec<u8>>, values: Vec<Vec<u8>>| -> TrieResult<()> {
+        copied += keys.len() as u64;
+        dst.insert_batch(keys, values)
+            .map_err(|e| TrieError::DB(e.to_string()))?;
+        dst.flush().map_err(|e| TrieError::DB(e.to_string()))?;
+        progress(copied);
+        Ok(())
+    };
+    // The roots share most of their nodes, so every node is copied once. The
+    // walk of "dst" takes the nodes out again.
+    let mut copied_nodes = HashSet::new();
+    let count = walk_nodes(src, codec, roots, |hash, data| {
+        if !copied_nodes.insert(hash.to_vec()) {
+            return Ok(false);
+        }
+        keys.push(hash.to_vec());
+        values.push(data.to_vec());
+        if keys.len() >= MIGRATE_BATCH {
+            write(keys.split_off(0), values.split_off(0))?;
+        }
+        Ok(true)
+    })?;
+    if !keys.is_empty() {
+        write(keys, values)?;
+    }
+
+    let verified = walk_nodes(dst, codec, roots, |hash, data| {
+        if hasher.digest(data) != hash {
+            return Err(TrieError::InvalidData);
+        }
+        Ok(copied_nodes.remove(hash))
+    })
+    .map_err(|e| match e {
+        TrieError::MissingNode { .. } => TrieError::InvalidData,
+        e => e,
+    })?;
+    if verified != count {
+        return Err(TrieError::InvalidData);
+    }
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use hasher::HasherKeccak;
+
+    use super::migrate;
+    use crate::codec::RLPNodeCodec;
+    use crate::db::{MemoryDB, DB};
+    use crate::trie::{PatriciaTrie, Trie};
+
+    #[test]
+    fn test_migrate() {
+        let src = Arc::new(MemoryDB::new(false));
+        let hasher = Arc::new(HasherKeccak::new());
+        let mut trie = PatriciaTrie::new(Arc::clone(&src), Arc::clone(&hasher));
+        for i in 0..1500u32 {
+            trie.insert(i.to_be_bytes(), format!("{:040}", i).into_bytes())
+                .unwrap();
+        }
+        let old_root = trie.root().unwrap();
+        trie.insert(b"new".to_vec(), b"value".to_vec()).unwrap();
+        let new_root = trie.root().unwrap();
+        src.insert(b"unreachable".to_vec(), vec![1]).unwrap();
+
+        let dst = MemoryDB::new(true);
+        let roots = vec![old_root.clone(), new_root.clone()];
+        let mut reports = vec![];
+        let count = migrate(&*src, &dst, &*hasher, &RLPNodeCodec, &roots, |n| {
+            reports.push(n)
+        })
+        .unwrap();
+        assert_eq!(count as usize, src.len().unwrap() - 1);
+        assert_eq!(dst.len().unwrap(), src.len().unwrap() - 1);
+        assert!(reports.len() > 1);
+        assert_eq!(*reports.last().unwrap(), count);
+
+        let dst = Arc::new(dst);
+        let old = PatriciaTrie::from(Arc::clone(&dst), Arc::clone(&hasher), &old_root).unwrap();
+        assert_eq!(old.iter().count(), 1500);
+        let new = PatriciaTrie::from(dst, hasher, &new_root).unwrap();
+        assert_eq!(new.get(b"new").unwrap(), Some(b"value".to_vec()));
+    }
+}
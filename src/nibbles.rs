@@ -1,5 +1,7 @@
 use std::cmp::min;
 
+use crate::errors::TrieError;
+
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub struct Nibbles {
     hex_data: Vec<u8>,
@@ -22,9 +24,12 @@ impl Nibbles {
         Nibbles { hex_data }
     }
 
-    pub fn from_compact(compact: Vec<u8>) -> Self {
+    pub fn from_compact(compact: Vec<u8>) -> Result<Self, TrieError> {
         let mut hex = vec![];
-        let flag = compact[0];
+        let flag = match compact.first() {
+            Some(flag) => *flag,
+            None => return Err(TrieError::InvalidData),
+        };
 
         let mut is_leaf = false;
         match flag >> 4 {
@@ -35,7 +40,7 @@ impl Nibbles {
                 is_leaf = true;
                 hex.push(flag % 16);
             }
-            _ => panic!("invalid data"),
+            _ => return Err(TrieError::InvalidData),
         }
 
         for item in &compact[1..] {
@@ -46,40 +51,23 @@ impl Nibbles {
             hex.push(16);
         }
 
-        Nibbles { hex_data: hex }
+        Ok(Nibbles { hex_data: hex })
     }
 
     pub fn is_leaf(&self) -> bool {
-        self.hex_data[self.hex_data.len() - 1] == 16
+        self.hex_data.last() == Some(&16)
     }
 
+    #[cfg(test)]
     pub fn encode_compact(&self) -> Vec<u8> {
         let mut compact = vec![];
         let is_leaf = self.is_leaf();
-        let mut hex = if is_leaf {
+        let hex = if is_leaf {
             &self.hex_data[0..self.hex_data.len() - 1]
         } else {
             &self.hex_data[0..]
         };
-        // node type    path length    |    prefix    hexchar
-        // --------------------------------------------------
-        // extension    even           |    0000      0x0
-        // extension    odd            |    0001      0x1
-        // leaf         even           |    0010      0x2
-        // leaf         odd            |    0011      0x3
-        let v = if hex.len() % 2 == 1 {
-            let v = 0x10 + hex[0];
-            hex = &hex[1..];
-            v
-        } else {
-            0x00
-        };
-
-        compact.push(v + if is_leaf { 0x20 } else { 0x00 });
-        for i in 0..(hex.len() / 2) {
-            compact.push((hex[i * 2] * 16) + (hex[i * 2 + 1]));
-        }
-
+        write_compact(hex, is_leaf, &mut compact);
         compact
     }
 
@@ -112,12 +100,19 @@ impl Nibbles {
     }
 
     pub fn common_prefix(&self, other_partial: &Nibbles) -> usize {
-        let s = min(self.len(), other_partial.len());
+        let (a, b) = (&self.hex_data, &other_partial.hex_data);
+        let s = min(a.len(), b.len());
         let mut i = 0usize;
-        while i < s {
-            if self.at(i) != other_partial.at(i) {
-                break;
+        // Compares eight nibbles at a time, the first nibble that differs is
+        // the lowest non-zero byte of the xor.
+        while i + 8 <= s {
+            let diff = read_u64(&a[i..i + 8]) ^ read_u64(&b[i..i + 8]);
+            if diff != 0 {
+                return i + (diff.trailing_zeros() / 8) as usize;
             }
+            i += 8;
+        }
+        while i < s && a[i] == b[i] {
             i += 1;
         }
         i
@@ -159,6 +154,35 @@ impl Nibbles {
     }
 }
 
+/// Appends the compact encoding of the nibbles "hex", which must not end
+/// with the leaf terminator.
+pub fn write_compact(mut hex: &[u8], is_leaf: bool, out: &mut Vec<u8>) {
+    // node type    path length    |    prefix    hexchar
+    // --------------------------------------------------
+    // extension    even           |    0000      0x0
+    // extension    odd            |    0001      0x1
+    // leaf         even           |    0010      0x2
+    // leaf         odd            |    0011      0x3
+    let v = if hex.len() % 2 == 1 {
+        let v = 0x10 + hex[0];
+        hex = &hex[1..];
+        v
+    } else {
+        0x00
+    };
+
+    out.push(v + if is_leaf { 0x20 } else { 0x00 });
+    for i in 0..(hex.len() / 2) {
+        out.push((hex[i * 2] * 16) + (hex[i * 2 + 1]));
+    }
+}
+
+fn read_u64(chunk: &[u8]) -> u64 {
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(chunk);
+    u64::from_le_bytes(bytes)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -167,9 +191,37 @@ mod tests {
     fn test_nibble() {
         let n = Nibbles::from_raw(b"key1".to_vec(), true);
         let compact = n.encode_compact();
-        let n2 = Nibbles::from_compact(compact);
+        let n2 = Nibbles::from_compact(compact).unwrap();
         let (raw, is_leaf) = n2.encode_raw();
         assert_eq!(is_leaf, true);
         assert_eq!(raw, b"key1");
     }
+
+    #[test]
+    fn test_nibble_invalid_compact() {
+        assert!(Nibbles::from_compact(vec![]).is_err());
+        assert!(Nibbles::from_compact(vec![0x40, 0x12]).is_err());
+        assert!(!Nibbles::from_hex(vec![]).is_leaf());
+    }
+
+    #[test]
+    fn test_common_prefix() {
+        let a = Nibbles::from_raw((0..20).collect(), true);
+        for len in 0..a.len() {
+            for diff in 0..=len {
+                let mut hex = a.get_data()[..len].to_vec();
+                if diff < len {
+                    hex[diff] = 15 - hex[diff] % 16;
+                }
+                let expected = a
+                    .get_data()
+                    .iter()
+                    .zip(hex.iter())
+                    .take_while(|(x, y)| x == y)
+                    .count();
+                assert_eq!(a.common_prefix(&Nibbles::from_hex(hex.clone())), expected);
+                assert_eq!(Nibbles::from_hex(hex).common_prefix(&a), expected);
+            }
+        }
+    }
 }
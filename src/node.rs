@@ -1,94 +1,386 @@
-use std::cell::RefCell;
-use std::rc::Rc;
+use std::mem;
+use std::sync::Arc;
 
 use crate::nibbles::Nibbles;
 
-#[derive(Debug, Clone)]
+/// Refers to a node in a "NodeArena". The generation tells the node apart
+/// from later nodes that reuse its slot once it is freed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NodeId {
+    index: u32,
+    generation: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Node {
     Empty,
-    Leaf(Rc<RefCell<LeafNode>>),
-    Extension(Rc<RefCell<ExtensionNode>>),
-    Branch(Rc<RefCell<BranchNode>>),
-    Hash(Rc<RefCell<HashNode>>),
+    Leaf(NodeId),
+    Extension(NodeId),
+    Branch(NodeId),
+    Hash(NodeId),
+}
+
+/// A value held by a leaf or branch, shared with "get_ref" rather than copied.
+pub type Value = Arc<Vec<u8>>;
+
+#[derive(Debug, Clone)]
+pub struct LeafNode {
+    pub key: Nibbles,
+    pub value: Value,
+    // The hash of the node when it was last loaded or encoded, None once it changes.
+    pub hash: Option<Vec<u8>>,
+}
+
+#[derive(Debug, Clone)]
+pub struct BranchNode {
+    pub children: [Node; 16],
+    pub value: Option<Value>,
+    pub hash: Option<Vec<u8>>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ExtensionNode {
+    pub prefix: Nibbles,
+    pub node: Node,
+    pub hash: Option<Vec<u8>>,
+}
+
+#[derive(Debug, Clone)]
+pub struct HashNode {
+    pub hash: Vec<u8>,
+}
+
+// Nodes are stored inline, boxing branches would undo the point of the arena.
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, Clone)]
+enum Slot {
+    Free,
+    Leaf(LeafNode),
+    Extension(ExtensionNode),
+    Branch(BranchNode),
+    Hash(HashNode),
+}
+
+/// "NodeArena" owns the nodes of an in-memory trie, so that building and
+/// updating a trie reuses freed slots instead of allocating every node.
+#[derive(Debug, Clone, Default)]
+pub struct NodeArena {
+    slots: Vec<(u32, Slot)>,
+    free: Vec<u32>,
 }
 
-impl Node {
-    pub fn from_leaf(key: Nibbles, value: Vec<u8>) -> Self {
-        let leaf = Rc::new(RefCell::new(LeafNode { key, value }));
-        Node::Leaf(leaf)
+impl NodeArena {
+    pub fn new() -> Self {
+        NodeArena::default()
     }
 
-    pub fn from_branch(children: [Node; 16], value: Option<Vec<u8>>) -> Self {
-        let branch = Rc::new(RefCell::new(BranchNode { children, value }));
-        Node::Branch(branch)
+    fn alloc(&mut self, slot: Slot) -> NodeId {
+        match self.free.pop() {
+            Some(index) => {
+                let entry = &mut self.slots[index as usize];
+                entry.1 = slot;
+                NodeId {
+                    index,
+                    generation: entry.0,
+                }
+            }
+            None => {
+                self.slots.push((0, slot));
+                NodeId {
+                    index: (self.slots.len() - 1) as u32,
+                    generation: 0,
+                }
+            }
+        }
     }
 
-    pub fn from_extension(prefix: Nibbles, node: Node) -> Self {
-        let ext = Rc::new(RefCell::new(ExtensionNode { prefix, node }));
-        Node::Extension(ext)
+    fn slot(&self, id: NodeId) -> &Slot {
+        match self.slots.get(id.index as usize) {
+            Some((generation, slot)) if *generation == id.generation => slot,
+            _ => panic!("stale node id {:?}", id),
+        }
     }
 
-    pub fn from_hash(hash: Vec<u8>) -> Self {
-        let hash_node = Rc::new(RefCell::new(HashNode { hash }));
-        Node::Hash(hash_node)
+    fn slot_mut(&mut self, id: NodeId) -> &mut Slot {
+        match self.slots.get_mut(id.index as usize) {
+            Some((generation, slot)) if *generation == id.generation => slot,
+            _ => panic!("stale node id {:?}", id),
+        }
     }
-}
 
-#[derive(Debug)]
-pub struct LeafNode {
-    pub key: Nibbles,
-    pub value: Vec<u8>,
-}
+    pub fn new_leaf(&mut self, key: Nibbles, value: Value) -> Node {
+        Node::Leaf(self.alloc(Slot::Leaf(LeafNode {
+            key,
+            value,
+            hash: None,
+        })))
+    }
 
-#[derive(Debug)]
-pub struct BranchNode {
-    pub children: [Node; 16],
-    pub value: Option<Vec<u8>>,
-}
+    pub fn new_branch(&mut self, children: [Node; 16], value: Option<Value>) -> Node {
+        Node::Branch(self.alloc(Slot::Branch(BranchNode {
+            children,
+            value,
+            hash: None,
+        })))
+    }
+
+    pub fn new_extension(&mut self, prefix: Nibbles, node: Node) -> Node {
+        Node::Extension(self.alloc(Slot::Extension(ExtensionNode {
+            prefix,
+            node,
+            hash: None,
+        })))
+    }
+
+    pub fn new_hash(&mut self, hash: Vec<u8>) -> Node {
+        Node::Hash(self.alloc(Slot::Hash(HashNode { hash })))
+    }
+
+    pub fn leaf(&self, id: NodeId) -> &LeafNode {
+        match self.slot(id) {
+            Slot::Leaf(leaf) => leaf,
+            _ => panic!("node {:?} is not a leaf", id),
+        }
+    }
+
+    pub fn leaf_mut(&mut self, id: NodeId) -> &mut LeafNode {
+        match self.slot_mut(id) {
+            Slot::Leaf(leaf) => leaf,
+            _ => panic!("node {:?} is not a leaf", id),
+        }
+    }
+
+    pub fn extension(&self, id: NodeId) -> &ExtensionNode {
+        match self.slot(id) {
+            Slot::Extension(ext) => ext,
+            _ => panic!("node {:?} is not an extension", id),
+        }
+    }
 
-impl BranchNode {
-    pub fn insert(&mut self, i: usize, n: Node) {
-        if i == 16 {
+    pub fn extension_mut(&mut self, id: NodeId) -> &mut ExtensionNode {
+        match self.slot_mut(id) {
+            Slot::Extension(ext) => ext,
+            _ => panic!("node {:?} is not an extension", id),
+        }
+    }
+
+    pub fn branch(&self, id: NodeId) -> &BranchNode {
+        match self.slot(id) {
+            Slot::Branch(branch) => branch,
+            _ => panic!("node {:?} is not a branch", id),
+        }
+    }
+
+    pub fn branch_mut(&mut self, id: NodeId) -> &mut BranchNode {
+        match self.slot_mut(id) {
+            Slot::Branch(branch) => branch,
+            _ => panic!("node {:?} is not a branch", id),
+        }
+    }
+
+    pub fn hash_node(&self, id: NodeId) -> &HashNode {
+        match self.slot(id) {
+            Slot::Hash(hash_node) => hash_node,
+            _ => panic!("node {:?} is not a hash node", id),
+        }
+    }
+
+    /// Returns the hash of a node that is unchanged since it was loaded or encoded.
+    pub fn node_hash(&self, n: Node) -> Option<&Vec<u8>> {
+        match n {
+            Node::Leaf(id) => self.leaf(id).hash.as_ref(),
+            Node::Extension(id) => self.extension(id).hash.as_ref(),
+            Node::Branch(id) => self.branch(id).hash.as_ref(),
+            Node::Empty | Node::Hash(_) => None,
+        }
+    }
+
+    /// Remembers the hash of the node, until the node is modified.
+    pub fn set_node_hash(&mut self, n: Node, hash: Vec<u8>) {
+        match n {
+            Node::Leaf(id) => self.leaf_mut(id).hash = Some(hash),
+            Node::Extension(id) => self.extension_mut(id).hash = Some(hash),
+            Node::Branch(id) => self.branch_mut(id).hash = Some(hash),
+            Node::Empty | Node::Hash(_) => {}
+        }
+    }
+
+    // Frees the slot of "id", returning what it held.
+    fn take(&mut self, id: NodeId) -> Slot {
+        if let Slot::Free = self.slot(id) {
+            panic!("node {:?} is already free", id);
+        }
+        let entry = &mut self.slots[id.index as usize];
+        entry.0 = entry.0.wrapping_add(1);
+        self.free.push(id.index);
+        mem::replace(&mut entry.1, Slot::Free)
+    }
+
+    /// Frees "n" alone, its children are left as they are.
+    pub fn free(&mut self, n: Node) {
+        if let Some(id) = node_id(n) {
+            self.take(id);
+        }
+    }
+
+    /// Frees "n" and every node below it.
+    pub fn free_tree(&mut self, n: Node) {
+        let mut stack = vec![n];
+        while let Some(n) = stack.pop() {
             match n {
-                Node::Leaf(leaf) => {
-                    self.value = Some(leaf.borrow().value.clone());
+                Node::Extension(id) => stack.push(self.extension(id).node),
+                Node::Branch(id) => stack.extend_from_slice(&self.branch(id).children),
+                _ => {}
+            }
+            self.free(n);
+        }
+    }
+
+    /// Copies "n" and every node below it to "to", returning the copy there.
+    pub fn copy_tree(&self, n: Node, to: &mut NodeArena) -> Node {
+        let id = match node_id(n) {
+            Some(id) => id,
+            None => return Node::Empty,
+        };
+        match self.slot(id).clone() {
+            Slot::Leaf(leaf) => Node::Leaf(to.alloc(Slot::Leaf(leaf))),
+            Slot::Extension(mut ext) => {
+                ext.node = self.copy_tree(ext.node, to);
+                Node::Extension(to.alloc(Slot::Extension(ext)))
+            }
+            Slot::Branch(mut branch) => {
+                for child in branch.children.iter_mut() {
+                    *child = self.copy_tree(*child, to);
                 }
-                _ => panic!("The n must be leaf node"),
+                Node::Branch(to.alloc(Slot::Branch(branch)))
             }
-        } else {
-            self.children[i] = n
+            Slot::Hash(hash_node) => Node::Hash(to.alloc(Slot::Hash(hash_node))),
+            Slot::Free => unreachable!(),
+        }
+    }
+
+    /// Moves "n" and every node below it to "to", returning the node there.
+    #[cfg(feature = "parallel")]
+    pub fn move_tree(&mut self, n: Node, to: &mut NodeArena) -> Node {
+        let id = match node_id(n) {
+            Some(id) => id,
+            None => return Node::Empty,
+        };
+        match self.take(id) {
+            Slot::Leaf(leaf) => Node::Leaf(to.alloc(Slot::Leaf(leaf))),
+            Slot::Extension(mut ext) => {
+                ext.node = self.move_tree(ext.node, to);
+                Node::Extension(to.alloc(Slot::Extension(ext)))
+            }
+            Slot::Branch(mut branch) => {
+                for child in branch.children.iter_mut() {
+                    *child = self.move_tree(*child, to);
+                }
+                Node::Branch(to.alloc(Slot::Branch(branch)))
+            }
+            Slot::Hash(hash_node) => Node::Hash(to.alloc(Slot::Hash(hash_node))),
+            Slot::Free => unreachable!(),
+        }
+    }
+
+    /// Returns the approximate number of bytes held by the arena, including
+    /// free slots kept for reuse.
+    pub fn memory_usage(&self) -> usize {
+        let mut bytes = self.slots.capacity() * mem::size_of::<(u32, Slot)>()
+            + self.free.capacity() * mem::size_of::<u32>();
+        for (_, slot) in self.slots.iter() {
+            bytes += match slot {
+                Slot::Free => 0,
+                Slot::Leaf(leaf) => leaf.key.len() + leaf.value.capacity() + heap_size(&leaf.hash),
+                Slot::Extension(ext) => ext.prefix.len() + heap_size(&ext.hash),
+                Slot::Branch(branch) => {
+                    branch.value.as_ref().map_or(0, |v| v.capacity()) + heap_size(&branch.hash)
+                }
+                Slot::Hash(hash_node) => hash_node.hash.capacity(),
+            };
+        }
+        bytes
+    }
+
+    /// Frees all nodes, ids handed out before stay invalid.
+    pub fn clear(&mut self) {
+        self.free.clear();
+        for (index, entry) in self.slots.iter_mut().enumerate() {
+            if let Slot::Free = entry.1 {
+            } else {
+                entry.0 = entry.0.wrapping_add(1);
+                entry.1 = Slot::Free;
+            }
+            self.free.push(index as u32);
         }
     }
 }
 
-#[derive(Debug)]
-pub struct ExtensionNode {
-    pub prefix: Nibbles,
-    pub node: Node,
+fn node_id(n: Node) -> Option<NodeId> {
+    match n {
+        Node::Empty => None,
+        Node::Leaf(id) | Node::Extension(id) | Node::Branch(id) | Node::Hash(id) => Some(id),
+    }
 }
 
-#[derive(Debug)]
-pub struct HashNode {
-    pub hash: Vec<u8>,
+fn heap_size(bytes: &Option<Vec<u8>>) -> usize {
+    bytes.as_ref().map_or(0, Vec::capacity)
+}
+
+/// Takes the bytes out of "value", copying them only if it is still shared.
+pub fn into_vec(value: Value) -> Vec<u8> {
+    Arc::try_unwrap(value).unwrap_or_else(|value| value.to_vec())
 }
 
 pub fn empty_children() -> [Node; 16] {
-    [
-        Node::Empty,
-        Node::Empty,
-        Node::Empty,
-        Node::Empty,
-        Node::Empty,
-        Node::Empty,
-        Node::Empty,
-        Node::Empty,
-        Node::Empty,
-        Node::Empty,
-        Node::Empty,
-        Node::Empty,
-        Node::Empty,
-        Node::Empty,
-        Node::Empty,
-        Node::Empty,
-    ]
+    [Node::Empty; 16]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_arena_reuses_freed_slots() {
+        let mut arena = NodeArena::new();
+        let leaf = arena.new_leaf(
+            Nibbles::from_raw(b"key".to_vec(), true),
+            Arc::new(b"value".to_vec()),
+        );
+        let mut children = empty_children();
+        children[3] = leaf;
+        let branch = arena.new_branch(children, None);
+        let root = arena.new_extension(Nibbles::from_hex(vec![1]), branch);
+
+        arena.free_tree(root);
+        let hash = arena.new_hash(vec![0; 32]);
+        let reused = match hash {
+            Node::Hash(id) => id,
+            _ => unreachable!(),
+        };
+        assert!(arena.slots.len() == 3 && arena.free.len() == 2);
+        assert_eq!(arena.hash_node(reused).hash, vec![0; 32]);
+
+        arena.clear();
+        assert_eq!(arena.free.len(), 3);
+    }
+
+    #[test]
+    #[should_panic(expected = "stale node id")]
+    fn test_arena_rejects_stale_ids() {
+        let mut arena = NodeArena::new();
+        let leaf = arena.new_leaf(
+            Nibbles::from_raw(b"key".to_vec(), true),
+            Arc::new(b"value".to_vec()),
+        );
+        arena.free(leaf);
+        arena.new_leaf(
+            Nibbles::from_raw(b"other".to_vec(), true),
+            Arc::new(b"value".to_vec()),
+        );
+        match leaf {
+            Node::Leaf(id) => arena.leaf(id),
+            _ => unreachable!(),
+        };
+    }
 }
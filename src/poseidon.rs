@@ -0,0 +1,372 @@
+use hasher::Hasher;
+
+// Width 3: one capacity element and two inputs.
+const WIDTH: usize = 3;
+const FULL_ROUNDS: usize = 8;
+const PARTIAL_ROUNDS: usize = 57;
+
+// Node data is packed into field elements 31 bytes at a time, so every chunk
+// is below the modulus.
+const CHUNK_LEN: usize = 31;
+
+/// Poseidon over the BN254 scalar field with x^5 S-boxes, 8 full and 57
+/// partial rounds, the parameters of circomlib's "Poseidon(2)", so proofs
+/// can be verified in-circuit, e.g. for zk-rollup state.
+///
+/// Data is padded with a single 0x01 byte and zeros to a multiple of 31 bytes,
+/// then absorbed one 31 byte big-endian chunk at a time: starting from zero,
+/// "h = Poseidon(h, chunk)". The hash is "h" as 32 big-endian bytes.
+#[derive(Debug, Clone)]
+pub struct HasherPoseidon {
+    round_constants: Vec<Fr>,
+    mds: [[Fr; WIDTH]; WIDTH],
+}
+
+impl Default for HasherPoseidon {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl HasherPoseidon {
+    /// Derives the round constants and the MDS matrix the way the reference
+    /// "generate_parameters_grain.sage" script of the Poseidon paper does.
+    pub fn new() -> Self {
+        let mut grain = Grain::new();
+
+        let mut round_constants = Vec::with_capacity((FULL_ROUNDS + PARTIAL_ROUNDS) * WIDTH);
+        while round_constants.len() < round_constants.capacity() {
+            let limbs = grain.next_limbs();
+            if less_than(&limbs, &MODULUS) {
+                round_constants.push(Fr::from_canonical(limbs));
+            }
+        }
+
+        // A Cauchy matrix, M[i][j] = 1 / (x[i] + y[j]). Unlike the round
+        // constants, the samples are reduced rather than rejected. The first
+        // matrix drawn for these parameters passes the script's security checks.
+        let samples: Vec<Fr> = (0..2 * WIDTH)
+            .map(|_| Fr::from_canonical(reduce_once(grain.next_limbs())))
+            .collect();
+        let mut mds = [[Fr::zero(); WIDTH]; WIDTH];
+        for (i, row) in mds.iter_mut().enumerate() {
+            for (j, entry) in row.iter_mut().enumerate() {
+                *entry = samples[i].add(&samples[WIDTH + j]).inverse();
+            }
+        }
+
+        HasherPoseidon {
+            round_constants,
+            mds,
+        }
+    }
+
+    // Poseidon(2) as circomlib defines it: the state is [0, a, b] and the
+    // first element of the permuted state is the hash.
+    fn hash2(&self, a: Fr, b: Fr) -> Fr {
+        let mut state = [Fr::zero(), a, b];
+        let half = FULL_ROUNDS / 2;
+        for round in 0..FULL_ROUNDS + PARTIAL_ROUNDS {
+            for (i, s) in state.iter_mut().enumerate() {
+                *s = s.add(&self.round_constants[round * WIDTH + i]);
+            }
+            if round < half || round >= half + PARTIAL_ROUNDS {
+                for s in state.iter_mut() {
+                    *s = s.pow5();
+                }
+            } else {
+                state[0] = state[0].pow5();
+            }
+
+            let mut mixed = [Fr::zero(); WIDTH];
+            for (m, row) in mixed.iter_mut().zip(self.mds.iter()) {
+                for (entry, s) in row.iter().zip(state.iter()) {
+                    *m = m.add(&entry.mul(s));
+                }
+            }
+            state = mixed;
+        }
+        state[0]
+    }
+}
+
+impl Hasher for HasherPoseidon {
+    const LENGTH: usize = 32;
+
+    fn digest(&self, data: &[u8]) -> Vec<u8> {
+        let mut padded = data.to_vec();
+        padded.push(1);
+        while padded.len() % CHUNK_LEN != 0 {
+            padded.push(0);
+        }
+
+        let mut h = Fr::zero();
+        for chunk in padded.chunks(CHUNK_LEN) {
+            let mut bytes = [0u8; 32];
+            bytes[32 - CHUNK_LEN..].copy_from_slice(chunk);
+            h = self.hash2(h, Fr::from_canonical(limbs_from_bytes(&bytes)));
+        }
+        h.to_bytes().to_vec()
+    }
+}
+
+// The BN254 scalar field modulus, little-endian 64 bit limbs.
+const MODULUS: [u64; 4] = [
+    0x43e1_f593_f000_0001,
+    0x2833_e848_79b9_7091,
+    0xb850_45b6_8181_585d,
+    0x3064_4e72_e131_a029,
+];
+
+// -MODULUS^-1 mod 2^64
+const INV: u64 = 0xc2e1_f593_efff_ffff;
+
+// 2^512 mod MODULUS, converts into Montgomery form.
+const R2: [u64; 4] = [
+    0x1bb8_e645_ae21_6da7,
+    0x53fe_3ab1_e35c_59e3,
+    0x8c49_833d_53bb_8085,
+    0x0216_d0b1_7f4e_44a5,
+];
+
+/// An element of the BN254 scalar field, in Montgomery form.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Fr([u64; 4]);
+
+impl Fr {
+    fn zero() -> Self {
+        Fr([0; 4])
+    }
+
+    // "limbs" must be below the modulus.
+    fn from_canonical(limbs: [u64; 4]) -> Self {
+        Fr(limbs).mul(&Fr(R2))
+    }
+
+    fn to_bytes(self) -> [u8; 32] {
+        let limbs = self.mul(&Fr([1, 0, 0, 0])).0;
+        let mut bytes = [0u8; 32];
+        for (i, limb) in limbs.iter().rev().enumerate() {
+            bytes[i * 8..i * 8 + 8].copy_from_slice(&limb.to_be_bytes());
+        }
+        bytes
+    }
+
+    fn add(&self, other: &Fr) -> Fr {
+        let mut limbs = [0u64; 4];
+        let mut carry = 0u128;
+        for (l, (a, b)) in limbs.iter_mut().zip(self.0.iter().zip(other.0.iter())) {
+            let sum = u128::from(*a) + u128::from(*b) + carry;
+            *l = sum as u64;
+            carry = sum >> 64;
+        }
+        // Both inputs are below the modulus, which is below 2^254, so the sum
+        // fits and a single subtraction reduces it.
+        Fr(reduce_once(limbs))
+    }
+
+    // Montgomery multiplication (CIOS).
+    #[allow(clippy::needless_range_loop)]
+    fn mul(&self, other: &Fr) -> Fr {
+        let (a, b) = (&self.0, &other.0);
+        let mut t = [0u64; 6];
+        for i in 0..4 {
+            let mut carry = 0u128;
+            for j in 0..4 {
+                let uv = u128::from(t[j]) + u128::from(a[j]) * u128::from(b[i]) + carry;
+                t[j] = uv as u64;
+                carry = uv >> 64;
+            }
+            let uv = u128::from(t[4]) + carry;
+            t[4] = uv as u64;
+            t[5] = (uv >> 64) as u64;
+
+            let m = t[0].wrapping_mul(INV);
+            let mut carry = (u128::from(t[0]) + u128::from(m) * u128::from(MODULUS[0])) >> 64;
+            for j in 1..4 {
+                let uv = u128::from(t[j]) + u128::from(m) * u128::from(MODULUS[j]) + carry;
+                t[j - 1] = uv as u64;
+                carry = uv >> 64;
+            }
+            let uv = u128::from(t[4]) + carry;
+            t[3] = uv as u64;
+            t[4] = t[5] + (uv >> 64) as u64;
+        }
+        // The result is below twice the modulus, so t[4] is always zero.
+        Fr(reduce_once([t[0], t[1], t[2], t[3]]))
+    }
+
+    fn pow5(&self) -> Fr {
+        let square = self.mul(self);
+        square.mul(&square).mul(self)
+    }
+
+    // Fermat: x^(p - 2). Only called on nonzero elements.
+    fn inverse(&self) -> Fr {
+        let mut exponent = MODULUS;
+        exponent[0] -= 2;
+
+        let mut result = Fr::from_canonical([1, 0, 0, 0]);
+        for limb in exponent.iter().rev() {
+            for bit in (0..64u32).rev() {
+                result = result.mul(&result);
+                if (limb >> bit) & 1 == 1 {
+                    result = result.mul(self);
+                }
+            }
+        }
+        result
+    }
+}
+
+fn less_than(a: &[u64; 4], b: &[u64; 4]) -> bool {
+    for (x, y) in a.iter().rev().zip(b.iter().rev()) {
+        if x != y {
+            return x < y;
+        }
+    }
+    false
+}
+
+// Subtracts the modulus once if "limbs" is not below it.
+fn reduce_once(limbs: [u64; 4]) -> [u64; 4] {
+    if less_than(&limbs, &MODULUS) {
+        return limbs;
+    }
+    let mut result = [0u64; 4];
+    let mut borrow = 0u64;
+    for (r, (a, m)) in result.iter_mut().zip(limbs.iter().zip(MODULUS.iter())) {
+        let (d1, b1) = a.overflowing_sub(*m);
+        let (d2, b2) = d1.overflowing_sub(borrow);
+        *r = d2;
+        borrow = (b1 || b2) as u64;
+    }
+    result
+}
+
+fn limbs_from_bytes(bytes: &[u8; 32]) -> [u64; 4] {
+    let mut limbs = [0u64; 4];
+    for (i, chunk) in bytes.chunks(8).rev().enumerate() {
+        limbs[i] = chunk.iter().fold(0u64, |acc, b| (acc << 8) | u64::from(*b));
+    }
+    limbs
+}
+
+/// The Grain LFSR the Poseidon reference script derives its parameters from.
+struct Grain {
+    // 80 bits, bit 0 is the oldest.
+    state: u128,
+}
+
+impl Grain {
+    fn new() -> Self {
+        // field (1 = prime field): 2 bits, S-box (0 = x^alpha): 4 bits,
+        // field size: 12 bits, width: 12 bits, full rounds: 10 bits,
+        // partial rounds: 10 bits, then 30 bits set.
+        let fields: [(u128, u32); 7] = [
+            (1, 2),
+            (0, 4),
+            (254, 12),
+            (WIDTH as u128, 12),
+            (FULL_ROUNDS as u128, 10),
+            (PARTIAL_ROUNDS as u128, 10),
+            ((1 << 30) - 1, 30),
+        ];
+        let mut state = 0u128;
+        let mut position = 0u32;
+        for (value, len) in fields.iter() {
+            for bit in (0..*len).rev() {
+                state |= ((value >> bit) & 1) << position;
+                position += 1;
+            }
+        }
+
+        let mut grain = Grain { state };
+        for _ in 0..160 {
+            grain.step();
+        }
+        grain
+    }
+
+    fn step(&mut self) -> u128 {
+        let s = self.state;
+        let bit = (s >> 62 ^ s >> 51 ^ s >> 38 ^ s >> 23 ^ s >> 13 ^ s) & 1;
+        self.state = (s >> 1) | (bit << 79);
+        bit
+    }
+
+    // Self-shrinking: of each pair of bits, the second is kept if the first is set.
+    fn next_bit(&mut self) -> u64 {
+        loop {
+            let keep = self.step();
+            let bit = self.step();
+            if keep == 1 {
+                return bit as u64;
+            }
+        }
+    }
+
+    // The next 254 bits as a big-endian integer.
+    fn next_limbs(&mut self) -> [u64; 4] {
+        let mut limbs = [0u64; 4];
+        for _ in 0..254 {
+            let bit = self.next_bit();
+            limbs[3] = limbs[3] << 1 | limbs[2] >> 63;
+            limbs[2] = limbs[2] << 1 | limbs[1] >> 63;
+            limbs[1] = limbs[1] << 1 | limbs[0] >> 63;
+            limbs[0] = limbs[0] << 1 | bit;
+        }
+        limbs
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::db::MemoryDB;
+    use crate::trie::{PatriciaTrie, Trie};
+
+    fn fr(n: u64) -> Fr {
+        Fr::from_canonical([n, 0, 0, 0])
+    }
+
+    #[test]
+    fn test_poseidon_circom_vector() {
+        // circomlib: poseidon([1, 2])
+        let hasher = HasherPoseidon::new();
+        assert_eq!(
+            hex::encode(hasher.hash2(fr(1), fr(2)).to_bytes()),
+            "115cc0f5e7d690413df64c6b9662e9cf2a3617f2743245519e19607a4417189a"
+        );
+    }
+
+    #[test]
+    fn test_poseidon_field() {
+        let minus_one = Fr::from_canonical([MODULUS[0] - 1, MODULUS[1], MODULUS[2], MODULUS[3]]);
+        assert_eq!(minus_one.add(&fr(1)), Fr::zero());
+        assert_eq!(minus_one.mul(&minus_one), fr(1));
+        assert_eq!(fr(7).mul(&fr(7).inverse()), fr(1));
+        assert_eq!(fr(3).pow5().to_bytes()[31], 243);
+    }
+
+    #[test]
+    fn test_poseidon_digest() {
+        let hasher = HasherPoseidon::new();
+        // The padding keeps trailing zeros significant.
+        assert_ne!(hasher.digest(b""), hasher.digest(&[0]));
+        assert_ne!(hasher.digest(&[0; 31]), hasher.digest(&[0; 30]));
+        assert_eq!(hasher.digest(&[5; 100]).len(), HasherPoseidon::LENGTH);
+
+        let memdb = Arc::new(MemoryDB::new(true));
+        let mut trie = PatriciaTrie::new(memdb, Arc::new(hasher));
+        for i in 0..100u8 {
+            trie.insert(vec![i], vec![i; 40]).unwrap();
+        }
+        let root = trie.root().unwrap();
+        let proof = trie.get_proof(&[7]).unwrap();
+        let value = trie.verify_proof(root, &[7], proof).unwrap();
+        assert_eq!(value, Some(vec![7; 40]));
+    }
+}
@@ -0,0 +1,132 @@
+use hasher::{Hasher, HasherKeccak};
+
+use crate::codec::{NodeCodec, RLPNodeCodec};
+use crate::db::DB;
+use crate::trie::{PatriciaTrie, Trie, TrieResult};
+
+/// "PrefixedTrie" prepends a namespace to every key of a borrowed trie, so
+/// that several logical tables share one trie and one root. Namespaces must
+/// not be prefixes of each other, e.g. end them with a separator.
+#[derive(Debug)]
+pub struct PrefixedTrie<'a, D, H = HasherKeccak, C = RLPNodeCodec>
+where
+    D: DB,
+    H: Hasher,
+    C: NodeCodec,
+{
+    trie: &'a mut PatriciaTrie<D, H, C>,
+    prefix: Vec<u8>,
+}
+
+impl<'a, D, H, C> PrefixedTrie<'a, D, H, C>
+where
+    D: DB,
+    H: Hasher,
+    C: NodeCodec,
+{
+    pub fn new(trie: &'a mut PatriciaTrie<D, H, C>, prefix: &[u8]) -> Self {
+        PrefixedTrie {
+            trie,
+            prefix: prefix.to_vec(),
+        }
+    }
+
+    pub fn prefix(&self) -> &[u8] {
+        &self.prefix
+    }
+
+    pub fn trie(&self) -> &PatriciaTrie<D, H, C> {
+        self.trie
+    }
+
+    /// Removes every key of the namespace, returns whether there was any.
+    pub fn clear(&mut self) -> TrieResult<bool> {
+        self.trie.remove_prefix(&self.prefix)
+    }
+
+    fn full_key(&self, key: &[u8]) -> Vec<u8> {
+        [&self.prefix[..], key].concat()
+    }
+}
+
+impl<'a, D, H, C> Trie<D, H> for PrefixedTrie<'a, D, H, C>
+where
+    D: DB,
+    H: Hasher,
+    C: NodeCodec,
+{
+    fn get<K: AsRef<[u8]>>(&self, key: K) -> TrieResult<Option<Vec<u8>>> {
+        self.trie.get(self.full_key(key.as_ref()))
+    }
+
+    fn contains<K: AsRef<[u8]>>(&self, key: K) -> TrieResult<bool> {
+        self.trie.contains(self.full_key(key.as_ref()))
+    }
+
+    fn insert<K: AsRef<[u8]>>(&mut self, key: K, value: Vec<u8>) -> TrieResult<()> {
+        let key = self.full_key(key.as_ref());
+        self.trie.insert(key, value)
+    }
+
+    fn remove<K: AsRef<[u8]>>(&mut self, key: K) -> TrieResult<bool> {
+        let key = self.full_key(key.as_ref());
+        self.trie.remove(key)
+    }
+
+    /// Commits the whole trie, the root covers every namespace.
+    fn root(&mut self) -> TrieResult<Vec<u8>> {
+        self.trie.root()
+    }
+
+    fn get_proof<K: AsRef<[u8]>>(&self, key: K) -> TrieResult<Vec<Vec<u8>>> {
+        self.trie.get_proof(self.full_key(key.as_ref()))
+    }
+
+    fn verify_proof<K: AsRef<[u8]>>(
+        &self,
+        root_hash: Vec<u8>,
+        key: K,
+        proof: Vec<Vec<u8>>,
+    ) -> TrieResult<Option<Vec<u8>>> {
+        self.trie
+            .verify_proof(root_hash, self.full_key(key.as_ref()), proof)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use hasher::HasherKeccak;
+
+    use super::PrefixedTrie;
+    use crate::db::MemoryDB;
+    use crate::trie::{PatriciaTrie, Trie};
+
+    #[test]
+    fn test_prefixed_trie() {
+        let mut trie =
+            PatriciaTrie::new(Arc::new(MemoryDB::new(true)), Arc::new(HasherKeccak::new()));
+        {
+            let mut accounts = PrefixedTrie::new(&mut trie, b"accounts/");
+            accounts.insert(b"alice".to_vec(), vec![1; 40]).unwrap();
+            accounts.insert(b"bob".to_vec(), vec![2; 40]).unwrap();
+        }
+        {
+            let mut codes = PrefixedTrie::new(&mut trie, b"codes/");
+            codes.insert(b"alice".to_vec(), vec![3; 40]).unwrap();
+            assert_eq!(codes.get(b"bob").unwrap(), None);
+        }
+        assert_eq!(trie.get(b"accounts/bob").unwrap(), Some(vec![2; 40]));
+
+        let mut accounts = PrefixedTrie::new(&mut trie, b"accounts/");
+        assert_eq!(accounts.get(b"alice").unwrap(), Some(vec![1; 40]));
+        let root = accounts.root().unwrap();
+        let proof = accounts.get_proof(b"alice").unwrap();
+        let value = accounts.verify_proof(root, b"alice", proof).unwrap();
+        assert_eq!(value, Some(vec![1; 40]));
+        assert!(accounts.clear().unwrap());
+        assert_eq!(trie.get(b"accounts/alice").unwrap(), None);
+        assert_eq!(trie.get(b"codes/alice").unwrap(), Some(vec![3; 40]));
+    }
+}
@@ -0,0 +1,131 @@
+use std::sync::Arc;
+
+use hasher::Hasher;
+use parking_lot::Mutex;
+
+use crate::db::DB;
+use crate::errors::TrieError;
+use crate::lru::LRUCache;
+use crate::trie::TrieResult;
+
+/// "NodeProvider" serves the encoded nodes of a DB by hash, e.g. to answer
+/// the "GetNodeData" requests of peers that sync a state. Nodes are read
+/// with one batch per request and kept in an LRU cache, as peers syncing the
+/// same state ask for the same nodes. Only data that hashes to the requested
+/// hash is served, so other keys of the DB never leak.
+#[derive(Debug)]
+pub struct NodeProvider<D: DB, H: Hasher> {
+    db: Arc<D>,
+    hasher: Arc<H>,
+    cache: Mutex<LRUCache<Vec<u8>, Vec<u8>>>,
+    response_limit: usize,
+}
+
+impl<D: DB, H: Hasher> NodeProvider<D, H> {
+    /// Keeps up to "capacity" served nodes in the cache.
+    pub fn new(db: Arc<D>, hasher: Arc<H>, capacity: usize) -> Self {
+        NodeProvider {
+            db,
+            hasher,
+            cache: Mutex::new(LRUCache::new(capacity)),
+            response_limit: usize::max_value(),
+        }
+    }
+
+    /// Stops a response once it holds "bytes" of nodes, the node that crosses
+    /// the limit included, so that a single request can't pin the server.
+    pub fn with_response_limit(self, bytes: usize) -> Self {
+        Self {
+            response_limit: bytes,
+            ..self
+        }
+    }
+
+    /// Returns the nodes of "hashes", in their order, None for those the DB
+    /// doesn't hold. Once the response limit is reached the rest of the
+    /// hashes are left out, peers ask again for them.
+    pub fn get_nodes(&self, hashes: &[Vec<u8>]) -> TrieResult<Vec<Option<Vec<u8>>>> {
+        let mut nodes: Vec<Option<Vec<u8>>> = {
+            let mut cache = self.cache.lock();
+            hashes.iter().map(|hash| cache.get(hash).cloned()).collect()
+        };
+        let missing: Vec<usize> = (0..nodes.len()).filter(|i| nodes[*i].is_none()).collect();
+        if !missing.is_empty() {
+            let keys: Vec<Vec<u8>> = missing.iter().map(|i| hashes[*i].clone()).collect();
+            let values = self
+                .db
+                .get_batch(&keys)
+                .map_err(|e| TrieError::DB(e.to_string()))?;
+            let mut cache = self.cache.lock();
+            for ((i, hash), value) in missing.into_iter().zip(keys).zip(values) {
+                if let Some(node) = value {
+                    if self.hasher.digest(&node) == hash {
+                        cache.insert(hash, node.clone());
+                        nodes[i] = Some(node);
+                    }
+                }
+            }
+        }
+
+        let mut size = 0;
+        let len = nodes
+            .iter()
+            .position(|node| {
+                let full = size >= self.response_limit;
+                size += node.as_ref().map_or(0, Vec::len);
+                full
+            })
+            .unwrap_or_else(|| nodes.len());
+        nodes.truncate(len);
+        Ok(nodes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use hasher::{Hasher, HasherKeccak};
+
+    use super::NodeProvider;
+    use crate::db::{MemoryDB, MeteredDB, DB};
+    use crate::trie::{PatriciaTrie, Trie};
+
+    #[test]
+    fn test_node_provider() {
+        let memdb = Arc::new(MeteredDB::new(MemoryDB::new(true)));
+        let hasher = Arc::new(HasherKeccak::new());
+        let mut trie = PatriciaTrie::new(Arc::clone(&memdb), Arc::clone(&hasher));
+        for i in 0..100u8 {
+            trie.insert(vec![i], vec![i; 40]).unwrap();
+        }
+        let root = trie.root().unwrap();
+        let proof = trie.get_proof(&[0]).unwrap();
+        let (root_node, child) = (proof[0].clone(), proof[1].clone());
+        let child_hash = hasher.digest(&child);
+        memdb.insert(b"cita-trie:secret".to_vec(), vec![1]).unwrap();
+
+        let provider = NodeProvider::new(Arc::clone(&memdb), Arc::clone(&hasher), 16);
+        let hashes = vec![
+            root.clone(),
+            vec![0; 32],
+            b"cita-trie:secret".to_vec(),
+            child_hash.clone(),
+        ];
+        memdb.reset();
+        let nodes = provider.get_nodes(&hashes).unwrap();
+        let expected = vec![Some(root_node.clone()), None, None, Some(child.clone())];
+        assert_eq!(nodes, expected);
+        assert_eq!(memdb.metrics().get_batches, 1);
+
+        // Found nodes are served from the cache.
+        memdb.reset();
+        let hashes = vec![child_hash.clone(), root.clone()];
+        assert_eq!(provider.get_nodes(&hashes).unwrap().len(), 2);
+        assert_eq!(memdb.metrics().get_batches, 0);
+
+        let provider = provider.with_response_limit(1);
+        let nodes = provider.get_nodes(&[root, child_hash]).unwrap();
+        assert_eq!(nodes, vec![Some(root_node)]);
+    }
+}
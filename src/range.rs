@@ -0,0 +1,532 @@
+use hashbrown::{HashMap, HashSet};
+use hasher::Hasher;
+
+use crate::codec::{ChildReference, NodeCodec, NodeData, ShortItem};
+use crate::db::DB;
+use crate::errors::TrieError;
+use crate::light::lookup;
+use crate::nibbles::Nibbles;
+use crate::trie::TrieResult;
+
+/// The leaves of a key range and the nodes that prove them, see "get_range".
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RangeProof {
+    /// The keys and values in the range, in key order.
+    pub leaves: Vec<(Vec<u8>, Vec<u8>)>,
+    /// The nodes on the paths from the root to the first and the last key of
+    /// the range, so their number does not grow with the range.
+    pub proof: Vec<Vec<u8>>,
+}
+
+/// Returns the leaves of the trie at "root" from "start" up to "end", or to
+/// the last key if None, at most "max_leaves" of them, e.g. to serve a peer
+/// that syncs a state by ranges. The proof lets the peer check, with
+/// "verify_range_proof", that no leaf of the range was left out.
+pub fn get_range<D, C>(
+    db: &D,
+    codec: &C,
+    root: &[u8],
+    start: &[u8],
+    end: Option<&[u8]>,
+    max_leaves: usize,
+) -> TrieResult<RangeProof>
+where
+    D: DB,
+    C: NodeCodec,
+{
+    let load = |hash: &[u8]| {
+        db.get(hash)
+            .map_err(|e| TrieError::DB(e.to_string()))?
+            .ok_or_else(|| TrieError::MissingNode {
+                hash: hash.to_vec(),
+            })
+    };
+    let mut walk = RangeWalk::new(codec, start, end, max_leaves);
+    walk.walk_root(root, &mut |hash| load(hash))?;
+
+    // Only the edges of the range are proven, the verifier rebuilds the
+    // nodes between them from the leaves.
+    let last = match walk.leaves.last() {
+        Some((key, _)) => Some(key.clone()),
+        None => end.map(<[u8]>::to_vec),
+    };
+    let mut proof = vec![];
+    let mut proven = HashSet::new();
+    for key in Some(start)
+        .into_iter()
+        .chain(last.as_ref().map(|key| &key[..]))
+    {
+        lookup(codec, root, key, |hash| {
+            let data = load(hash)?;
+            if proven.insert(hash.to_vec()) {
+                proof.push(data.clone());
+            }
+            Ok(data)
+        })?;
+    }
+    Ok(RangeProof {
+        leaves: walk.leaves,
+        proof,
+    })
+}
+
+/// Checks that "range" holds every leaf of the trie at "root" from "start"
+/// up to its last leaf, or up to "end" if it holds none, as "get_range"
+/// returns them: the trie is rebuilt from the edge proofs and the leaves
+/// and must hash to "root". Returns whether the trie has keys after that.
+pub fn verify_range_proof<H, C>(
+    hasher: &H,
+    codec: &C,
+    root: &[u8],
+    start: &[u8],
+    end: Option<&[u8]>,
+    range: &RangeProof,
+) -> TrieResult<bool>
+where
+    H: Hasher,
+    C: NodeCodec,
+{
+    let nibbles = |key: &[u8]| Nibbles::from_raw(key.to_vec(), false).get_data().to_vec();
+    let leaves: Vec<(Vec<u8>, &[u8])> = range
+        .leaves
+        .iter()
+        .map(|(key, value)| (nibbles(key), &value[..]))
+        .collect();
+    let start = nibbles(start);
+    let end = end.map(nibbles);
+    let sorted = leaves.windows(2).all(|pair| pair[0].0 < pair[1].0);
+    let after_start = leaves.first().map_or(true, |(key, _)| *key >= start);
+    let before_end = match (leaves.last(), &end) {
+        (Some((key, _)), Some(end)) => key <= end,
+        _ => true,
+    };
+    if !sorted || !after_start || !before_end {
+        return Err(TrieError::InvalidProof);
+    }
+
+    let last = match leaves.last() {
+        Some((key, _)) => Some(key.clone()),
+        None => end,
+    };
+    let mut rebuild = RangeRebuild {
+        hasher,
+        codec,
+        nodes: range
+            .proof
+            .iter()
+            .map(|node| (hasher.digest(node), node))
+            .collect(),
+        start,
+        last,
+        leaves,
+        used: 0,
+        more: false,
+    };
+    let rebuilt = rebuild
+        .rebuild_root(root)
+        .map_err(|_| TrieError::InvalidProof)?;
+    if hasher.digest(&codec.encode(&rebuilt)) != root || rebuild.used != rebuild.leaves.len() {
+        return Err(TrieError::InvalidProof);
+    }
+    Ok(rebuild.more)
+}
+
+// Rebuilds a trie from the nodes on the paths to "start" and "last", the
+// edges of a range, and the leaves between them. Subtries outside the range
+// are kept as the edge nodes refer to them, those inside it are built from
+// the leaves. Paths are in nibbles.
+struct RangeRebuild<'a, H, C> {
+    hasher: &'a H,
+    codec: &'a C,
+    nodes: HashMap<Vec<u8>, &'a Vec<u8>>,
+    start: Vec<u8>,
+    // None if the range is open, it then holds every key from "start".
+    last: Option<Vec<u8>>,
+    leaves: Vec<(Vec<u8>, &'a [u8])>,
+    // The number of leaves placed in the trie, each is placed at most once.
+    used: usize,
+    // Whether a subtrie or a value after "last" was kept.
+    more: bool,
+}
+
+impl<'a, H: Hasher, C: NodeCodec> RangeRebuild<'a, H, C> {
+    fn rebuild_root(&mut self, root: &[u8]) -> TrieResult<NodeData> {
+        let node = self.load(root)?;
+        Ok(self.edge(node, &mut vec![])?.unwrap_or(NodeData::Empty))
+    }
+
+    // Rebuilds the child at "path" of a node on an edge.
+    fn child(
+        &mut self,
+        child: Option<ChildReference>,
+        path: &mut Vec<u8>,
+    ) -> TrieResult<Option<ChildReference>> {
+        let child = match child {
+            Some(child) => child,
+            None => return Ok(self.build(path).map(|node| self.reference(&node))),
+        };
+        if self.on_edge(path) {
+            let node = match child {
+                ChildReference::Inline(data) => self.codec.decode(&data)?,
+                ChildReference::Hash(hash) => self.load(&hash)?,
+            };
+            Ok(self.edge(node, path)?.map(|node| self.reference(&node)))
+        } else if self.outside(path) {
+            Ok(Some(child))
+        } else {
+            Ok(self.build(path).map(|node| self.reference(&node)))
+        }
+    }
+
+    // Rebuilds "node", which is at "path" on an edge.
+    fn edge(&mut self, node: NodeData, path: &mut Vec<u8>) -> TrieResult<Option<NodeData>> {
+        match node {
+            NodeData::Empty => Ok(self.build(path)),
+            NodeData::Short { path: short, item } => {
+                let len = path.len();
+                path.extend_from_slice(&short);
+                let rebuilt = match item {
+                    ShortItem::Child(child) => {
+                        if self.on_edge(path) {
+                            self.child(Some(child), path)?.map(|child| NodeData::Short {
+                                path: short,
+                                item: ShortItem::Child(child),
+                            })
+                        } else if self.outside(path) {
+                            Some(NodeData::Short {
+                                path: short,
+                                item: ShortItem::Child(child),
+                            })
+                        } else {
+                            None
+                        }
+                    }
+                    ShortItem::Value(value) => {
+                        if self.outside(path) || *path < self.start {
+                            Some(NodeData::Short {
+                                path: short,
+                                item: ShortItem::Value(value),
+                            })
+                        } else {
+                            None
+                        }
+                    }
+                };
+                path.truncate(len);
+                // The keys of the node are in the range, the leaves hold them.
+                Ok(rebuilt.or_else(|| self.build(path)))
+            }
+            NodeData::Branch {
+                mut children,
+                value,
+            } => {
+                let value = if *path < self.start || self.outside(path) {
+                    value
+                } else {
+                    self.take_leaf(path)
+                };
+                for (i, child) in children.iter_mut().enumerate() {
+                    path.push(i as u8);
+                    let rebuilt = self.child(child.take(), path);
+                    path.pop();
+                    *child = rebuilt?;
+                }
+                if value.is_none() && children.iter().all(Option::is_none) {
+                    return Ok(None);
+                }
+                Ok(Some(NodeData::Branch { children, value }))
+            }
+        }
+    }
+
+    // Whether "path" leads to the first or the last key of the range.
+    fn on_edge(&self, path: &[u8]) -> bool {
+        self.start.starts_with(path)
+            || self
+                .last
+                .as_ref()
+                .map_or(false, |last| last.starts_with(path))
+    }
+
+    // Whether the keys below "path", which is not on an edge, are all before
+    // or all after the range. Keeps track of the keys after it.
+    fn outside(&mut self, path: &[u8]) -> bool {
+        if *path < self.start[..] && !self.start.starts_with(path) {
+            return true;
+        }
+        let after = self.last.as_ref().map_or(false, |last| *path > last[..]);
+        self.more |= after;
+        after
+    }
+
+    // Builds the subtrie of the leaves below "path".
+    fn build(&mut self, path: &[u8]) -> Option<NodeData> {
+        let from = match self.leaves.binary_search_by(|(key, _)| key[..].cmp(path)) {
+            Ok(i) | Err(i) => i,
+        };
+        let count = self.leaves[from..]
+            .iter()
+            .take_while(|(key, _)| key.starts_with(path))
+            .count();
+        self.used += count;
+        let leaves = self.leaves[from..from + count].to_vec();
+        self.build_at(&leaves, path.len())
+    }
+
+    // The node of sorted "leaves" that share their first "depth" nibbles.
+    fn build_at(&self, leaves: &[(Vec<u8>, &[u8])], depth: usize) -> Option<NodeData> {
+        let (first, last) = match (leaves.first(), leaves.last()) {
+            (Some(first), Some(last)) => (&first.0[depth..], &last.0[depth..]),
+            _ => return None,
+        };
+        if leaves.len() == 1 {
+            return Some(NodeData::Short {
+                path: first.to_vec(),
+                item: ShortItem::Value(leaves[0].1.to_vec()),
+            });
+        }
+        let common = first
+            .iter()
+            .zip(last.iter())
+            .take_while(|(a, b)| a == b)
+            .count();
+        if common > 0 {
+            let child = self.build_at(leaves, depth + common)?;
+            return Some(NodeData::Short {
+                path: first[..common].to_vec(),
+                item: ShortItem::Child(self.reference(&child)),
+            });
+        }
+
+        let mut children: [Option<ChildReference>; 16] = Default::default();
+        let (value, mut rest) = if first.is_empty() {
+            (Some(leaves[0].1.to_vec()), &leaves[1..])
+        } else {
+            (None, leaves)
+        };
+        while let Some((key, _)) = rest.first() {
+            let nibble = key[depth];
+            let count = rest
+                .iter()
+                .take_while(|(key, _)| key[depth] == nibble)
+                .count();
+            let child = self.build_at(&rest[..count], depth + 1)?;
+            children[nibble as usize] = Some(self.reference(&child));
+            rest = &rest[count..];
+        }
+        Some(NodeData::Branch { children, value })
+    }
+
+    // The value of the leaf at "path", if any.
+    fn take_leaf(&mut self, path: &[u8]) -> Option<Vec<u8>> {
+        let i = self
+            .leaves
+            .binary_search_by(|(key, _)| key[..].cmp(path))
+            .ok()?;
+        self.used += 1;
+        Some(self.leaves[i].1.to_vec())
+    }
+
+    fn reference(&self, node: &NodeData) -> ChildReference {
+        let data = self.codec.encode(node);
+        if data.len() < H::LENGTH {
+            ChildReference::Inline(data)
+        } else {
+            ChildReference::Hash(self.hasher.digest(&data))
+        }
+    }
+
+    fn load(&self, hash: &[u8]) -> TrieResult<NodeData> {
+        match self.nodes.get(hash) {
+            Some(data) => self.codec.decode(data),
+            None => Err(TrieError::InvalidProof),
+        }
+    }
+}
+
+type LoadNode<'a> = dyn FnMut(&[u8]) -> TrieResult<Vec<u8>> + 'a;
+
+// Visits the leaves between "start" and "end", in key order, loading only
+// the nodes whose subtries may hold some. Paths are in nibbles.
+struct RangeWalk<'a, C> {
+    codec: &'a C,
+    start: Vec<u8>,
+    end: Option<Vec<u8>>,
+    max_leaves: usize,
+    leaves: Vec<(Vec<u8>, Vec<u8>)>,
+    last: Vec<u8>,
+}
+
+impl<'a, C: NodeCodec> RangeWalk<'a, C> {
+    fn new(codec: &'a C, start: &[u8], end: Option<&[u8]>, max_leaves: usize) -> Self {
+        let nibbles = |key: &[u8]| Nibbles::from_raw(key.to_vec(), false).get_data().to_vec();
+        RangeWalk {
+            codec,
+            start: nibbles(start),
+            end: end.map(nibbles),
+            max_leaves: max_leaves.max(1),
+            leaves: vec![],
+            last: vec![],
+        }
+    }
+
+    fn walk_root(&mut self, root: &[u8], load: &mut LoadNode) -> TrieResult<()> {
+        let node = self.codec.decode(&load(root)?)?;
+        self.walk(node, &mut vec![], load)
+    }
+
+    fn walk(&mut self, node: NodeData, path: &mut Vec<u8>, load: &mut LoadNode) -> TrieResult<()> {
+        match node {
+            NodeData::Empty => Ok(()),
+            NodeData::Short { path: short, item } => {
+                let len = path.len();
+                path.extend(short);
+                let walked = match item {
+                    ShortItem::Value(value) => {
+                        self.leaf(path, value);
+                        Ok(())
+                    }
+                    ShortItem::Child(child) => self.child(child, path, load),
+                };
+                path.truncate(len);
+                walked
+            }
+            NodeData::Branch { children, value } => {
+                if let Some(value) = value {
+                    self.leaf(path, value);
+                }
+                for (i, child) in children.iter().enumerate() {
+                    if let Some(child) = child {
+                        path.push(i as u8);
+                        let walked = self.child(child.clone(), path, load);
+                        path.pop();
+                        walked?;
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+
+    // Walks the subtrie "child" at "path" if it may hold leaves of the range.
+    fn child(
+        &mut self,
+        child: ChildReference,
+        path: &mut Vec<u8>,
+        load: &mut LoadNode,
+    ) -> TrieResult<()> {
+        // All keys below "path" are before "start".
+        if path[..] < self.start[..] && !self.start.starts_with(path) {
+            return Ok(());
+        }
+        if self.after_end(path) {
+            return Ok(());
+        }
+        let node = match child {
+            ChildReference::Inline(data) => self.codec.decode(&data)?,
+            ChildReference::Hash(hash) => self.codec.decode(&load(&hash)?)?,
+        };
+        self.walk(node, path, load)
+    }
+
+    fn leaf(&mut self, path: &[u8], value: Vec<u8>) {
+        // Keys given as nibbles may not be whole bytes.
+        if path.len() % 2 == 1 || *path < self.start[..] {
+            return;
+        }
+        if self.after_end(path) {
+            return;
+        }
+        if self.leaves.len() == self.max_leaves {
+            // The range ends at the last leaf that fits.
+            self.end = Some(self.last.clone());
+            return;
+        }
+        self.last = path.to_vec();
+        let key = Nibbles::from_hex(path.to_vec()).encode_raw().0;
+        self.leaves.push((key, value));
+    }
+
+    // Whether all keys below "path" are after "end".
+    fn after_end(&self, path: &[u8]) -> bool {
+        self.end.as_ref().map_or(false, |end| *path > end[..])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use hasher::{Hasher, HasherKeccak};
+
+    use super::{get_range, verify_range_proof};
+    use crate::codec::RLPNodeCodec;
+    use crate::db::{MemoryDB, DB};
+    use crate::errors::TrieError;
+    use crate::trie::{PatriciaTrie, Trie};
+
+    #[test]
+    fn test_range_proof() {
+        let memdb = Arc::new(MemoryDB::new(true));
+        let hasher = HasherKeccak::new();
+        let codec = RLPNodeCodec::new();
+        let mut trie = PatriciaTrie::new(Arc::clone(&memdb), Arc::new(HasherKeccak::new()));
+        for i in 0..200u8 {
+            trie.insert(vec![i, i], vec![i; 40]).unwrap();
+        }
+        trie.insert(vec![50], b"prefix".to_vec()).unwrap();
+        let root = trie.root().unwrap();
+
+        // Pages of 30 leaves from [50], which is a prefix of [50, 50].
+        let range = get_range(&*memdb, &codec, &root, &[50], None, 30).unwrap();
+        assert_eq!(range.leaves.len(), 30);
+        assert_eq!(range.leaves[0], (vec![50], b"prefix".to_vec()));
+        assert_eq!(range.leaves[29].0, vec![78, 78]);
+        let more = verify_range_proof(&hasher, &codec, &root, &[50], None, &range).unwrap();
+        assert!(more);
+
+        // The last page, up to an end key.
+        let range = get_range(&*memdb, &codec, &root, &[190], Some(&[255]), 30).unwrap();
+        assert_eq!(range.leaves.len(), 10);
+        let more = verify_range_proof(&hasher, &codec, &root, &[190], Some(&[255]), &range);
+        assert!(!more.unwrap());
+
+        // An empty range.
+        let range = get_range(&*memdb, &codec, &root, &[10, 11], Some(&[10, 12]), 30).unwrap();
+        assert!(range.leaves.is_empty());
+        let more = verify_range_proof(&hasher, &codec, &root, &[10, 11], Some(&[10, 12]), &range);
+        assert!(more.unwrap());
+
+        // A leaf left out, or a node missing, is caught.
+        let mut range = get_range(&*memdb, &codec, &root, &[100], None, 20).unwrap();
+        let mut tampered = range.clone();
+        tampered.leaves.remove(5);
+        match verify_range_proof(&hasher, &codec, &root, &[100], None, &tampered) {
+            Err(TrieError::InvalidProof) => {}
+            other => panic!("unexpected {:?}", other),
+        }
+        let mut tampered = range.clone();
+        tampered.leaves[5].1 = vec![0; 40];
+        assert!(verify_range_proof(&hasher, &codec, &root, &[100], None, &tampered).is_err());
+        let mut tampered = range.clone();
+        tampered.leaves.insert(5, (vec![104, 105], vec![0; 40]));
+        assert!(verify_range_proof(&hasher, &codec, &root, &[100], None, &tampered).is_err());
+        range.proof.remove(1);
+        assert!(verify_range_proof(&hasher, &codec, &root, &[100], None, &range).is_err());
+
+        // The proof only holds the edges of the range, not its interior.
+        let range = get_range(&*memdb, &codec, &root, &[0], None, 1000).unwrap();
+        assert_eq!(range.leaves.len(), 201);
+        assert!(range.proof.len() <= 5);
+        let more = verify_range_proof(&hasher, &codec, &root, &[0], None, &range).unwrap();
+        assert!(!more);
+
+        // A node missing from the DB is reported as such.
+        let hash = hasher.digest(&range.proof[1]);
+        memdb.remove(&hash).unwrap();
+        match get_range(&*memdb, &codec, &root, &[0], None, 1000) {
+            Err(TrieError::MissingNode { hash: missing }) => assert_eq!(missing, hash),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,93 @@
+use hasher::Hasher;
+
+use crate::codec::NodeCodec;
+use crate::db::DB;
+use crate::errors::TrieError;
+use crate::gc::preimage_key;
+use crate::trie::{PatriciaTrie, TrieResult};
+
+// A key, its value and the preimage of the key, if known.
+pub(crate) type Record = (Vec<u8>, Vec<u8>, Option<Vec<u8>>);
+
+impl<D, H, C> PatriciaTrie<D, H, C>
+where
+    D: DB,
+    H: Hasher,
+    C: NodeCodec,
+{
+    // Passes every key and value of the trie at "root" to "write", in key
+    // order, with the preimage of the key if "preimages" is set and it was
+    // recorded. Nodes missing from the DB fail the walk, as with
+    // "with_strict_nodes", rather than leave keys out. Returns the number of
+    // keys.
+    pub(crate) fn dump_records<F>(
+        &self,
+        root: &[u8],
+        preimages: bool,
+        mut write: F,
+    ) -> TrieResult<u64>
+    where
+        F: FnMut(&[u8], &[u8], Option<&[u8]>) -> TrieResult<()>,
+    {
+        let trie = self.at_root(root)?.with_strict_nodes();
+        let mut values = trie.iter();
+        let mut count = 0;
+        while let Some((key, value)) = values.try_next()? {
+            let preimage = if preimages {
+                self.db()
+                    .get(&preimage_key(&key))
+                    .map_err(|e| TrieError::DB(e.to_string()))?
+            } else {
+                None
+            };
+            write(&key, &value, preimage.as_ref().map(|p| &p[..]))?;
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    // Builds a trie from "records", in key order, records the preimages they
+    // carry and opens it.
+    pub(crate) fn load_records<I>(&self, records: I) -> TrieResult<Self>
+    where
+        I: IntoIterator<Item = TrieResult<Record>>,
+    {
+        let (mut keys, mut values) = (vec![], vec![]);
+        let root = self.build_sorted(records.into_iter().map(|record| {
+            let (key, value, preimage) = record?;
+            if let Some(preimage) = preimage {
+                keys.push(preimage_key(&key));
+                values.push(preimage);
+            }
+            Ok((key, value))
+        }))?;
+        self.db()
+            .insert_batch(keys, values)
+            .map_err(|e| TrieError::DB(e.to_string()))?;
+        self.at_root(&root)
+    }
+}
+
+pub(crate) fn to_hex(bytes: &[u8]) -> String {
+    let mut hex = String::with_capacity(2 + bytes.len() * 2);
+    hex.push_str("0x");
+    for byte in bytes.iter() {
+        hex.push_str(&format!("{:02x}", byte));
+    }
+    hex
+}
+
+pub(crate) fn from_hex(hex: &str) -> TrieResult<Vec<u8>> {
+    let hex = hex.trim_start_matches("0x");
+    if hex.len() % 2 == 1 {
+        return Err(TrieError::InvalidData);
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| {
+            hex.get(i..i + 2)
+                .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+                .ok_or(TrieError::InvalidData)
+        })
+        .collect()
+}
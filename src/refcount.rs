@@ -0,0 +1,84 @@
+use hashbrown::HashMap;
+
+use crate::db::DB;
+use crate::errors::TrieError;
+use crate::trie::TrieResult;
+
+/// The reference count of a node is kept in the DB under this prefix and the
+/// hash of the node. Node keys are hashes, so these keys never collide with them.
+pub const REF_COUNT_PREFIX: &[u8] = b"cita-trie:ref-count:";
+
+pub(crate) fn ref_count_key(hash: &[u8]) -> Vec<u8> {
+    [REF_COUNT_PREFIX, hash].concat()
+}
+
+/// Returns the number of places that reference the node "hash", in all the
+/// tries on "db" that count references, see "with_ref_counting".
+pub fn ref_count<D: DB>(db: &D, hash: &[u8]) -> TrieResult<u64> {
+    match db
+        .get(&ref_count_key(hash))
+        .map_err(|e| TrieError::DB(e.to_string()))?
+    {
+        Some(data) => decode_count(&data),
+        None => Ok(0),
+    }
+}
+
+fn decode_count(data: &[u8]) -> TrieResult<u64> {
+    if data.len() != 8 {
+        return Err(TrieError::InvalidData);
+    }
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(data);
+    Ok(u64::from_le_bytes(bytes))
+}
+
+/// Sums the reference changes of several commits, e.g. of tries that are
+/// written in one batch, so that the count of a shared node is read once.
+pub(crate) fn merge_deltas(into: &mut HashMap<Vec<u8>, i32>, deltas: Vec<(Vec<u8>, i32)>) {
+    for (hash, delta) in deltas.into_iter() {
+        *into.entry(hash).or_insert(0) += delta;
+    }
+}
+
+/// The DB writes that apply reference changes.
+#[derive(Debug, Default)]
+pub(crate) struct RefCountWrites {
+    /// The new counts, by count key.
+    pub counts: Vec<(Vec<u8>, Vec<u8>)>,
+    /// The nodes that are no longer referenced, and their count keys.
+    pub removed: Vec<Vec<u8>>,
+}
+
+/// Adds "deltas" to the counts in "db". A node that was written before
+/// counting started has no count, it is removed once a change drops it.
+pub(crate) fn ref_count_writes<D: DB>(
+    db: &D,
+    deltas: &[(Vec<u8>, i32)],
+) -> TrieResult<RefCountWrites> {
+    let keys: Vec<Vec<u8>> = deltas.iter().map(|(hash, _)| ref_count_key(hash)).collect();
+    let counts = db
+        .get_batch(&keys)
+        .map_err(|e| TrieError::DB(e.to_string()))?;
+
+    let mut writes = RefCountWrites::default();
+    for ((change, key), count) in deltas.iter().zip(keys).zip(counts) {
+        let (hash, delta) = change;
+        if *delta == 0 {
+            continue;
+        }
+        let count = match count {
+            Some(data) => decode_count(&data)? as i64,
+            None => 0,
+        } + i64::from(*delta);
+        if count > 0 {
+            writes
+                .counts
+                .push((key, (count as u64).to_le_bytes().to_vec()));
+        } else {
+            writes.removed.push(hash.clone());
+            writes.removed.push(key);
+        }
+    }
+    Ok(writes)
+}
@@ -0,0 +1,227 @@
+use hasher::Hasher;
+
+use crate::builder::SortedBuilder;
+use crate::codec::{ChildReference, NodeCodec, NodeData, ShortItem};
+use crate::db::DB;
+use crate::errors::TrieError;
+use crate::nibbles::Nibbles;
+use crate::trie::TrieResult;
+
+/// The keys of a subtrie "recover" could not read.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LostRange {
+    /// The nibbles every lost key starts with.
+    pub path: Vec<u8>,
+    /// The hash of the node that is missing or corrupt.
+    pub hash: Vec<u8>,
+    /// Whether the node is in the DB but does not hash to its key or fails to
+    /// decode, rather than missing.
+    pub corrupt: bool,
+}
+
+impl LostRange {
+    /// Whether "key" may have been in the lost subtrie.
+    pub fn contains(&self, key: &[u8]) -> bool {
+        Nibbles::from_raw(key.to_vec(), false)
+            .get_data()
+            .starts_with(&self.path)
+    }
+}
+
+/// What "recover" salvaged of a damaged trie.
+#[derive(Debug, Clone, Default)]
+pub struct Recovery {
+    values: Vec<(Vec<u8>, Vec<u8>)>,
+    lost: Vec<LostRange>,
+}
+
+/// Walks the trie at "root" in "db", e.g. after disk corruption, skipping
+/// the subtries whose node is missing or does not hash to its key, and
+/// collects the values of the rest. Only errors of the DB itself fail.
+pub fn recover<D, H, C>(db: &D, hasher: &H, codec: &C, root: &[u8]) -> TrieResult<Recovery>
+where
+    D: DB,
+    H: Hasher,
+    C: NodeCodec,
+{
+    let mut walk = RecoveryWalk {
+        db,
+        hasher,
+        codec,
+        recovery: Recovery::default(),
+    };
+    walk.load(root, vec![])?;
+    Ok(walk.recovery)
+}
+
+impl Recovery {
+    /// The salvaged keys and values, sorted by key.
+    pub fn values(&self) -> &[(Vec<u8>, Vec<u8>)] {
+        &self.values
+    }
+
+    /// The unreadable subtries, in key order.
+    pub fn lost(&self) -> &[LostRange] {
+        &self.lost
+    }
+
+    /// Whether the whole trie was read.
+    pub fn is_complete(&self) -> bool {
+        self.lost.is_empty()
+    }
+
+    /// Writes a trie of the salvaged values to "db" and returns its root.
+    pub fn repair<D, H, C>(&self, db: &D, hasher: &H, codec: &C) -> TrieResult<Vec<u8>>
+    where
+        D: DB,
+        H: Hasher,
+        C: NodeCodec,
+    {
+        let mut builder = SortedBuilder::new(db, hasher, codec);
+        for (key, value) in self.values.iter() {
+            builder.push(key.clone(), value.clone())?;
+        }
+        builder.finish()
+    }
+}
+
+struct RecoveryWalk<'a, D, H, C> {
+    db: &'a D,
+    hasher: &'a H,
+    codec: &'a C,
+    recovery: Recovery,
+}
+
+impl<'a, D: DB, H: Hasher, C: NodeCodec> RecoveryWalk<'a, D, H, C> {
+    fn load(&mut self, hash: &[u8], path: Vec<u8>) -> TrieResult<()> {
+        let data = match self
+            .db
+            .get(hash)
+            .map_err(|e| TrieError::DB(e.to_string()))?
+        {
+            Some(data) => data,
+            None => {
+                self.lose(path, hash, false);
+                return Ok(());
+            }
+        };
+        if self.hasher.digest(&data) != hash {
+            self.lose(path, hash, true);
+            return Ok(());
+        }
+        match self.codec.decode(&data) {
+            Ok(node) => self.walk(node, path, hash),
+            Err(_) => {
+                self.lose(path, hash, true);
+                Ok(())
+            }
+        }
+    }
+
+    // "hash" is of the node that holds "node", which may be inline.
+    fn walk(&mut self, node: NodeData, mut path: Vec<u8>, hash: &[u8]) -> TrieResult<()> {
+        match node {
+            NodeData::Empty => Ok(()),
+            NodeData::Short { path: rest, item } => {
+                path.extend(rest);
+                match item {
+                    ShortItem::Value(value) => {
+                        self.value(path, value, hash);
+                        Ok(())
+                    }
+                    ShortItem::Child(child) => self.child(child, path, hash),
+                }
+            }
+            NodeData::Branch { children, value } => {
+                if let Some(value) = value {
+                    self.value(path.clone(), value, hash);
+                }
+                for (i, child) in children.iter().enumerate() {
+                    if let Some(child) = child {
+                        let mut child_path = path.clone();
+                        child_path.push(i as u8);
+                        self.child(child.clone(), child_path, hash)?;
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn child(&mut self, child: ChildReference, path: Vec<u8>, hash: &[u8]) -> TrieResult<()> {
+        match child {
+            ChildReference::Hash(child_hash) => self.load(&child_hash, path),
+            ChildReference::Inline(data) => match self.codec.decode(&data) {
+                Ok(node) => self.walk(node, path, hash),
+                Err(_) => {
+                    self.lose(path, hash, true);
+                    Ok(())
+                }
+            },
+        }
+    }
+
+    // A value at an odd number of nibbles has no key, so its node is taken
+    // as corrupt.
+    fn value(&mut self, path: Vec<u8>, value: Vec<u8>, hash: &[u8]) {
+        if path.len() % 2 == 1 {
+            self.lose(path, hash, true);
+            return;
+        }
+        let (key, _) = Nibbles::from_hex(path).encode_raw();
+        self.recovery.values.push((key, value));
+    }
+
+    fn lose(&mut self, path: Vec<u8>, hash: &[u8], corrupt: bool) {
+        self.recovery.lost.push(LostRange {
+            path,
+            hash: hash.to_vec(),
+            corrupt,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use hasher::{Hasher, HasherKeccak};
+
+    use super::recover;
+    use crate::codec::RLPNodeCodec;
+    use crate::db::{MemoryDB, DB};
+    use crate::trie::{PatriciaTrie, Trie};
+
+    #[test]
+    fn test_recover() {
+        let memdb = Arc::new(MemoryDB::new(true));
+        let hasher = Arc::new(HasherKeccak::new());
+        let mut trie = PatriciaTrie::new(Arc::clone(&memdb), Arc::clone(&hasher));
+        for i in 0..100u8 {
+            trie.insert(vec![i, i], vec![i; 40]).unwrap();
+        }
+        let root = trie.root().unwrap();
+        let missing = hasher.digest(&trie.get_proof(&[7, 7]).unwrap()[1]);
+        memdb.remove(&missing).unwrap();
+        let corrupt = hasher.digest(&trie.get_proof(&[90, 90]).unwrap()[1]);
+        memdb.insert(corrupt.clone(), b"garbage".to_vec()).unwrap();
+
+        let recovery = recover(&*memdb, &*hasher, &RLPNodeCodec, &root).unwrap();
+        let lost = recovery.lost();
+        assert_eq!(lost.len(), 2);
+        assert_eq!((&lost[0].hash, lost[0].corrupt), (&missing, false));
+        assert_eq!((&lost[1].hash, lost[1].corrupt), (&corrupt, true));
+        for i in 0..100u8 {
+            let key = vec![i, i];
+            let salvaged = recovery.values().iter().any(|(k, _)| *k == key);
+            assert_ne!(salvaged, lost.iter().any(|range| range.contains(&key)));
+        }
+
+        let other = Arc::new(MemoryDB::new(true));
+        let repaired = recovery.repair(&*other, &*hasher, &RLPNodeCodec).unwrap();
+        let trie = PatriciaTrie::from(other, hasher, &repaired).unwrap();
+        assert_eq!(trie.iter().count(), recovery.values().len());
+        assert_eq!(trie.get(&[7, 7]).unwrap(), None);
+        assert_eq!(trie.get(&[50, 50]).unwrap(), Some(vec![50; 40]));
+    }
+}
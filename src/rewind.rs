@@ -0,0 +1,66 @@
+use hashbrown::HashSet;
+
+use crate::db::{read_bytes, write_bytes, DB};
+use crate::errors::TrieError;
+use crate::trie::TrieResult;
+
+/// The reverse diff of a commit is kept in the DB under this prefix and the
+/// root hash the commit returned, see "with_reverse_diffs".
+pub const REVERSE_DIFF_PREFIX: &[u8] = b"cita-trie:reverse-diff:";
+
+pub(crate) fn reverse_diff_key(root: &[u8]) -> Vec<u8> {
+    [REVERSE_DIFF_PREFIX, root].concat()
+}
+
+/// Encodes the previous root and the nodes of it that a commit made stale,
+/// which are read from "db" before the commit removes them.
+pub(crate) fn reverse_diff<D: DB>(
+    db: &D,
+    prev_root: &[u8],
+    removed: &[Vec<u8>],
+) -> TrieResult<Vec<u8>> {
+    let values = db
+        .get_batch(removed)
+        .map_err(|e| TrieError::DB(e.to_string()))?;
+    let mut data = vec![];
+    write_bytes(&mut data, prev_root);
+    // Nodes of candidate roots that were never written are not needed.
+    for (hash, value) in removed.iter().zip(values) {
+        if let Some(value) = value {
+            write_bytes(&mut data, hash);
+            write_bytes(&mut data, &value);
+        }
+    }
+    Ok(data)
+}
+
+/// Returns the nodes to write to go back from "from" to "to", following the
+/// reverse diffs of the commits in between.
+pub(crate) fn rewind_nodes<D: DB>(
+    db: &D,
+    from: &[u8],
+    to: &[u8],
+) -> TrieResult<Vec<(Vec<u8>, Vec<u8>)>> {
+    let mut nodes = vec![];
+    let mut visited = HashSet::new();
+    let mut root = from.to_vec();
+    while root != to {
+        // A root that was reached twice would lead around in circles.
+        if !visited.insert(root.clone()) {
+            return Err(TrieError::NoReverseDiff);
+        }
+        let data = db
+            .get(&reverse_diff_key(&root))
+            .map_err(|e| TrieError::DB(e.to_string()))?
+            .ok_or(TrieError::NoReverseDiff)?;
+        let mut rest = &data[..];
+        root = read_bytes(&mut rest).ok_or(TrieError::InvalidData)?;
+        while !rest.is_empty() {
+            match (read_bytes(&mut rest), read_bytes(&mut rest)) {
+                (Some(hash), Some(value)) => nodes.push((hash, value)),
+                _ => return Err(TrieError::InvalidData),
+            }
+        }
+    }
+    Ok(nodes)
+}
@@ -0,0 +1,178 @@
+use std::collections::BTreeMap;
+
+use hasher::{Hasher, HasherKeccak};
+
+use crate::builder::SortedBuilder;
+use crate::codec::{NodeCodec, RLPNodeCodec};
+use crate::db::DB;
+use crate::errors::MemDBError;
+use crate::trie::TrieResult;
+
+/// Returns the root of the trie holding "pairs", with keccak and RLP, e.g.
+/// for a header or a test. No node is stored. A later pair wins over an
+/// earlier one with the same key and empty values are skipped, as "insert"
+/// treats them.
+pub fn trie_root<I>(pairs: I) -> TrieResult<Vec<u8>>
+where
+    I: IntoIterator<Item = (Vec<u8>, Vec<u8>)>,
+{
+    trie_root_with_codec(&HasherKeccak::new(), &RLPNodeCodec::new(), pairs)
+}
+
+/// Like "trie_root", with any hasher and codec.
+pub fn trie_root_with_codec<H, C, I>(hasher: &H, codec: &C, pairs: I) -> TrieResult<Vec<u8>>
+where
+    H: Hasher,
+    C: NodeCodec,
+    I: IntoIterator<Item = (Vec<u8>, Vec<u8>)>,
+{
+    build_root(hasher, codec, pairs.into_iter().collect())
+}
+
+/// Like "trie_root", but every key is hashed first, as by "SecureTrie".
+pub fn sec_trie_root<I>(pairs: I) -> TrieResult<Vec<u8>>
+where
+    I: IntoIterator<Item = (Vec<u8>, Vec<u8>)>,
+{
+    sec_trie_root_with_codec(&HasherKeccak::new(), &RLPNodeCodec::new(), pairs)
+}
+
+/// Like "sec_trie_root", with any hasher and codec, which hashes the keys too.
+pub fn sec_trie_root_with_codec<H, C, I>(hasher: &H, codec: &C, pairs: I) -> TrieResult<Vec<u8>>
+where
+    H: Hasher,
+    C: NodeCodec,
+    I: IntoIterator<Item = (Vec<u8>, Vec<u8>)>,
+{
+    let pairs = pairs
+        .into_iter()
+        .map(|(key, value)| (hasher.digest(&key), value))
+        .collect();
+    build_root(hasher, codec, pairs)
+}
+
+/// Returns the root of the trie that maps the RLP encoding of every index to
+/// its item, as the transaction and receipt tries of a block do, with keccak
+/// and RLP. No node is stored.
+pub fn ordered_trie_root<I>(items: I) -> TrieResult<Vec<u8>>
+where
+    I: IntoIterator<Item = Vec<u8>>,
+{
+    ordered_trie_root_with_codec(&HasherKeccak::new(), &RLPNodeCodec::new(), items)
+}
+
+/// Like "ordered_trie_root", with any hasher and codec.
+pub fn ordered_trie_root_with_codec<H, C, I>(hasher: &H, codec: &C, items: I) -> TrieResult<Vec<u8>>
+where
+    H: Hasher,
+    C: NodeCodec,
+    I: IntoIterator<Item = Vec<u8>>,
+{
+    let pairs = items
+        .into_iter()
+        .enumerate()
+        .map(|(i, item)| (rlp::encode(&(i as u64)), item))
+        .collect();
+    build_root(hasher, codec, pairs)
+}
+
+// Builds the trie of "pairs" bottom-up, dropping every node once encoded.
+fn build_root<H, C>(hasher: &H, codec: &C, pairs: BTreeMap<Vec<u8>, Vec<u8>>) -> TrieResult<Vec<u8>>
+where
+    H: Hasher,
+    C: NodeCodec,
+{
+    let mut builder = SortedBuilder::new(&NullDB, hasher, codec);
+    for (key, value) in pairs.into_iter() {
+        builder.push(key, value)?;
+    }
+    builder.finish()
+}
+
+// Discards every write, for tries whose nodes are only hashed.
+#[derive(Debug)]
+struct NullDB;
+
+impl DB for NullDB {
+    type Error = MemDBError;
+
+    fn get(&self, _key: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+        Ok(None)
+    }
+
+    fn contains(&self, _key: &[u8]) -> Result<bool, Self::Error> {
+        Ok(false)
+    }
+
+    fn insert(&self, _key: Vec<u8>, _value: Vec<u8>) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    fn insert_batch(&self, _keys: Vec<Vec<u8>>, _values: Vec<Vec<u8>>) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    fn remove(&self, _key: &[u8]) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    #[cfg(test)]
+    fn len(&self) -> Result<usize, Self::Error> {
+        Ok(0)
+    }
+
+    #[cfg(test)]
+    fn is_empty(&self) -> Result<bool, Self::Error> {
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use hasher::{Hasher, HasherKeccak};
+
+    use super::{ordered_trie_root, sec_trie_root, trie_root};
+    use crate::db::MemoryDB;
+    use crate::trie::{PatriciaTrie, Trie};
+
+    #[test]
+    fn test_ordered_trie_root() {
+        assert_eq!(
+            hex::encode(ordered_trie_root(vec![]).unwrap()),
+            "56e81f171bcc55a6ff8345e692c0f86e5b48e01b996cadc001622fb5e363b421"
+        );
+
+        let items: Vec<Vec<u8>> = (0..300u32).map(|i| vec![i as u8; 40]).collect();
+        let mut trie =
+            PatriciaTrie::new(Arc::new(MemoryDB::new(true)), Arc::new(HasherKeccak::new()));
+        for (i, item) in items.iter().enumerate() {
+            trie.insert(rlp::encode(&(i as u64)), item.clone()).unwrap();
+        }
+        assert_eq!(ordered_trie_root(items).unwrap(), trie.root().unwrap());
+    }
+
+    #[test]
+    fn test_trie_root() {
+        let hasher = HasherKeccak::new();
+        let mut pairs: Vec<(Vec<u8>, Vec<u8>)> =
+            (0..100u8).map(|i| (vec![i, i / 3], vec![i; 40])).collect();
+        pairs.push((vec![7, 2], b"later".to_vec()));
+        pairs.push((vec![8, 2], vec![]));
+        let mut trie =
+            PatriciaTrie::new(Arc::new(MemoryDB::new(true)), Arc::new(HasherKeccak::new()));
+        let mut secure =
+            PatriciaTrie::new(Arc::new(MemoryDB::new(true)), Arc::new(HasherKeccak::new()));
+        for (key, value) in pairs.iter() {
+            trie.insert(key, value.clone()).unwrap();
+            secure.insert(hasher.digest(key), value.clone()).unwrap();
+        }
+        assert_eq!(trie_root(pairs.clone()).unwrap(), trie.root().unwrap());
+        assert_eq!(sec_trie_root(pairs).unwrap(), secure.root().unwrap());
+    }
+}
@@ -0,0 +1,288 @@
+//! A sparse Merkle tree, as rollups use to commit to key/value state. Keys
+//! are hashed to a path of "Hasher::LENGTH" bytes, so the tree has a leaf for
+//! every possible key and a fixed depth. Subtrees without values hash to
+//! known defaults and are not stored, which also makes absent keys provable.
+
+use std::sync::Arc;
+
+use hasher::Hasher;
+
+use crate::db::DB;
+use crate::errors::TrieError;
+use crate::trie::TrieResult;
+
+/// "SparseMerkleTree" keeps its nodes in a "DB", under their hash: inner
+/// nodes as the hashes of their two children, leaves as the value, so that
+/// the hash of a leaf is the hash of its value. Writes go to the DB at once,
+/// nodes are never removed.
+#[derive(Debug)]
+pub struct SparseMerkleTree<D: DB, H: Hasher> {
+    db: Arc<D>,
+    hasher: Arc<H>,
+    root: Vec<u8>,
+    // The hash of an empty subtree by height, the leaves at 0.
+    defaults: Vec<Vec<u8>>,
+}
+
+/// The proof of the value of a key, or of its absence, see "verify_proof".
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SmtProof {
+    /// A bit per level, from the root down, set if the sibling there is not
+    /// the empty subtree.
+    pub bitmap: Vec<u8>,
+    /// The siblings whose bit is set, from the root down.
+    pub siblings: Vec<Vec<u8>>,
+}
+
+impl<D: DB, H: Hasher> SparseMerkleTree<D, H> {
+    pub fn new(db: Arc<D>, hasher: Arc<H>) -> Self {
+        let defaults = default_hashes(&*hasher);
+        SparseMerkleTree {
+            db,
+            hasher,
+            root: defaults[defaults.len() - 1].clone(),
+            defaults,
+        }
+    }
+
+    pub fn from(db: Arc<D>, hasher: Arc<H>, root: &[u8]) -> TrieResult<Self> {
+        let mut tree = SparseMerkleTree::new(db, hasher);
+        if tree.root[..] != *root
+            && !tree
+                .db
+                .contains(root)
+                .map_err(|e| TrieError::DB(e.to_string()))?
+        {
+            return Err(TrieError::InvalidStateRoot);
+        }
+        tree.root = root.to_vec();
+        Ok(tree)
+    }
+
+    /// Flushes the DB and returns the root hash.
+    pub fn root(&mut self) -> TrieResult<Vec<u8>> {
+        self.db.flush().map_err(|e| TrieError::DB(e.to_string()))?;
+        Ok(self.root.clone())
+    }
+
+    pub fn get(&self, key: &[u8]) -> TrieResult<Option<Vec<u8>>> {
+        let (_, leaf) = self.siblings(&self.hasher.digest(key))?;
+        if leaf == self.defaults[0] {
+            return Ok(None);
+        }
+        self.db
+            .get(&leaf)
+            .map_err(|e| TrieError::DB(e.to_string()))?
+            .map(Some)
+            .ok_or(TrieError::MissingNode { hash: leaf })
+    }
+
+    pub fn contains(&self, key: &[u8]) -> TrieResult<bool> {
+        Ok(self.get(key)?.is_some())
+    }
+
+    /// An empty value removes the key.
+    pub fn insert(&mut self, key: &[u8], value: Vec<u8>) -> TrieResult<()> {
+        if value.is_empty() {
+            self.remove(key)?;
+            return Ok(());
+        }
+        let leaf = self.hasher.digest(&value);
+        self.db
+            .insert(leaf.clone(), value)
+            .map_err(|e| TrieError::DB(e.to_string()))?;
+        self.update(&self.hasher.digest(key), leaf)
+    }
+
+    pub fn remove(&mut self, key: &[u8]) -> TrieResult<bool> {
+        let path = self.hasher.digest(key);
+        let (_, leaf) = self.siblings(&path)?;
+        if leaf == self.defaults[0] {
+            return Ok(false);
+        }
+        let empty = self.defaults[0].clone();
+        self.update(&path, empty)?;
+        Ok(true)
+    }
+
+    /// Returns the proof of the value of "key" at the current root, or of
+    /// its absence.
+    pub fn get_proof(&self, key: &[u8]) -> TrieResult<SmtProof> {
+        let (siblings, _) = self.siblings(&self.hasher.digest(key))?;
+        let depth = siblings.len();
+        let mut proof = SmtProof {
+            bitmap: vec![0; (depth + 7) / 8],
+            siblings: vec![],
+        };
+        for (i, sibling) in siblings.into_iter().enumerate() {
+            if sibling != self.defaults[depth - i - 1] {
+                proof.bitmap[i / 8] |= 0x80 >> (i % 8);
+                proof.siblings.push(sibling);
+            }
+        }
+        Ok(proof)
+    }
+
+    // Returns the siblings of the path, from the root down, and its leaf.
+    fn siblings(&self, path: &[u8]) -> TrieResult<(Vec<Vec<u8>>, Vec<u8>)> {
+        let depth = self.defaults.len() - 1;
+        let mut siblings = Vec::with_capacity(depth);
+        let mut node = self.root.clone();
+        for i in 0..depth {
+            let (left, right) = self.children(&node, depth - i)?;
+            if bit(path, i) {
+                siblings.push(left);
+                node = right;
+            } else {
+                siblings.push(right);
+                node = left;
+            }
+        }
+        Ok((siblings, node))
+    }
+
+    fn children(&self, hash: &[u8], height: usize) -> TrieResult<(Vec<u8>, Vec<u8>)> {
+        if self.defaults[height][..] == *hash {
+            let child = self.defaults[height - 1].clone();
+            return Ok((child.clone(), child));
+        }
+        let data = self
+            .db
+            .get(hash)
+            .map_err(|e| TrieError::DB(e.to_string()))?
+            .ok_or_else(|| TrieError::MissingNode {
+                hash: hash.to_vec(),
+            })?;
+        if data.len() != 2 * H::LENGTH {
+            return Err(TrieError::InvalidData);
+        }
+        let (left, right) = data.split_at(H::LENGTH);
+        Ok((left.to_vec(), right.to_vec()))
+    }
+
+    // Sets the leaf of "path" and writes the inner nodes above it.
+    fn update(&mut self, path: &[u8], leaf: Vec<u8>) -> TrieResult<()> {
+        let (siblings, _) = self.siblings(path)?;
+        let depth = siblings.len();
+        let (mut keys, mut values) = (vec![], vec![]);
+        let mut node = leaf;
+        for (i, sibling) in siblings.iter().enumerate().rev() {
+            let data = if bit(path, i) {
+                [&sibling[..], &node[..]].concat()
+            } else {
+                [&node[..], &sibling[..]].concat()
+            };
+            node = self.hasher.digest(&data);
+            if node != self.defaults[depth - i] {
+                keys.push(node.clone());
+                values.push(data);
+            }
+        }
+        self.db
+            .insert_batch(keys, values)
+            .map_err(|e| TrieError::DB(e.to_string()))?;
+        self.root = node;
+        Ok(())
+    }
+}
+
+/// Whether "proof" proves that "key" holds "value" at "root", or is absent
+/// if "value" is None.
+pub fn verify_proof<H: Hasher>(
+    hasher: &H,
+    root: &[u8],
+    key: &[u8],
+    value: Option<&[u8]>,
+    proof: &SmtProof,
+) -> bool {
+    let defaults = default_hashes(hasher);
+    let depth = defaults.len() - 1;
+    if proof.bitmap.len() != (depth + 7) / 8 {
+        return false;
+    }
+    let path = hasher.digest(key);
+    let mut siblings = proof.siblings.iter().rev();
+    let mut node = match value {
+        Some(value) if !value.is_empty() => hasher.digest(value),
+        _ => defaults[0].clone(),
+    };
+    for i in (0..depth).rev() {
+        let sibling = if bit(&proof.bitmap, i) {
+            match siblings.next() {
+                Some(sibling) => sibling,
+                None => return false,
+            }
+        } else {
+            &defaults[depth - i - 1]
+        };
+        node = if bit(&path, i) {
+            hasher.digest(&[&sibling[..], &node[..]].concat())
+        } else {
+            hasher.digest(&[&node[..], &sibling[..]].concat())
+        };
+    }
+    siblings.next().is_none() && node[..] == *root
+}
+
+fn default_hashes<H: Hasher>(hasher: &H) -> Vec<Vec<u8>> {
+    let mut defaults = vec![vec![0; H::LENGTH]];
+    for height in 1..=H::LENGTH * 8 {
+        let child = &defaults[height - 1];
+        let hash = hasher.digest(&[&child[..], &child[..]].concat());
+        defaults.push(hash);
+    }
+    defaults
+}
+
+// The bit "i" of "bytes", the most significant first.
+fn bit(bytes: &[u8], i: usize) -> bool {
+    bytes[i / 8] & (0x80 >> (i % 8)) != 0
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use hasher::HasherKeccak;
+
+    use super::{verify_proof, SparseMerkleTree};
+    use crate::db::MemoryDB;
+
+    #[test]
+    fn test_sparse_merkle_tree() {
+        let memdb = Arc::new(MemoryDB::new(true));
+        let hasher = Arc::new(HasherKeccak::new());
+        let mut tree = SparseMerkleTree::new(Arc::clone(&memdb), Arc::clone(&hasher));
+        let empty = tree.root().unwrap();
+        for i in 0..50u8 {
+            tree.insert(&[i], vec![i; 10]).unwrap();
+        }
+        let root = tree.root().unwrap();
+        assert_ne!(root, empty);
+        assert_eq!(tree.get(&[7]).unwrap(), Some(vec![7; 10]));
+        assert_eq!(tree.get(&[70]).unwrap(), None);
+
+        // The root does not depend on the order of the inserts.
+        let mut other = SparseMerkleTree::new(Arc::new(MemoryDB::new(true)), Arc::clone(&hasher));
+        for i in (0..50u8).rev() {
+            other.insert(&[i], vec![i; 10]).unwrap();
+        }
+        other.insert(&[70], vec![1]).unwrap();
+        assert!(other.remove(&[70]).unwrap());
+        assert!(!other.remove(&[70]).unwrap());
+        assert_eq!(other.root().unwrap(), root);
+
+        let proof = tree.get_proof(&[7]).unwrap();
+        assert!(verify_proof(&*hasher, &root, &[7], Some(&[7; 10]), &proof));
+        assert!(!verify_proof(&*hasher, &root, &[7], Some(&[8; 10]), &proof));
+        assert!(!verify_proof(&*hasher, &root, &[7], None, &proof));
+        let proof = tree.get_proof(&[70]).unwrap();
+        assert!(verify_proof(&*hasher, &root, &[70], None, &proof));
+        assert!(!verify_proof(&*hasher, &root, &[70], Some(&[1]), &proof));
+
+        tree.insert(&[7], vec![]).unwrap();
+        let tree = SparseMerkleTree::from(memdb, hasher, &root).unwrap();
+        assert_eq!(tree.get(&[7]).unwrap(), Some(vec![7; 10]));
+        assert_eq!(tree.get(&[49]).unwrap(), Some(vec![49; 10]));
+    }
+}
@@ -0,0 +1,371 @@
+use std::io::{Read, Write};
+
+use hashbrown::HashSet;
+use hasher::Hasher;
+
+use crate::codec::NodeCodec;
+use crate::db::DB;
+use crate::errors::TrieError;
+use crate::gc::child_hashes;
+use crate::trie::TrieResult;
+
+// Identifies a snapshot file and the version of its format.
+const SNAPSHOT_MAGIC: &[u8] = b"cita-trie-snapshot-1";
+
+// Frames longer than this are taken as corruption rather than allocated.
+const MAX_FRAME_LEN: usize = 1 << 26;
+
+// Imported nodes are written in batches of this size.
+const IMPORT_BATCH: usize = 1024;
+
+/// Writes every node of "root" in "db" to "writer", parents before their
+/// children, and returns the number of nodes written. A subtrie referenced
+/// twice is written twice. The format is the magic, the root, a frame with
+/// the hash and one with the data of every node, and an empty frame. A frame
+/// is the length as 4 bytes little endian and the bytes.
+pub fn export_snapshot<D, C, W>(db: &D, codec: &C, root: &[u8], mut writer: W) -> TrieResult<u64>
+where
+    D: DB,
+    C: NodeCodec,
+    W: Write,
+{
+    writer.write_all(SNAPSHOT_MAGIC)?;
+    write_frame(&mut writer, root)?;
+    let count = walk_nodes(db, codec, &[root.to_vec()], |hash, data| {
+        write_frame(&mut writer, hash)?;
+        write_frame(&mut writer, data)?;
+        Ok(true)
+    })?;
+    write_frame(&mut writer, &[])?;
+    writer.flush()?;
+    Ok(count)
+}
+
+/// Writes the nodes of a snapshot from "reader" to "db" and returns its root.
+/// Every node must hash to its key and be referenced by a node before it, and
+/// every referenced node must be in the snapshot, so the nodes are those of
+/// the root. Nodes written before an error are left for "find_orphans".
+pub fn import_snapshot<R, D, H, C>(
+    mut reader: R,
+    db: &D,
+    hasher: &H,
+    codec: &C,
+) -> TrieResult<Vec<u8>>
+where
+    R: Read,
+    D: DB,
+    H: Hasher,
+    C: NodeCodec,
+{
+    let mut magic = vec![0; SNAPSHOT_MAGIC.len()];
+    reader.read_exact(&mut magic)?;
+    if magic != SNAPSHOT_MAGIC {
+        return Err(TrieError::InvalidData);
+    }
+    let root = read_frame(&mut reader)?;
+    let mut import = NodeImport::new(db, hasher, codec, &root);
+    loop {
+        let hash = read_frame(&mut reader)?;
+        if hash.is_empty() {
+            break;
+        }
+        if import.add(read_frame(&mut reader)?)? != hash {
+            return Err(TrieError::InvalidData);
+        }
+    }
+    import.finish()?;
+    Ok(root)
+}
+
+/// Writes the nodes of the trie at "root" in "db" to "writer" as RLP strings,
+/// the root, then every node, parents before their children. Returns the
+/// number of nodes written. See "PatriciaTrie::dump".
+pub(crate) fn dump_nodes<D, C, W>(db: &D, codec: &C, root: &[u8], mut writer: W) -> TrieResult<u64>
+where
+    D: DB,
+    C: NodeCodec,
+    W: Write,
+{
+    writer.write_all(&rlp::encode(&root))?;
+    let count = walk_nodes(db, codec, &[root.to_vec()], |_, data| {
+        writer.write_all(&rlp::encode(&data))?;
+        Ok(true)
+    })?;
+    writer.flush()?;
+    Ok(count)
+}
+
+/// Writes the nodes of a stream of "dump_nodes" from "reader" to "db" and
+/// returns its root, checked as by "import_snapshot".
+pub(crate) fn restore_nodes<R, D, H, C>(
+    mut reader: R,
+    db: &D,
+    hasher: &H,
+    codec: &C,
+) -> TrieResult<Vec<u8>>
+where
+    R: Read,
+    D: DB,
+    H: Hasher,
+    C: NodeCodec,
+{
+    let root = read_rlp_string(&mut reader)?.ok_or(TrieError::InvalidData)?;
+    let mut import = NodeImport::new(db, hasher, codec, &root);
+    while let Some(data) = read_rlp_string(&mut reader)? {
+        import.add(data)?;
+    }
+    import.finish()?;
+    Ok(root)
+}
+
+/// Calls "visit" with the hash and data of the nodes of "roots", parents
+/// before their children, and returns the number of nodes it walked into. If
+/// "visit" returns false, the children of the node are skipped. The tries are
+/// walked depth first, reading the children of a node in one batch, so only
+/// the siblings along the current path are held. A subtrie referenced twice
+/// is walked twice, unless "visit" skips it. A missing node is "MissingNode".
+pub(crate) fn walk_nodes<D, C, F>(
+    db: &D,
+    codec: &C,
+    roots: &[Vec<u8>],
+    mut visit: F,
+) -> TrieResult<u64>
+where
+    D: DB,
+    C: NodeCodec,
+    F: FnMut(&[u8], &[u8]) -> TrieResult<bool>,
+{
+    let mut stack = load_nodes(db, roots.iter().rev().cloned().collect())?;
+    let mut children = vec![];
+    let mut count = 0;
+    while let Some((hash, data)) = stack.pop() {
+        if !visit(&hash, &data)? {
+            continue;
+        }
+        count += 1;
+        child_hashes(codec, &data, &mut children)?;
+        children.reverse();
+        stack.extend(load_nodes(db, children.split_off(0))?);
+    }
+    Ok(count)
+}
+
+// Reads the nodes of "hashes" in one batch.
+fn load_nodes<D: DB>(db: &D, hashes: Vec<Vec<u8>>) -> TrieResult<Vec<(Vec<u8>, Vec<u8>)>> {
+    if hashes.is_empty() {
+        return Ok(vec![]);
+    }
+    let values = db
+        .get_batch(&hashes)
+        .map_err(|e| TrieError::DB(e.to_string()))?;
+    hashes
+        .into_iter()
+        .zip(values)
+        .map(|(hash, value)| match value {
+            Some(data) => Ok((hash, data)),
+            None => Err(TrieError::MissingNode { hash }),
+        })
+        .collect()
+}
+
+// Writes imported nodes in batches, checking that every node is referenced by
+// a node before it and, once finished, that every referenced node came, so
+// the nodes are those of the root. Nodes written before an error are left
+// for "find_orphans".
+struct NodeImport<'a, D, H, C> {
+    db: &'a D,
+    hasher: &'a H,
+    codec: &'a C,
+    // The nodes that were referenced, but are not imported yet.
+    expected: HashSet<Vec<u8>>,
+    imported: HashSet<Vec<u8>>,
+    keys: Vec<Vec<u8>>,
+    values: Vec<Vec<u8>>,
+}
+
+impl<'a, D: DB, H: Hasher, C: NodeCodec> NodeImport<'a, D, H, C> {
+    fn new(db: &'a D, hasher: &'a H, codec: &'a C, root: &[u8]) -> Self {
+        let mut expected = HashSet::new();
+        expected.insert(root.to_vec());
+        NodeImport {
+            db,
+            hasher,
+            codec,
+            expected,
+            imported: HashSet::new(),
+            keys: vec![],
+            values: vec![],
+        }
+    }
+
+    // Returns the hash of "data", which must be expected. A subtrie that is
+    // referenced twice comes twice, its nodes are only written once.
+    fn add(&mut self, data: Vec<u8>) -> TrieResult<Vec<u8>> {
+        let hash = self.hasher.digest(&data);
+        if self.imported.contains(&hash) {
+            return Ok(hash);
+        }
+        if !self.expected.remove(&hash) {
+            return Err(TrieError::InvalidData);
+        }
+        let mut children = vec![];
+        child_hashes(self.codec, &data, &mut children)?;
+        for child in children.into_iter() {
+            if !self.imported.contains(&child) {
+                self.expected.insert(child);
+            }
+        }
+        self.imported.insert(hash.clone());
+
+        self.keys.push(hash.clone());
+        self.values.push(data);
+        if self.keys.len() >= IMPORT_BATCH {
+            self.db
+                .insert_batch(self.keys.split_off(0), self.values.split_off(0))
+                .map_err(|e| TrieError::DB(e.to_string()))?;
+        }
+        Ok(hash)
+    }
+
+    fn finish(self) -> TrieResult<()> {
+        if !self.expected.is_empty() {
+            return Err(TrieError::InvalidData);
+        }
+        self.db
+            .insert_batch(self.keys, self.values)
+            .map_err(|e| TrieError::DB(e.to_string()))?;
+        self.db.flush().map_err(|e| TrieError::DB(e.to_string()))
+    }
+}
+
+fn write_frame<W: Write>(writer: &mut W, bytes: &[u8]) -> TrieResult<()> {
+    writer.write_all(&(bytes.len() as u32).to_le_bytes())?;
+    writer.write_all(bytes)?;
+    Ok(())
+}
+
+fn read_frame<R: Read>(reader: &mut R) -> TrieResult<Vec<u8>> {
+    let mut len = [0u8; 4];
+    reader.read_exact(&mut len)?;
+    let len = u32::from_le_bytes(len) as usize;
+    if len > MAX_FRAME_LEN {
+        return Err(TrieError::InvalidData);
+    }
+    let mut bytes = vec![0; len];
+    reader.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+// Reads an RLP string, or None at the end of "reader".
+fn read_rlp_string<R: Read>(reader: &mut R) -> TrieResult<Option<Vec<u8>>> {
+    let mut prefix = [0u8; 1];
+    if reader.read(&mut prefix)? == 0 {
+        return Ok(None);
+    }
+    let len = match prefix[0] {
+        byte @ 0..=0x7f => return Ok(Some(vec![byte])),
+        byte @ 0x80..=0xb7 => (byte - 0x80) as usize,
+        byte @ 0xb8..=0xbf => {
+            let mut len = [0u8; 8];
+            reader.read_exact(&mut len[8 - (byte - 0xb7) as usize..])?;
+            u64::from_be_bytes(len) as usize
+        }
+        // Lists are never written.
+        _ => return Err(TrieError::InvalidData),
+    };
+    if len > MAX_FRAME_LEN {
+        return Err(TrieError::InvalidData);
+    }
+    let mut bytes = vec![0; len];
+    reader.read_exact(&mut bytes)?;
+    Ok(Some(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use hasher::{Hasher, HasherKeccak};
+
+    use super::{export_snapshot, import_snapshot};
+    use crate::codec::RLPNodeCodec;
+    use crate::db::{MemoryDB, DB};
+    use crate::errors::TrieError;
+    use crate::trie::{PatriciaTrie, Trie};
+
+    #[test]
+    fn test_snapshot_roundtrip() {
+        let hasher = Arc::new(HasherKeccak::new());
+        let memdb = Arc::new(MemoryDB::new(true));
+        let mut trie = PatriciaTrie::new(Arc::clone(&memdb), Arc::clone(&hasher));
+        for i in 0..500u32 {
+            trie.insert(i.to_le_bytes().to_vec(), vec![i as u8; 40])
+                .unwrap();
+        }
+        let root = trie.root().unwrap();
+
+        // The leaves of i and i + 256 are alike, so they are written twice,
+        // but imported once.
+        let mut file = vec![];
+        let count = export_snapshot(&*memdb, &RLPNodeCodec, &root, &mut file).unwrap();
+        assert!(count as usize > memdb.len().unwrap());
+
+        let other = Arc::new(MemoryDB::new(true));
+        let imported = import_snapshot(&file[..], &*other, &*hasher, &RLPNodeCodec).unwrap();
+        assert_eq!(imported, root);
+        assert_eq!(other.len().unwrap(), memdb.len().unwrap());
+        let copy = PatriciaTrie::from(Arc::clone(&other), Arc::clone(&hasher), &root).unwrap();
+        assert!(copy.iter().eq(trie.iter()));
+
+        // A flipped byte, and a snapshot cut short.
+        let mut corrupt = file.clone();
+        let last = corrupt.len() - 10;
+        corrupt[last] ^= 1;
+        let empty = MemoryDB::new(true);
+        assert!(import_snapshot(&corrupt[..], &empty, &*hasher, &RLPNodeCodec).is_err());
+        let cut = &file[..file.len() / 2];
+        assert!(import_snapshot(cut, &empty, &*hasher, &RLPNodeCodec).is_err());
+
+        let missing = hasher.digest(&trie.get_proof(&7u32.to_le_bytes()).unwrap()[1]);
+        memdb.remove(&missing).unwrap();
+        match export_snapshot(&*memdb, &RLPNodeCodec, &root, vec![]) {
+            Err(TrieError::MissingNode { hash }) => assert_eq!(hash, missing),
+            _ => panic!("the missing node was not reported"),
+        }
+    }
+
+    #[test]
+    fn test_dump_restore() {
+        let hasher = Arc::new(HasherKeccak::new());
+        let memdb = Arc::new(MemoryDB::new(true));
+        let mut trie = PatriciaTrie::new(Arc::clone(&memdb), Arc::clone(&hasher));
+        for i in 0..300u32 {
+            trie.insert(i.to_be_bytes(), vec![i as u8; (i % 80) as usize + 1])
+                .unwrap();
+        }
+        let root = trie.root().unwrap();
+
+        let mut stream = vec![];
+        let count = trie.dump(&root, &mut stream).unwrap();
+        assert_eq!(count as usize, memdb.len().unwrap());
+
+        let other = PatriciaTrie::new(Arc::new(MemoryDB::new(true)), Arc::clone(&hasher));
+        let copy = other.restore(&stream[..]).unwrap();
+        assert!(copy.iter().eq(trie.iter()));
+
+        // A node cut short, a node left out, and a different root.
+        assert!(other.restore(&stream[..stream.len() - 1]).is_err());
+        let first = 1 + root.len();
+        let info = rlp::Rlp::new(&stream[first..]).payload_info().unwrap();
+        let len = info.header_len + info.value_len;
+        let mut rest = stream[..first].to_vec();
+        rest.extend(&stream[first + len..]);
+        assert!(other.restore(&rest[..]).is_err());
+        let mut wrong = stream.clone();
+        wrong[1] ^= 1;
+        match other.restore(&wrong[..]) {
+            Err(TrieError::InvalidData) => {}
+            _ => panic!("the root was not checked"),
+        }
+    }
+}
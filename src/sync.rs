@@ -0,0 +1,206 @@
+use std::collections::VecDeque;
+use std::sync::Arc;
+
+use hashbrown::HashSet;
+use hasher::Hasher;
+
+use crate::codec::NodeCodec;
+use crate::db::DB;
+use crate::errors::TrieError;
+use crate::gc::child_hashes;
+use crate::heal::find_missing;
+use crate::trie::TrieResult;
+
+/// How far a "SyncScheduler" got.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SyncProgress {
+    /// The nodes written since the scheduler was created.
+    pub synced_nodes: usize,
+    /// Their encoded size.
+    pub synced_bytes: usize,
+    /// The missing nodes not requested yet.
+    pub pending: usize,
+    /// The requested nodes not received yet.
+    pub in_flight: usize,
+}
+
+/// "SyncScheduler" downloads the trie at a target root into a DB. It hands
+/// out batches of missing hashes to request from peers with "next_request",
+/// writes the nodes they return with "process" once checked by hash, and
+/// schedules the children those nodes lead to. Requests that fail or time
+/// out go back to the queue with "reschedule".
+#[derive(Debug)]
+pub struct SyncScheduler<D: DB, H: Hasher, C: NodeCodec> {
+    db: Arc<D>,
+    hasher: Arc<H>,
+    codec: Arc<C>,
+    root: Vec<u8>,
+    queue: VecDeque<Vec<u8>>,
+    queued: HashSet<Vec<u8>>,
+    in_flight: HashSet<Vec<u8>>,
+    synced_nodes: usize,
+    synced_bytes: usize,
+}
+
+impl<D: DB, H: Hasher, C: NodeCodec> SyncScheduler<D, H, C> {
+    /// Schedules the nodes of the trie at "root" that "db" lacks, the nodes
+    /// it already holds are walked but not fetched again.
+    pub fn new(db: Arc<D>, hasher: Arc<H>, codec: Arc<C>, root: &[u8]) -> TrieResult<Self> {
+        let mut scheduler = SyncScheduler {
+            db,
+            hasher,
+            codec,
+            root: root.to_vec(),
+            queue: VecDeque::new(),
+            queued: HashSet::new(),
+            in_flight: HashSet::new(),
+            synced_nodes: 0,
+            synced_bytes: 0,
+        };
+        let missing = find_missing(&*scheduler.db, &*scheduler.codec, vec![root.to_vec()])?;
+        scheduler.schedule(missing);
+        Ok(scheduler)
+    }
+
+    pub fn root(&self) -> &[u8] {
+        &self.root
+    }
+
+    /// Returns up to "max" hashes to request, oldest first, and marks them
+    /// in flight.
+    pub fn next_request(&mut self, max: usize) -> Vec<Vec<u8>> {
+        let len = max.min(self.queue.len());
+        let hashes: Vec<Vec<u8>> = self.queue.drain(..len).collect();
+        for hash in hashes.iter() {
+            self.queued.remove(hash);
+            self.in_flight.insert(hash.clone());
+        }
+        hashes
+    }
+
+    /// Writes those of "nodes" that are in flight, checked by their hash,
+    /// schedules their missing children and returns how many were written.
+    /// Other nodes are ignored, requested nodes not in "nodes" stay in
+    /// flight.
+    pub fn process(&mut self, nodes: Vec<Vec<u8>>) -> TrieResult<usize> {
+        let mut keys = vec![];
+        let mut values = vec![];
+        let mut children = vec![];
+        for node in nodes.into_iter() {
+            let hash = self.hasher.digest(&node);
+            if !self.in_flight.contains(&hash) {
+                continue;
+            }
+            child_hashes(&*self.codec, &node, &mut children)?;
+            self.in_flight.remove(&hash);
+            self.synced_bytes += node.len();
+            keys.push(hash);
+            values.push(node);
+        }
+        if keys.is_empty() {
+            return Ok(0);
+        }
+
+        let written = keys.len();
+        self.db
+            .insert_batch(keys, values)
+            .map_err(|e| TrieError::DB(e.to_string()))?;
+        self.db.flush().map_err(|e| TrieError::DB(e.to_string()))?;
+        self.synced_nodes += written;
+        let missing = find_missing(&*self.db, &*self.codec, children)?;
+        self.schedule(missing);
+        Ok(written)
+    }
+
+    /// Puts those of "hashes" still in flight back at the front of the
+    /// queue, e.g. after a peer failed to return them.
+    pub fn reschedule(&mut self, hashes: &[Vec<u8>]) {
+        for hash in hashes.iter().rev() {
+            if self.in_flight.remove(hash) {
+                self.queued.insert(hash.clone());
+                self.queue.push_front(hash.clone());
+            }
+        }
+    }
+
+    pub fn progress(&self) -> SyncProgress {
+        SyncProgress {
+            synced_nodes: self.synced_nodes,
+            synced_bytes: self.synced_bytes,
+            pending: self.queue.len(),
+            in_flight: self.in_flight.len(),
+        }
+    }
+
+    /// Whether every node of the trie is in the DB.
+    pub fn is_done(&self) -> bool {
+        self.queue.is_empty() && self.in_flight.is_empty()
+    }
+
+    // Queues the hashes that are neither queued nor in flight, as subtries
+    // may be shared.
+    fn schedule(&mut self, hashes: Vec<Vec<u8>>) {
+        for hash in hashes.into_iter() {
+            if !self.in_flight.contains(&hash) && self.queued.insert(hash.clone()) {
+                self.queue.push_back(hash);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use hasher::HasherKeccak;
+
+    use super::SyncScheduler;
+    use crate::codec::RLPNodeCodec;
+    use crate::db::{MemoryDB, DB};
+    use crate::trie::{PatriciaTrie, Trie};
+
+    #[test]
+    fn test_sync_scheduler() {
+        let source = Arc::new(MemoryDB::new(true));
+        let hasher = Arc::new(HasherKeccak::new());
+        let mut trie = PatriciaTrie::new(Arc::clone(&source), Arc::clone(&hasher));
+        for i in 0..=255u8 {
+            trie.insert(vec![i, i / 7], vec![i; 40]).unwrap();
+        }
+        let root = trie.root().unwrap();
+
+        let memdb = Arc::new(MemoryDB::new(true));
+        let codec = Arc::new(RLPNodeCodec::new());
+        let mut scheduler = SyncScheduler::new(Arc::clone(&memdb), hasher, codec, &root).unwrap();
+        assert_eq!(scheduler.progress().pending, 1);
+
+        // A request that fails is asked again.
+        let hashes = scheduler.next_request(16);
+        assert_eq!(hashes, vec![root.clone()]);
+        scheduler.reschedule(&hashes);
+        assert_eq!(scheduler.progress().in_flight, 0);
+
+        while !scheduler.is_done() {
+            let hashes = scheduler.next_request(16);
+            assert!(hashes.len() <= 16);
+            let mut nodes: Vec<Vec<u8>> = source
+                .get_batch(&hashes)
+                .unwrap()
+                .into_iter()
+                .map(Option::unwrap)
+                .collect();
+            // The last node is lost and a bogus one is sent instead.
+            let lost = if hashes.len() > 1 { nodes.pop() } else { None };
+            nodes.push(b"bogus".to_vec());
+            let written = scheduler.process(nodes).unwrap();
+            assert_eq!(written, hashes.len() - lost.map_or(0, |_| 1));
+            scheduler.reschedule(&hashes);
+        }
+
+        let progress = scheduler.progress();
+        assert_eq!(progress.synced_nodes, source.len().unwrap());
+        assert_eq!(memdb.len().unwrap(), source.len().unwrap());
+        let trie = PatriciaTrie::from(memdb, Arc::new(HasherKeccak::new()), &root).unwrap();
+        assert_eq!(trie.iter().count(), 256);
+    }
+}
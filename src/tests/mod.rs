@@ -4,9 +4,9 @@ mod trie_tests {
     use rand::Rng;
     use std::sync::Arc;
 
-    use hasher::HasherKeccak;
+    use hasher::{Hasher, HasherKeccak};
 
-    use crate::db::MemoryDB;
+    use crate::db::{MemoryDB, DB};
     use crate::trie::{PatriciaTrie, Trie};
 
     fn assert_root(data: Vec<(&[u8], &[u8])>, hash: &str) {
@@ -665,4 +665,177 @@ mod trie_tests {
             .unwrap();
         assert_eq!(value, Some(b"v".to_vec()));
     }
+
+    // Every public API must return an error, never panic, on malformed nodes.
+    fn assert_no_panic(data: Vec<u8>) {
+        let memdb = Arc::new(MemoryDB::new(true));
+        let hasher = Arc::new(HasherKeccak::new());
+        let root = hasher.digest(&data);
+        memdb.insert(root.clone(), data).unwrap();
+
+        if let Ok(mut trie) = PatriciaTrie::from(Arc::clone(&memdb), hasher, &root) {
+            let _ = trie.get(b"test");
+            let _ = trie.contains(b"");
+            let _ = trie.get_proof(b"test");
+            let _ = trie.iter().count();
+            let _ = trie.insert(b"test".to_vec(), b"test".to_vec());
+            let _ = trie.remove(b"test");
+            let _ = trie.root();
+        }
+    }
+
+    #[test]
+    fn test_malformed_nodes() {
+        let cases = vec![
+            // empty list
+            "c0",
+            // leaf with an empty key
+            "c28080",
+            // unknown compact flag
+            "c4824000820102",
+            // extension with an empty prefix
+            "c3820000c0",
+            // root that is a bare hash
+            "a0aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa",
+            // branch with a truncated child list
+            "d18080808080808080808080808080808080",
+            // not rlp
+            "ffffffff",
+        ];
+        for case in cases.into_iter() {
+            assert_no_panic(Vec::from_hex(case).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_random_nodes() {
+        let mut rng = rand::thread_rng();
+        for _ in 0..1000 {
+            let data: Vec<u8> = (0..rng.gen_range(0, 80))
+                .map(|_| rand::random::<u8>())
+                .collect();
+            assert_no_panic(data);
+        }
+    }
+
+    #[test]
+    fn test_malformed_proofs() {
+        let memdb = Arc::new(MemoryDB::new(true));
+        let mut trie = PatriciaTrie::new(Arc::clone(&memdb), Arc::new(HasherKeccak::new()));
+        trie.insert(b"doe".to_vec(), b"reindeer".to_vec()).unwrap();
+        trie.insert(b"dog".to_vec(), b"puppy".to_vec()).unwrap();
+        let root = trie.root().unwrap();
+
+        let mut rng = rand::thread_rng();
+        for _ in 0..100 {
+            let mut proof = trie.get_proof(b"doe").unwrap();
+            let i = rng.gen_range(0, proof.len());
+            let j = rng.gen_range(0, proof[i].len());
+            proof[i][j] = rand::random::<u8>();
+            let _ = trie.verify_proof(root.clone(), b"doe", proof);
+        }
+        assert!(trie.verify_proof(vec![], b"doe", vec![vec![]]).is_err());
+    }
+
+    // Keccak truncated or extended to "LENGTH" bytes.
+    struct HasherResized<L>(std::marker::PhantomData<L>);
+
+    trait Length: Send + Sync {
+        const LENGTH: usize;
+    }
+
+    struct L20;
+    struct L64;
+
+    impl Length for L20 {
+        const LENGTH: usize = 20;
+    }
+
+    impl Length for L64 {
+        const LENGTH: usize = 64;
+    }
+
+    impl<L: Length> Hasher for HasherResized<L> {
+        const LENGTH: usize = L::LENGTH;
+
+        fn digest(&self, data: &[u8]) -> Vec<u8> {
+            let mut hash = HasherKeccak::new().digest(data);
+            while hash.len() < Self::LENGTH {
+                let next = HasherKeccak::new().digest(&hash);
+                hash.extend_from_slice(&next);
+            }
+            hash.truncate(Self::LENGTH);
+            hash
+        }
+    }
+
+    fn assert_hash_length<L: Length>() {
+        let hasher = Arc::new(HasherResized::<L>(std::marker::PhantomData));
+        let memdb = Arc::new(MemoryDB::new(true));
+        let mut trie = PatriciaTrie::new(Arc::clone(&memdb), Arc::clone(&hasher));
+        let mut rng = rand::thread_rng();
+        let mut keys = vec![];
+        for _ in 0..200 {
+            let random_bytes: Vec<u8> = (0..rng.gen_range(1, 80))
+                .map(|_| rand::random::<u8>())
+                .collect();
+            if keys.contains(&random_bytes) {
+                continue;
+            }
+            trie.insert(random_bytes.clone(), random_bytes.clone())
+                .unwrap();
+            keys.push(random_bytes);
+        }
+        let root = trie.root().unwrap();
+        assert_eq!(root.len(), L::LENGTH);
+
+        let mut trie = PatriciaTrie::from(Arc::clone(&memdb), Arc::clone(&hasher), &root).unwrap();
+        for k in keys.iter() {
+            assert_eq!(trie.get(k).unwrap(), Some(k.clone()));
+            let proof = trie.get_proof(k).unwrap();
+            let value = trie.verify_proof(root.clone(), k, proof).unwrap();
+            assert_eq!(value, Some(k.clone()));
+        }
+        assert_eq!(trie.iter().count(), keys.len());
+
+        for k in keys.iter() {
+            trie.remove(k).unwrap();
+        }
+        let empty_root = trie.root().unwrap();
+        assert_eq!(empty_root, hasher.digest(&rlp::NULL_RLP));
+    }
+
+    #[test]
+    fn test_hash_length_20() {
+        assert_hash_length::<L20>();
+    }
+
+    #[test]
+    fn test_hash_length_64() {
+        assert_hash_length::<L64>();
+    }
+
+    #[test]
+    fn test_remove_branch_value_then_child() {
+        let memdb = Arc::new(MemoryDB::new(true));
+        let mut trie = PatriciaTrie::new(Arc::clone(&memdb), Arc::new(HasherKeccak::new()));
+        trie.insert(vec![0x12], vec![0x12]).unwrap();
+        trie.insert(vec![0x12, 0x34], vec![0x12, 0x34]).unwrap();
+        trie.insert(vec![0x12, 0x56], vec![0x12, 0x56]).unwrap();
+
+        // Leaves a branch with a value and one child, then removes the value.
+        trie.remove(&[0x12, 0x56]).unwrap();
+        assert_eq!(trie.remove(&[0x12]).unwrap(), true);
+        assert_eq!(trie.remove(&[0x12]).unwrap(), false);
+
+        let mut expected = PatriciaTrie::new(memdb, Arc::new(HasherKeccak::new()));
+        expected.insert(vec![0x12, 0x34], vec![0x12, 0x34]).unwrap();
+        assert_eq!(trie.root().unwrap(), expected.root().unwrap());
+
+        trie.remove(&[0x12, 0x34]).unwrap();
+        assert_eq!(
+            trie.root().unwrap(),
+            HasherKeccak::new().digest(&rlp::NULL_RLP)
+        );
+    }
 }
@@ -1,30 +1,48 @@
 use std::cell::RefCell;
-use std::rc::Rc;
+use std::collections::BTreeMap;
+use std::io::{Read, Write};
+use std::mem;
+use std::ops::Deref;
+use std::path::Path;
 use std::sync::Arc;
 
 use hashbrown::{HashMap, HashSet};
 use hasher::Hasher;
-use rlp::{Prototype, Rlp, RlpStream};
-
-use crate::db::{MemoryDB, DB};
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
+
+use crate::bloom::key_filter;
+use crate::builder::SortedBuilder;
+use crate::cache::SharedNodeCache;
+use crate::codec::{ChildReference, NodeCodec, NodeData, RLPNodeCodec, ShortItem};
+use crate::db::{BloomFilter, MemoryDB, DB};
 use crate::errors::TrieError;
+use crate::journal::{ChangeSet, Journal};
+use crate::light::lookup;
+use crate::lru::LRUCache;
 use crate::nibbles::Nibbles;
-use crate::node::{empty_children, BranchNode, Node};
+use crate::node::{empty_children, into_vec, Node, NodeArena, NodeId, Value};
+use crate::refcount::ref_count_writes;
+use crate::rewind::{reverse_diff, reverse_diff_key, rewind_nodes};
+use crate::snapshot::{dump_nodes, restore_nodes};
+use crate::window::{PruningWindow, PRUNING_WINDOW_KEY};
 
 pub type TrieResult<T> = Result<T, TrieError>;
 
+/// Keys are anything that can be viewed as bytes, see "TrieKey" to encode
+/// numbers and hashes.
 pub trait Trie<D: DB, H: Hasher> {
     /// Returns the value for key stored in the trie.
-    fn get(&self, key: &[u8]) -> TrieResult<Option<Vec<u8>>>;
+    fn get<K: AsRef<[u8]>>(&self, key: K) -> TrieResult<Option<Vec<u8>>>;
 
     /// Checks that the key is present in the trie
-    fn contains(&self, key: &[u8]) -> TrieResult<bool>;
+    fn contains<K: AsRef<[u8]>>(&self, key: K) -> TrieResult<bool>;
 
     /// Inserts value into trie and modifies it if it exists
-    fn insert(&mut self, key: Vec<u8>, value: Vec<u8>) -> TrieResult<()>;
+    fn insert<K: AsRef<[u8]>>(&mut self, key: K, value: Vec<u8>) -> TrieResult<()>;
 
     /// Removes any existing value for key from the trie.
-    fn remove(&mut self, key: &[u8]) -> TrieResult<bool>;
+    fn remove<K: AsRef<[u8]>>(&mut self, key: K) -> TrieResult<bool>;
 
     /// Saves all the nodes in the db, clears the cache data, recalculates the root.
     /// Returns the root hash of the trie.
@@ -37,35 +55,187 @@ pub trait Trie<D: DB, H: Hasher> {
     /// If the trie does not contain a value for key, the returned proof contains all
     /// nodes of the longest existing prefix of the key (at least the root node), ending
     /// with the node that proves the absence of the key.
-    fn get_proof(&self, key: &[u8]) -> TrieResult<Vec<Vec<u8>>>;
+    fn get_proof<K: AsRef<[u8]>>(&self, key: K) -> TrieResult<Vec<Vec<u8>>>;
 
     /// return value if key exists, None if key not exist, Error if proof is wrong
-    fn verify_proof(
+    fn verify_proof<K: AsRef<[u8]>>(
         &self,
         root_hash: Vec<u8>,
-        key: &[u8],
+        key: K,
         proof: Vec<Vec<u8>>,
     ) -> TrieResult<Option<Vec<u8>>>;
 }
 
 #[derive(Debug)]
-pub struct PatriciaTrie<D, H>
+pub struct PatriciaTrie<D, H, C = RLPNodeCodec>
 where
     D: DB,
     H: Hasher,
+    C: NodeCodec,
 {
     root: Node,
     root_hash: Vec<u8>,
+    // Owns the nodes of the in-memory tree. Nodes that are only read, e.g. by
+    // "get", are freed again once the read is done.
+    arena: RefCell<NodeArena>,
 
     db: Arc<D>,
     hasher: Arc<H>,
+    codec: Arc<C>,
 
     cache: RefCell<HashMap<Vec<u8>, Vec<u8>>>,
-    passing_keys: RefCell<HashSet<Vec<u8>>>,
-    gen_keys: RefCell<HashSet<Vec<u8>>>,
+    // The nodes encoded and made stale since the last commit.
+    journal: RefCell<Journal>,
+    last_changeset: ChangeSet,
+    // Decoded nodes by hash, the trie updates nodes in place so every read
+    // builds a new node from the cached description.
+    node_cache: RefCell<LRUCache<Vec<u8>, NodeData>>,
+    // Consulted after "node_cache", before the DB.
+    shared_cache: Option<Arc<SharedNodeCache>>,
+    config: TrieConfig,
+    pending_deletes: HashSet<Vec<u8>>,
+    // Keeps stale nodes for a number of commits if set.
+    window: Option<PruningWindow>,
+    // Every key of the trie, so that lookups of absent keys mostly return
+    // without loading any node.
+    bloom: Option<BloomFilter>,
+    // The states "rollback" returns to, of the innermost transaction last.
+    transactions: Vec<TrieSnapshot>,
+}
+
+// The options set by the "with_*" methods, which the tries derived from a
+// trie, e.g. by "fork" or "at_root", inherit.
+#[derive(Debug, Clone)]
+struct TrieConfig {
+    // Stale nodes are only removed from the DB by "flush_deletes" if set.
+    defer_deletes: bool,
+    // "root" keeps the encoded nodes in "cache" instead of writing them if set.
+    hash_only: bool,
+    // Commits maintain a reference count per node in the DB if set.
+    ref_counting: bool,
+    // Commits never remove nodes from the DB if set.
+    archive: bool,
+    // Commits record what "rewind" needs to return to the previous root if set.
+    reverse_diffs: bool,
+    // Empty values are stored instead of removing their keys if set.
+    empty_values: bool,
+    // A node missing from the DB is an error instead of empty if set.
+    strict: bool,
+    // Nodes read from the DB are checked against their hash if set.
+    verify_nodes: bool,
+    // The name of the table of every value in the DB that commits maintain,
+    // if set, see "with_flat_snapshot".
+    flat: Option<Vec<u8>>,
+    // Inserts of longer keys or values fail, see "with_size_limits".
+    max_key_len: usize,
+    max_value_len: usize,
+}
+
+impl Default for TrieConfig {
+    fn default() -> Self {
+        TrieConfig {
+            defer_deletes: false,
+            hash_only: false,
+            ref_counting: false,
+            archive: false,
+            reverse_diffs: false,
+            empty_values: false,
+            strict: false,
+            verify_nodes: false,
+            flat: None,
+            max_key_len: usize::max_value(),
+            max_value_len: usize::max_value(),
+        }
+    }
+}
+
+// The in-memory state of a trie when a transaction began.
+#[derive(Debug)]
+struct TrieSnapshot {
+    root: Node,
+    arena: NodeArena,
+    journal: Journal,
+    bloom: Option<BloomFilter>,
+}
+
+/// Approximate bytes held in memory by a trie, see "memory_usage".
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct TrieMemoryStats {
+    /// The in-memory node tree.
+    pub nodes: usize,
+    /// Encoded nodes waiting for the next commit.
+    pub cache: usize,
+    /// The record of the nodes encoded and made stale since the last commit.
+    pub journal: usize,
+    /// Hashes of the stale nodes waiting for "flush_deletes".
+    pub pending_deletes: usize,
+    /// The bloom filter over the keys, if enabled.
+    pub bloom: usize,
+}
+
+impl TrieMemoryStats {
+    pub fn total(&self) -> usize {
+        self.nodes + self.cache + self.journal + self.pending_deletes + self.bloom
+    }
+}
+
+/// A value returned by "get_ref", shared with a node in memory or read from
+/// the DB. It does not borrow the trie.
+#[derive(Debug, Clone)]
+pub enum ValueRef {
+    Shared(Value),
+    Owned(Vec<u8>),
+}
+
+impl ValueRef {
+    pub fn into_owned(self) -> Vec<u8> {
+        match self {
+            ValueRef::Shared(value) => into_vec(value),
+            ValueRef::Owned(value) => value,
+        }
+    }
+}
+
+impl Deref for ValueRef {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            ValueRef::Shared(value) => value,
+            ValueRef::Owned(value) => value,
+        }
+    }
+}
+
+impl PartialEq for ValueRef {
+    fn eq(&self, other: &Self) -> bool {
+        **self == **other
+    }
+}
+
+impl Eq for ValueRef {}
+
+/// A key whose value changed since the last commit, with the committed and
+/// the current value, see "pending_diff".
+pub type PendingChange = (Vec<u8>, Option<Vec<u8>>, Option<Vec<u8>>);
+
+/// The work buffered since the last commit, see "pending_changes".
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct PendingStats {
+    /// In-memory nodes that were added or modified.
+    pub dirty_nodes: usize,
+    /// Approximate size of the nodes the next commit writes.
+    pub encoded_bytes: usize,
+    /// Stale nodes waiting to be removed by the next commit or "flush_deletes".
+    pub deletions: usize,
+}
+
+// The table of a hash set or map holds one control byte per entry.
+fn key_set_usage(keys: &HashSet<Vec<u8>>) -> usize {
+    keys.capacity() * (mem::size_of::<Vec<u8>>() + 1) + keys.iter().map(Vec::len).sum::<usize>()
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Copy, Debug)]
 enum TraceStatus {
     Start,
     Doing,
@@ -73,13 +243,26 @@ enum TraceStatus {
     End,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Debug)]
 struct TraceNode {
     node: Node,
     status: TraceStatus,
+    // Whether the node lives in the scratch arena of the iterator.
+    scratch: bool,
+    // The hash children of a branch, loaded into the scratch arena.
+    resolved: Option<[Option<Node>; 16]>,
 }
 
 impl TraceNode {
+    fn new(node: Node, scratch: bool) -> TraceNode {
+        TraceNode {
+            node,
+            status: TraceStatus::Start,
+            scratch,
+            resolved: None,
+        }
+    }
+
     fn advance(&mut self) {
         self.status = match &self.status {
             TraceStatus::Start => TraceStatus::Doing,
@@ -93,106 +276,197 @@ impl TraceNode {
     }
 }
 
-impl From<Node> for TraceNode {
-    fn from(node: Node) -> TraceNode {
-        TraceNode {
-            node,
-            status: TraceStatus::Start,
-        }
-    }
-}
-
-pub struct TrieIterator<'a, D, H>
+pub struct TrieIterator<'a, D, H, C = RLPNodeCodec>
 where
     D: DB,
     H: Hasher,
+    C: NodeCodec,
 {
-    trie: &'a PatriciaTrie<D, H>,
+    trie: &'a PatriciaTrie<D, H, C>,
     nibble: Nibbles,
     nodes: Vec<TraceNode>,
+    // Nodes loaded from the DB while iterating, they are not part of the trie.
+    scratch: NodeArena,
+    // Keys are returned as nibbles instead of bytes if set.
+    nibble_keys: bool,
+    // The error that ended the iteration, see "try_next".
+    error: Option<TrieError>,
+}
+
+impl<'a, D, H, C> TrieIterator<'a, D, H, C>
+where
+    D: DB,
+    H: Hasher,
+    C: NodeCodec,
+{
+    // The key at the current path.
+    fn key(&self) -> Vec<u8> {
+        if !self.nibble_keys {
+            return self.nibble.encode_raw().0;
+        }
+        let hex = self.nibble.get_data();
+        let len = if self.nibble.is_leaf() {
+            hex.len() - 1
+        } else {
+            hex.len()
+        };
+        hex[..len].to_vec()
+    }
+
+    // Like "next", but returns the error, e.g. of a missing node, that ends
+    // the iteration instead of ending it early.
+    pub(crate) fn try_next(&mut self) -> TrieResult<Option<(Vec<u8>, Vec<u8>)>> {
+        match self.next() {
+            Some(item) => Ok(Some(item)),
+            None => self.error.take().map_or(Ok(None), Err),
+        }
+    }
+
+    // Ends the iteration with "error".
+    fn fail(&mut self, error: TrieError) {
+        self.nodes.clear();
+        self.error = Some(error);
+    }
 }
 
-impl<'a, D, H> Iterator for TrieIterator<'a, D, H>
+impl<'a, D, H, C> Iterator for TrieIterator<'a, D, H, C>
 where
     D: DB,
     H: Hasher,
+    C: NodeCodec,
 {
     type Item = (Vec<u8>, Vec<u8>);
 
     fn next(&mut self) -> Option<Self::Item> {
+        let trie_arena = self.trie.arena.borrow();
         loop {
-            let mut now = self.nodes.last().cloned();
-            if let Some(ref mut now) = now {
-                self.nodes.last_mut().unwrap().advance();
-
-                match (now.status.clone(), &now.node) {
-                    (TraceStatus::End, node) => {
-                        match *node {
-                            Node::Leaf(ref leaf) => {
-                                let cur_len = self.nibble.len();
-                                self.nibble.truncate(cur_len - leaf.borrow().key.len());
-                            }
+            let (node, status, scratch) = match self.nodes.last_mut() {
+                Some(now) => {
+                    let status = now.status;
+                    now.advance();
+                    (now.node, status, now.scratch)
+                }
+                None => return None,
+            };
+            let arena = if scratch { &self.scratch } else { &*trie_arena };
 
-                            Node::Extension(ref ext) => {
-                                let cur_len = self.nibble.len();
-                                self.nibble.truncate(cur_len - ext.borrow().prefix.len());
-                            }
+            match (status, node) {
+                (TraceStatus::End, node) => {
+                    match node {
+                        Node::Leaf(id) => {
+                            let cur_len = self.nibble.len();
+                            self.nibble.truncate(cur_len - arena.leaf(id).key.len());
+                        }
 
-                            Node::Branch(_) => {
-                                self.nibble.pop();
-                            }
-                            _ => {}
+                        Node::Extension(id) => {
+                            let cur_len = self.nibble.len();
+                            self.nibble
+                                .truncate(cur_len - arena.extension(id).prefix.len());
                         }
-                        self.nodes.pop();
-                    }
 
-                    (TraceStatus::Doing, Node::Extension(ref ext)) => {
-                        self.nibble.extend(&ext.borrow().prefix);
-                        self.nodes.push((ext.borrow().node.clone()).into());
+                        Node::Branch(_) => {
+                            self.nibble.pop();
+                        }
+                        _ => {}
+                    }
+                    self.nodes.pop();
+                    if scratch {
+                        self.scratch.free(node);
                     }
+                }
+
+                (TraceStatus::Doing, Node::Extension(id)) => {
+                    let ext = arena.extension(id);
+                    self.nibble.extend(&ext.prefix);
+                    self.nodes.push(TraceNode::new(ext.node, scratch));
+                }
+
+                (TraceStatus::Doing, Node::Leaf(id)) => {
+                    let leaf = arena.leaf(id);
+                    self.nibble.extend(&leaf.key);
+                    return Some((self.key(), leaf.value.to_vec()));
+                }
 
-                    (TraceStatus::Doing, Node::Leaf(ref leaf)) => {
-                        self.nibble.extend(&leaf.borrow().key);
-                        return Some((self.nibble.encode_raw().0, leaf.borrow().value.clone()));
+                (TraceStatus::Doing, Node::Branch(id)) => {
+                    let branch = arena.branch(id);
+                    let value = branch.value.as_ref().map(|v| v.to_vec());
+                    let mut indexes = vec![];
+                    let mut keys = vec![];
+                    for (i, child) in branch.children.iter().enumerate() {
+                        if let Node::Hash(hash_id) = child {
+                            indexes.push(i);
+                            keys.push(arena.hash_node(*hash_id).hash.clone());
+                        }
                     }
 
-                    (TraceStatus::Doing, Node::Branch(ref branch)) => {
-                        let value = branch.borrow().value.clone();
-                        if value.is_none() {
-                            continue;
-                        } else {
-                            return Some((self.nibble.encode_raw().0, value.unwrap()));
+                    if !keys.is_empty() {
+                        match self.trie.resolve_children(&mut self.scratch, &keys) {
+                            Ok(nodes) => {
+                                let mut resolved = [None; 16];
+                                for (i, n) in indexes.into_iter().zip(nodes.into_iter()) {
+                                    resolved[i] = Some(n);
+                                }
+                                self.nodes.last_mut().unwrap().resolved = Some(resolved);
+                            }
+                            Err(e) => {
+                                self.fail(e);
+                                return None;
+                            }
                         }
                     }
 
-                    (TraceStatus::Doing, Node::Hash(ref hash_node)) => {
-                        if let Ok(n) = self.trie.recover_from_db(&hash_node.borrow().hash.clone()) {
+                    if let Some(value) = value {
+                        return Some((self.key(), value));
+                    }
+                }
+
+                (TraceStatus::Doing, Node::Hash(id)) => {
+                    let hash = arena.hash_node(id).hash.clone();
+                    match self.trie.recover_from_db(&mut self.scratch, &hash) {
+                        Ok(n) => {
                             self.nodes.pop();
-                            self.nodes.push(n.into());
-                        } else {
-                            //error!();
+                            if scratch {
+                                self.scratch.free(node);
+                            }
+                            self.nodes.push(TraceNode::new(n, true));
+                        }
+                        Err(e) => {
+                            self.fail(e);
                             return None;
                         }
                     }
+                }
 
-                    (TraceStatus::Child(i), Node::Branch(ref branch)) => {
-                        if i == 0 {
-                            self.nibble.push(0);
-                        } else {
-                            self.nibble.pop();
-                            self.nibble.push(i);
+                (TraceStatus::Child(i), Node::Branch(id)) => {
+                    if i == 0 {
+                        self.nibble.push(0);
+                    } else {
+                        self.nibble.pop();
+                        self.nibble.push(i);
+                    }
+                    let child = arena.branch(id).children[i as usize];
+                    let resolved = self
+                        .nodes
+                        .last()
+                        .unwrap()
+                        .resolved
+                        .and_then(|resolved| resolved[i as usize]);
+                    match resolved {
+                        // The hash child is replaced by the node it refers to.
+                        Some(n) => {
+                            if scratch {
+                                self.scratch.free(child);
+                            }
+                            self.nodes.push(TraceNode::new(n, true));
                         }
-                        self.nodes
-                            .push((branch.borrow().children[i as usize].clone()).into());
+                        None => self.nodes.push(TraceNode::new(child, scratch)),
                     }
+                }
 
-                    (_, Node::Empty) => {
-                        self.nodes.pop();
-                    }
-                    _ => {}
+                (_, Node::Empty) => {
+                    self.nodes.pop();
                 }
-            } else {
-                return None;
+                _ => {}
             }
         }
     }
@@ -203,832 +477,4826 @@ where
     D: DB,
     H: Hasher,
 {
-    pub fn iter(&self) -> TrieIterator<D, H> {
+    pub fn new(db: Arc<D>, hasher: Arc<H>) -> Self {
+        Self::new_with_codec(db, hasher, Arc::new(RLPNodeCodec::new()))
+    }
+
+    pub fn from(db: Arc<D>, hasher: Arc<H>, root: &[u8]) -> TrieResult<Self> {
+        Self::from_with_codec(db, hasher, Arc::new(RLPNodeCodec::new()), root)
+    }
+
+    /// Builds the trie for key/value pairs in ascending key order, writing
+    /// nodes to the DB as they are completed. Much faster than inserting
+    /// the pairs one by one, e.g. for a genesis or an import.
+    pub fn from_sorted_iter<I>(db: Arc<D>, hasher: Arc<H>, iter: I) -> TrieResult<Self>
+    where
+        I: IntoIterator<Item = (Vec<u8>, Vec<u8>)>,
+    {
+        Self::from_sorted_iter_with_codec(db, hasher, Arc::new(RLPNodeCodec::new()), iter)
+    }
+}
+
+impl<D, H, C> PatriciaTrie<D, H, C>
+where
+    D: DB,
+    H: Hasher,
+    C: NodeCodec,
+{
+    pub fn iter(&self) -> TrieIterator<D, H, C> {
         let mut nodes = Vec::new();
-        nodes.push((self.root.clone()).into());
+        nodes.push(TraceNode::new(self.root, false));
         TrieIterator {
             trie: self,
             nibble: Nibbles::from_raw(vec![], false),
             nodes,
+            scratch: NodeArena::new(),
+            nibble_keys: false,
+            error: None,
         }
     }
-    pub fn new(db: Arc<D>, hasher: Arc<H>) -> Self {
+
+    /// Like "iter", but returns the keys as nibbles, one per byte, see
+    /// "get_nibbles". "iter" drops the last nibble of odd-length keys.
+    pub fn iter_nibbles(&self) -> TrieIterator<D, H, C> {
+        TrieIterator {
+            nibble_keys: true,
+            ..self.iter()
+        }
+    }
+
+    pub fn new_with_codec(db: Arc<D>, hasher: Arc<H>, codec: Arc<C>) -> Self {
         Self {
             root: Node::Empty,
-            root_hash: hasher.digest(&rlp::NULL_RLP.to_vec()),
+            root_hash: hasher.digest(&codec.encode(&NodeData::Empty)),
+            arena: RefCell::new(NodeArena::new()),
 
             cache: RefCell::new(HashMap::new()),
-            passing_keys: RefCell::new(HashSet::new()),
-            gen_keys: RefCell::new(HashSet::new()),
+            journal: RefCell::new(Journal::new()),
+            last_changeset: ChangeSet::default(),
+            node_cache: RefCell::new(LRUCache::new(0)),
+            shared_cache: None,
+            config: TrieConfig::default(),
+            pending_deletes: HashSet::new(),
+            window: None,
+            bloom: None,
+            transactions: vec![],
 
             db,
             hasher,
+            codec,
         }
     }
 
-    pub fn from(db: Arc<D>, hasher: Arc<H>, root: &[u8]) -> TrieResult<Self> {
-        match db.get(&root).map_err(|e| TrieError::DB(e.to_string()))? {
-            Some(data) => {
-                let mut trie = Self {
-                    root: Node::Empty,
-                    root_hash: root.to_vec(),
+    /// Keeps up to "capacity" decoded nodes in memory, so that nodes on hot
+    /// paths are neither read from the DB nor decoded again. Disabled by default.
+    pub fn with_node_cache(self, capacity: usize) -> Self {
+        Self {
+            node_cache: RefCell::new(LRUCache::new(capacity)),
+            ..self
+        }
+    }
 
-                    cache: RefCell::new(HashMap::new()),
-                    passing_keys: RefCell::new(HashSet::new()),
-                    gen_keys: RefCell::new(HashSet::new()),
+    /// Keeps the nodes a commit makes stale in the DB until "flush_deletes",
+    /// so that commits are faster and older roots stay readable meanwhile.
+    pub fn with_deferred_deletes(mut self) -> Self {
+        self.config.defer_deletes = true;
+        self
+    }
 
-                    db,
-                    hasher,
-                };
+    /// Removes the nodes made stale by the commits since the last call from
+    /// the DB. Returns the number of removed nodes.
+    pub fn flush_deletes(&mut self) -> TrieResult<usize> {
+        let removed_keys: Vec<Vec<u8>> = self.pending_deletes.drain().collect();
+        self.remove_stale(&removed_keys)?;
+        self.db.flush().map_err(|e| TrieError::DB(e.to_string()))?;
+        Ok(removed_keys.len())
+    }
 
-                trie.root = trie.decode_node(&data)?;
-                Ok(trie)
-            }
-            None => Err(TrieError::InvalidStateRoot),
-        }
+    /// Keeps a count of the references to every node next to it in the DB,
+    /// so that a node shared by identical subtries, or by several tries on
+    /// the DB, is only removed once nothing references it. Every trie on the
+    /// DB must count from its first commit on. Unreferenced nodes are removed
+    /// by the commit, "with_deferred_deletes" does not apply.
+    pub fn with_ref_counting(mut self) -> Self {
+        self.config.ref_counting = true;
+        self
     }
-}
 
-impl<D, H> Trie<D, H> for PatriciaTrie<D, H>
-where
-    D: DB,
-    H: Hasher,
-{
-    /// Returns the value for key stored in the trie.
-    fn get(&self, key: &[u8]) -> TrieResult<Option<Vec<u8>>> {
-        self.get_at(self.root.clone(), &Nibbles::from_raw(key.to_vec(), true))
+    /// Makes commits keep the nodes they make stale in the DB, so that every
+    /// past root can still be opened with "from". By default commits prune
+    /// them. References are not counted, as nothing is ever removed.
+    pub fn with_archive_mode(mut self) -> Self {
+        self.config.archive = true;
+        self
     }
 
-    /// Checks that the key is present in the trie
-    fn contains(&self, key: &[u8]) -> TrieResult<bool> {
-        Ok(self
-            .get_at(self.root.clone(), &Nibbles::from_raw(key.to_vec(), true))?
-            .map_or(false, |_| true))
+    /// Keeps the nodes a commit makes stale for "generations" commits, so that
+    /// the roots of the last "generations" commits stay readable, e.g. the
+    /// state of the last N blocks. A window of 0 is taken as 1. The window is
+    /// kept in the DB by every commit and read back here, so a trie opened
+    /// again later prunes the nodes made stale before. Archive mode and
+    /// reference counting take precedence.
+    pub fn with_pruning_window(mut self, generations: u64) -> TrieResult<Self> {
+        self.window = Some(PruningWindow::load(&*self.db, generations.max(1))?);
+        Ok(self)
     }
 
-    /// Inserts value into trie and modifies it if it exists
-    fn insert(&mut self, key: Vec<u8>, value: Vec<u8>) -> TrieResult<()> {
-        if value.is_empty() {
-            self.remove(&key)?;
-            return Ok(());
+    /// Returns the number of commits since the pruning window was first used
+    /// on the DB.
+    pub fn generation(&self) -> Option<u64> {
+        self.window.as_ref().map(PruningWindow::generation)
+    }
+
+    /// Makes every commit record a reverse diff, the nodes of the previous
+    /// root that it makes stale, so that "rewind" can return to earlier roots,
+    /// e.g. on a reorg, without archive mode. Not recorded by hash only
+    /// commits or with reference counting.
+    pub fn with_reverse_diffs(mut self) -> Self {
+        self.config.reverse_diffs = true;
+        self
+    }
+
+    /// Returns to "to_root", a root that an earlier commit of the trie returned,
+    /// by writing back the nodes that the commits since made stale. The
+    /// changes since the last commit are dropped. The nodes of the rewound
+    /// commits are left for "collect_garbage".
+    pub fn rewind(&mut self, to_root: &[u8]) -> TrieResult<()> {
+        if !self.transactions.is_empty() {
+            return Err(TrieError::TransactionOpen);
         }
-        let root = self.root.clone();
-        self.root = self.insert_at(root, Nibbles::from_raw(key, true), value.to_vec())?;
-        Ok(())
+        let nodes = rewind_nodes(&*self.db, &self.root_hash, to_root)?;
+        let (keys, values): (Vec<Vec<u8>>, Vec<Vec<u8>>) = nodes.into_iter().unzip();
+        // The nodes are live again, a later commit must not remove them.
+        for key in keys.iter() {
+            self.pending_deletes.remove(key);
+        }
+        if let Some(window) = &mut self.window {
+            window.revive(&keys);
+        }
+        self.db
+            .insert_batch(keys, values)
+            .map_err(|e| TrieError::DB(e.to_string()))?;
+        self.db.flush().map_err(|e| TrieError::DB(e.to_string()))?;
+
+        self.root_hash = to_root.to_vec();
+        self.cache.get_mut().clear();
+        self.discard()
     }
 
-    /// Removes any existing value for key from the trie.
-    fn remove(&mut self, key: &[u8]) -> TrieResult<bool> {
-        let (n, removed) =
-            self.delete_at(self.root.clone(), &Nibbles::from_raw(key.to_vec(), true))?;
-        self.root = n;
-        Ok(removed)
+    /// Makes "root" only compute the root hash, the encoded nodes stay in
+    /// memory until "persist" writes them. Useful to get candidate roots,
+    /// e.g. of blocks that may never be imported.
+    pub fn with_hash_only_commits(mut self) -> Self {
+        self.config.hash_only = true;
+        self
     }
 
-    /// Saves all the nodes in the db, clears the cache data, recalculates the root.
-    /// Returns the root hash of the trie.
-    fn root(&mut self) -> TrieResult<Vec<u8>> {
+    /// Writes the changes since the last commit to the DB, whether or not
+    /// "root" was called meanwhile. Returns the root hash.
+    pub fn persist(&mut self) -> TrieResult<Vec<u8>> {
         self.commit()
     }
 
-    /// Prove constructs a merkle proof for key. The result contains all encoded nodes
-    /// on the path to the value at key. The value itself is also included in the last
-    /// node and can be retrieved by verifying the proof.
+    /// Starts a transaction, the changes made until "commit_tx" take effect
+    /// together, or none of them once "rollback" is called. Transactions nest.
     ///
-    /// If the trie does not contain a value for key, the returned proof contains all
-    /// nodes of the longest existing prefix of the key (at least the root node), ending
-    /// with the node that proves the absence of the key.
-    fn get_proof(&self, key: &[u8]) -> TrieResult<Vec<Vec<u8>>> {
-        let mut path =
-            self.get_path_at(self.root.clone(), &Nibbles::from_raw(key.to_vec(), true))?;
-        match self.root {
-            Node::Empty => {}
-            _ => path.push(self.root.clone()),
+    /// The in-memory nodes are copied, so it is cheapest right after a commit.
+    pub fn begin(&mut self) {
+        let snapshot = TrieSnapshot {
+            root: self.root,
+            arena: self.arena.borrow().clone(),
+            journal: self.journal.borrow().clone(),
+            bloom: self.bloom.clone(),
+        };
+        self.transactions.push(snapshot);
+    }
+
+    /// Keeps the changes of the innermost transaction, they are undone with
+    /// those of an outer transaction that is rolled back.
+    pub fn commit_tx(&mut self) -> TrieResult<()> {
+        match self.transactions.pop() {
+            Some(_) => Ok(()),
+            None => Err(TrieError::NoTransaction),
         }
-        Ok(path.into_iter().rev().map(|n| self.encode_raw(n)).collect())
     }
 
-    /// return value if key exists, None if key not exist, Error if proof is wrong
-    fn verify_proof(
-        &self,
-        root_hash: Vec<u8>,
-        key: &[u8],
-        proof: Vec<Vec<u8>>,
-    ) -> TrieResult<Option<Vec<u8>>> {
-        let memdb = Arc::new(MemoryDB::new(true));
-        for node_encoded in proof.into_iter() {
-            let hash = self.hasher.digest(&node_encoded);
+    /// Undoes the changes made since the innermost transaction began.
+    pub fn rollback(&mut self) -> TrieResult<()> {
+        let snapshot = self.transactions.pop().ok_or(TrieError::NoTransaction)?;
+        self.root = snapshot.root;
+        *self.arena.get_mut() = snapshot.arena;
+        *self.journal.get_mut() = snapshot.journal;
+        self.bloom = snapshot.bloom;
+        Ok(())
+    }
 
-            if root_hash.eq(&hash) || node_encoded.len() >= H::LENGTH {
-                memdb.insert(hash, node_encoded).unwrap();
+    /// Applies a write set, e.g. of a block, where "None" removes the key,
+    /// also with "with_empty_values", and a later change of a key wins over
+    /// an earlier one, and commits. Returns the new root hash. If a change fails, none of
+    /// them is applied.
+    pub fn apply(
+        &mut self,
+        changes: impl IntoIterator<Item = (Vec<u8>, Option<Vec<u8>>)>,
+    ) -> TrieResult<Vec<u8>> {
+        if !self.transactions.is_empty() {
+            return Err(TrieError::TransactionOpen);
+        }
+        // The last change of a key wins.
+        let changes: BTreeMap<Vec<u8>, Option<Vec<u8>>> = changes.into_iter().collect();
+        let (mut pairs, mut removals) = (vec![], vec![]);
+        for (key, value) in changes {
+            match value {
+                Some(value) => pairs.push((key, value)),
+                None => removals.push(key),
             }
         }
-        let trie = PatriciaTrie::from(memdb, Arc::clone(&self.hasher), &root_hash)
-            .or(Err(TrieError::InvalidProof))?;
-        trie.get(key).or(Err(TrieError::InvalidProof))
+        self.begin();
+        let applied = self
+            .insert_batch(&pairs)
+            .and_then(|_| self.remove_batch(&removals));
+        if let Err(e) = applied {
+            self.rollback()?;
+            return Err(e);
+        }
+        self.commit_tx()?;
+        self.root()
     }
-}
 
-impl<D, H> PatriciaTrie<D, H>
-where
-    D: DB,
-    H: Hasher,
-{
-    fn get_at(&self, n: Node, partial: &Nibbles) -> TrieResult<Option<Vec<u8>>> {
-        match n {
-            Node::Empty => Ok(None),
-            Node::Leaf(leaf) => {
-                let borrow_leaf = leaf.borrow();
+    /// Drops the changes since the last commit, and any open transaction. The
+    /// trie is loaded from the last root again once it is used.
+    pub fn discard(&mut self) -> TrieResult<()> {
+        self.journal.get_mut().clear();
+        self.transactions.clear();
+        let mut arena = self.arena.borrow_mut();
+        arena.clear();
+        let root = arena.new_hash(self.root_hash.clone());
+        // The nodes of a root that "with_hash_only_commits" computed are only
+        // kept in memory, they are loaded now.
+        self.root = if self.config.hash_only {
+            self.expand_cached(&mut arena, root)?
+        } else {
+            self.cache.borrow_mut().clear();
+            root
+        };
+        drop(arena);
+        // The filter may lack keys of the root, e.g. after "clear" or removes
+        // that "rewind" undoes.
+        if let Some(mut bloom) = self.bloom.take() {
+            let filled = self.fill_bloom(&mut bloom);
+            self.bloom = Some(bloom);
+            filled?;
+        }
+        Ok(())
+    }
 
-                if &borrow_leaf.key == partial {
-                    Ok(Some(borrow_leaf.value.clone()))
-                } else {
-                    Ok(None)
-                }
-            }
-            Node::Branch(branch) => {
-                let borrow_branch = branch.borrow();
+    /// Returns the root hash the next commit would return, without writing
+    /// or remembering any node, so that updates can go on afterwards.
+    pub fn peek_root(&self) -> TrieResult<Vec<u8>> {
+        let arena = self.arena.borrow();
+        let mut encoder = NodeEncoder::new(&*self.hasher, &*self.codec);
+        let mut sources = vec![];
+        self.encode_root(&arena, &mut encoder, &mut sources)
+    }
 
-                if partial.is_empty() || partial.at(0) == 16 {
-                    Ok(borrow_branch.value.clone())
-                } else {
-                    let index = partial.at(0);
-                    self.get_at(borrow_branch.children[index].clone(), &partial.offset(1))
-                }
-            }
-            Node::Extension(extension) => {
-                let extension = extension.borrow();
+    /// Opens the trie at "root" on the same DB, hasher and codec, and with the
+    /// same options, e.g. to read an earlier root.
+    pub fn at_root(&self, root: &[u8]) -> TrieResult<Self> {
+        let trie = Self::from_with_codec(
+            Arc::clone(&self.db),
+            Arc::clone(&self.hasher),
+            Arc::clone(&self.codec),
+            root,
+        )?;
+        Ok(Self {
+            config: self.config.clone(),
+            ..trie
+        })
+    }
 
-                let prefix = &extension.prefix;
-                let match_len = partial.common_prefix(&prefix);
-                if match_len == prefix.len() {
-                    self.get_at(extension.node.clone(), &partial.offset(match_len))
-                } else {
-                    Ok(None)
-                }
-            }
-            Node::Hash(hash_node) => {
-                let borrow_hash_node = hash_node.borrow();
-                let n = self.recover_from_db(&borrow_hash_node.hash)?;
-                self.get_at(n, partial)
-            }
+    /// Reads nodes through "cache" before the DB, see "SharedNodeCache".
+    pub fn with_shared_node_cache(self, cache: Arc<SharedNodeCache>) -> Self {
+        Self {
+            shared_cache: Some(cache),
+            ..self
         }
     }
 
-    fn insert_at(&self, n: Node, partial: Nibbles, value: Vec<u8>) -> TrieResult<Node> {
-        match n {
-            Node::Empty => Ok(Node::from_leaf(partial, value)),
-            Node::Leaf(leaf) => {
-                let mut borrow_leaf = leaf.borrow_mut();
+    /// Stores empty values, so that a key inserted with one is present, e.g.
+    /// for sets or flags. By default inserting an empty value removes the key,
+    /// as in Ethereum. Fails if the codec can not tell an empty value of a
+    /// branch from none, as RLP can not, see "BinaryNodeCodec".
+    pub fn with_empty_values(mut self) -> TrieResult<Self> {
+        if !self.codec.keeps_empty_values() {
+            return Err(TrieError::EmptyValuesUnsupported);
+        }
+        self.config.empty_values = true;
+        Ok(self)
+    }
 
-                let old_partial = &borrow_leaf.key;
-                let match_index = partial.common_prefix(old_partial);
-                if match_index == old_partial.len() {
-                    // replace leaf value
-                    borrow_leaf.value = value;
-                    return Ok(Node::Leaf(leaf.clone()));
-                }
+    /// Fails with "MissingNode" when a node the trie refers to is not in the
+    /// DB, e.g. an incomplete or corrupted DB. By default the node is taken as
+    /// empty, which may return wrong values and roots.
+    pub fn with_strict_nodes(mut self) -> Self {
+        self.config.strict = true;
+        self
+    }
 
-                let mut branch = BranchNode {
-                    children: empty_children(),
-                    value: None,
-                };
+    /// Checks that every node read from the DB hashes to its key, failing
+    /// with "CorruptNode" instead of decoding a corrupted node. Off by
+    /// default, as it hashes every node read.
+    pub fn with_node_checksums(mut self) -> Self {
+        self.config.verify_nodes = true;
+        self
+    }
 
-                let n = Node::from_leaf(
-                    old_partial.offset(match_index + 1),
-                    borrow_leaf.value.clone(),
-                );
-                branch.insert(old_partial.at(match_index), n);
+    /// Keeps every value of the trie in a flat table in the DB as well,
+    /// updated by every commit, so that reads of the committed trie take a
+    /// lookup instead of a walk from the root. Reads fall back to the walk if
+    /// the table holds another root, e.g. after a commit without it, and the
+    /// next commit then writes the table again. The table is kept under
+    /// "name", so tries sharing a DB need names of their own; tries of the
+    /// same name write the table again whenever they commit other roots.
+    /// Errors of the walk that writes the table fail the commit; nodes missing
+    /// from the DB are taken as empty unless "with_strict_nodes" is set.
+    pub fn with_flat_snapshot(mut self, name: &[u8]) -> Self {
+        self.config.flat = Some(name.to_vec());
+        self
+    }
 
-                let n = Node::from_leaf(partial.offset(match_index + 1), value);
-                branch.insert(partial.at(match_index), n);
+    /// Makes inserts of keys longer than "max_key_len" or values longer than
+    /// "max_value_len" bytes fail with "KeyTooLarge" or "ValueTooLarge", so
+    /// that untrusted input can't build very deep paths or huge leaves.
+    /// A batch fails as a whole, before any of it is applied.
+    pub fn with_size_limits(mut self, max_key_len: usize, max_value_len: usize) -> Self {
+        self.config.max_key_len = max_key_len;
+        self.config.max_value_len = max_value_len;
+        self
+    }
 
-                if match_index == 0 {
-                    return Ok(Node::Branch(Rc::new(RefCell::new(branch))));
-                }
+    /// Keeps a bloom filter over the keys, sized for "expected_keys", so that
+    /// "get" and "contains" on absent keys mostly return without walking the
+    /// trie. Enabling it reads every key already in the trie, and so does
+    /// every "discard" and "rewind" after. Removed keys stay in the filter,
+    /// which only costs false positives.
+    pub fn with_bloom_filter(mut self, expected_keys: usize) -> TrieResult<Self> {
+        let mut bloom = key_filter(expected_keys)?;
+        self.fill_bloom(&mut bloom)?;
+        self.bloom = Some(bloom);
+        Ok(self)
+    }
 
-                // if include a common prefix
-                Ok(Node::from_extension(
-                    partial.slice(0, match_index),
-                    Node::Branch(Rc::new(RefCell::new(branch))),
-                ))
+    /// Inserts the pairs like calling "insert" for each would, a later pair
+    /// wins over an earlier one with the same key. Every node on the paths of
+    /// the keys is visited once and new subtries are built in one pass.
+    /// Empty values remove their keys, unless "with_empty_values" is set.
+    pub fn insert_batch(&mut self, pairs: &[(Vec<u8>, Vec<u8>)]) -> TrieResult<()> {
+        for (key, value) in pairs.iter() {
+            self.check_sizes(key.len(), value)?;
+        }
+        let mut sorted: Vec<&(Vec<u8>, Vec<u8>)> = pairs.iter().collect();
+        // The sort is stable, so the last pair of a key comes last.
+        sorted.sort_by(|a, b| a.0.cmp(&b.0));
+        let mut inserts = Vec::with_capacity(sorted.len());
+        let mut removals = vec![];
+        for (i, (key, value)) in sorted.iter().enumerate() {
+            if sorted.get(i + 1).map_or(false, |next| next.0 == *key) {
+                continue;
             }
-            Node::Branch(branch) => {
-                let mut borrow_branch = branch.borrow_mut();
-
-                if partial.at(0) == 0x10 {
-                    borrow_branch.value = Some(value);
-                    return Ok(Node::Branch(branch.clone()));
-                }
-
-                let child = borrow_branch.children[partial.at(0)].clone();
-                let new_child = self.insert_at(child, partial.offset(1), value)?;
-                borrow_branch.children[partial.at(0)] = new_child;
-                Ok(Node::Branch(branch.clone()))
+            if self.removes(value) {
+                removals.push(key.clone());
+                continue;
             }
-            Node::Extension(ext) => {
-                let mut borrow_ext = ext.borrow_mut();
-
-                let prefix = &borrow_ext.prefix;
-                let sub_node = borrow_ext.node.clone();
-                let match_index = partial.common_prefix(&prefix);
+            if let Some(bloom) = &mut self.bloom {
+                bloom.insert(key);
+            }
+            inserts.push((Nibbles::from_raw(key.clone(), true), value.clone()));
+        }
+        // A key sorts after the keys it is a prefix of once it is terminated.
+        inserts.sort_by(|a, b| a.0.get_data().cmp(b.0.get_data()));
 
-                if match_index == 0 {
-                    let mut branch = BranchNode {
-                        children: empty_children(),
-                        value: None,
-                    };
-                    branch.insert(
-                        prefix.at(0),
-                        if prefix.len() == 1 {
-                            sub_node
-                        } else {
-                            Node::from_extension(prefix.offset(1), sub_node)
-                        },
-                    );
-                    let node = Node::Branch(Rc::new(RefCell::new(branch)));
+        {
+            let mut arena = self.arena.borrow_mut();
+            self.root = self.insert_many_at(&mut arena, self.root, inserts)?;
+        }
+        self.remove_batch(&removals)?;
+        Ok(())
+    }
 
-                    return self.insert_at(node, partial, value);
-                }
+    /// Removes the keys like calling "remove" for each would, but every node
+    /// on their paths is visited, and collapsed if needed, once.
+    /// Returns the number of keys that were present.
+    pub fn remove_batch(&mut self, keys: &[Vec<u8>]) -> TrieResult<usize> {
+        let mut partials: Vec<Nibbles> = keys
+            .iter()
+            .map(|key| Nibbles::from_raw(key.clone(), true))
+            .collect();
+        partials.sort_by(|a, b| a.get_data().cmp(b.get_data()));
+        partials.dedup();
 
-                if match_index == prefix.len() {
-                    let new_node = self.insert_at(sub_node, partial.offset(match_index), value)?;
-                    return Ok(Node::from_extension(prefix.clone(), new_node));
-                }
+        let mut arena = self.arena.borrow_mut();
+        let (n, removed) = self.delete_many_at(&mut arena, self.root, partials)?;
+        self.root = n;
+        Ok(removed)
+    }
 
-                let new_ext = Node::from_extension(prefix.offset(match_index), sub_node);
-                let new_node = self.insert_at(new_ext, partial.offset(match_index), value)?;
-                borrow_ext.prefix = prefix.slice(0, match_index);
-                borrow_ext.node = new_node;
-                Ok(Node::Extension(ext.clone()))
+    /// Inserts the pairs in order, like "insert" would. Once the root is a
+    /// branch, the subtries below its 16 children are updated on worker
+    /// threads. Empty values remove their keys.
+    #[cfg(feature = "parallel")]
+    pub fn par_insert_batch(&mut self, kvs: Vec<(Vec<u8>, Vec<u8>)>) -> TrieResult<()> {
+        for (key, value) in kvs.iter() {
+            self.check_sizes(key.len(), value)?;
+        }
+        let mut kvs = kvs.into_iter();
+        let root_id = loop {
+            if let Node::Branch(id) = self.root {
+                break id;
+            }
+            match kvs.next() {
+                Some((key, value)) => self.insert(key, value)?,
+                None => return Ok(()),
             }
-            Node::Hash(hash_node) => {
-                let borrow_hash_node = hash_node.borrow();
+        };
 
-                self.passing_keys
-                    .borrow_mut()
-                    .insert(borrow_hash_node.hash.to_vec());
-                let n = self.recover_from_db(&borrow_hash_node.hash)?;
-                self.insert_at(n, partial, value)
+        // Empty keys are the value of the root, they are set at the end so
+        // that the root stays a branch meanwhile.
+        let mut root_values = vec![];
+        let mut buckets: Vec<Vec<(Nibbles, Vec<u8>)>> = (0..16).map(|_| vec![]).collect();
+        for (key, value) in kvs {
+            if key.is_empty() {
+                root_values.push(value);
+                continue;
+            }
+            let removes = self.removes(&value);
+            if let (Some(bloom), false) = (&mut self.bloom, removes) {
+                bloom.insert(&key);
             }
+            let path = Nibbles::from_raw(key, true);
+            buckets[path.at(0)].push((path.offset(1), value));
+        }
+
+        let mut arena = self.arena.borrow_mut();
+        let mut jobs = vec![];
+        for (index, updates) in buckets.into_iter().enumerate() {
+            if updates.is_empty() {
+                continue;
+            }
+            // Every updated subtrie moves to an arena of its own.
+            let mut sub_arena = NodeArena::new();
+            let child = arena.branch(root_id).children[index];
+            let child = arena.move_tree(child, &mut sub_arena);
+            jobs.push((index, sub_arena, child, updates));
+        }
+        if jobs.is_empty() {
+            drop(arena);
+            return self.set_root_values(root_values);
+        }
+
+        let (db, hasher, codec) = (&self.db, &self.hasher, &self.codec);
+        let (config, shared_cache) = (&self.config, &self.shared_cache);
+        let node_cache_capacity = self.node_cache.borrow().capacity();
+        let done: Vec<_> = jobs
+            .into_par_iter()
+            .map(|(index, mut sub_arena, child, updates)| {
+                // The trie is not "Sync", each worker reads the DB through a trie of its own.
+                let mut worker =
+                    Self::new_with_codec(Arc::clone(db), Arc::clone(hasher), Arc::clone(codec));
+                worker.config = config.clone();
+                worker.node_cache = RefCell::new(LRUCache::new(node_cache_capacity));
+                worker.shared_cache = shared_cache.clone();
+                let (child, result) = worker.update_at(&mut sub_arena, child, updates);
+                (index, sub_arena, child, worker.journal.into_inner(), result)
+            })
+            .collect();
+
+        // Subtries are merged back even if an update failed, as "insert" keeps
+        // the changes made before an error.
+        let mut error = None;
+        for (index, mut sub_arena, child, journal, result) in done {
+            arena.branch_mut(root_id).children[index] = sub_arena.move_tree(child, &mut arena);
+            self.journal.borrow_mut().append(journal);
+            if let (None, Err(e)) = (&error, result) {
+                error = Some(e);
+            }
+        }
+        self.mark_dirty(&mut arena.branch_mut(root_id).hash);
+        self.root = self.degenerate(&mut arena, self.root)?;
+        drop(arena);
+
+        match error {
+            Some(e) => Err(e),
+            None => self.set_root_values(root_values),
         }
     }
 
-    fn delete_at(&self, n: Node, partial: &Nibbles) -> TrieResult<(Node, bool)> {
-        let (new_n, deleted) = match n {
-            Node::Empty => Ok((Node::Empty, false)),
-            Node::Leaf(leaf) => {
-                let borrow_leaf = leaf.borrow();
+    #[cfg(feature = "parallel")]
+    fn set_root_values(&mut self, values: Vec<Vec<u8>>) -> TrieResult<()> {
+        for value in values {
+            self.insert(vec![], value)?;
+        }
+        Ok(())
+    }
 
-                if &borrow_leaf.key == partial {
-                    return Ok((Node::Empty, true));
-                }
-                Ok((Node::Leaf(leaf.clone()), false))
+    /// Like "get", but shares the value with the node that holds it in memory,
+    /// e.g. one inserted or read since the last commit, instead of copying
+    /// it. Values that have to be read from the DB are returned owned.
+    pub fn get_ref(&self, key: &[u8]) -> TrieResult<Option<ValueRef>> {
+        if self.surely_absent(key) {
+            return Ok(None);
+        }
+        let partial = Nibbles::from_raw(key.to_vec(), true);
+        let mut arena = self.arena.borrow_mut();
+        match locate(&arena, self.root, &partial) {
+            Located::Missing => Ok(None),
+            Located::Leaf(id) => Ok(Some(ValueRef::Shared(Arc::clone(&arena.leaf(id).value)))),
+            Located::Branch(id) => Ok(arena.branch(id).value.clone().map(ValueRef::Shared)),
+            Located::Hash(n, consumed) => {
+                let value = self.value_at(&mut arena, n, &partial.offset(consumed))?;
+                Ok(value.map(ValueRef::Owned))
             }
-            Node::Branch(branch) => {
-                let mut borrow_branch = branch.borrow_mut();
+        }
+    }
 
-                if partial.at(0) == 0x10 {
-                    borrow_branch.value = None;
-                    return Ok((Node::Branch(branch.clone()), true));
-                }
+    /// Like "insert", but returns the value the key had before, found on the
+    /// way down instead of by a "get" beforehand.
+    pub fn insert_return_old(
+        &mut self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+    ) -> TrieResult<Option<Vec<u8>>> {
+        self.check_sizes(key.len(), &value)?;
+        if self.removes(&value) {
+            return self.take(&key);
+        }
+        if let Some(bloom) = &mut self.bloom {
+            bloom.insert(&key);
+        }
+        let mut arena = self.arena.borrow_mut();
+        let partial = Nibbles::from_raw(key, true);
+        let (n, old) = self.insert_at(&mut arena, self.root, partial, value)?;
+        self.root = n;
+        Ok(old)
+    }
 
-                let index = partial.at(0);
-                let node = borrow_branch.children[index].clone();
+    /// Removes every key. The nodes of the trie are removed from the DB by
+    /// the next commit, so they are all visited now.
+    pub fn clear(&mut self) -> TrieResult<()> {
+        let mut arena = self.arena.borrow_mut();
+        self.forget_tree_at(&mut arena, self.root)?;
+        arena.clear();
+        self.root = Node::Empty;
+        if let Some(bloom) = &mut self.bloom {
+            bloom.clear();
+        }
+        Ok(())
+    }
 
-                let (new_n, deleted) = self.delete_at(node, &partial.offset(1))?;
-                if deleted {
-                    borrow_branch.children[index] = new_n;
-                }
+    /// Removes every key that starts with "prefix", e.g. the storage of a
+    /// contract, by unlinking their subtrie. Its nodes are removed from the
+    /// DB by the next commit. Returns whether any key was removed.
+    pub fn remove_prefix(&mut self, prefix: &[u8]) -> TrieResult<bool> {
+        let mut arena = self.arena.borrow_mut();
+        let prefix = Nibbles::from_raw(prefix.to_vec(), false);
+        let (n, removed) = self.remove_prefix_at(&mut arena, self.root, &prefix)?;
+        self.root = n;
+        Ok(removed)
+    }
 
-                Ok((Node::Branch(branch.clone()), deleted))
-            }
-            Node::Extension(ext) => {
-                let mut borrow_ext = ext.borrow_mut();
+    /// Writes "new" to the key only if its value is "expected", where "None"
+    /// means that the key is absent. Returns whether it was written.
+    pub fn insert_if(
+        &mut self,
+        key: &[u8],
+        expected: Option<&[u8]>,
+        new: &[u8],
+    ) -> TrieResult<bool> {
+        let current = self.get(key)?;
+        if current.as_ref().map(|v| &v[..]) != expected {
+            return Ok(false);
+        }
+        self.insert(key.to_vec(), new.to_vec())?;
+        Ok(true)
+    }
 
-                let prefix = &borrow_ext.prefix;
-                let match_len = partial.common_prefix(prefix);
+    /// Returns the value of the key given as nibbles, one per byte, e.g. for
+    /// key spaces that aren't byte-aligned. Keys may have an odd length, a key
+    /// of whole bytes is the same as its nibbles, the high nibble first.
+    pub fn get_nibbles(&self, nibbles: &[u8]) -> TrieResult<Option<Vec<u8>>> {
+        let partial = nibble_path(nibbles)?;
+        let mut arena = self.arena.borrow_mut();
+        self.value_at(&mut arena, self.root, &partial)
+    }
 
-                if match_len == prefix.len() {
-                    let (new_n, deleted) =
-                        self.delete_at(borrow_ext.node.clone(), &partial.offset(match_len))?;
+    /// Inserts the key given as nibbles, see "get_nibbles".
+    pub fn insert_nibbles(&mut self, nibbles: &[u8], value: Vec<u8>) -> TrieResult<()> {
+        self.check_sizes((nibbles.len() + 1) / 2, &value)?;
+        if self.removes(&value) {
+            self.remove_nibbles(nibbles)?;
+            return Ok(());
+        }
+        let partial = nibble_path(nibbles)?;
+        // Odd-length keys can not be looked up by bytes.
+        if let (Some(bloom), 0) = (&mut self.bloom, nibbles.len() % 2) {
+            bloom.insert(&partial.encode_raw().0);
+        }
+        let mut arena = self.arena.borrow_mut();
+        let (n, _) = self.insert_at(&mut arena, self.root, partial, value)?;
+        self.root = n;
+        Ok(())
+    }
 
-                    if deleted {
-                        borrow_ext.node = new_n;
-                    }
+    /// Removes the key given as nibbles, see "get_nibbles".
+    pub fn remove_nibbles(&mut self, nibbles: &[u8]) -> TrieResult<bool> {
+        let partial = nibble_path(nibbles)?;
+        let mut arena = self.arena.borrow_mut();
+        let (n, removed) = self.delete_at(&mut arena, self.root, &partial)?;
+        self.root = n;
+        Ok(removed.is_some())
+    }
 
-                    Ok((Node::Extension(ext.clone()), deleted))
-                } else {
-                    Ok((Node::Extension(ext.clone()), false))
-                }
+    /// Removes the key, returning the value it had.
+    pub fn take(&mut self, key: &[u8]) -> TrieResult<Option<Vec<u8>>> {
+        let mut arena = self.arena.borrow_mut();
+        let partial = Nibbles::from_raw(key.to_vec(), true);
+        let (n, removed) = self.delete_at(&mut arena, self.root, &partial)?;
+        self.root = n;
+        Ok(removed)
+    }
+
+    /// Returns the values of "keys", in their order. Lookups share the nodes
+    /// on common paths, and the nodes they need from the DB are read with one
+    /// batch per level of the trie.
+    pub fn get_many(&self, keys: &[Vec<u8>]) -> TrieResult<Vec<Option<Vec<u8>>>> {
+        let mut values = vec![None; keys.len()];
+        let mut lookups: Vec<(usize, Nibbles)> = keys
+            .iter()
+            .enumerate()
+            .filter(|(_, key)| !self.surely_absent(key))
+            .map(|(i, key)| (i, Nibbles::from_raw(key.clone(), true)))
+            .collect();
+        lookups.sort_by(|a, b| a.1.get_data().cmp(b.1.get_data()));
+
+        let mut arena = self.arena.borrow_mut();
+        let mut pending = vec![];
+        get_many_at(&arena, self.root, lookups, &mut values, &mut pending);
+        while !pending.is_empty() {
+            let hashes: Vec<Vec<u8>> = pending.iter().map(|(hash, _)| hash.clone()).collect();
+            let nodes = self.resolve_children(&mut arena, &hashes)?;
+            let mut next = vec![];
+            for (n, (_, lookups)) in nodes.into_iter().zip(pending.into_iter()) {
+                get_many_at(&arena, n, lookups, &mut values, &mut next);
+                arena.free_tree(n);
             }
-            Node::Hash(hash_node) => {
-                let hash = hash_node.borrow().hash.clone();
-                self.passing_keys.borrow_mut().insert(hash.clone());
+            pending = next;
+        }
+        Ok(values)
+    }
+
+    /// Returns the approximate memory held by the trie, so that callers can
+    /// commit before it exceeds a budget.
+    pub fn memory_usage(&self) -> TrieMemoryStats {
+        let cache = self.cache.borrow();
+        let entry = 2 * mem::size_of::<Vec<u8>>() + 1;
+        TrieMemoryStats {
+            nodes: self.arena.borrow().memory_usage(),
+            cache: cache.capacity() * entry
+                + cache.iter().map(|(k, v)| k.len() + v.len()).sum::<usize>(),
+            journal: self.journal.borrow().memory_usage(),
+            pending_deletes: key_set_usage(&self.pending_deletes),
+            bloom: self.bloom.as_ref().map_or(0, BloomFilter::memory_usage),
+        }
+    }
 
-                let n = self.recover_from_db(&hash)?;
-                self.delete_at(n, partial)
+    /// Returns the nodes the last commit encoded and made stale, e.g. to
+    /// replicate the commit to another DB.
+    pub fn last_changeset(&self) -> &ChangeSet {
+        &self.last_changeset
+    }
+
+    /// Returns the work the next commit would do, so that callers can commit
+    /// once enough has been buffered. Nothing is encoded, the sizes are estimates.
+    pub fn pending_changes(&self) -> PendingStats {
+        let mut stats = PendingStats::default();
+        pending_at(&self.arena.borrow(), self.root, H::LENGTH, &mut stats);
+        // Nodes encoded since the last commit, e.g. by "get_proof".
+        stats.encoded_bytes += self.cache.borrow().values().map(Vec::len).sum::<usize>();
+
+        stats.deletions =
+            self.journal.borrow_mut().changeset().removed.len() + self.pending_deletes.len();
+        stats
+    }
+
+    /// Returns the keys whose values changed since the last commit, sorted,
+    /// with their committed and their current value.
+    pub fn pending_diff(&self) -> TrieResult<Vec<PendingChange>> {
+        let mut arena = self.arena.borrow_mut();
+        let mut unchanged = HashSet::new();
+        let mut new_values = BTreeMap::new();
+        let mut path = Nibbles::from_hex(vec![]);
+        changed_values(
+            &arena,
+            self.root,
+            &mut path,
+            &mut unchanged,
+            &mut new_values,
+        );
+
+        let mut old_values = BTreeMap::new();
+        let old_root = arena.new_hash(self.root_hash.clone());
+        let result =
+            self.committed_values_at(&mut arena, old_root, &mut path, &unchanged, &mut old_values);
+        arena.free(old_root);
+        result?;
+
+        let mut changes = vec![];
+        for (key, old) in old_values.into_iter() {
+            let new = new_values.remove(&key);
+            if new.as_ref() != Some(&old) {
+                changes.push((key, Some(old), new));
             }
-        }?;
+        }
+        changes.extend(
+            new_values
+                .into_iter()
+                .map(|(key, new)| (key, None, Some(new))),
+        );
+        changes.sort_by(|a, b| a.0.cmp(&b.0));
+        Ok(changes)
+    }
 
-        if deleted {
-            Ok((self.degenerate(new_n)?, deleted))
-        } else {
-            Ok((new_n, deleted))
+    /// Returns a trie at the last committed root, on the same DB and shared
+    /// node cache, so that speculative changes can diverge from this trie
+    /// cheaply. Nodes are loaded once the fork uses them. If both tries are
+    /// committed, archive mode or reference counting must keep the nodes one
+    /// of them makes stale, as the other one may still use them.
+    pub fn fork(&self) -> TrieResult<Self> {
+        let mut fork = Self::new_with_codec(
+            Arc::clone(&self.db),
+            Arc::clone(&self.hasher),
+            Arc::clone(&self.codec),
+        );
+        fork.node_cache = RefCell::new(LRUCache::new(self.node_cache.borrow().capacity()));
+        fork.shared_cache = self.shared_cache.clone();
+        fork.config = self.config.clone();
+        if self.config.hash_only {
+            *fork.cache.get_mut() = self.cache.borrow().clone();
         }
+        fork.root_hash = self.root_hash.clone();
+        fork.discard()?;
+        Ok(fork)
     }
 
-    fn degenerate(&self, n: Node) -> TrieResult<Node> {
-        match n {
-            Node::Branch(branch) => {
-                let borrow_branch = branch.borrow();
+    /// Returns the keys under "prefix", without the prefix, as a trie of their
+    /// own on the same DB and with the same options, e.g. to split a state
+    /// into a trie per module. The unchanged subtries are shared, so mostly
+    /// the new root is written when the new trie is committed.
+    pub fn subtrie(&self, prefix: &[u8]) -> TrieResult<Self> {
+        let trie = Self::new_with_codec(
+            Arc::clone(&self.db),
+            Arc::clone(&self.hasher),
+            Arc::clone(&self.codec),
+        );
+        let arena = self.arena.borrow();
+        let mut to = trie.arena.borrow_mut();
+        let prefix = Nibbles::from_raw(prefix.to_vec(), false);
+        let root = self.copy_under(&arena, self.root, &prefix, &mut to)?;
+        // The nodes of a root that "with_hash_only_commits" computed are only
+        // kept in memory, the new trie writes those it shares.
+        if self.config.hash_only {
+            let mut live = HashSet::new();
+            live_hashes(&to, root, &mut live);
+            let cache = self.cache.borrow();
+            trie.cache.borrow_mut().extend(
+                cache
+                    .iter()
+                    .filter(|(k, _)| live.contains(*k))
+                    .map(|(k, v)| (k.clone(), v.clone())),
+            );
+        }
+        drop(to);
+        Ok(Self {
+            root,
+            config: self.config.clone(),
+            ..trie
+        })
+    }
 
-                let mut used_indexs = vec![];
-                for (index, node) in borrow_branch.children.iter().enumerate() {
-                    match node {
-                        Node::Empty => continue,
-                        _ => used_indexs.push(index),
+    /// Returns the size in bytes of the nodes that prove "keys" at the last
+    /// committed root, each node once, as their proofs would hold them, e.g.
+    /// to budget the witness of a block. Nodes are read but not collected.
+    pub fn estimate_witness_size<K: AsRef<[u8]>>(&self, keys: &[K]) -> TrieResult<usize> {
+        let mut paths: Vec<Vec<u8>> = keys
+            .iter()
+            .map(|key| {
+                Nibbles::from_raw(key.as_ref().to_vec(), false)
+                    .get_data()
+                    .to_vec()
+            })
+            .collect();
+        if paths.is_empty() {
+            return Ok(0);
+        }
+        paths.sort();
+        paths.dedup();
+        let paths: Vec<&[u8]> = paths.iter().map(|path| &path[..]).collect();
+        let root = ChildReference::Hash(self.root_hash.clone());
+        self.witness_size_at(root, &paths)
+    }
+
+    // Sums the sizes of "child", if it is hashed, and of the nodes below it
+    // on the sorted "paths", which are relative to it.
+    fn witness_size_at(&self, child: ChildReference, paths: &[&[u8]]) -> TrieResult<usize> {
+        let (node, mut size) = match child {
+            ChildReference::Inline(data) => (self.codec.decode(&data)?, 0),
+            ChildReference::Hash(hash) => {
+                match self
+                    .db
+                    .get(&hash)
+                    .map_err(|e| TrieError::DB(e.to_string()))?
+                {
+                    Some(data) => {
+                        self.check_node(&hash, &data)?;
+                        (self.codec.decode(&data)?, data.len())
                     }
+                    None => (self.missing_node_data(&hash)?, 0),
                 }
-
-                // if only a value node, transmute to leaf.
-                if used_indexs.is_empty() && borrow_branch.value.is_some() {
-                    let key = Nibbles::from_raw([].to_vec(), true);
-                    let value = borrow_branch.value.clone().unwrap();
-                    Ok(Node::from_leaf(key, value))
-                // if only one node. make an extension.
-                } else if used_indexs.len() == 1 && borrow_branch.value.is_none() {
-                    let used_index = used_indexs[0];
-                    let n = borrow_branch.children[used_index].clone();
-
-                    let new_node =
-                        Node::from_extension(Nibbles::from_hex(vec![used_index as u8]), n);
-                    self.degenerate(new_node)
-                } else {
-                    Ok(Node::Branch(branch.clone()))
+            }
+        };
+        match node {
+            NodeData::Empty => {}
+            NodeData::Short { path, item } => {
+                if let ShortItem::Child(child) = item {
+                    let rest: Vec<&[u8]> = paths
+                        .iter()
+                        .filter(|p| p.starts_with(&path))
+                        .map(|p| &p[path.len()..])
+                        .collect();
+                    if !rest.is_empty() {
+                        size += self.witness_size_at(child, &rest)?;
+                    }
                 }
             }
-            Node::Extension(ext) => {
-                let borrow_ext = ext.borrow();
-
-                let prefix = &borrow_ext.prefix;
-                match borrow_ext.node.clone() {
-                    Node::Extension(sub_ext) => {
-                        let borrow_sub_ext = sub_ext.borrow();
-
-                        let new_prefix = prefix.join(&borrow_sub_ext.prefix);
-                        let new_n = Node::from_extension(new_prefix, borrow_sub_ext.node.clone());
-                        self.degenerate(new_n)
+            NodeData::Branch { children, .. } => {
+                let mut paths = paths.iter().filter(|p| !p.is_empty()).peekable();
+                while let Some(first) = paths.next() {
+                    let nibble = first[0];
+                    let mut rest = vec![&first[1..]];
+                    while let Some(next) = paths.peek() {
+                        if next[0] != nibble {
+                            break;
+                        }
+                        rest.push(&next[1..]);
+                        paths.next();
                     }
-                    Node::Leaf(leaf) => {
-                        let borrow_leaf = leaf.borrow();
-
-                        let new_prefix = prefix.join(&borrow_leaf.key);
-                        Ok(Node::from_leaf(new_prefix, borrow_leaf.value.clone()))
+                    if let Some(child) = &children[nibble as usize] {
+                        size += self.witness_size_at(child.clone(), &rest)?;
                     }
-                    // try again after recovering node from the db.
-                    Node::Hash(hash_node) => {
-                        let hash = hash_node.borrow().hash.clone();
-                        self.passing_keys.borrow_mut().insert(hash.clone());
+                }
+            }
+        }
+        Ok(size)
+    }
 
-                        let new_node = self.recover_from_db(&hash)?;
+    /// Compares the contents of the roots "root_a" and "root_b" in the DB,
+    /// skipping the subtries whose hashes are equal. Returns None if they hold
+    /// the same keys and values, or the first key, in key order, whose value
+    /// differs.
+    pub fn same_content(&self, root_a: &[u8], root_b: &[u8]) -> TrieResult<Option<Vec<u8>>> {
+        let mut first = None;
+        self.diff_roots(root_a, root_b, &mut |key, _, _| {
+            first = Some(key.to_vec());
+            true
+        })?;
+        Ok(first)
+    }
 
-                        let n = Node::from_extension(borrow_ext.prefix.clone(), new_node);
-                        self.degenerate(n)
-                    }
-                    _ => Ok(Node::Extension(ext.clone())),
+    /// Merges "ours" and "theirs", two roots derived from "base", and returns
+    /// a trie at "ours" with the changes of "theirs" applied, to be committed
+    /// by the caller. The subtries "base" and "theirs" share are skipped. For
+    /// a key both sides changed differently, "resolver" gets the key and its
+    /// values in "base", "ours" and "theirs", and returns the merged value,
+    /// None to remove the key.
+    pub fn merge<F>(
+        &self,
+        base: &[u8],
+        ours: &[u8],
+        theirs: &[u8],
+        mut resolver: F,
+    ) -> TrieResult<Self>
+    where
+        F: FnMut(&[u8], Option<&[u8]>, Option<&[u8]>, Option<&[u8]>) -> Option<Vec<u8>>,
+    {
+        let mut changes = vec![];
+        self.diff_roots(base, theirs, &mut |key, base, theirs| {
+            changes.push((key.to_vec(), base, theirs));
+            false
+        })?;
+
+        let mut merged = self.at_root(ours)?;
+        for (key, base, theirs) in changes.into_iter() {
+            let ours = merged.get(&key)?;
+            let value = if ours == base {
+                theirs
+            } else if ours == theirs {
+                continue;
+            } else {
+                resolver(
+                    &key,
+                    base.as_ref().map(Vec::as_slice),
+                    ours.as_ref().map(Vec::as_slice),
+                    theirs.as_ref().map(Vec::as_slice),
+                )
+            };
+            match value {
+                Some(value) => merged.insert(key, value)?,
+                None => {
+                    merged.remove(&key)?;
                 }
             }
-            _ => Ok(n),
         }
+        Ok(merged)
     }
 
-    // Get nodes path along the key, only the nodes whose encode length is greater than
-    // hash length are added.
-    // For embedded nodes whose data are already contained in their parent node, we don't need to
-    // add them in the path.
-    // In the code below, we only add the nodes get by `get_node_from_hash`, because they contains
-    // all data stored in db, including nodes whose encoded data is less than hash length.
-    fn get_path_at(&self, n: Node, partial: &Nibbles) -> TrieResult<Vec<Node>> {
-        match n {
-            Node::Empty | Node::Leaf(_) => Ok(vec![]),
-            Node::Branch(branch) => {
-                let borrow_branch = branch.borrow();
+    // Calls "visit" with every key whose value differs between "root_a" and
+    // "root_b", in key order, and both values, until it returns true.
+    fn diff_roots(&self, root_a: &[u8], root_b: &[u8], visit: &mut DiffVisitor) -> TrieResult<()> {
+        for root in [root_a, root_b].iter() {
+            if !self
+                .db
+                .contains(root)
+                .map_err(|e| TrieError::DB(e.to_string()))?
+            {
+                return Err(TrieError::InvalidStateRoot);
+            }
+        }
+        let a = Subtrie::Ref(ChildReference::Hash(root_a.to_vec()));
+        let b = Subtrie::Ref(ChildReference::Hash(root_b.to_vec()));
+        self.diff_at(a, b, &mut vec![], visit)?;
+        Ok(())
+    }
 
-                if partial.is_empty() || partial.at(0) == 16 {
-                    Ok(vec![])
-                } else {
-                    let node = borrow_branch.children[partial.at(0)].clone();
-                    self.get_path_at(node, &partial.offset(1))
-                }
+    /// Commits the trie, then writes a checkpoint of the DB to "path".
+    /// Returns the root hash the checkpoint was taken at.
+    pub fn snapshot_to<P: AsRef<Path>>(&mut self, path: P) -> TrieResult<Vec<u8>> {
+        let root = self.commit()?;
+        let supported = self
+            .db
+            .checkpoint(path.as_ref())
+            .map_err(|e| TrieError::DB(e.to_string()))?;
+        if !supported {
+            return Err(TrieError::DB("checkpoints are not supported".to_owned()));
+        }
+        Ok(root)
+    }
+
+    /// Writes every node of the trie at "root" to "writer" as a stream of RLP
+    /// strings, the root hash first, then parents before their children, e.g.
+    /// for a backup or to move a trie to another machine. Unlike
+    /// "export_snapshot" no hash is written with the nodes, "restore" computes
+    /// them. Returns the number of nodes written, a subtrie referenced twice
+    /// is written twice.
+    pub fn dump<W: Write>(&self, root: &[u8], writer: W) -> TrieResult<u64> {
+        dump_nodes(&*self.db, &*self.codec, root, writer)
+    }
+
+    /// Writes the nodes of a stream of "dump" to the DB and opens the trie at
+    /// its root. Every node must hash to a node referenced before it, the
+    /// first to the root, and every referenced node must come, or nothing is
+    /// opened and "InvalidData" is returned.
+    pub fn restore<R: Read>(&self, reader: R) -> TrieResult<Self> {
+        let root = restore_nodes(reader, &*self.db, &*self.hasher, &*self.codec)?;
+        self.at_root(&root)
+    }
+
+    // Writes the trie of the sorted, fallible "entries" bottom-up as
+    // "from_sorted_iter" does and returns its root.
+    #[cfg(any(feature = "json", feature = "csv"))]
+    pub(crate) fn build_sorted<I>(&self, entries: I) -> TrieResult<Vec<u8>>
+    where
+        I: IntoIterator<Item = TrieResult<(Vec<u8>, Vec<u8>)>>,
+    {
+        let mut builder = SortedBuilder::new(&*self.db, &*self.hasher, &*self.codec);
+        for entry in entries {
+            let (key, value) = entry?;
+            builder.push(key, value)?;
+        }
+        builder.finish()
+    }
+
+    pub fn from_sorted_iter_with_codec<I>(
+        db: Arc<D>,
+        hasher: Arc<H>,
+        codec: Arc<C>,
+        iter: I,
+    ) -> TrieResult<Self>
+    where
+        I: IntoIterator<Item = (Vec<u8>, Vec<u8>)>,
+    {
+        let root = {
+            let mut builder = SortedBuilder::new(&*db, &*hasher, &*codec);
+            for (key, value) in iter {
+                builder.push(key, value)?;
             }
-            Node::Extension(ext) => {
-                let borrow_ext = ext.borrow();
+            builder.finish()?
+        };
+        Self::from_with_codec(db, hasher, codec, &root)
+    }
 
-                let prefix = &borrow_ext.prefix;
-                let match_len = partial.common_prefix(prefix);
+    pub fn from_with_codec(
+        db: Arc<D>,
+        hasher: Arc<H>,
+        codec: Arc<C>,
+        root: &[u8],
+    ) -> TrieResult<Self> {
+        match db.get(&root).map_err(|e| TrieError::DB(e.to_string()))? {
+            Some(data) => {
+                let mut trie = Self {
+                    root: Node::Empty,
+                    root_hash: root.to_vec(),
+                    arena: RefCell::new(NodeArena::new()),
 
-                if match_len == prefix.len() {
-                    self.get_path_at(borrow_ext.node.clone(), &partial.offset(match_len))
-                } else {
-                    Ok(vec![])
-                }
+                    cache: RefCell::new(HashMap::new()),
+                    journal: RefCell::new(Journal::new()),
+                    last_changeset: ChangeSet::default(),
+                    node_cache: RefCell::new(LRUCache::new(0)),
+                    shared_cache: None,
+                    config: TrieConfig::default(),
+                    pending_deletes: HashSet::new(),
+                    window: None,
+                    bloom: None,
+                    transactions: vec![],
+
+                    db,
+                    hasher,
+                    codec,
+                };
+
+                let mut arena = trie.arena.borrow_mut();
+                let root_node = trie.decode_node(&mut arena, &data)?;
+                arena.set_node_hash(root_node, root.to_vec());
+                drop(arena);
+                trie.root = root_node;
+                Ok(trie)
             }
-            Node::Hash(hash_node) => {
-                let n = self.recover_from_db(&hash_node.borrow().hash.clone())?;
-                let mut rest = self.get_path_at(n.clone(), partial)?;
-                rest.push(n);
-                Ok(rest)
+            None => Err(TrieError::InvalidStateRoot),
+        }
+    }
+}
+
+impl<D, H, C> Trie<D, H> for PatriciaTrie<D, H, C>
+where
+    D: DB,
+    H: Hasher,
+    C: NodeCodec,
+{
+    /// Returns the value for key stored in the trie.
+    fn get<K: AsRef<[u8]>>(&self, key: K) -> TrieResult<Option<Vec<u8>>> {
+        let key = key.as_ref();
+        if self.surely_absent(key) {
+            return Ok(None);
+        }
+        if let Some(name) = &self.config.flat {
+            if let Some(flat) = self.committed_flat(name)? {
+                return flat.get(&*self.db, key);
             }
         }
+        let mut arena = self.arena.borrow_mut();
+        self.value_at(
+            &mut arena,
+            self.root,
+            &Nibbles::from_raw(key.to_vec(), true),
+        )
     }
 
-    fn commit(&mut self) -> TrieResult<Vec<u8>> {
-        let encoded = self.encode_node(self.root.clone());
-        let root_hash = if encoded.len() < H::LENGTH {
-            let hash = self.hasher.digest(&encoded);
-            self.cache.borrow_mut().insert(hash.clone(), encoded);
-            hash
-        } else {
-            encoded
-        };
+    /// Checks that the key is present in the trie
+    fn contains<K: AsRef<[u8]>>(&self, key: K) -> TrieResult<bool> {
+        Ok(self.get(key)?.map_or(false, |_| true))
+    }
 
-        let mut keys = Vec::with_capacity(self.cache.borrow().len());
-        let mut values = Vec::with_capacity(self.cache.borrow().len());
-        for (k, v) in self.cache.borrow_mut().drain() {
-            keys.push(k.to_vec());
-            values.push(v);
-        }
+    /// Inserts value into trie and modifies it if it exists
+    fn insert<K: AsRef<[u8]>>(&mut self, key: K, value: Vec<u8>) -> TrieResult<()> {
+        self.insert_return_old(key.as_ref().to_vec(), value)?;
+        Ok(())
+    }
 
-        self.db
-            .insert_batch(keys, values)
-            .map_err(|e| TrieError::DB(e.to_string()))?;
+    /// Removes any existing value for key from the trie.
+    fn remove<K: AsRef<[u8]>>(&mut self, key: K) -> TrieResult<bool> {
+        Ok(self.take(key.as_ref())?.is_some())
+    }
 
-        let removed_keys: Vec<Vec<u8>> = self
-            .passing_keys
-            .borrow()
+    /// Saves all the nodes in the db, clears the cache data, recalculates the root.
+    /// Returns the root hash of the trie.
+    fn root(&mut self) -> TrieResult<Vec<u8>> {
+        if self.config.hash_only {
+            self.hash_root()
+        } else {
+            self.commit()
+        }
+    }
+
+    /// Prove constructs a merkle proof for key. The result contains all encoded nodes
+    /// on the path to the value at key. The value itself is also included in the last
+    /// node and can be retrieved by verifying the proof.
+    ///
+    /// If the trie does not contain a value for key, the returned proof contains all
+    /// nodes of the longest existing prefix of the key (at least the root node), ending
+    /// with the node that proves the absence of the key.
+    fn get_proof<K: AsRef<[u8]>>(&self, key: K) -> TrieResult<Vec<Vec<u8>>> {
+        let mut arena = self.arena.borrow_mut();
+        let mut path = self.get_path_at(
+            &mut arena,
+            self.root,
+            &Nibbles::from_raw(key.as_ref().to_vec(), true),
+        )?;
+        let recovered = path.len();
+        match self.root {
+            Node::Empty => {}
+            _ => path.push(self.root),
+        }
+        let proof = path
             .iter()
-            .filter(|h| !self.gen_keys.borrow().contains(&h.to_vec()))
-            .map(|h| h.to_vec())
+            .rev()
+            .map(|n| self.encode_raw(&mut arena, *n))
             .collect();
+        for n in path.into_iter().take(recovered) {
+            arena.free_tree(n);
+        }
+        proof
+    }
 
-        self.db
-            .remove_batch(&removed_keys)
-            .map_err(|e| TrieError::DB(e.to_string()))?;
+    /// return value if key exists, None if key not exist, Error if proof is wrong
+    fn verify_proof<K: AsRef<[u8]>>(
+        &self,
+        root_hash: Vec<u8>,
+        key: K,
+        proof: Vec<Vec<u8>>,
+    ) -> TrieResult<Option<Vec<u8>>> {
+        let memdb = Arc::new(MemoryDB::new(true));
+        for node_encoded in proof.into_iter() {
+            let hash = self.hasher.digest(&node_encoded);
 
-        self.root_hash = root_hash.to_vec();
-        self.gen_keys.borrow_mut().clear();
-        self.passing_keys.borrow_mut().clear();
-        self.root = self.recover_from_db(&root_hash)?;
-        Ok(root_hash)
+            if root_hash.eq(&hash) || node_encoded.len() >= H::LENGTH {
+                memdb
+                    .insert(hash, node_encoded)
+                    .map_err(|e| TrieError::DB(e.to_string()))?;
+            }
+        }
+        let trie = PatriciaTrie::from_with_codec(
+            memdb,
+            Arc::clone(&self.hasher),
+            Arc::clone(&self.codec),
+            &root_hash,
+        )
+        .or(Err(TrieError::InvalidProof))?;
+        trie.get(key).or(Err(TrieError::InvalidProof))
+    }
+}
+
+/// Returns the value of "key" at "root" in "db", decoding only the nodes
+/// on its path, without building a trie, e.g. for reads at historical roots.
+pub fn get_at<D: DB, C: NodeCodec>(
+    db: &D,
+    codec: &C,
+    root: &[u8],
+    key: &[u8],
+) -> TrieResult<Option<Vec<u8>>> {
+    if !db
+        .contains(root)
+        .map_err(|e| TrieError::DB(e.to_string()))?
+    {
+        return Err(TrieError::InvalidStateRoot);
     }
+    lookup(codec, root, key, |hash| {
+        db.get(hash)
+            .map_err(|e| TrieError::DB(e.to_string()))?
+            .ok_or_else(|| TrieError::MissingNode {
+                hash: hash.to_vec(),
+            })
+    })
+}
 
-    fn encode_node(&self, n: Node) -> Vec<u8> {
-        // Returns the hash value directly to avoid double counting.
-        if let Node::Hash(hash_node) = n {
-            return hash_node.borrow().hash.clone();
+// The path of the key given as "nibbles", see "get_nibbles".
+fn nibble_path(nibbles: &[u8]) -> TrieResult<Nibbles> {
+    if nibbles.iter().any(|nibble| *nibble >= 16) {
+        return Err(TrieError::InvalidNibbles);
+    }
+    let mut hex = nibbles.to_vec();
+    hex.push(16);
+    Ok(Nibbles::from_hex(hex))
+}
+
+// The branch child a lookup of "partial" continues with, 16 for the value.
+fn lookup_index(partial: &Nibbles) -> usize {
+    if partial.is_empty() {
+        16
+    } else {
+        partial.at(0)
+    }
+}
+
+// Where the in-memory part of the trie puts the value of a key.
+enum Located {
+    Missing,
+    Leaf(NodeId),
+    Branch(NodeId),
+    // The lookup continues at a node in the DB, after "usize" nibbles.
+    Hash(Node, usize),
+}
+
+fn locate(arena: &NodeArena, mut n: Node, partial: &Nibbles) -> Located {
+    let mut consumed = 0;
+    loop {
+        let rest = partial.offset(consumed);
+        match n {
+            Node::Empty => return Located::Missing,
+            Node::Leaf(id) if arena.leaf(id).key == rest => return Located::Leaf(id),
+            Node::Leaf(_) => return Located::Missing,
+            Node::Branch(id) => match lookup_index(&rest) {
+                16 => return Located::Branch(id),
+                i => {
+                    n = arena.branch(id).children[i];
+                    consumed += 1;
+                }
+            },
+            Node::Extension(id) => {
+                let ext = arena.extension(id);
+                if rest.common_prefix(&ext.prefix) < ext.prefix.len() {
+                    return Located::Missing;
+                }
+                n = ext.node;
+                consumed += ext.prefix.len();
+            }
+            Node::Hash(_) => return Located::Hash(n, consumed),
         }
+    }
+}
 
-        let data = self.encode_raw(n.clone());
-        // Nodes smaller than 32 bytes are stored inside their parent,
-        // Nodes equal to 32 bytes are returned directly
-        if data.len() < H::LENGTH {
-            data
-        } else {
-            let hash = self.hasher.digest(&data);
-            self.cache.borrow_mut().insert(hash.clone(), data);
+// Lookups of "get_many", the index of the key and the rest of its path.
+type Lookups = Vec<(usize, Nibbles)>;
+
+// Puts the values of "lookups" below "n" into "values". The lookups that
+// reach a node in the DB are added to "pending" with the hash of that node.
+fn get_many_at(
+    arena: &NodeArena,
+    n: Node,
+    lookups: Lookups,
+    values: &mut [Option<Vec<u8>>],
+    pending: &mut Vec<(Vec<u8>, Lookups)>,
+) {
+    match n {
+        Node::Empty => {}
+        Node::Leaf(id) => {
+            let leaf = arena.leaf(id);
+            for (i, partial) in lookups.into_iter() {
+                if leaf.key == partial {
+                    values[i] = Some(leaf.value.to_vec());
+                }
+            }
+        }
+        Node::Branch(id) => {
+            let branch = arena.branch(id);
+            let mut groups: Vec<Lookups> = (0..16).map(|_| vec![]).collect();
+            for (i, partial) in lookups.into_iter() {
+                match lookup_index(&partial) {
+                    16 => values[i] = branch.value.as_ref().map(|v| v.to_vec()),
+                    index => groups[index].push((i, partial.offset(1))),
+                }
+            }
+            for (index, group) in groups.into_iter().enumerate() {
+                if !group.is_empty() {
+                    get_many_at(arena, branch.children[index], group, values, pending);
+                }
+            }
+        }
+        Node::Extension(id) => {
+            let ext = arena.extension(id);
+            let prefix_len = ext.prefix.len();
+            let below: Lookups = lookups
+                .into_iter()
+                .filter(|(_, partial)| partial.common_prefix(&ext.prefix) == prefix_len)
+                .map(|(i, partial)| (i, partial.offset(prefix_len)))
+                .collect();
+            if !below.is_empty() {
+                get_many_at(arena, ext.node, below, values, pending);
+            }
+        }
+        Node::Hash(id) => pending.push((arena.hash_node(id).hash.clone(), lookups)),
+    }
+}
+
+// Puts a leaf for "key" below a new branch, a key that ends at the branch
+// becomes the value of the branch.
+fn offset_pairs(pairs: Vec<(Nibbles, Vec<u8>)>, index: usize) -> Vec<(Nibbles, Vec<u8>)> {
+    pairs
+        .into_iter()
+        .map(|(partial, value)| (partial.offset(index), value))
+        .collect()
+}
 
-            self.gen_keys.borrow_mut().insert(hash.clone());
-            hash
+fn insert_leaf(
+    arena: &mut NodeArena,
+    children: &mut [Node; 16],
+    branch_value: &mut Option<Value>,
+    key: Nibbles,
+    value: Value,
+) -> TrieResult<()> {
+    match key.at(0) {
+        16 => *branch_value = Some(value),
+        i if i < 16 => children[i] = arena.new_leaf(key.offset(1), value),
+        _ => return Err(TrieError::InvalidData),
+    }
+    Ok(())
+}
+
+impl<D, H, C> PatriciaTrie<D, H, C>
+where
+    D: DB,
+    H: Hasher,
+    C: NodeCodec,
+{
+    // Nodes recovered from the DB are freed once they have been read.
+    fn value_at(
+        &self,
+        arena: &mut NodeArena,
+        n: Node,
+        partial: &Nibbles,
+    ) -> TrieResult<Option<Vec<u8>>> {
+        match n {
+            Node::Empty => Ok(None),
+            Node::Leaf(id) => {
+                let leaf = arena.leaf(id);
+
+                if &leaf.key == partial {
+                    Ok(Some(leaf.value.to_vec()))
+                } else {
+                    Ok(None)
+                }
+            }
+            Node::Branch(id) => {
+                let branch = arena.branch(id);
+
+                if partial.is_empty() || partial.at(0) == 16 {
+                    Ok(branch.value.as_ref().map(|v| v.to_vec()))
+                } else {
+                    let child = branch.children[partial.at(0)];
+                    self.value_at(arena, child, &partial.offset(1))
+                }
+            }
+            Node::Extension(id) => {
+                let ext = arena.extension(id);
+
+                let prefix = &ext.prefix;
+                let match_len = partial.common_prefix(&prefix);
+                if match_len == prefix.len() {
+                    let child = ext.node;
+                    self.value_at(arena, child, &partial.offset(match_len))
+                } else {
+                    Ok(None)
+                }
+            }
+            Node::Hash(id) => {
+                let hash = arena.hash_node(id).hash.clone();
+                let n = self.recover_at(arena, &hash, lookup_index(partial))?;
+                let value = self.value_at(arena, n, partial);
+                arena.free_tree(n);
+                value
+            }
+        }
+    }
+
+    // Fails if a key of "key_len" bytes or "value" exceed the size limits.
+    fn check_sizes(&self, key_len: usize, value: &[u8]) -> TrieResult<()> {
+        if key_len > self.config.max_key_len {
+            return Err(TrieError::KeyTooLarge(key_len));
+        }
+        if value.len() > self.config.max_value_len {
+            return Err(TrieError::ValueTooLarge(value.len()));
+        }
+        Ok(())
+    }
+
+    // Whether inserting "value" removes the key instead.
+    fn removes(&self, value: &[u8]) -> bool {
+        value.is_empty() && !self.config.empty_values
+    }
+
+    // Whether the bloom filter rules out "key".
+    fn surely_absent(&self, key: &[u8]) -> bool {
+        self.bloom
+            .as_ref()
+            .map_or(false, |bloom| !bloom.may_contain(key))
+    }
+
+    // Nodes are updated in place, "insert_at" and "delete_at" only return a new
+    // node when "n" has to be replaced by a different kind of node.
+    fn insert_at(
+        &self,
+        arena: &mut NodeArena,
+        n: Node,
+        partial: Nibbles,
+        value: Vec<u8>,
+    ) -> TrieResult<(Node, Option<Vec<u8>>)> {
+        match n {
+            Node::Empty => Ok((arena.new_leaf(partial, Arc::new(value)), None)),
+            Node::Leaf(id) => {
+                let leaf = arena.leaf_mut(id);
+                self.mark_dirty(&mut leaf.hash);
+
+                let match_index = partial.common_prefix(&leaf.key);
+                if match_index == leaf.key.len() {
+                    // replace leaf value
+                    let old = mem::replace(&mut leaf.value, Arc::new(value));
+                    return Ok((n, Some(into_vec(old))));
+                }
+
+                // The old leaf is replaced by the branch, so its key and value can be moved.
+                let old_partial = mem::replace(&mut leaf.key, Nibbles::from_hex(vec![]));
+                let old_value = Arc::clone(&leaf.value);
+                arena.free(n);
+
+                let mut children = empty_children();
+                let mut branch_value = None;
+                insert_leaf(
+                    arena,
+                    &mut children,
+                    &mut branch_value,
+                    old_partial.offset(match_index),
+                    old_value,
+                )?;
+                insert_leaf(
+                    arena,
+                    &mut children,
+                    &mut branch_value,
+                    partial.offset(match_index),
+                    Arc::new(value),
+                )?;
+                let branch = arena.new_branch(children, branch_value);
+
+                if match_index == 0 {
+                    return Ok((branch, None));
+                }
+
+                // if include a common prefix
+                let ext = arena.new_extension(partial.slice(0, match_index), branch);
+                Ok((ext, None))
+            }
+            Node::Branch(id) => {
+                let branch = arena.branch_mut(id);
+                self.mark_dirty(&mut branch.hash);
+
+                if partial.at(0) == 0x10 {
+                    let old = branch.value.replace(Arc::new(value));
+                    return Ok((n, old.map(into_vec)));
+                }
+
+                let index = partial.at(0);
+                let child = branch.children[index];
+                let (new_child, old) = self.insert_at(arena, child, partial.offset(1), value)?;
+                arena.branch_mut(id).children[index] = new_child;
+                Ok((n, old))
+            }
+            Node::Extension(id) => {
+                let ext = arena.extension_mut(id);
+                self.mark_dirty(&mut ext.hash);
+
+                let match_index = partial.common_prefix(&ext.prefix);
+
+                if match_index == 0 {
+                    // The extension is replaced by a branch holding what is left of it.
+                    let index = ext.prefix.at(0);
+                    if index >= 16 {
+                        return Err(TrieError::InvalidData);
+                    }
+                    let rest = ext.prefix.offset(1);
+                    let sub_node = ext.node;
+                    arena.free(n);
+
+                    let mut children = empty_children();
+                    children[index] = if rest.is_empty() {
+                        sub_node
+                    } else {
+                        arena.new_extension(rest, sub_node)
+                    };
+                    let branch = arena.new_branch(children, None);
+                    return self.insert_at(arena, branch, partial, value);
+                }
+
+                if match_index == ext.prefix.len() {
+                    let child = ext.node;
+                    let (new_node, old) =
+                        self.insert_at(arena, child, partial.offset(match_index), value)?;
+                    arena.extension_mut(id).node = new_node;
+                    return Ok((n, old));
+                }
+
+                let rest = ext.prefix.offset(match_index);
+                let child = ext.node;
+                let new_ext = arena.new_extension(rest, child);
+                let (new_node, old) =
+                    self.insert_at(arena, new_ext, partial.offset(match_index), value)?;
+                let ext = arena.extension_mut(id);
+                ext.prefix = ext.prefix.slice(0, match_index);
+                ext.node = new_node;
+                Ok((n, old))
+            }
+            Node::Hash(id) => {
+                let hash = arena.hash_node(id).hash.clone();
+                let resolved = self.resolve_hash(arena, &hash)?;
+                let inserted = self.insert_at(arena, resolved, partial, value)?;
+                arena.free(n);
+                Ok(inserted)
+            }
+        }
+    }
+
+    // Inserts "pairs", sorted by path and without duplicates, below "n".
+    fn insert_many_at(
+        &self,
+        arena: &mut NodeArena,
+        n: Node,
+        mut pairs: Vec<(Nibbles, Vec<u8>)>,
+    ) -> TrieResult<Node> {
+        match pairs.len() {
+            0 => return Ok(n),
+            1 => {
+                let (partial, value) = pairs.pop().unwrap();
+                return Ok(self.insert_at(arena, n, partial, value)?.0);
+            }
+            _ => {}
+        }
+        match n {
+            Node::Empty => {
+                // The pairs are sorted, so the first and the last share the
+                // prefix of all of them.
+                let first = &pairs[0].0;
+                let prefix_len = first.common_prefix(&pairs[pairs.len() - 1].0);
+                if prefix_len == 0 {
+                    let branch = arena.new_branch(empty_children(), None);
+                    return self.insert_many_at(arena, branch, pairs);
+                }
+                let prefix = first.slice(0, prefix_len);
+                let rest = offset_pairs(pairs, prefix_len);
+                let branch = self.insert_many_at(arena, Node::Empty, rest)?;
+                Ok(arena.new_extension(prefix, branch))
+            }
+            Node::Leaf(id) => {
+                // The leaf joins the pairs, unless one of them replaces it.
+                let leaf = arena.leaf_mut(id);
+                self.mark_dirty(&mut leaf.hash);
+                let key = mem::replace(&mut leaf.key, Nibbles::from_hex(vec![]));
+                let value = Arc::clone(&leaf.value);
+                arena.free(n);
+                if let Err(i) = pairs.binary_search_by(|(k, _)| k.get_data().cmp(key.get_data())) {
+                    pairs.insert(i, (key, into_vec(value)));
+                }
+                self.insert_many_at(arena, Node::Empty, pairs)
+            }
+            Node::Branch(id) => {
+                self.mark_dirty(&mut arena.branch_mut(id).hash);
+                let mut groups: Vec<Vec<(Nibbles, Vec<u8>)>> = (0..16).map(|_| vec![]).collect();
+                for (partial, value) in pairs.into_iter() {
+                    match partial.at(0) {
+                        16 => arena.branch_mut(id).value = Some(Arc::new(value)),
+                        i => groups[i].push((partial.offset(1), value)),
+                    }
+                }
+                for (index, group) in groups.into_iter().enumerate() {
+                    if group.is_empty() {
+                        continue;
+                    }
+                    let child = arena.branch(id).children[index];
+                    let new_child = self.insert_many_at(arena, child, group)?;
+                    arena.branch_mut(id).children[index] = new_child;
+                }
+                Ok(n)
+            }
+            Node::Extension(id) => {
+                let ext = arena.extension_mut(id);
+                self.mark_dirty(&mut ext.hash);
+                // The nibbles of the prefix that all pairs share, the pairs
+                // with the most and the fewest in common sort first or last.
+                let match_index = pairs[0]
+                    .0
+                    .common_prefix(&ext.prefix)
+                    .min(pairs[pairs.len() - 1].0.common_prefix(&ext.prefix));
+                let child = ext.node;
+
+                if match_index == ext.prefix.len() {
+                    let rest = offset_pairs(pairs, match_index);
+                    let new_node = self.insert_many_at(arena, child, rest)?;
+                    arena.extension_mut(id).node = new_node;
+                    return Ok(n);
+                }
+
+                // The extension is split where the pairs leave it.
+                let index = ext.prefix.at(match_index);
+                if index >= 16 {
+                    return Err(TrieError::InvalidData);
+                }
+                let rest = ext.prefix.offset(match_index + 1);
+                let head = ext.prefix.slice(0, match_index);
+                let mut children = empty_children();
+                children[index] = if rest.is_empty() {
+                    child
+                } else {
+                    arena.new_extension(rest, child)
+                };
+                let branch = arena.new_branch(children, None);
+                let branch =
+                    self.insert_many_at(arena, branch, offset_pairs(pairs, match_index))?;
+                if match_index == 0 {
+                    arena.free(n);
+                    return Ok(branch);
+                }
+                let ext = arena.extension_mut(id);
+                ext.prefix = head;
+                ext.node = branch;
+                Ok(n)
+            }
+            Node::Hash(id) => {
+                let hash = arena.hash_node(id).hash.clone();
+                let resolved = self.resolve_hash(arena, &hash)?;
+                let new_node = self.insert_many_at(arena, resolved, pairs)?;
+                arena.free(n);
+                Ok(new_node)
+            }
+        }
+    }
+
+    // Returns the new node and the value of the deleted key.
+    fn delete_at(
+        &self,
+        arena: &mut NodeArena,
+        n: Node,
+        partial: &Nibbles,
+    ) -> TrieResult<(Node, Option<Vec<u8>>)> {
+        let (new_n, deleted) = match n {
+            Node::Empty => (Node::Empty, None),
+            Node::Leaf(id) => {
+                let leaf = arena.leaf_mut(id);
+
+                if &leaf.key == partial {
+                    self.mark_dirty(&mut leaf.hash);
+                    let value = Arc::clone(&leaf.value);
+                    arena.free(n);
+                    return Ok((Node::Empty, Some(into_vec(value))));
+                }
+                (n, None)
+            }
+            Node::Branch(id) => {
+                let deleted = if partial.at(0) == 0x10 {
+                    // No early return, the branch may have to degenerate
+                    // once its value is gone.
+                    arena.branch_mut(id).value.take().map(into_vec)
+                } else {
+                    let index = partial.at(0);
+                    let child = arena.branch(id).children[index];
+                    let (new_n, deleted) = self.delete_at(arena, child, &partial.offset(1))?;
+                    if deleted.is_some() {
+                        arena.branch_mut(id).children[index] = new_n;
+                    }
+                    deleted
+                };
+                if deleted.is_some() {
+                    self.mark_dirty(&mut arena.branch_mut(id).hash);
+                }
+
+                (n, deleted)
+            }
+            Node::Extension(id) => {
+                let ext = arena.extension(id);
+
+                let prefix = &ext.prefix;
+                let match_len = partial.common_prefix(prefix);
+
+                if match_len == prefix.len() {
+                    let child = ext.node;
+                    let (new_n, deleted) =
+                        self.delete_at(arena, child, &partial.offset(match_len))?;
+
+                    if deleted.is_some() {
+                        let ext = arena.extension_mut(id);
+                        self.mark_dirty(&mut ext.hash);
+                        ext.node = new_n;
+                    }
+
+                    (n, deleted)
+                } else {
+                    (n, None)
+                }
+            }
+            Node::Hash(id) => {
+                let hash = arena.hash_node(id).hash.clone();
+                let resolved = self.resolve_hash(arena, &hash)?;
+                let (new_n, deleted) = self.delete_at(arena, resolved, partial)?;
+                // An unchanged subtrie stays behind its hash node.
+                if deleted.is_none() {
+                    arena.free_tree(resolved);
+                    return Ok((n, None));
+                }
+                arena.free(n);
+                (new_n, deleted)
+            }
+        };
+
+        if deleted.is_some() {
+            Ok((self.degenerate(arena, new_n)?, deleted))
+        } else {
+            Ok((new_n, deleted))
+        }
+    }
+
+    // Unlinks the subtrie of the keys that start with "prefix" below "n".
+    // Returns the new node and whether any key was removed.
+    fn remove_prefix_at(
+        &self,
+        arena: &mut NodeArena,
+        n: Node,
+        prefix: &Nibbles,
+    ) -> TrieResult<(Node, bool)> {
+        if prefix.is_empty() {
+            if n == Node::Empty {
+                return Ok((n, false));
+            }
+            self.forget_tree_at(arena, n)?;
+            arena.free_tree(n);
+            return Ok((Node::Empty, true));
+        }
+        let (new_n, removed) = match n {
+            Node::Empty => (n, false),
+            Node::Leaf(id) => {
+                let leaf = arena.leaf_mut(id);
+                if leaf.key.common_prefix(prefix) < prefix.len() {
+                    return Ok((n, false));
+                }
+                self.mark_dirty(&mut leaf.hash);
+                arena.free(n);
+                return Ok((Node::Empty, true));
+            }
+            Node::Branch(id) => {
+                let index = prefix.at(0);
+                let child = arena.branch(id).children[index];
+                let (new_child, removed) =
+                    self.remove_prefix_at(arena, child, &prefix.offset(1))?;
+                if removed {
+                    let branch = arena.branch_mut(id);
+                    self.mark_dirty(&mut branch.hash);
+                    branch.children[index] = new_child;
+                }
+                (n, removed)
+            }
+            Node::Extension(id) => {
+                let ext = arena.extension(id);
+                let match_len = prefix.common_prefix(&ext.prefix);
+                // The whole subtrie starts with the prefix.
+                if match_len == prefix.len() {
+                    return self.remove_prefix_at(arena, n, &prefix.offset(match_len));
+                }
+                if match_len < ext.prefix.len() {
+                    return Ok((n, false));
+                }
+                let child = ext.node;
+                let (new_child, removed) =
+                    self.remove_prefix_at(arena, child, &prefix.offset(match_len))?;
+                if removed {
+                    let ext = arena.extension_mut(id);
+                    self.mark_dirty(&mut ext.hash);
+                    ext.node = new_child;
+                }
+                (n, removed)
+            }
+            Node::Hash(id) => {
+                let hash = arena.hash_node(id).hash.clone();
+                let resolved = self.resolve_hash(arena, &hash)?;
+                let (new_n, removed) = self.remove_prefix_at(arena, resolved, prefix)?;
+                // An unchanged subtrie stays behind its hash node.
+                if !removed {
+                    arena.free_tree(resolved);
+                    return Ok((n, false));
+                }
+                arena.free(n);
+                (new_n, removed)
+            }
+        };
+
+        if removed {
+            Ok((self.degenerate(arena, new_n)?, removed))
+        } else {
+            Ok((new_n, removed))
+        }
+    }
+
+    // Deletes "partials", sorted and without duplicates, below "n". Returns
+    // the new node and the number of deleted keys.
+    fn delete_many_at(
+        &self,
+        arena: &mut NodeArena,
+        n: Node,
+        mut partials: Vec<Nibbles>,
+    ) -> TrieResult<(Node, usize)> {
+        match partials.len() {
+            0 => return Ok((n, 0)),
+            1 => {
+                let (n, deleted) = self.delete_at(arena, n, &partials.pop().unwrap())?;
+                return Ok((n, deleted.is_some() as usize));
+            }
+            _ => {}
+        }
+        let (new_n, deleted) = match n {
+            Node::Empty => (Node::Empty, 0),
+            Node::Leaf(id) => {
+                let leaf = arena.leaf_mut(id);
+                if partials
+                    .binary_search_by(|p| p.get_data().cmp(leaf.key.get_data()))
+                    .is_err()
+                {
+                    return Ok((n, 0));
+                }
+                self.mark_dirty(&mut leaf.hash);
+                arena.free(n);
+                return Ok((Node::Empty, 1));
+            }
+            Node::Branch(id) => {
+                let mut groups: Vec<Vec<Nibbles>> = (0..16).map(|_| vec![]).collect();
+                let mut deleted = 0;
+                for partial in partials.into_iter() {
+                    match partial.at(0) {
+                        16 => {
+                            if arena.branch_mut(id).value.take().is_some() {
+                                deleted += 1;
+                            }
+                        }
+                        i => groups[i].push(partial.offset(1)),
+                    }
+                }
+                for (index, group) in groups.into_iter().enumerate() {
+                    let child = arena.branch(id).children[index];
+                    let (new_child, count) = self.delete_many_at(arena, child, group)?;
+                    if count > 0 {
+                        arena.branch_mut(id).children[index] = new_child;
+                        deleted += count;
+                    }
+                }
+                if deleted > 0 {
+                    let branch = arena.branch_mut(id);
+                    self.mark_dirty(&mut branch.hash);
+                    // Unlike single deletes, a batch can empty a branch.
+                    if branch.value.is_none() && branch.children.iter().all(|c| *c == Node::Empty) {
+                        arena.free(n);
+                        return Ok((Node::Empty, deleted));
+                    }
+                }
+                (n, deleted)
+            }
+            Node::Extension(id) => {
+                let ext = arena.extension(id);
+                let prefix_len = ext.prefix.len();
+                let child = ext.node;
+                // Only the keys below the extension can be present.
+                let below: Vec<Nibbles> = partials
+                    .into_iter()
+                    .filter(|p| p.common_prefix(&ext.prefix) == prefix_len)
+                    .map(|p| p.offset(prefix_len))
+                    .collect();
+                let (new_child, deleted) = self.delete_many_at(arena, child, below)?;
+                if deleted > 0 {
+                    let ext = arena.extension_mut(id);
+                    self.mark_dirty(&mut ext.hash);
+                    ext.node = new_child;
+                    if new_child == Node::Empty {
+                        arena.free(n);
+                        return Ok((Node::Empty, deleted));
+                    }
+                }
+                (n, deleted)
+            }
+            Node::Hash(id) => {
+                let hash = arena.hash_node(id).hash.clone();
+                let resolved = self.resolve_hash(arena, &hash)?;
+                let (new_n, deleted) = self.delete_many_at(arena, resolved, partials)?;
+                // An unchanged subtrie stays behind its hash node.
+                if deleted == 0 {
+                    arena.free_tree(resolved);
+                    return Ok((n, 0));
+                }
+                arena.free(n);
+                // The resolved node is collapsed already.
+                return Ok((new_n, deleted));
+            }
+        };
+
+        if deleted > 0 {
+            Ok((self.degenerate(arena, new_n)?, deleted))
+        } else {
+            Ok((new_n, deleted))
+        }
+    }
+
+    fn degenerate(&self, arena: &mut NodeArena, n: Node) -> TrieResult<Node> {
+        match n {
+            Node::Branch(id) => {
+                let branch = arena.branch_mut(id);
+
+                let mut used_indexs = vec![];
+                for (index, node) in branch.children.iter().enumerate() {
+                    match node {
+                        Node::Empty => continue,
+                        _ => used_indexs.push(index),
+                    }
+                }
+
+                // if only a value node, transmute to leaf.
+                if used_indexs.is_empty() && branch.value.is_some() {
+                    let key = Nibbles::from_raw([].to_vec(), true);
+                    let value = branch.value.take().unwrap();
+                    arena.free(n);
+                    Ok(arena.new_leaf(key, value))
+                // if only one node. make an extension.
+                } else if used_indexs.len() == 1 && branch.value.is_none() {
+                    let used_index = used_indexs[0];
+                    let child = branch.children[used_index];
+                    arena.free(n);
+
+                    let new_node =
+                        arena.new_extension(Nibbles::from_hex(vec![used_index as u8]), child);
+                    self.degenerate(arena, new_node)
+                } else {
+                    Ok(n)
+                }
+            }
+            Node::Extension(id) => {
+                let child = arena.extension(id).node;
+                match child {
+                    // The child is merged into the extension.
+                    Node::Extension(sub_id) => {
+                        let sub_ext = arena.extension_mut(sub_id);
+                        self.mark_dirty(&mut sub_ext.hash);
+
+                        let sub_prefix =
+                            mem::replace(&mut sub_ext.prefix, Nibbles::from_hex(vec![]));
+                        let sub_node = sub_ext.node;
+                        arena.free(child);
+
+                        let ext = arena.extension_mut(id);
+                        self.mark_dirty(&mut ext.hash);
+                        ext.prefix = ext.prefix.join(&sub_prefix);
+                        ext.node = sub_node;
+                        self.degenerate(arena, n)
+                    }
+                    // The extension is merged into the leaf.
+                    Node::Leaf(leaf_id) => {
+                        let prefix = mem::replace(
+                            &mut arena.extension_mut(id).prefix,
+                            Nibbles::from_hex(vec![]),
+                        );
+                        arena.free(n);
+
+                        let leaf = arena.leaf_mut(leaf_id);
+                        self.mark_dirty(&mut leaf.hash);
+                        leaf.key = prefix.join(&leaf.key);
+                        Ok(child)
+                    }
+                    // try again after recovering node from the db.
+                    Node::Hash(hash_id) => {
+                        let hash = arena.hash_node(hash_id).hash.clone();
+                        let new_node = self.resolve_hash(arena, &hash)?;
+                        arena.free(child);
+
+                        arena.extension_mut(id).node = new_node;
+                        self.degenerate(arena, n)
+                    }
+                    _ => Ok(n),
+                }
+            }
+            _ => Ok(n),
+        }
+    }
+
+    // Applies "updates" to the subtrie "n" in order, see "removes" for when
+    // a value removes the key. Returns the new subtrie, also when an update failed.
+    #[cfg(feature = "parallel")]
+    fn update_at(
+        &self,
+        arena: &mut NodeArena,
+        mut n: Node,
+        updates: Vec<(Nibbles, Vec<u8>)>,
+    ) -> (Node, TrieResult<()>) {
+        for (partial, value) in updates {
+            let updated = if self.removes(&value) {
+                self.delete_at(arena, n, &partial).map(|(n, _)| n)
+            } else {
+                self.insert_at(arena, n, partial, value).map(|(n, _)| n)
+            };
+            match updated {
+                Ok(updated) => n = updated,
+                Err(e) => return (n, Err(e)),
+            }
+        }
+        (n, Ok(()))
+    }
+
+    // Once a node is modified or merged into another node, the DB entry under
+    // its old hash may no longer be needed.
+    fn mark_dirty(&self, hash: &mut Option<Vec<u8>>) {
+        if let Some(hash) = hash.take() {
+            self.journal.borrow_mut().removed(hash);
+        }
+    }
+
+    // Get nodes path along the key, only the nodes whose encode length is greater than
+    // hash length are added.
+    // For embedded nodes whose data are already contained in their parent node, we don't need to
+    // add them in the path.
+    // In the code below, we only add the nodes get by `get_node_from_hash`, because they contains
+    // all data stored in db, including nodes whose encoded data is less than hash length.
+    fn get_path_at(
+        &self,
+        arena: &mut NodeArena,
+        n: Node,
+        partial: &Nibbles,
+    ) -> TrieResult<Vec<Node>> {
+        match n {
+            Node::Empty | Node::Leaf(_) => Ok(vec![]),
+            Node::Branch(id) => {
+                let branch = arena.branch(id);
+
+                if partial.is_empty() || partial.at(0) == 16 {
+                    Ok(vec![])
+                } else {
+                    let child = branch.children[partial.at(0)];
+                    self.get_path_at(arena, child, &partial.offset(1))
+                }
+            }
+            Node::Extension(id) => {
+                let ext = arena.extension(id);
+
+                let prefix = &ext.prefix;
+                let match_len = partial.common_prefix(prefix);
+
+                if match_len == prefix.len() {
+                    let child = ext.node;
+                    self.get_path_at(arena, child, &partial.offset(match_len))
+                } else {
+                    Ok(vec![])
+                }
+            }
+            Node::Hash(id) => {
+                let hash = arena.hash_node(id).hash.clone();
+                let n = self.recover_from_db(arena, &hash)?;
+                let mut rest = self.get_path_at(arena, n, partial)?;
+                rest.push(n);
+                Ok(rest)
+            }
+        }
+    }
+
+    pub(crate) fn db(&self) -> &Arc<D> {
+        &self.db
+    }
+
+    // The root hash of the last commit.
+    pub(crate) fn root_hash(&self) -> &[u8] {
+        &self.root_hash
+    }
+
+    /// Returns the hash of the first node on the path of "key" that is absent from the DB.
+    #[cfg(feature = "async")]
+    pub(crate) fn missing_node(&self, key: &[u8]) -> TrieResult<Option<Vec<u8>>> {
+        let mut arena = self.arena.borrow_mut();
+        self.missing_node_at(
+            &mut arena,
+            self.root,
+            &Nibbles::from_raw(key.to_vec(), true),
+        )
+    }
+
+    #[cfg(feature = "async")]
+    fn missing_node_at(
+        &self,
+        arena: &mut NodeArena,
+        n: Node,
+        partial: &Nibbles,
+    ) -> TrieResult<Option<Vec<u8>>> {
+        match n {
+            Node::Empty | Node::Leaf(_) => Ok(None),
+            Node::Branch(id) => {
+                let branch = arena.branch(id);
+
+                if partial.is_empty() || partial.at(0) == 16 {
+                    Ok(None)
+                } else {
+                    let child = branch.children[partial.at(0)];
+                    self.missing_node_at(arena, child, &partial.offset(1))
+                }
+            }
+            Node::Extension(id) => {
+                let ext = arena.extension(id);
+
+                let prefix = &ext.prefix;
+                let match_len = partial.common_prefix(prefix);
+                if match_len == prefix.len() {
+                    let child = ext.node;
+                    self.missing_node_at(arena, child, &partial.offset(match_len))
+                } else {
+                    Ok(None)
+                }
+            }
+            Node::Hash(id) => {
+                let hash = arena.hash_node(id).hash.clone();
+                match self
+                    .db
+                    .get(&hash)
+                    .map_err(|e| TrieError::DB(e.to_string()))?
+                {
+                    Some(data) => {
+                        self.check_node(&hash, &data)?;
+                        let node = self.codec.decode_at(&data, lookup_index(partial))?;
+                        let n = self.node_from_data(arena, node)?;
+                        let missing = self.missing_node_at(arena, n, partial);
+                        arena.free_tree(n);
+                        missing
+                    }
+                    None => Ok(Some(hash)),
+                }
+            }
+        }
+    }
+
+    fn commit(&mut self) -> TrieResult<Vec<u8>> {
+        // The stale nodes are removed, a rollback could not return to them.
+        if !self.transactions.is_empty() {
+            return Err(TrieError::TransactionOpen);
+        }
+        match self.config.flat.clone() {
+            Some(name) => self.commit_flat(&name),
+            None => self.commit_nodes(),
+        }
+    }
+
+    // Whether the trie holds no change since the last commit.
+    pub(crate) fn is_committed(&self) -> bool {
+        let arena = self.arena.borrow();
+        let hash = match self.root {
+            Node::Hash(id) => Some(&arena.hash_node(id).hash),
+            n => arena.node_hash(n),
+        };
+        hash == Some(&self.root_hash)
+    }
+
+    pub(crate) fn commit_nodes(&mut self) -> TrieResult<Vec<u8>> {
+        let mut arena = self.arena.borrow_mut();
+        // Written nodes are looked up in the net changes.
+        self.journal.borrow_mut().compact();
+        let journal_guard = self.journal.borrow();
+        let (db, journal, old_root) = (&*self.db, &*journal_guard, &self.root_hash);
+        let write = |nodes: Vec<(Vec<u8>, Vec<u8>)>| {
+            let mut keys = Vec::with_capacity(nodes.len());
+            let mut values = Vec::with_capacity(nodes.len());
+            for (k, v) in nodes.into_iter() {
+                // A regenerated node that was loaded from the db doesn't need to be written again.
+                if (k == *old_root || journal.is_removed(&k))
+                    && db.contains(&k).map_err(|e| TrieError::DB(e.to_string()))?
+                {
+                    continue;
+                }
+                keys.push(k);
+                values.push(v);
+            }
+            db.insert_batch(keys, values)
+                .map_err(|e| TrieError::DB(e.to_string()))
+        };
+
+        // Nodes encoded since the last commit, e.g. by "get_proof".
+        let mut cache = self.cache.borrow_mut();
+        if self.config.hash_only {
+            // The nodes of earlier candidate roots may have changed since.
+            let mut live = HashSet::new();
+            live_hashes(&arena, self.root, &mut live);
+            cache.retain(|k, _| live.contains(k));
+        }
+        write(cache.drain().collect())?;
+        drop(cache);
+
+        // The encoded nodes are written in chunks while the trie is encoded,
+        // so they are never all held in memory.
+        let mut encoder = NodeEncoder::new(&*self.hasher, &*self.codec);
+        encoder.sink = Some(&write);
+        let mut sources = vec![];
+        let root_hash = self.encode_root(&arena, &mut encoder, &mut sources)?;
+        encoder.flush()?;
+        let NodeEncoder { hashed, .. } = encoder;
+        drop(journal_guard);
+        self.keep_written(&mut arena, hashed, vec![], &sources);
+
+        let changes = self.journal.borrow_mut().changeset();
+        if self.records_reverse_diff(&self.root_hash, &root_hash) {
+            let diff = reverse_diff(&*self.db, &self.root_hash, &changes.removed)?;
+            self.db
+                .insert(reverse_diff_key(&root_hash), diff)
+                .map_err(|e| TrieError::DB(e.to_string()))?;
+        }
+        let stale = if self.config.archive {
+            vec![]
+        } else if self.config.ref_counting {
+            let deltas = self.journal.borrow_mut().deltas();
+            let writes = ref_count_writes(&*self.db, &deltas)?;
+            let (keys, values) = writes.counts.into_iter().unzip();
+            self.db
+                .insert_batch(keys, values)
+                .map_err(|e| TrieError::DB(e.to_string()))?;
+            writes.removed
+        } else {
+            let stale = stale_nodes(
+                self.window.as_mut(),
+                self.config.defer_deletes,
+                &mut self.pending_deletes,
+                &changes,
+            );
+            if let Some(window) = &self.window {
+                self.db
+                    .insert(PRUNING_WINDOW_KEY.to_vec(), window.encode())
+                    .map_err(|e| TrieError::DB(e.to_string()))?;
+            }
+            stale
+        };
+        self.remove_stale(&stale)?;
+        self.db.flush().map_err(|e| TrieError::DB(e.to_string()))?;
+
+        self.root_hash = root_hash.to_vec();
+        self.journal.borrow_mut().clear();
+        self.last_changeset = changes;
+        // The tree is reloaded from the root, so all slots can be reused.
+        arena.clear();
+        self.root = self.resolve_hash(&mut arena, &root_hash)?;
+        Ok(root_hash)
+    }
+
+    // Like "commit", but the encoded nodes are kept until the next commit and
+    // the tree stays in memory.
+    fn hash_root(&mut self) -> TrieResult<Vec<u8>> {
+        let mut arena = self.arena.borrow_mut();
+        let mut encoder = NodeEncoder::new(&*self.hasher, &*self.codec);
+        let mut sources = vec![];
+        let root_hash = self.encode_root(&arena, &mut encoder, &mut sources)?;
+        let NodeEncoder {
+            hashed, written, ..
+        } = encoder;
+        self.keep_written(&mut arena, hashed, written, &sources);
+        self.root_hash = root_hash.clone();
+        Ok(root_hash)
+    }
+
+    // Returns the hash of the root, which is referenced by hash even if it
+    // is shorter than one.
+    fn encode_root(
+        &self,
+        arena: &NodeArena,
+        encoder: &mut NodeEncoder<H, C>,
+        sources: &mut Vec<Node>,
+    ) -> TrieResult<Vec<u8>> {
+        // The subtrees below the top branch are independent and large enough
+        // to be worth a task each.
+        #[cfg(feature = "parallel")]
+        {
+            encoder.parallel = true;
+        }
+        let encoded = encoder.encode_node(DetachedNode::from_node(arena, self.root, sources))?;
+        if encoded.len() < H::LENGTH {
+            let hash = self.hasher.digest(&encoded);
+            encoder.written.push((hash.clone(), encoded));
+            Ok(hash)
+        } else {
+            Ok(encoded)
+        }
+    }
+
+    /// Does the in-memory part of a commit, the caller writes and removes the
+    /// returned nodes. The tree is dropped, it is loaded from the root again
+    /// once it is used, so the nodes must be written by then.
+    pub(crate) fn prepare_commit(&mut self) -> TrieResult<PreparedCommit> {
+        if !self.transactions.is_empty() {
+            return Err(TrieError::TransactionOpen);
+        }
+        let prev_root = self.root_hash.clone();
+        let root_hash = self.hash_root()?;
+        let mut arena = self.arena.borrow_mut();
+        // The nodes of earlier candidate roots may have changed since. A root
+        // shorter than a hash does not keep its hash.
+        let mut live = HashSet::new();
+        live_hashes(&arena, self.root, &mut live);
+        let mut nodes: Vec<(Vec<u8>, Vec<u8>)> = self
+            .cache
+            .borrow_mut()
+            .drain()
+            .filter(|(k, _)| live.contains(k) || *k == root_hash)
+            .collect();
+
+        let changes = self.journal.borrow_mut().changeset();
+        if self.records_reverse_diff(&prev_root, &root_hash) {
+            let diff = reverse_diff(&*self.db, &prev_root, &changes.removed)?;
+            nodes.push((reverse_diff_key(&root_hash), diff));
+        }
+        // Counted nodes are removed by the writer, once it has read the counts.
+        let (removed, ref_deltas) = if self.config.archive {
+            (vec![], vec![])
+        } else if self.config.ref_counting {
+            (vec![], self.journal.borrow_mut().deltas())
+        } else {
+            let stale = stale_nodes(
+                self.window.as_mut(),
+                self.config.defer_deletes,
+                &mut self.pending_deletes,
+                &changes,
+            );
+            if let Some(window) = &self.window {
+                nodes.push((PRUNING_WINDOW_KEY.to_vec(), window.encode()));
+            }
+            (stale, vec![])
+        };
+        self.forget_nodes(&removed);
+        self.journal.borrow_mut().clear();
+        self.last_changeset = changes;
+
+        arena.clear();
+        self.root = arena.new_hash(root_hash.clone());
+        Ok(PreparedCommit {
+            root_hash,
+            nodes,
+            removed,
+            ref_deltas,
+        })
+    }
+
+    // Whether the commit from "prev_root" to "root_hash" records a reverse
+    // diff. A commit that changes nothing would make the root its own parent.
+    fn records_reverse_diff(&self, prev_root: &[u8], root_hash: &[u8]) -> bool {
+        self.config.reverse_diffs
+            && !self.config.hash_only
+            && !self.config.ref_counting
+            && prev_root != root_hash
+    }
+
+    // Copies the subtrie under "prefix" from "arena" to "to", with the prefix
+    // stripped from its keys.
+    fn copy_under(
+        &self,
+        arena: &NodeArena,
+        n: Node,
+        prefix: &Nibbles,
+        to: &mut NodeArena,
+    ) -> TrieResult<Node> {
+        if prefix.is_empty() {
+            return Ok(arena.copy_tree(n, to));
+        }
+        match n {
+            Node::Empty => Ok(Node::Empty),
+            Node::Leaf(id) => {
+                let leaf = arena.leaf(id);
+                if leaf.key.common_prefix(prefix) < prefix.len() {
+                    return Ok(Node::Empty);
+                }
+                Ok(to.new_leaf(leaf.key.offset(prefix.len()), leaf.value.clone()))
+            }
+            Node::Branch(id) => {
+                let child = arena.branch(id).children[prefix.at(0)];
+                self.copy_under(arena, child, &prefix.offset(1), to)
+            }
+            Node::Extension(id) => {
+                let ext = arena.extension(id);
+                let match_len = prefix.common_prefix(&ext.prefix);
+                if match_len == prefix.len() {
+                    let child = arena.copy_tree(ext.node, to);
+                    if match_len == ext.prefix.len() {
+                        return Ok(child);
+                    }
+                    return Ok(to.new_extension(ext.prefix.offset(match_len), child));
+                }
+                if match_len < ext.prefix.len() {
+                    return Ok(Node::Empty);
+                }
+                self.copy_under(arena, ext.node, &prefix.offset(match_len), to)
+            }
+            Node::Hash(id) => {
+                let mut scratch = NodeArena::new();
+                let resolved = self.resolve_hash(&mut scratch, &arena.hash_node(id).hash)?;
+                self.copy_under(&scratch, resolved, prefix, to)
+            }
+        }
+    }
+
+    // Visits the values that differ between "a" and "b", which are at "path"
+    // in nibbles, see "diff_roots". Returns true once "visit" did.
+    fn diff_at(
+        &self,
+        a: Subtrie,
+        b: Subtrie,
+        path: &mut Vec<u8>,
+        visit: &mut DiffVisitor,
+    ) -> TrieResult<bool> {
+        match (&a, &b) {
+            (Subtrie::Ref(a), Subtrie::Ref(b)) if a == b => return Ok(false),
+            (Subtrie::Missing, Subtrie::Missing) => return Ok(false),
+            _ => {}
+        }
+        let (a, b) = (self.load_subtrie(a)?, self.load_subtrie(b)?);
+        if a == b {
+            return Ok(false);
+        }
+        let (value_a, children_a) = split_node(a)?;
+        let (value_b, children_b) = split_node(b)?;
+        if value_a != value_b {
+            let key = Nibbles::from_hex(path.clone()).encode_raw().0;
+            if visit(&key, value_a, value_b) {
+                return Ok(true);
+            }
+        }
+        for (i, (a, b)) in children_a.into_iter().zip(children_b).enumerate() {
+            path.push(i as u8);
+            let stopped = self.diff_at(a, b, path, visit)?;
+            path.pop();
+            if stopped {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    // A missing node is empty, as in "resolve_hash", unless strict.
+    fn load_subtrie(&self, subtrie: Subtrie) -> TrieResult<NodeData> {
+        match subtrie {
+            Subtrie::Missing => Ok(NodeData::Empty),
+            Subtrie::Short(path, item) => Ok(NodeData::Short { path, item }),
+            Subtrie::Ref(ChildReference::Inline(data)) => self.codec.decode(&data),
+            Subtrie::Ref(ChildReference::Hash(hash)) => {
+                match self
+                    .db
+                    .get(&hash)
+                    .map_err(|e| TrieError::DB(e.to_string()))?
+                {
+                    Some(data) => {
+                        self.check_node(&hash, &data)?;
+                        self.codec.decode(&data)
+                    }
+                    None => self.missing_node_data(&hash),
+                }
+            }
+        }
+    }
+
+    fn remove_stale(&self, keys: &[Vec<u8>]) -> TrieResult<()> {
+        self.db
+            .remove_batch(keys)
+            .map_err(|e| TrieError::DB(e.to_string()))?;
+        self.forget_nodes(keys);
+        Ok(())
+    }
+
+    // Drops removed nodes from the node caches.
+    fn forget_nodes(&self, keys: &[Vec<u8>]) {
+        let mut node_cache = self.node_cache.borrow_mut();
+        for key in keys.iter() {
+            node_cache.remove(key);
+            if let Some(shared_cache) = &self.shared_cache {
+                shared_cache.remove(key);
+            }
+        }
+    }
+
+    fn encode_raw(&self, arena: &mut NodeArena, n: Node) -> TrieResult<Vec<u8>> {
+        let mut encoder = NodeEncoder::new(&*self.hasher, &*self.codec);
+        let mut sources = vec![];
+        let data = encoder.encode_raw(DetachedNode::expand(arena, n, &mut sources))?;
+        self.keep_written(arena, encoder.hashed, encoder.written, &sources);
+        Ok(data)
+    }
+
+    // Keeps the nodes referenced by hash until the next commit, and stores
+    // their hashes in the nodes they were encoded from.
+    fn keep_written(
+        &self,
+        arena: &mut NodeArena,
+        hashed: Vec<(usize, Vec<u8>)>,
+        written: Vec<(Vec<u8>, Vec<u8>)>,
+        sources: &[Node],
+    ) {
+        let mut journal = self.journal.borrow_mut();
+        for (id, hash) in hashed.into_iter() {
+            arena.set_node_hash(sources[id], hash.clone());
+            journal.inserted(hash);
+        }
+        self.cache.borrow_mut().extend(written);
+    }
+
+    fn decode_node(&self, arena: &mut NodeArena, data: &[u8]) -> TrieResult<Node> {
+        self.node_from_data(arena, self.codec.decode(data)?)
+    }
+
+    fn node_from_data(&self, arena: &mut NodeArena, node: NodeData) -> TrieResult<Node> {
+        match node {
+            NodeData::Empty => Ok(Node::Empty),
+            // A short node is a leaf if it holds a value, an extension if it
+            // holds a child.
+            NodeData::Short {
+                path,
+                item: ShortItem::Value(value),
+            } => {
+                let mut hex = path;
+                hex.push(16);
+                Ok(arena.new_leaf(Nibbles::from_hex(hex), Arc::new(value)))
+            }
+            NodeData::Short {
+                path,
+                item: ShortItem::Child(child),
+            } => {
+                if path.is_empty() {
+                    return Err(TrieError::InvalidData);
+                }
+                let n = self.decode_child(arena, child)?;
+                Ok(arena.new_extension(Nibbles::from_hex(path), n))
+            }
+            NodeData::Branch { children, value } => {
+                let mut nodes = empty_children();
+                for (i, child) in children.iter().enumerate() {
+                    if let Some(child) = child {
+                        nodes[i] = self.decode_child(arena, child.clone())?;
+                    }
+                }
+                Ok(arena.new_branch(nodes, value.map(Arc::new)))
+            }
+        }
+    }
+
+    fn decode_child(&self, arena: &mut NodeArena, child: ChildReference) -> TrieResult<Node> {
+        match child {
+            ChildReference::Hash(hash) => {
+                if hash.len() == H::LENGTH {
+                    Ok(arena.new_hash(hash))
+                } else {
+                    Err(TrieError::InvalidData)
+                }
+            }
+            ChildReference::Inline(data) => {
+                if data.len() >= H::LENGTH {
+                    return Err(TrieError::InvalidData);
+                }
+                self.decode_node(arena, &data)
+            }
+        }
+    }
+
+    // Loads the nodes under "keys", e.g. all hash children of a branch, with
+    // a single batch read.
+    fn resolve_children(&self, arena: &mut NodeArena, keys: &[Vec<u8>]) -> TrieResult<Vec<Node>> {
+        let values = self
+            .db
+            .get_batch(keys)
+            .map_err(|e| TrieError::DB(e.to_string()))?;
+        values
+            .into_iter()
+            .zip(keys)
+            .map(|(value, key)| match value {
+                Some(data) => {
+                    self.check_node(key, &data)?;
+                    self.decode_node(arena, &data)
+                }
+                None => self.missing_node_data(key).map(|_| Node::Empty),
+            })
+            .collect()
+    }
+
+    // Collects the values below "n" of the committed trie, skipping the
+    // subtries "changed_values" found unchanged at the same path.
+    fn committed_values_at(
+        &self,
+        arena: &mut NodeArena,
+        n: Node,
+        path: &mut Nibbles,
+        unchanged: &HashSet<(Vec<u8>, Vec<u8>)>,
+        values: &mut BTreeMap<Vec<u8>, Vec<u8>>,
+    ) -> TrieResult<()> {
+        match n {
+            Node::Empty => {}
+            Node::Leaf(id) => {
+                let leaf = arena.leaf(id);
+                values.insert(path.join(&leaf.key).encode_raw().0, leaf.value.to_vec());
+            }
+            Node::Branch(id) => {
+                if let Some(value) = &arena.branch(id).value {
+                    values.insert(path.encode_raw().0, value.to_vec());
+                }
+                for i in 0..16 {
+                    let child = arena.branch(id).children[i];
+                    path.push(i as u8);
+                    self.committed_values_at(arena, child, path, unchanged, values)?;
+                    path.pop();
+                }
+            }
+            Node::Extension(id) => {
+                let ext = arena.extension(id);
+                let (len, child) = (path.len(), ext.node);
+                path.extend(&ext.prefix);
+                self.committed_values_at(arena, child, path, unchanged, values)?;
+                path.truncate(len);
+            }
+            Node::Hash(id) => {
+                let hash = arena.hash_node(id).hash.clone();
+                if unchanged.contains(&(path.get_data().to_vec(), hash.clone())) {
+                    return Ok(());
+                }
+                let n = self.recover_committed(arena, &hash)?;
+                let result = self.committed_values_at(arena, n, path, unchanged, values);
+                arena.free_tree(n);
+                result?;
+            }
+        }
+        Ok(())
+    }
+
+    // Like "recover_from_db", but also finds the nodes of a root that
+    // "with_hash_only_commits" computed, which are not written yet.
+    fn recover_committed(&self, arena: &mut NodeArena, hash: &[u8]) -> TrieResult<Node> {
+        let data = self.cache.borrow().get(hash).cloned();
+        match data {
+            Some(data) => self.decode_node(arena, &data),
+            None => self.recover_from_db(arena, hash),
+        }
+    }
+
+    // Records the nodes below "n" as stale, loading the subtries that were
+    // committed.
+    fn forget_tree_at(&self, arena: &mut NodeArena, n: Node) -> TrieResult<()> {
+        match n {
+            Node::Empty => {}
+            Node::Leaf(id) => self.mark_dirty(&mut arena.leaf_mut(id).hash),
+            Node::Extension(id) => {
+                self.mark_dirty(&mut arena.extension_mut(id).hash);
+                let child = arena.extension(id).node;
+                self.forget_tree_at(arena, child)?;
+            }
+            Node::Branch(id) => {
+                self.mark_dirty(&mut arena.branch_mut(id).hash);
+                for i in 0..16 {
+                    let child = arena.branch(id).children[i];
+                    self.forget_tree_at(arena, child)?;
+                }
+            }
+            Node::Hash(id) => {
+                let hash = arena.hash_node(id).hash.clone();
+                let loaded = self.recover_committed(arena, &hash)?;
+                self.journal.borrow_mut().removed(hash);
+                let result = self.forget_tree_at(arena, loaded);
+                arena.free_tree(loaded);
+                result?;
+            }
+        }
+        Ok(())
+    }
+
+    // Replaces the hash nodes below "n" whose nodes are in "cache" with the
+    // nodes, which keep their hash.
+    fn expand_cached(&self, arena: &mut NodeArena, n: Node) -> TrieResult<Node> {
+        match n {
+            Node::Hash(id) => {
+                let hash = arena.hash_node(id).hash.clone();
+                let data = self.cache.borrow().get(&hash).cloned();
+                match data {
+                    Some(data) => {
+                        arena.free(n);
+                        let loaded = self.decode_node(arena, &data)?;
+                        arena.set_node_hash(loaded, hash);
+                        self.expand_cached(arena, loaded)
+                    }
+                    None => Ok(n),
+                }
+            }
+            Node::Branch(id) => {
+                for i in 0..16 {
+                    let child = arena.branch(id).children[i];
+                    arena.branch_mut(id).children[i] = self.expand_cached(arena, child)?;
+                }
+                Ok(n)
+            }
+            Node::Extension(id) => {
+                let child = arena.extension(id).node;
+                arena.extension_mut(id).node = self.expand_cached(arena, child)?;
+                Ok(n)
+            }
+            Node::Empty | Node::Leaf(_) => Ok(n),
+        }
+    }
+
+    // Loads the node that replaces a hash node in the tree. It keeps its hash
+    // until it is modified, so unchanged subtries are not encoded again.
+    fn resolve_hash(&self, arena: &mut NodeArena, hash: &[u8]) -> TrieResult<Node> {
+        let n = self.recover_from_db(arena, hash)?;
+        arena.set_node_hash(n, hash.to_vec());
+        Ok(n)
+    }
+
+    // Like "recover_from_db", but of a branch only the child at "index" is
+    // decoded. Only for lookups, the node must not be modified.
+    fn recover_at(&self, arena: &mut NodeArena, key: &[u8], index: usize) -> TrieResult<Node> {
+        // The node caches keep whole nodes.
+        if self.node_cache.borrow().capacity() > 0 || self.shared_cache.is_some() {
+            return self.recover_from_db(arena, key);
+        }
+        match self.db.get(key).map_err(|e| TrieError::DB(e.to_string()))? {
+            Some(data) => {
+                self.check_node(key, &data)?;
+                self.node_from_data(arena, self.codec.decode_at(&data, index)?)
+            }
+            None => self.missing_node_data(key).map(|_| Node::Empty),
+        }
+    }
+
+    // The node of a hash the DB lacks, empty unless "strict" is set.
+    fn missing_node_data(&self, hash: &[u8]) -> TrieResult<NodeData> {
+        if self.config.strict {
+            return Err(TrieError::MissingNode {
+                hash: hash.to_vec(),
+            });
+        }
+        Ok(NodeData::Empty)
+    }
+
+    // Fails if "data", read under "hash", does not hash to it and nodes are
+    // verified.
+    fn check_node(&self, hash: &[u8], data: &[u8]) -> TrieResult<()> {
+        if self.config.verify_nodes && self.hasher.digest(data) != hash {
+            return Err(TrieError::CorruptNode {
+                hash: hash.to_vec(),
+            });
+        }
+        Ok(())
+    }
+
+    fn recover_from_db(&self, arena: &mut NodeArena, key: &[u8]) -> TrieResult<Node> {
+        let cached = self.node_cache.borrow_mut().get(key).cloned();
+        if let Some(node) = cached {
+            return self.node_from_data(arena, node);
+        }
+        let shared = self.shared_cache.as_ref().and_then(|cache| cache.get(key));
+        let node = match shared {
+            Some(node) => node,
+            None => match self.db.get(key).map_err(|e| TrieError::DB(e.to_string()))? {
+                Some(value) => {
+                    self.check_node(key, &value)?;
+                    let node = self.codec.decode(&value)?;
+                    if let Some(shared_cache) = &self.shared_cache {
+                        shared_cache.insert(key.to_vec(), node.clone());
+                    }
+                    node
+                }
+                None => return self.missing_node_data(key).map(|_| Node::Empty),
+            },
+        };
+
+        let mut node_cache = self.node_cache.borrow_mut();
+        if node_cache.capacity() > 0 {
+            node_cache.insert(key.to_vec(), node.clone());
+        }
+        drop(node_cache);
+        self.node_from_data(arena, node)
+    }
+}
+
+// An in-memory node copied out of the arena, so that it can be encoded
+// without the trie, e.g. on another thread.
+// Copies of nodes carry the index of their source node in "sources".
+enum DetachedNode {
+    Empty,
+    Leaf(usize, Nibbles, Vec<u8>),
+    Extension(usize, Nibbles, Box<DetachedNode>),
+    Branch(usize, Vec<DetachedNode>, Option<Vec<u8>>),
+    Hash(Vec<u8>),
+}
+
+impl DetachedNode {
+    // Nodes whose hash is known are not copied.
+    fn from_node(arena: &NodeArena, n: Node, sources: &mut Vec<Node>) -> Self {
+        match arena.node_hash(n) {
+            Some(hash) => DetachedNode::Hash(hash.clone()),
+            None => DetachedNode::expand(arena, n, sources),
+        }
+    }
+
+    fn expand(arena: &NodeArena, n: Node, sources: &mut Vec<Node>) -> Self {
+        let id = sources.len();
+        match n {
+            Node::Empty => DetachedNode::Empty,
+            Node::Leaf(leaf_id) => {
+                let leaf = arena.leaf(leaf_id);
+                sources.push(n);
+                DetachedNode::Leaf(id, leaf.key.clone(), leaf.value.to_vec())
+            }
+            Node::Extension(ext_id) => {
+                let ext = arena.extension(ext_id);
+                sources.push(n);
+                let child = DetachedNode::from_node(arena, ext.node, sources);
+                DetachedNode::Extension(id, ext.prefix.clone(), Box::new(child))
+            }
+            Node::Branch(branch_id) => {
+                let branch = arena.branch(branch_id);
+                sources.push(n);
+                let children = branch
+                    .children
+                    .iter()
+                    .map(|child| DetachedNode::from_node(arena, *child, sources))
+                    .collect();
+                DetachedNode::Branch(id, children, branch.value.as_ref().map(|v| v.to_vec()))
+            }
+            Node::Hash(hash_id) => DetachedNode::Hash(arena.hash_node(hash_id).hash.clone()),
+        }
+    }
+
+    fn id(&self) -> Option<usize> {
+        match self {
+            DetachedNode::Leaf(id, ..)
+            | DetachedNode::Extension(id, ..)
+            | DetachedNode::Branch(id, ..) => Some(*id),
+            DetachedNode::Empty | DetachedNode::Hash(_) => None,
+        }
+    }
+}
+
+// Counts the modified nodes below "n" and estimates their encoded size,
+// taking every child as a reference of "hash_len" bytes.
+fn pending_at(arena: &NodeArena, n: Node, hash_len: usize, stats: &mut PendingStats) {
+    if arena.node_hash(n).is_some() {
+        return;
+    }
+    // Two bytes for the list and string headers of short nodes.
+    stats.encoded_bytes += match n {
+        Node::Leaf(id) => {
+            let leaf = arena.leaf(id);
+            leaf.key.len() / 2 + leaf.value.len() + 2
+        }
+        Node::Extension(id) => {
+            let ext = arena.extension(id);
+            pending_at(arena, ext.node, hash_len, stats);
+            ext.prefix.len() / 2 + hash_len + 2
+        }
+        Node::Branch(id) => {
+            let branch = arena.branch(id);
+            for child in branch.children.iter() {
+                pending_at(arena, *child, hash_len, stats);
+            }
+            let children = branch.children.iter().filter(|c| **c != Node::Empty);
+            17 + children.count() * hash_len + branch.value.as_ref().map_or(0, |v| v.len())
+        }
+        Node::Empty | Node::Hash(_) => return,
+    };
+    stats.dirty_nodes += 1;
+}
+
+// Returns the stale nodes that the commit of "changes" removes now, see
+// "with_pruning_window". Deferred deletes wait in "pending" for
+// "flush_deletes" instead.
+fn stale_nodes(
+    window: Option<&mut PruningWindow>,
+    defer: bool,
+    pending: &mut HashSet<Vec<u8>>,
+    changes: &ChangeSet,
+) -> Vec<Vec<u8>> {
+    let expired;
+    let changes = match window {
+        Some(window) => {
+            expired = ChangeSet {
+                inserted: changes.inserted.clone(),
+                removed: window.advance(changes),
+            };
+            &expired
+        }
+        None => changes,
+    };
+    if !defer {
+        return changes.removed.clone();
+    }
+    // A pending node that this commit wrote again is live.
+    let inserted: HashSet<&Vec<u8>> = changes.inserted.iter().collect();
+    pending.retain(|k| !inserted.contains(k));
+    pending.extend(changes.removed.iter().cloned());
+    vec![]
+}
+
+// Collects the values below "n" that may have changed since the last commit.
+// The subtries whose hash is known are unchanged, they are only added to
+// "unchanged" with their path.
+fn changed_values(
+    arena: &NodeArena,
+    n: Node,
+    path: &mut Nibbles,
+    unchanged: &mut HashSet<(Vec<u8>, Vec<u8>)>,
+    values: &mut BTreeMap<Vec<u8>, Vec<u8>>,
+) {
+    let hash = match n {
+        Node::Hash(id) => Some(&arena.hash_node(id).hash),
+        _ => arena.node_hash(n),
+    };
+    if let Some(hash) = hash {
+        unchanged.insert((path.get_data().to_vec(), hash.clone()));
+        return;
+    }
+    match n {
+        Node::Empty | Node::Hash(_) => {}
+        Node::Leaf(id) => {
+            let leaf = arena.leaf(id);
+            values.insert(path.join(&leaf.key).encode_raw().0, leaf.value.to_vec());
+        }
+        Node::Branch(id) => {
+            let branch = arena.branch(id);
+            if let Some(value) = &branch.value {
+                values.insert(path.encode_raw().0, value.to_vec());
+            }
+            for (i, child) in branch.children.iter().enumerate() {
+                path.push(i as u8);
+                changed_values(arena, *child, path, unchanged, values);
+                path.pop();
+            }
+        }
+        Node::Extension(id) => {
+            let ext = arena.extension(id);
+            let len = path.len();
+            path.extend(&ext.prefix);
+            changed_values(arena, ext.node, path, unchanged, values);
+            path.truncate(len);
+        }
+    }
+}
+
+// Collects the hashes of the in-memory nodes below "n" that are known.
+fn live_hashes(arena: &NodeArena, n: Node, live: &mut HashSet<Vec<u8>>) {
+    if let Some(hash) = arena.node_hash(n) {
+        live.insert(hash.clone());
+    }
+    match n {
+        Node::Extension(id) => live_hashes(arena, arena.extension(id).node, live),
+        Node::Branch(id) => {
+            for child in arena.branch(id).children.iter() {
+                live_hashes(arena, *child, live);
+            }
+        }
+        _ => {}
+    }
+}
+
+// Receives a key and its differing values, returns true to stop.
+type DiffVisitor<'a> = dyn FnMut(&[u8], Option<Vec<u8>>, Option<Vec<u8>>) -> bool + 'a;
+
+// A subtrie that "same_content" compares.
+enum Subtrie {
+    Missing,
+    Ref(ChildReference),
+    Short(Vec<u8>, ShortItem),
+}
+
+// Splits "node" into its value and the subtries of its 16 children, a short
+// node is split as if it were a branch with a single child.
+fn split_node(node: NodeData) -> TrieResult<(Option<Vec<u8>>, Vec<Subtrie>)> {
+    let mut children: Vec<Subtrie> = (0..16).map(|_| Subtrie::Missing).collect();
+    match node {
+        NodeData::Empty => Ok((None, children)),
+        NodeData::Short { path, item } => {
+            let (first, rest) = match path.split_first() {
+                Some((first, rest)) => (*first as usize, rest.to_vec()),
+                None => match item {
+                    ShortItem::Value(value) => return Ok((Some(value), children)),
+                    ShortItem::Child(_) => return Err(TrieError::InvalidData),
+                },
+            };
+            if first >= 16 {
+                return Err(TrieError::InvalidData);
+            }
+            children[first] = match (rest.is_empty(), item) {
+                (true, ShortItem::Child(child)) => Subtrie::Ref(child),
+                (_, item) => Subtrie::Short(rest, item),
+            };
+            Ok((None, children))
+        }
+        NodeData::Branch {
+            children: refs,
+            value,
+        } => {
+            for (i, child) in refs.iter().enumerate() {
+                if let Some(child) = child {
+                    children[i] = Subtrie::Ref(child.clone());
+                }
+            }
+            Ok((value, children))
+        }
+    }
+}
+
+/// The DB writes of a commit, see "prepare_commit".
+pub(crate) struct PreparedCommit {
+    pub root_hash: Vec<u8>,
+    pub nodes: Vec<(Vec<u8>, Vec<u8>)>,
+    pub removed: Vec<Vec<u8>>,
+    /// The reference changes, if the trie counts them.
+    pub ref_deltas: Vec<(Vec<u8>, i32)>,
+}
+
+impl PreparedCommit {
+    /// Adds the writes of the reference counts in "db" to the commit.
+    #[cfg(feature = "tokio")]
+    pub fn count_refs<D: DB>(&mut self, db: &D) -> TrieResult<()> {
+        let deltas = mem::replace(&mut self.ref_deltas, vec![]);
+        let writes = ref_count_writes(db, &deltas)?;
+        self.nodes.extend(writes.counts);
+        self.removed.extend(writes.removed);
+        Ok(())
+    }
+}
+
+// Receives encoded nodes by hash, e.g. to write them to the DB.
+type NodeSink<'a> = dyn Fn(Vec<(Vec<u8>, Vec<u8>)>) -> TrieResult<()> + Sync + 'a;
+
+// Encoded nodes are handed to the sink in chunks of this size.
+const SINK_CHUNK: usize = 1024;
+
+// Encodes detached nodes, collecting the nodes that are referenced by hash
+// with the index of their source node.
+struct NodeEncoder<'a, H, C> {
+    hasher: &'a H,
+    codec: &'a C,
+    // The source node and hash of every node referenced by hash.
+    hashed: Vec<(usize, Vec<u8>)>,
+    // The encoded nodes referenced by hash that were not handed to the sink yet.
+    written: Vec<(Vec<u8>, Vec<u8>)>,
+    sink: Option<&'a NodeSink<'a>>,
+    // Every node is encoded here, then copied out once at its final size.
+    buffer: Vec<u8>,
+    // Whether the children of the next branch are encoded on the rayon pool.
+    #[cfg(feature = "parallel")]
+    parallel: bool,
+}
+
+impl<'a, H: Hasher, C: NodeCodec> NodeEncoder<'a, H, C> {
+    fn new(hasher: &'a H, codec: &'a C) -> Self {
+        NodeEncoder {
+            hasher,
+            codec,
+            hashed: vec![],
+            written: vec![],
+            sink: None,
+            buffer: vec![],
+            #[cfg(feature = "parallel")]
+            parallel: false,
+        }
+    }
+
+    // Hands the written nodes to the sink, if there is one.
+    fn flush(&mut self) -> TrieResult<()> {
+        if let Some(sink) = self.sink {
+            sink(mem::replace(&mut self.written, vec![]))?;
+        }
+        Ok(())
+    }
+
+    fn encode_node(&mut self, n: DetachedNode) -> TrieResult<Vec<u8>> {
+        // Returns the hash value directly to avoid double counting.
+        let id = match n {
+            DetachedNode::Hash(hash) => return Ok(hash),
+            ref n => n.id(),
+        };
+
+        self.encode_into_buffer(n)?;
+        // Nodes shorter than a hash are stored inside their parent,
+        // all other nodes are referenced by their hash.
+        // Parents tell the two apart by length, so this holds for any "H::LENGTH".
+        if self.buffer.len() < H::LENGTH {
+            Ok(self.buffer.clone())
+        } else {
+            let hash = self.hasher.digest(&self.buffer);
+            debug_assert_eq!(hash.len(), H::LENGTH, "digest length must be H::LENGTH");
+            if let Some(id) = id {
+                self.hashed.push((id, hash.clone()));
+            }
+            self.written.push((hash.clone(), self.buffer.clone()));
+            if self.written.len() >= SINK_CHUNK {
+                self.flush()?;
+            }
+            Ok(hash)
+        }
+    }
+
+    fn encode_raw(&mut self, n: DetachedNode) -> TrieResult<Vec<u8>> {
+        self.encode_into_buffer(n)?;
+        Ok(self.buffer.clone())
+    }
+
+    fn encode_into_buffer(&mut self, n: DetachedNode) -> TrieResult<()> {
+        let node = match n {
+            DetachedNode::Empty => NodeData::Empty,
+            DetachedNode::Leaf(_id, key, value) => {
+                // The terminator is implied by the value.
+                let hex = key.get_data();
+                let path = if key.is_leaf() {
+                    &hex[..hex.len() - 1]
+                } else {
+                    hex
+                };
+                NodeData::Short {
+                    path: path.to_vec(),
+                    item: ShortItem::Value(value),
+                }
+            }
+            DetachedNode::Branch(_id, nodes, value) => {
+                let mut children: [Option<ChildReference>; 16] = Default::default();
+                for (child, reference) in children.iter_mut().zip(self.child_references(nodes)?) {
+                    *child = reference;
+                }
+                NodeData::Branch { children, value }
+            }
+            DetachedNode::Extension(_id, prefix, child) => NodeData::Short {
+                path: prefix.get_data().to_vec(),
+                item: ShortItem::Child(self.child_reference(*child)?),
+            },
+            // A hash node only appears as a child reference, e.g. a root whose
+            // stored data is itself a hash is malformed.
+            DetachedNode::Hash(_hash) => return Err(TrieError::InvalidData),
+        };
+        // The children are encoded by now, so the buffer is free.
+        self.buffer.clear();
+        self.codec.encode_into(&node, &mut self.buffer);
+        Ok(())
+    }
+
+    fn child_reference(&mut self, n: DetachedNode) -> TrieResult<ChildReference> {
+        let data = self.encode_node(n)?;
+        if data.len() == H::LENGTH {
+            Ok(ChildReference::Hash(data))
+        } else {
+            Ok(ChildReference::Inline(data))
+        }
+    }
+
+    fn child_references(
+        &mut self,
+        nodes: Vec<DetachedNode>,
+    ) -> TrieResult<Vec<Option<ChildReference>>> {
+        #[cfg(feature = "parallel")]
+        {
+            if mem::replace(&mut self.parallel, false) {
+                return self.par_child_references(nodes);
+            }
+        }
+        nodes
+            .into_iter()
+            .map(|n| match n {
+                DetachedNode::Empty => Ok(None),
+                n => self.child_reference(n).map(Some),
+            })
+            .collect()
+    }
+
+    // Every subtree gets its own encoder, the nodes they did not hand to the
+    // sink are merged after.
+    #[cfg(feature = "parallel")]
+    fn par_child_references(
+        &mut self,
+        nodes: Vec<DetachedNode>,
+    ) -> TrieResult<Vec<Option<ChildReference>>> {
+        let (hasher, codec, sink) = (self.hasher, self.codec, self.sink);
+        let encoded: Vec<_> = nodes
+            .into_par_iter()
+            .map(|n| {
+                let mut encoder = NodeEncoder::new(hasher, codec);
+                encoder.sink = sink;
+                let reference = match n {
+                    DetachedNode::Empty => None,
+                    n => Some(encoder.child_reference(n)?),
+                };
+                Ok((reference, encoder.hashed, encoder.written))
+            })
+            .collect::<TrieResult<_>>()?;
+
+        let mut references = Vec::with_capacity(encoded.len());
+        for (reference, hashed, written) in encoded.into_iter() {
+            references.push(reference);
+            self.hashed.extend(hashed);
+            self.written.extend(written);
+        }
+        Ok(references)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::distributions::Alphanumeric;
+    use rand::seq::SliceRandom;
+    use rand::{thread_rng, Rng};
+    use std::collections::{HashMap, HashSet};
+    use std::sync::Arc;
+
+    use ethereum_types;
+    use hasher::{Hasher, HasherKeccak};
+    use uuid::Uuid;
+
+    use super::{get_at, PatriciaTrie, PendingStats, Trie, ValueRef};
+    use crate::cache::SharedNodeCache;
+    use crate::codec::{BinaryNodeCodec, RLPNodeCodec};
+    use crate::db::{MemoryDB, MeteredDB, DB};
+    use crate::errors::TrieError;
+    use crate::node::Node;
+
+    #[test]
+    fn test_trie_insert() {
+        let memdb = Arc::new(MemoryDB::new(true));
+        let mut trie = PatriciaTrie::new(memdb, Arc::new(HasherKeccak::new()));
+        trie.insert(b"test".to_vec(), b"test".to_vec()).unwrap();
+    }
+
+    #[test]
+    fn test_trie_get() {
+        let memdb = Arc::new(MemoryDB::new(true));
+        let mut trie = PatriciaTrie::new(memdb, Arc::new(HasherKeccak::new()));
+        trie.insert(b"test".to_vec(), b"test".to_vec()).unwrap();
+        let v = trie.get(b"test").unwrap();
+
+        assert_eq!(Some(b"test".to_vec()), v)
+    }
+
+    #[test]
+    fn test_trie_random_insert() {
+        let memdb = Arc::new(MemoryDB::new(true));
+        let mut trie = PatriciaTrie::new(memdb, Arc::new(HasherKeccak::new()));
+
+        for _ in 0..1000 {
+            let rand_str: String = thread_rng().sample_iter(&Alphanumeric).take(30).collect();
+            let val = rand_str.as_bytes();
+            trie.insert(val.to_vec(), val.to_vec()).unwrap();
+
+            let v = trie.get(val).unwrap();
+            assert_eq!(v.map(|v| v.to_vec()), Some(val.to_vec()));
+        }
+    }
+
+    #[test]
+    fn test_trie_contains() {
+        let memdb = Arc::new(MemoryDB::new(true));
+        let mut trie = PatriciaTrie::new(memdb, Arc::new(HasherKeccak::new()));
+        trie.insert(b"test".to_vec(), b"test".to_vec()).unwrap();
+        assert_eq!(true, trie.contains(b"test").unwrap());
+        assert_eq!(false, trie.contains(b"test2").unwrap());
+    }
+
+    #[test]
+    fn test_trie_remove() {
+        let memdb = Arc::new(MemoryDB::new(true));
+        let mut trie = PatriciaTrie::new(memdb, Arc::new(HasherKeccak::new()));
+        trie.insert(b"test".to_vec(), b"test".to_vec()).unwrap();
+        let removed = trie.remove(b"test").unwrap();
+        assert_eq!(true, removed)
+    }
+
+    #[test]
+    fn test_trie_random_remove() {
+        let memdb = Arc::new(MemoryDB::new(true));
+        let mut trie = PatriciaTrie::new(memdb, Arc::new(HasherKeccak::new()));
+
+        for _ in 0..1000 {
+            let rand_str: String = thread_rng().sample_iter(&Alphanumeric).take(30).collect();
+            let val = rand_str.as_bytes();
+            trie.insert(val.to_vec(), val.to_vec()).unwrap();
+
+            let removed = trie.remove(val).unwrap();
+            assert_eq!(true, removed);
+        }
+    }
+
+    #[test]
+    fn test_trie_from_root() {
+        let memdb = Arc::new(MemoryDB::new(true));
+        let root = {
+            let mut trie = PatriciaTrie::new(Arc::clone(&memdb), Arc::new(HasherKeccak::new()));
+            trie.insert(b"test".to_vec(), b"test".to_vec()).unwrap();
+            trie.insert(b"test1".to_vec(), b"test".to_vec()).unwrap();
+            trie.insert(b"test2".to_vec(), b"test".to_vec()).unwrap();
+            trie.insert(b"test23".to_vec(), b"test".to_vec()).unwrap();
+            trie.insert(b"test33".to_vec(), b"test".to_vec()).unwrap();
+            trie.insert(b"test44".to_vec(), b"test".to_vec()).unwrap();
+            trie.root().unwrap()
+        };
+
+        let mut trie =
+            PatriciaTrie::from(Arc::clone(&memdb), Arc::new(HasherKeccak::new()), &root).unwrap();
+        let v1 = trie.get(b"test33").unwrap();
+        assert_eq!(Some(b"test".to_vec()), v1);
+        let v2 = trie.get(b"test44").unwrap();
+        assert_eq!(Some(b"test".to_vec()), v2);
+        let root2 = trie.root().unwrap();
+        assert_eq!(hex::encode(root), hex::encode(root2));
+    }
+
+    #[test]
+    fn test_trie_from_root_and_insert() {
+        let memdb = Arc::new(MemoryDB::new(true));
+        let root = {
+            let mut trie = PatriciaTrie::new(Arc::clone(&memdb), Arc::new(HasherKeccak::new()));
+            trie.insert(b"test".to_vec(), b"test".to_vec()).unwrap();
+            trie.insert(b"test1".to_vec(), b"test".to_vec()).unwrap();
+            trie.insert(b"test2".to_vec(), b"test".to_vec()).unwrap();
+            trie.insert(b"test23".to_vec(), b"test".to_vec()).unwrap();
+            trie.insert(b"test33".to_vec(), b"test".to_vec()).unwrap();
+            trie.insert(b"test44".to_vec(), b"test".to_vec()).unwrap();
+            trie.commit().unwrap()
+        };
+
+        let mut trie =
+            PatriciaTrie::from(Arc::clone(&memdb), Arc::new(HasherKeccak::new()), &root).unwrap();
+        trie.insert(b"test55".to_vec(), b"test55".to_vec()).unwrap();
+        trie.commit().unwrap();
+        let v = trie.get(b"test55").unwrap();
+        assert_eq!(Some(b"test55".to_vec()), v);
+    }
+
+    #[test]
+    fn test_trie_from_root_and_delete() {
+        let memdb = Arc::new(MemoryDB::new(true));
+        let root = {
+            let mut trie = PatriciaTrie::new(Arc::clone(&memdb), Arc::new(HasherKeccak::new()));
+            trie.insert(b"test".to_vec(), b"test".to_vec()).unwrap();
+            trie.insert(b"test1".to_vec(), b"test".to_vec()).unwrap();
+            trie.insert(b"test2".to_vec(), b"test".to_vec()).unwrap();
+            trie.insert(b"test23".to_vec(), b"test".to_vec()).unwrap();
+            trie.insert(b"test33".to_vec(), b"test".to_vec()).unwrap();
+            trie.insert(b"test44".to_vec(), b"test".to_vec()).unwrap();
+            trie.commit().unwrap()
+        };
+
+        let mut trie =
+            PatriciaTrie::from(Arc::clone(&memdb), Arc::new(HasherKeccak::new()), &root).unwrap();
+        let removed = trie.remove(b"test44").unwrap();
+        assert_eq!(true, removed);
+        let removed = trie.remove(b"test33").unwrap();
+        assert_eq!(true, removed);
+        let removed = trie.remove(b"test23").unwrap();
+        assert_eq!(true, removed);
+    }
+
+    #[test]
+    fn test_multiple_trie_roots() {
+        let k0: ethereum_types::H256 = 0.into();
+        let k1: ethereum_types::H256 = 1.into();
+        let v: ethereum_types::H256 = 0x1234.into();
+
+        let root1 = {
+            let memdb = Arc::new(MemoryDB::new(true));
+            let mut trie = PatriciaTrie::new(memdb, Arc::new(HasherKeccak::new()));
+            trie.insert(k0.as_bytes().to_vec(), v.as_bytes().to_vec())
+                .unwrap();
+            trie.root().unwrap()
+        };
+
+        let root2 = {
+            let memdb = Arc::new(MemoryDB::new(true));
+            let mut trie = PatriciaTrie::new(memdb, Arc::new(HasherKeccak::new()));
+            trie.insert(k0.as_bytes().to_vec(), v.as_bytes().to_vec())
+                .unwrap();
+            trie.insert(k1.as_bytes().to_vec(), v.as_bytes().to_vec())
+                .unwrap();
+            trie.root().unwrap();
+            trie.remove(k1.as_ref()).unwrap();
+            trie.root().unwrap()
+        };
+
+        let root3 = {
+            let memdb = Arc::new(MemoryDB::new(true));
+            let mut trie1 = PatriciaTrie::new(Arc::clone(&memdb), Arc::new(HasherKeccak::new()));
+            trie1
+                .insert(k0.as_bytes().to_vec(), v.as_bytes().to_vec())
+                .unwrap();
+            trie1
+                .insert(k1.as_bytes().to_vec(), v.as_bytes().to_vec())
+                .unwrap();
+            trie1.root().unwrap();
+            let root = trie1.root().unwrap();
+            let mut trie2 =
+                PatriciaTrie::from(Arc::clone(&memdb), Arc::new(HasherKeccak::new()), &root)
+                    .unwrap();
+            trie2.remove(&k1.as_bytes().to_vec()).unwrap();
+            trie2.root().unwrap()
+        };
+
+        assert_eq!(root1, root2);
+        assert_eq!(root2, root3);
+    }
+
+    #[test]
+    fn test_delete_stale_keys_with_random_insert_and_delete() {
+        let memdb = Arc::new(MemoryDB::new(true));
+        let mut trie = PatriciaTrie::new(memdb, Arc::new(HasherKeccak::new()));
+
+        let mut rng = rand::thread_rng();
+        let mut keys = vec![];
+        for _ in 0..100 {
+            let random_bytes: Vec<u8> = (0..rng.gen_range(2, 30))
+                .map(|_| rand::random::<u8>())
+                .collect();
+            trie.insert(random_bytes.clone(), random_bytes.clone())
+                .unwrap();
+            keys.push(random_bytes.clone());
+        }
+        trie.commit().unwrap();
+        let slice = &mut keys;
+        slice.shuffle(&mut rng);
+
+        for key in slice.iter() {
+            trie.remove(key).unwrap();
+        }
+        trie.commit().unwrap();
+
+        let empty_node_key = HasherKeccak::new().digest(&rlp::NULL_RLP);
+        let value = trie.db.get(empty_node_key.as_ref()).unwrap().unwrap();
+        assert_eq!(value, &rlp::NULL_RLP)
+    }
+
+    #[test]
+    fn test_commit_skips_stored_nodes() {
+        let memdb = Arc::new(MeteredDB::new(MemoryDB::new(true)));
+        let mut trie = PatriciaTrie::new(Arc::clone(&memdb), Arc::new(HasherKeccak::new()));
+        for i in 0..100u8 {
+            trie.insert(vec![i], vec![i; 40]).unwrap();
+        }
+        let root1 = trie.commit().unwrap();
+
+        trie.insert(vec![100], vec![100; 40]).unwrap();
+        trie.remove(&[100]).unwrap();
+        memdb.reset();
+        let root2 = trie.commit().unwrap();
+
+        assert_eq!(root1, root2);
+        assert_eq!(memdb.metrics().inserted_batch_keys, 0);
+        for i in 0..100u8 {
+            assert_eq!(trie.get(&[i]).unwrap(), Some(vec![i; 40]));
+        }
+    }
+
+    #[test]
+    fn test_commit_keeps_unchanged_nodes() {
+        let memdb = Arc::new(MeteredDB::new(MemoryDB::new(true)));
+        let hasher = Arc::new(HasherKeccak::new());
+        let root = {
+            let mut trie = PatriciaTrie::new(Arc::clone(&memdb), Arc::clone(&hasher));
+            for i in 0..100u8 {
+                trie.insert(vec![i, i], vec![i; 40]).unwrap();
+            }
+            trie.root().unwrap()
+        };
+
+        // Removing a missing key loads nodes but changes none of them.
+        let mut trie = PatriciaTrie::from(Arc::clone(&memdb), Arc::clone(&hasher), &root).unwrap();
+        assert_eq!(trie.remove(&[7, 8]).unwrap(), false);
+        assert_eq!(trie.remove(&[200]).unwrap(), false);
+        memdb.reset();
+        assert_eq!(trie.root().unwrap(), root);
+        assert_eq!(memdb.metrics().inserted_batch_keys, 0);
+        assert_eq!(memdb.metrics().removed_batch_keys, 0);
+
+        let trie = PatriciaTrie::from(Arc::clone(&memdb), hasher, &root).unwrap();
+        for i in 0..100u8 {
+            assert_eq!(trie.get(&[i, i]).unwrap(), Some(vec![i; 40]));
+        }
+    }
+
+    #[test]
+    fn test_commit_writes_in_chunks() {
+        let memdb = Arc::new(MeteredDB::new(MemoryDB::new(true)));
+        let hasher = Arc::new(HasherKeccak::new());
+        let mut trie = PatriciaTrie::new(Arc::clone(&memdb), Arc::clone(&hasher));
+        for i in 0..5000u32 {
+            let value = format!("value-{:032}", i).into_bytes();
+            trie.insert(i.to_be_bytes().to_vec(), value).unwrap();
+        }
+        let root = trie.root().unwrap();
+
+        let metrics = memdb.metrics();
+        assert!(metrics.insert_batches > 1);
+        assert_eq!(
+            metrics.inserted_batch_keys,
+            memdb.inner().len().unwrap() as u64
+        );
+        let trie = PatriciaTrie::from(Arc::clone(&memdb), hasher, &root).unwrap();
+        assert_eq!(trie.iter().count(), 5000);
+    }
+
+    #[cfg(feature = "parallel")]
+    #[test]
+    fn test_par_insert_batch() {
+        let mut rng = thread_rng();
+        let hasher = Arc::new(HasherKeccak::new());
+        let mut expected = PatriciaTrie::new(Arc::new(MemoryDB::new(true)), Arc::clone(&hasher));
+        let mut trie = PatriciaTrie::new(Arc::new(MemoryDB::new(true)), Arc::clone(&hasher));
+
+        let mut keys = vec![];
+        for round in 0..20 {
+            let mut kvs = vec![];
+            for i in 0..1000 {
+                let key: Vec<u8> = (0..rng.gen_range(1, 4)).map(|_| rng.gen()).collect();
+                // Unique values, commits may prune nodes shared by equal subtries.
+                let value = format!("value-{}-{:032}", round, i).into_bytes();
+                kvs.push((key.clone(), value));
+                keys.push(key);
+            }
+            // Remove some keys again, and set and remove the value of the root.
+            for key in keys.choose_multiple(&mut rng, 100) {
+                kvs.push((key.clone(), vec![]));
+            }
+            kvs.push((vec![], vec![round + 1]));
+            if round == 1 {
+                kvs.push((vec![], vec![]));
+            }
+
+            for (k, v) in kvs.iter() {
+                expected.insert(k.clone(), v.clone()).unwrap();
+            }
+            trie.par_insert_batch(kvs).unwrap();
+            assert_eq!(trie.root().unwrap(), expected.root().unwrap());
+        }
+
+        // Removing everything leaves an empty trie.
+        let kvs = keys.into_iter().map(|k| (k, vec![])).collect();
+        trie.par_insert_batch(kvs).unwrap();
+        trie.remove(&[]).unwrap();
+        assert_eq!(
+            trie.root().unwrap(),
+            PatriciaTrie::new(Arc::new(MemoryDB::new(true)), hasher)
+                .root()
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn test_shared_node_cache() {
+        let memdb = Arc::new(MeteredDB::new(MemoryDB::new(true)));
+        let hasher = Arc::new(HasherKeccak::new());
+        let mut roots = vec![];
+        // Both roots stay readable.
+        let mut trie =
+            PatriciaTrie::new(Arc::clone(&memdb), Arc::clone(&hasher)).with_deferred_deletes();
+        for i in 0..100u8 {
+            trie.insert(vec![i], vec![i; 40]).unwrap();
+        }
+        roots.push(trie.root().unwrap());
+        // The second trie shares all nodes below the root but one.
+        trie.insert(vec![255], vec![255; 40]).unwrap();
+        roots.push(trie.root().unwrap());
+
+        let cache = Arc::new(SharedNodeCache::new(1000));
+        let tries: Vec<_> = roots
+            .iter()
+            .map(|root| {
+                PatriciaTrie::from(Arc::clone(&memdb), Arc::clone(&hasher), root)
+                    .unwrap()
+                    .with_shared_node_cache(Arc::clone(&cache))
+            })
+            .collect();
+        for i in 0..100u8 {
+            assert_eq!(tries[0].get(&[i]).unwrap(), Some(vec![i; 40]));
+        }
+        memdb.reset();
+        for i in 0..100u8 {
+            assert_eq!(tries[1].get(&[i]).unwrap(), Some(vec![i; 40]));
+        }
+        assert_eq!(memdb.metrics().gets, 0);
+        assert_eq!(tries[1].get(&[255]).unwrap(), Some(vec![255; 40]));
+        assert_eq!(memdb.metrics().gets, 1);
+    }
+
+    #[test]
+    fn test_deferred_deletes() {
+        let memdb = Arc::new(MemoryDB::new(true));
+        let hasher = Arc::new(HasherKeccak::new());
+        let mut trie =
+            PatriciaTrie::new(Arc::clone(&memdb), Arc::clone(&hasher)).with_deferred_deletes();
+        for i in 0..100u8 {
+            trie.insert(vec![i], vec![i; 40]).unwrap();
+        }
+        let old_root = trie.root().unwrap();
+
+        for i in 0..50u8 {
+            trie.insert(vec![i], vec![i + 1; 40]).unwrap();
+        }
+        trie.root().unwrap();
+        // Changing the values back makes some pending nodes live again.
+        for i in 0..10u8 {
+            trie.insert(vec![i], vec![i; 40]).unwrap();
+        }
+        let root = trie.root().unwrap();
+
+        // The old root is still readable.
+        let old_trie =
+            PatriciaTrie::from(Arc::clone(&memdb), Arc::clone(&hasher), &old_root).unwrap();
+        assert_eq!(old_trie.get(&[20]).unwrap(), Some(vec![20; 40]));
+        assert!(trie.memory_usage().pending_deletes > 0);
+        let nodes = memdb.len().unwrap();
+
+        let removed = trie.flush_deletes().unwrap();
+        assert!(removed > 0);
+        assert_eq!(memdb.len().unwrap(), nodes - removed);
+        assert_eq!(trie.flush_deletes().unwrap(), 0);
+        let trie = PatriciaTrie::from(Arc::clone(&memdb), hasher, &root).unwrap();
+        for i in 0..100u8 {
+            let value = if i >= 10 && i < 50 { i + 1 } else { i };
+            assert_eq!(trie.get(&[i]).unwrap(), Some(vec![value; 40]));
+        }
+    }
+
+    #[test]
+    fn test_hash_only_commits() {
+        let memdb = Arc::new(MemoryDB::new(true));
+        let hasher = Arc::new(HasherKeccak::new());
+        let mut trie =
+            PatriciaTrie::new(Arc::clone(&memdb), Arc::clone(&hasher)).with_hash_only_commits();
+        let expected_db = Arc::new(MemoryDB::new(true));
+        let mut expected = PatriciaTrie::new(Arc::clone(&expected_db), Arc::clone(&hasher));
+        for i in 0..100u8 {
+            trie.insert(vec![i], vec![i; 40]).unwrap();
+            expected.insert(vec![i], vec![i; 40]).unwrap();
+        }
+        assert_eq!(trie.persist().unwrap(), expected.root().unwrap());
+        let nodes = memdb.len().unwrap();
+
+        // Two candidate roots, only the nodes of the last one are persisted.
+        for i in 0..50u8 {
+            trie.insert(vec![i], vec![i + 100; 40]).unwrap();
+            expected.insert(vec![i], vec![i + 100; 40]).unwrap();
+        }
+        assert_eq!(trie.root().unwrap(), expected.root().unwrap());
+        for i in 40..60u8 {
+            trie.insert(vec![i], vec![i + 150; 40]).unwrap();
+            expected.insert(vec![i], vec![i + 150; 40]).unwrap();
+        }
+        let root = trie.root().unwrap();
+        assert_eq!(root, expected.root().unwrap());
+        assert_eq!(trie.get(&[45]).unwrap(), Some(vec![195; 40]));
+        assert_eq!(memdb.len().unwrap(), nodes);
+
+        assert_eq!(trie.persist().unwrap(), root);
+        // Nodes of the first candidate are not written, "expected" committed it.
+        assert!(memdb.len().unwrap() <= expected_db.len().unwrap());
+        let trie = PatriciaTrie::from(Arc::clone(&memdb), hasher, &root).unwrap();
+        assert_eq!(trie.get(&[20]).unwrap(), Some(vec![120; 40]));
+        assert_eq!(trie.get(&[50]).unwrap(), Some(vec![200; 40]));
+    }
+
+    #[test]
+    fn test_insert_return_old() {
+        let mut trie =
+            PatriciaTrie::new(Arc::new(MemoryDB::new(true)), Arc::new(HasherKeccak::new()));
+        assert_eq!(
+            trie.insert_return_old(b"test".to_vec(), b"a".to_vec())
+                .unwrap(),
+            None
+        );
+        assert_eq!(
+            trie.insert_return_old(b"test".to_vec(), b"b".to_vec())
+                .unwrap(),
+            Some(b"a".to_vec())
+        );
+        // "test" becomes the value of a branch.
+        assert_eq!(
+            trie.insert_return_old(b"test1".to_vec(), b"c".to_vec())
+                .unwrap(),
+            None
+        );
+        assert_eq!(
+            trie.insert_return_old(b"test".to_vec(), b"d".to_vec())
+                .unwrap(),
+            Some(b"b".to_vec())
+        );
+
+        trie.root().unwrap();
+        assert_eq!(
+            trie.insert_return_old(b"test1".to_vec(), b"e".to_vec())
+                .unwrap(),
+            Some(b"c".to_vec())
+        );
+        assert_eq!(
+            trie.insert_return_old(b"test".to_vec(), vec![]).unwrap(),
+            Some(b"d".to_vec())
+        );
+        assert_eq!(trie.get(b"test").unwrap(), None);
+    }
+
+    #[test]
+    fn test_clear() {
+        let memdb = Arc::new(MemoryDB::new(true));
+        let mut trie = PatriciaTrie::new(Arc::clone(&memdb), Arc::new(HasherKeccak::new()))
+            .with_bloom_filter(100)
+            .unwrap();
+        let empty = trie.root().unwrap();
+        for i in 0..100u8 {
+            trie.insert(vec![i], vec![i; 40]).unwrap();
+        }
+        trie.root().unwrap();
+        trie.insert(vec![7, 7], vec![7; 40]).unwrap();
+        trie.clear().unwrap();
+        assert_eq!(trie.get(&[7]).unwrap(), None);
+        assert_eq!(trie.root().unwrap(), empty);
+        assert_eq!(memdb.len().unwrap(), 1);
+
+        trie.insert(vec![7], vec![7; 40]).unwrap();
+        assert_eq!(trie.get(&[7]).unwrap(), Some(vec![7; 40]));
+    }
+
+    #[test]
+    fn test_remove_prefix() {
+        let memdb = Arc::new(MemoryDB::new(true));
+        let hasher = Arc::new(HasherKeccak::new());
+        let mut trie = PatriciaTrie::new(Arc::clone(&memdb), Arc::clone(&hasher));
+        let expected_db = Arc::new(MemoryDB::new(true));
+        let mut expected = PatriciaTrie::new(Arc::clone(&expected_db), hasher);
+        let mut keys = vec![b"acct".to_vec(), b"acct1".to_vec(), b"acct2".to_vec()];
+        for i in 0..50u8 {
+            keys.push([&b"acct1/"[..], &[i]].concat());
+            keys.push([&b"acct2/"[..], &[i]].concat());
+        }
+        for (i, key) in keys.iter().enumerate() {
+            let value = [key.clone(), vec![i as u8; 40]].concat();
+            trie.insert(key.clone(), value.clone()).unwrap();
+            if !key.starts_with(b"acct1") {
+                expected.insert(key.clone(), value).unwrap();
+            }
+        }
+        trie.root().unwrap();
+
+        assert!(!trie.remove_prefix(b"acct3").unwrap());
+        assert!(trie.remove_prefix(b"acct1").unwrap());
+        assert_eq!(trie.get(b"acct1/\x07").unwrap(), None);
+        assert!(trie.get(b"acct2/\x07").unwrap().is_some());
+        assert_eq!(trie.root().unwrap(), expected.root().unwrap());
+        assert_eq!(memdb.len().unwrap(), expected_db.len().unwrap());
+
+        // Every key starts with the empty prefix.
+        assert!(trie.remove_prefix(b"").unwrap());
+        assert_eq!(trie.iter().count(), 0);
+    }
+
+    #[test]
+    fn test_ref_counting() {
+        let memdb = Arc::new(MemoryDB::new(true));
+        let hasher = Arc::new(HasherKeccak::new());
+        let mut tries: Vec<_> = (0..2)
+            .map(|_| PatriciaTrie::new(Arc::clone(&memdb), Arc::clone(&hasher)).with_ref_counting())
+            .collect();
+        // The leaves under [1] and [2] are identical, and so are the tries.
+        for trie in tries.iter_mut() {
+            for i in 0..3u8 {
+                trie.insert(vec![1, i], vec![i; 40]).unwrap();
+                trie.insert(vec![2, i], vec![i; 40]).unwrap();
+            }
+            trie.root().unwrap();
+        }
+
+        tries[0].remove(&[1, 0]).unwrap();
+        let root = tries[0].root().unwrap();
+        let trie = PatriciaTrie::from(Arc::clone(&memdb), Arc::clone(&hasher), &root).unwrap();
+        assert_eq!(trie.get(&[2, 0]).unwrap(), Some(vec![0; 40]));
+
+        tries[0].clear().unwrap();
+        tries[0].root().unwrap();
+        assert_eq!(tries[1].get(&[1, 0]).unwrap(), Some(vec![0; 40]));
+        tries[1].clear().unwrap();
+        // Only the empty root is left, roots shorter than a hash are not counted.
+        let empty = tries[1].root().unwrap();
+        assert_eq!(memdb.len().unwrap(), 1);
+        assert!(memdb.contains(&empty).unwrap());
+    }
+
+    #[test]
+    fn test_archive_mode() {
+        let memdb = Arc::new(MemoryDB::new(true));
+        let hasher = Arc::new(HasherKeccak::new());
+        let mut trie =
+            PatriciaTrie::new(Arc::clone(&memdb), Arc::clone(&hasher)).with_archive_mode();
+        let mut roots = vec![];
+        for i in 0..10u8 {
+            trie.insert(vec![i], vec![i; 40]).unwrap();
+            trie.insert(vec![0], vec![i; 40]).unwrap();
+            roots.push(trie.root().unwrap());
+        }
+        trie.clear().unwrap();
+        trie.root().unwrap();
+
+        for (i, root) in roots.iter().enumerate() {
+            let past = PatriciaTrie::from(Arc::clone(&memdb), Arc::clone(&hasher), root).unwrap();
+            assert_eq!(past.get(&[0]).unwrap(), Some(vec![i as u8; 40]));
+            assert_eq!(past.iter().count(), i + 1);
+        }
+    }
+
+    #[test]
+    fn test_pruning_window() {
+        let memdb = Arc::new(MemoryDB::new(true));
+        let hasher = Arc::new(HasherKeccak::new());
+        let mut trie = PatriciaTrie::new(Arc::clone(&memdb), Arc::clone(&hasher))
+            .with_pruning_window(3)
+            .unwrap();
+        for i in 0..50u8 {
+            trie.insert(vec![i], vec![i; 40]).unwrap();
+        }
+        let mut roots = vec![];
+        for i in 0..10u8 {
+            trie.insert(vec![7], vec![i; 40]).unwrap();
+            roots.push(trie.root().unwrap());
+        }
+        assert_eq!(trie.generation(), Some(10));
+
+        for (i, root) in roots.iter().enumerate() {
+            let value = PatriciaTrie::from(Arc::clone(&memdb), Arc::clone(&hasher), root)
+                .and_then(|past| past.get(&[7]));
+            if i < 7 {
+                assert!(value.is_err());
+            } else {
+                assert_eq!(value.unwrap(), Some(vec![i as u8; 40]));
+            }
+        }
+
+        // The window is kept in the DB, a trie opened again carries on with it.
+        drop(trie);
+        let mut trie = PatriciaTrie::from(Arc::clone(&memdb), Arc::clone(&hasher), &roots[9])
+            .unwrap()
+            .with_pruning_window(3)
+            .unwrap();
+        assert_eq!(trie.generation(), Some(10));
+        trie.insert(vec![7], vec![10; 40]).unwrap();
+        trie.root().unwrap();
+        let past = PatriciaTrie::from(Arc::clone(&memdb), Arc::clone(&hasher), &roots[7]);
+        assert!(past.and_then(|past| past.get(&[7])).is_err());
+        let past = PatriciaTrie::from(Arc::clone(&memdb), Arc::clone(&hasher), &roots[8]);
+        assert_eq!(past.unwrap().get(&[7]).unwrap(), Some(vec![8; 40]));
+    }
+
+    #[test]
+    fn test_rewind() {
+        let memdb = Arc::new(MemoryDB::new(true));
+        let mut trie = PatriciaTrie::new(Arc::clone(&memdb), Arc::new(HasherKeccak::new()))
+            .with_reverse_diffs();
+        for i in 0..50u8 {
+            trie.insert(vec![i], vec![i; 40]).unwrap();
+        }
+        let mut roots = vec![trie.root().unwrap()];
+        for i in 0..5u8 {
+            trie.insert(vec![7], vec![i; 40]).unwrap();
+            trie.remove(&[20 + i]).unwrap();
+            roots.push(trie.root().unwrap());
+        }
+
+        // A reorg drops the last three blocks and imports another one.
+        trie.insert(vec![60], vec![60; 40]).unwrap();
+        trie.rewind(&roots[2]).unwrap();
+        assert_eq!(trie.get(&[60]).unwrap(), None);
+        assert_eq!(trie.get(&[7]).unwrap(), Some(vec![1; 40]));
+        assert_eq!(trie.get(&[22]).unwrap(), Some(vec![22; 40]));
+        trie.insert(vec![7], vec![70; 40]).unwrap();
+        trie.root().unwrap();
+
+        trie.rewind(&roots[0]).unwrap();
+        assert_eq!(trie.root().unwrap(), roots[0]);
+        assert_eq!(trie.iter().count(), 50);
+        match trie.rewind(&roots[4]) {
+            Err(TrieError::NoReverseDiff) => {}
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_empty_values() {
+        let memdb = Arc::new(MemoryDB::new(true));
+        let hasher = Arc::new(HasherKeccak::new());
+        let codec = Arc::new(BinaryNodeCodec::new());
+        assert!(PatriciaTrie::new(Arc::clone(&memdb), Arc::clone(&hasher))
+            .with_empty_values()
+            .is_err());
+        let mut trie = PatriciaTrie::new_with_codec(Arc::clone(&memdb), Arc::clone(&hasher), codec)
+            .with_empty_values()
+            .unwrap();
+        for i in 0..50u8 {
+            trie.insert(vec![i, i], vec![i; 40]).unwrap();
+        }
+        // The value of a leaf, and of a branch as another key extends it.
+        trie.insert(b"leaf".to_vec(), vec![]).unwrap();
+        trie.insert(vec![7], vec![]).unwrap();
+        trie.insert_batch(&[(vec![8], vec![])]).unwrap();
+        let root = trie.root().unwrap();
+
+        let mut trie = trie.at_root(&root).unwrap();
+        for key in [&b"leaf"[..], &[7], &[8]].iter() {
+            assert_eq!(trie.get(key).unwrap(), Some(vec![]));
+        }
+        assert_eq!(trie.get(&[9]).unwrap(), None);
+        assert!(trie.remove(&[7]).unwrap());
+        assert_eq!(trie.get(&[7]).unwrap(), None);
+        assert_eq!(trie.get(&[7, 7]).unwrap(), Some(vec![7; 40]));
+    }
+
+    #[cfg(feature = "parallel")]
+    #[test]
+    fn test_par_insert_batch_config() {
+        let memdb = Arc::new(MemoryDB::new(true));
+        let hasher = Arc::new(HasherKeccak::new());
+        let codec = Arc::new(BinaryNodeCodec::new());
+        let mut trie = PatriciaTrie::new_with_codec(memdb, hasher, codec)
+            .with_empty_values()
+            .unwrap()
+            .with_size_limits(8, 64);
+        for i in 0..50u8 {
+            trie.insert(vec![i, i], vec![i; 40]).unwrap();
+        }
+        // The workers store empty values as the trie does.
+        let kvs: Vec<_> = (0..50u8).map(|i| (vec![i, i], vec![])).collect();
+        trie.par_insert_batch(kvs).unwrap();
+        assert_eq!(trie.get(&[7, 7]).unwrap(), Some(vec![]));
+        assert_eq!(trie.get(&[49, 49]).unwrap(), Some(vec![]));
+        assert!(trie.par_insert_batch(vec![(vec![0; 9], vec![1])]).is_err());
+    }
+
+    #[test]
+    fn test_strict_nodes() {
+        let memdb = Arc::new(MemoryDB::new(true));
+        let hasher = Arc::new(HasherKeccak::new());
+        let mut trie = PatriciaTrie::new(Arc::clone(&memdb), Arc::clone(&hasher));
+        for i in 0..100u8 {
+            trie.insert(vec![i, i], vec![i; 40]).unwrap();
+        }
+        let root = trie.root().unwrap();
+        let proof = trie.get_proof(&[7, 7]).unwrap();
+        let lost = hasher.digest(&proof[1]);
+        memdb.remove(&lost).unwrap();
+
+        let trie = PatriciaTrie::from(Arc::clone(&memdb), Arc::clone(&hasher), &root).unwrap();
+        assert_eq!(trie.get(&[7, 7]).unwrap(), None);
+        let mut trie = trie.with_strict_nodes();
+        match trie.get(&[7, 7]) {
+            Err(TrieError::MissingNode { hash }) => assert_eq!(hash, lost),
+            other => panic!("unexpected {:?}", other),
+        }
+        assert!(trie.insert(vec![7, 8], vec![1]).is_err());
+        assert_eq!(trie.get(&[50, 50]).unwrap(), Some(vec![50; 40]));
+    }
+
+    #[test]
+    fn test_node_checksums() {
+        let memdb = Arc::new(MemoryDB::new(true));
+        let hasher = Arc::new(HasherKeccak::new());
+        let mut trie = PatriciaTrie::new(Arc::clone(&memdb), Arc::clone(&hasher));
+        for i in 0..100u8 {
+            trie.insert(vec![i, i], vec![i; 40]).unwrap();
+        }
+        let root = trie.root().unwrap();
+        let proof = trie.get_proof(&[7, 7]).unwrap();
+        let corrupt = hasher.digest(&proof[1]);
+        let mut data = proof[1].clone();
+        let last = data.len() - 1;
+        data[last] ^= 1;
+        memdb.insert(corrupt.clone(), data).unwrap();
+
+        let trie = PatriciaTrie::from(Arc::clone(&memdb), Arc::clone(&hasher), &root)
+            .unwrap()
+            .with_node_checksums();
+        match trie.get(&[7, 7]) {
+            Err(TrieError::CorruptNode { hash }) => assert_eq!(hash, corrupt),
+            other => panic!("unexpected {:?}", other),
+        }
+        assert_eq!(trie.get(&[50, 50]).unwrap(), Some(vec![50; 40]));
+    }
+
+    #[test]
+    fn test_estimate_witness_size() {
+        let mut trie =
+            PatriciaTrie::new(Arc::new(MemoryDB::new(true)), Arc::new(HasherKeccak::new()));
+        for i in 0..200u8 {
+            trie.insert(vec![i / 10, i], vec![i; 40]).unwrap();
+        }
+        trie.insert(vec![3], b"short".to_vec()).unwrap();
+        trie.root().unwrap();
+
+        let keys = vec![vec![3], vec![3, 35], vec![3, 36], vec![19, 199], vec![30]];
+        let mut nodes = HashSet::new();
+        for key in keys.iter() {
+            nodes.extend(trie.get_proof(key).unwrap());
+        }
+        let expected: usize = nodes.iter().map(Vec::len).sum();
+        assert_eq!(trie.estimate_witness_size(&keys).unwrap(), expected);
+        assert_eq!(trie.estimate_witness_size::<Vec<u8>>(&[]).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_nibble_keys() {
+        let memdb = Arc::new(MemoryDB::new(true));
+        let hasher = Arc::new(HasherKeccak::new());
+        let mut trie = PatriciaTrie::new(Arc::clone(&memdb), Arc::clone(&hasher));
+        trie.insert(vec![0x12], b"byte".to_vec()).unwrap();
+        trie.insert_nibbles(&[1], b"one".to_vec()).unwrap();
+        trie.insert_nibbles(&[1, 2, 3], b"three".to_vec()).unwrap();
+        trie.insert_nibbles(&[0xa], vec![0xa; 40]).unwrap();
+        assert!(trie.insert_nibbles(&[16], b"bad".to_vec()).is_err());
+        let root = trie.root().unwrap();
+
+        let mut trie = PatriciaTrie::from(memdb, hasher, &root).unwrap();
+        assert_eq!(trie.get_nibbles(&[1, 2]).unwrap(), Some(b"byte".to_vec()));
+        assert_eq!(trie.get_nibbles(&[1]).unwrap(), Some(b"one".to_vec()));
+        assert_eq!(
+            trie.get_nibbles(&[1, 2, 3]).unwrap(),
+            Some(b"three".to_vec())
+        );
+        assert_eq!(trie.get_nibbles(&[1, 2, 4]).unwrap(), None);
+        let keys: Vec<Vec<u8>> = trie.iter_nibbles().map(|(k, _)| k).collect();
+        assert_eq!(keys, vec![vec![1], vec![1, 2], vec![1, 2, 3], vec![0xa]]);
+
+        assert!(trie.remove_nibbles(&[1]).unwrap());
+        assert!(!trie.remove_nibbles(&[1]).unwrap());
+        assert_eq!(trie.get(&[0x12]).unwrap(), Some(b"byte".to_vec()));
+        assert_eq!(
+            trie.get_nibbles(&[1, 2, 3]).unwrap(),
+            Some(b"three".to_vec())
+        );
+    }
+
+    #[test]
+    fn test_size_limits() {
+        let mut trie =
+            PatriciaTrie::new(Arc::new(MemoryDB::new(true)), Arc::new(HasherKeccak::new()))
+                .with_size_limits(32, 64);
+        trie.insert(vec![1; 32], vec![1; 64]).unwrap();
+        match trie.insert(vec![2; 33], vec![2]) {
+            Err(TrieError::KeyTooLarge(33)) => {}
+            other => panic!("unexpected {:?}", other),
+        }
+        match trie.insert(vec![2], vec![2; 65]) {
+            Err(TrieError::ValueTooLarge(65)) => {}
+            other => panic!("unexpected {:?}", other),
+        }
+        let batch = vec![(vec![3], vec![3]), (vec![4], vec![4; 65])];
+        assert!(trie.insert_batch(&batch).is_err());
+        assert!(trie.insert_nibbles(&[5; 65], vec![5]).is_err());
+        assert_eq!(trie.get(&[3]).unwrap(), None);
+        assert_eq!(trie.iter().count(), 1);
+    }
+
+    #[test]
+    fn test_get_at() {
+        let memdb = Arc::new(MemoryDB::new(true));
+        let mut trie = PatriciaTrie::new(Arc::clone(&memdb), Arc::new(HasherKeccak::new()))
+            .with_archive_mode();
+        for i in 0..100u8 {
+            trie.insert(vec![i, i], vec![i; 40]).unwrap();
+        }
+        trie.insert(vec![7], b"short".to_vec()).unwrap();
+        let old_root = trie.root().unwrap();
+        trie.insert(vec![7, 7], b"new".to_vec()).unwrap();
+        trie.root().unwrap();
+
+        let codec = RLPNodeCodec::new();
+        let get = |key: &[u8]| get_at(&*memdb, &codec, &old_root, key).unwrap();
+        assert_eq!(get(&[7, 7]), Some(vec![7; 40]));
+        assert_eq!(get(&[7]), Some(b"short".to_vec()));
+        assert_eq!(get(&[50, 50]), Some(vec![50; 40]));
+        assert_eq!(get(&[50]), None);
+        assert_eq!(get(&[50, 50, 0]), None);
+        assert_eq!(get(&[200, 200]), None);
+        assert!(get_at(&*memdb, &codec, &[0; 32], &[7]).is_err());
+
+        // A node missing below the root fails the read.
+        let hash = HasherKeccak::new().digest(&trie.get_proof(&[50, 50]).unwrap()[1]);
+        memdb.remove(&hash).unwrap();
+        match get_at(&*memdb, &codec, &old_root, &[50, 50]) {
+            Err(TrieError::MissingNode { hash: missing }) => assert_eq!(missing, hash),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_same_content() {
+        let memdb = Arc::new(MeteredDB::new(MemoryDB::new(true)));
+        let hasher = Arc::new(HasherKeccak::new());
+        let mut trie =
+            PatriciaTrie::new(Arc::clone(&memdb), Arc::clone(&hasher)).with_archive_mode();
+        for i in 0..=255u8 {
+            trie.insert(vec![i, 1], vec![i; 40]).unwrap();
+            trie.insert(vec![i, 2], vec![i; 41]).unwrap();
+        }
+        let root_a = trie.root().unwrap();
+        trie.insert(vec![200, 2], vec![0; 40]).unwrap();
+        trie.insert(vec![201, 0], vec![0; 40]).unwrap();
+        let root_b = trie.root().unwrap();
+
+        assert_eq!(trie.same_content(&root_a, &root_a).unwrap(), None);
+        memdb.reset();
+        assert_eq!(
+            trie.same_content(&root_a, &root_b).unwrap(),
+            Some(vec![200, 2])
+        );
+        // Only the five nodes on the path to the changed keys are loaded, of
+        // each root.
+        assert_eq!(memdb.metrics().gets, 10);
+        trie.remove(&[201, 0]).unwrap();
+        trie.insert(vec![200, 2], vec![200; 41]).unwrap();
+        let root_c = trie.root().unwrap();
+        assert_eq!(trie.same_content(&root_a, &root_c).unwrap(), None);
+        assert!(trie.same_content(&root_a, &[0; 32]).is_err());
+    }
+
+    #[test]
+    fn test_merge() {
+        let memdb = Arc::new(MemoryDB::new(true));
+        let hasher = Arc::new(HasherKeccak::new());
+        let mut trie =
+            PatriciaTrie::new(Arc::clone(&memdb), Arc::clone(&hasher)).with_archive_mode();
+        for i in 0..100u8 {
+            trie.insert(vec![i], vec![i; 40]).unwrap();
+        }
+        let base = trie.root().unwrap();
+
+        let mut ours = trie.fork().unwrap();
+        ours.insert(vec![1], b"ours".to_vec()).unwrap();
+        ours.insert(vec![2], b"both".to_vec()).unwrap();
+        ours.insert(vec![3], b"conflict ours".to_vec()).unwrap();
+        let ours = ours.root().unwrap();
+        let mut theirs = trie.fork().unwrap();
+        theirs.insert(vec![2], b"both".to_vec()).unwrap();
+        theirs.insert(vec![3], b"conflict theirs".to_vec()).unwrap();
+        theirs.remove(&[4]).unwrap();
+        theirs.insert(vec![200], b"theirs".to_vec()).unwrap();
+        let theirs = theirs.root().unwrap();
+
+        let mut conflicts = vec![];
+        let mut merged = trie
+            .merge(&base, &ours, &theirs, |key, base, ours, theirs| {
+                conflicts.push((
+                    key.to_vec(),
+                    base.is_some(),
+                    ours.is_some(),
+                    theirs.is_some(),
+                ));
+                Some(b"resolved".to_vec())
+            })
+            .unwrap();
+        assert_eq!(conflicts, vec![(vec![3], true, true, true)]);
+        assert_eq!(merged.get(&[1]).unwrap(), Some(b"ours".to_vec()));
+        assert_eq!(merged.get(&[2]).unwrap(), Some(b"both".to_vec()));
+        assert_eq!(merged.get(&[3]).unwrap(), Some(b"resolved".to_vec()));
+        assert_eq!(merged.get(&[4]).unwrap(), None);
+        assert_eq!(merged.get(&[200]).unwrap(), Some(b"theirs".to_vec()));
+        assert_eq!(merged.get(&[5]).unwrap(), Some(vec![5; 40]));
+        merged.root().unwrap();
+        assert!(trie
+            .merge(&base, &ours, &[0; 32], |_, _, _, _| None)
+            .is_err());
+    }
+
+    #[test]
+    fn test_subtrie() {
+        let memdb = Arc::new(MemoryDB::new(true));
+        let hasher = Arc::new(HasherKeccak::new());
+        let mut trie = PatriciaTrie::new(Arc::clone(&memdb), Arc::clone(&hasher));
+        let mut expected = PatriciaTrie::new(Arc::new(MemoryDB::new(true)), Arc::clone(&hasher));
+        for i in 0..50u8 {
+            trie.insert([&b"acct1/"[..], &[i]].concat(), vec![i; 40])
+                .unwrap();
+            trie.insert([&b"acct2/"[..], &[i]].concat(), vec![i; 40])
+                .unwrap();
+            expected.insert(vec![i], vec![i; 40]).unwrap();
+        }
+        trie.root().unwrap();
+        // Uncommitted changes are part of the subtrie.
+        trie.insert(b"acct1/x".to_vec(), vec![1; 40]).unwrap();
+        expected.insert(b"x".to_vec(), vec![1; 40]).unwrap();
+
+        let mut acct1 = trie.subtrie(b"acct1/").unwrap();
+        assert_eq!(acct1.get(&[7]).unwrap(), Some(vec![7; 40]));
+        assert_eq!(acct1.root().unwrap(), expected.root().unwrap());
+        let acct1 = PatriciaTrie::from(
+            Arc::clone(&memdb),
+            Arc::clone(&hasher),
+            &expected.root().unwrap(),
+        )
+        .unwrap();
+        assert_eq!(acct1.iter().count(), 51);
+
+        // The prefix ends inside the path of an extension.
+        let mut acct = trie.subtrie(b"ac").unwrap();
+        assert_eq!(acct.get(b"ct2/\x07").unwrap(), Some(vec![7; 40]));
+        assert_eq!(acct.iter().count(), 101);
+        acct.root().unwrap();
+        assert_eq!(trie.subtrie(b"b").unwrap().iter().count(), 0);
+        assert_eq!(trie.get(b"acct2/\x07").unwrap(), Some(vec![7; 40]));
+    }
+
+    #[test]
+    fn test_fork() {
+        let memdb = Arc::new(MeteredDB::new(MemoryDB::new(true)));
+        let mut trie = PatriciaTrie::new(Arc::clone(&memdb), Arc::new(HasherKeccak::new()))
+            .with_archive_mode();
+        for i in 0..50u8 {
+            trie.insert(vec![i], vec![i; 40]).unwrap();
         }
+        let root = trie.root().unwrap();
+        trie.insert(vec![60], vec![60; 40]).unwrap();
+
+        memdb.reset();
+        let mut fork = trie.fork().unwrap();
+        assert_eq!(memdb.metrics().gets, 0);
+        assert_eq!(fork.get(&[60]).unwrap(), None);
+        fork.insert(vec![7], vec![70; 40]).unwrap();
+        let fork_root = fork.root().unwrap();
+        assert_ne!(fork_root, root);
+        assert_eq!(trie.get(&[7]).unwrap(), Some(vec![7; 40]));
+        trie.root().unwrap();
+        assert_eq!(fork.get(&[7]).unwrap(), Some(vec![70; 40]));
     }
 
-    fn encode_raw(&self, n: Node) -> Vec<u8> {
-        match n {
-            Node::Empty => rlp::NULL_RLP.to_vec(),
-            Node::Leaf(leaf) => {
-                let borrow_leaf = leaf.borrow();
+    #[test]
+    fn test_insert_if() {
+        let mut trie =
+            PatriciaTrie::new(Arc::new(MemoryDB::new(true)), Arc::new(HasherKeccak::new()));
+        assert!(!trie.insert_if(b"test", Some(b"a"), b"b").unwrap());
+        assert!(trie.insert_if(b"test", None, b"a").unwrap());
+        assert!(!trie.insert_if(b"test", None, b"b").unwrap());
+        assert!(!trie.insert_if(b"test", Some(b"b"), b"c").unwrap());
+        assert_eq!(trie.get(b"test").unwrap(), Some(b"a".to_vec()));
 
-                let mut stream = RlpStream::new_list(2);
-                stream.append(&borrow_leaf.key.encode_compact());
-                stream.append(&borrow_leaf.value);
-                stream.out()
-            }
-            Node::Branch(branch) => {
-                let borrow_branch = branch.borrow();
+        trie.root().unwrap();
+        assert!(trie.insert_if(b"test", Some(b"a"), b"b").unwrap());
+        assert_eq!(trie.get(b"test").unwrap(), Some(b"b".to_vec()));
+        // An empty value removes the key, like "insert".
+        assert!(trie.insert_if(b"test", Some(b"b"), b"").unwrap());
+        assert_eq!(trie.get(b"test").unwrap(), None);
+    }
 
-                let mut stream = RlpStream::new_list(17);
-                for i in 0..16 {
-                    let n = borrow_branch.children[i].clone();
-                    let data = self.encode_node(n);
-                    if data.len() == H::LENGTH {
-                        stream.append(&data);
-                    } else {
-                        stream.append_raw(&data, 1);
-                    }
-                }
+    #[test]
+    fn test_take() {
+        let mut trie =
+            PatriciaTrie::new(Arc::new(MemoryDB::new(true)), Arc::new(HasherKeccak::new()));
+        for i in 0..10u8 {
+            trie.insert(vec![i], vec![i; 40]).unwrap();
+            trie.insert(vec![i, i], vec![i; 2]).unwrap();
+        }
+        trie.root().unwrap();
+        assert_eq!(trie.take(&[3]).unwrap(), Some(vec![3; 40]));
+        assert_eq!(trie.take(&[3]).unwrap(), None);
+        assert_eq!(trie.take(&[4, 4]).unwrap(), Some(vec![4; 2]));
+        assert_eq!(trie.take(&[4, 5]).unwrap(), None);
+        assert_eq!(trie.get(&[3, 3]).unwrap(), Some(vec![3; 2]));
+        assert_eq!(trie.get(&[4]).unwrap(), Some(vec![4; 40]));
+    }
 
-                match &borrow_branch.value {
-                    Some(v) => stream.append(v),
-                    None => stream.append_empty_data(),
-                };
-                stream.out()
+    #[test]
+    fn test_insert_batch() {
+        let mut rng = thread_rng();
+        let hasher = Arc::new(HasherKeccak::new());
+        let mut trie = PatriciaTrie::new(Arc::new(MemoryDB::new(true)), Arc::clone(&hasher));
+        let mut expected = PatriciaTrie::new(Arc::new(MemoryDB::new(true)), hasher);
+        for round in 0..20 {
+            // Short keys from a small alphabet share prefixes, contain each
+            // other and repeat.
+            let pairs: Vec<(Vec<u8>, Vec<u8>)> = (0..rng.gen_range(0, 200))
+                .map(|_| {
+                    let key = (0..rng.gen_range(0, 5))
+                        .map(|_| rng.gen_range(0, 4) * 17)
+                        .collect();
+                    // Identical subtries share their DB entries, so values
+                    // are random.
+                    let len = if rng.gen_range(0, 10) == 0 { 0 } else { 40 };
+                    (key, (0..len).map(|_| rng.gen()).collect())
+                })
+                .collect();
+            trie.insert_batch(&pairs).unwrap();
+            for (key, value) in pairs.iter() {
+                expected.insert(key.clone(), value.clone()).unwrap();
             }
-            Node::Extension(ext) => {
-                let borrow_ext = ext.borrow();
-
-                let mut stream = RlpStream::new_list(2);
-                stream.append(&borrow_ext.prefix.encode_compact());
-                let data = self.encode_node(borrow_ext.node.clone());
-                if data.len() == H::LENGTH {
-                    stream.append(&data);
-                } else {
-                    stream.append_raw(&data, 1);
-                }
-                stream.out()
+            // Later rounds update a trie that is partly loaded from the DB.
+            if round % 3 == 0 {
+                assert_eq!(trie.root().unwrap(), expected.root().unwrap());
+            } else {
+                assert_eq!(trie.peek_root().unwrap(), expected.peek_root().unwrap());
             }
-            Node::Hash(_hash) => unreachable!(),
+        }
+        for (key, value) in expected.iter() {
+            assert_eq!(trie.get(&key).unwrap(), Some(value));
         }
     }
 
-    fn decode_node(&self, data: &[u8]) -> TrieResult<Node> {
-        let r = Rlp::new(data);
-
-        match r.prototype()? {
-            Prototype::Data(0) => Ok(Node::Empty),
-            Prototype::List(2) => {
-                let key = r.at(0)?.data()?;
-                let key = Nibbles::from_compact(key.to_vec());
-
-                if key.is_leaf() {
-                    Ok(Node::from_leaf(key, r.at(1)?.data()?.to_vec()))
-                } else {
-                    let n = self.decode_node(r.at(1)?.as_raw())?;
+    #[test]
+    fn test_remove_batch() {
+        let mut rng = thread_rng();
+        let hasher = Arc::new(HasherKeccak::new());
+        let random_key = |rng: &mut rand::rngs::ThreadRng| -> Vec<u8> {
+            (0..rng.gen_range(0, 5))
+                .map(|_| rng.gen_range(0, 4) * 17)
+                .collect()
+        };
+        for _ in 0..20 {
+            let pairs: Vec<(Vec<u8>, Vec<u8>)> = (0..200)
+                .map(|_| (random_key(&mut rng), (0..40).map(|_| rng.gen()).collect()))
+                .collect();
+            let mut trie = PatriciaTrie::new(Arc::new(MemoryDB::new(true)), Arc::clone(&hasher));
+            let mut expected =
+                PatriciaTrie::new(Arc::new(MemoryDB::new(true)), Arc::clone(&hasher));
+            trie.insert_batch(&pairs).unwrap();
+            expected.insert_batch(&pairs).unwrap();
+            trie.root().unwrap();
 
-                    Ok(Node::from_extension(key, n))
+            // Some keys are absent and some repeat.
+            let keys: Vec<Vec<u8>> = (0..rng.gen_range(0, 100))
+                .map(|_| random_key(&mut rng))
+                .collect();
+            let mut removed = 0;
+            for key in keys.iter() {
+                if expected.remove(key).unwrap() {
+                    removed += 1;
                 }
             }
-            Prototype::List(17) => {
-                let mut nodes = empty_children();
-                #[allow(clippy::needless_range_loop)]
-                for i in 0..nodes.len() {
-                    let rlp_data = r.at(i)?;
-                    let n = self.decode_node(rlp_data.as_raw())?;
-                    nodes[i] = n;
-                }
+            assert_eq!(trie.remove_batch(&keys).unwrap(), removed);
+            assert_eq!(trie.root().unwrap(), expected.root().unwrap());
+        }
 
-                // The last element is a value node.
-                let value_rlp = r.at(16)?;
-                let value = if value_rlp.is_empty() {
-                    None
-                } else {
-                    Some(value_rlp.data()?.to_vec())
-                };
+        let mut trie = PatriciaTrie::new(Arc::new(MemoryDB::new(true)), Arc::clone(&hasher));
+        let empty_root = trie.root().unwrap();
+        let keys: Vec<Vec<u8>> = (0..100u8).map(|i| vec![i]).collect();
+        let pairs: Vec<_> = keys.iter().map(|k| (k.clone(), vec![k[0]; 40])).collect();
+        trie.insert_batch(&pairs).unwrap();
+        assert_eq!(trie.remove_batch(&keys).unwrap(), 100);
+        assert_eq!(trie.root().unwrap(), empty_root);
+    }
 
-                Ok(Node::from_branch(nodes, value))
-            }
-            _ => {
-                if r.is_data() && r.size() == H::LENGTH {
-                    Ok(Node::from_hash(r.data()?.to_vec()))
-                } else {
-                    Err(TrieError::InvalidData)
-                }
+    #[test]
+    fn test_peek_root() {
+        let memdb = Arc::new(MemoryDB::new(true));
+        let hasher = Arc::new(HasherKeccak::new());
+        let mut trie = PatriciaTrie::new(Arc::clone(&memdb), Arc::clone(&hasher));
+        let mut expected = PatriciaTrie::new(Arc::new(MemoryDB::new(true)), hasher);
+        assert_eq!(trie.peek_root().unwrap(), expected.root().unwrap());
+
+        for i in 0..100u8 {
+            trie.insert(vec![i], vec![i; 40]).unwrap();
+            expected.insert(vec![i], vec![i; 40]).unwrap();
+            if i % 25 == 0 {
+                assert_eq!(trie.peek_root().unwrap(), expected.root().unwrap());
             }
         }
+        assert_eq!(memdb.len().unwrap(), 0);
+        assert_eq!(trie.memory_usage().journal, 0);
+
+        let root = trie.peek_root().unwrap();
+        assert_eq!(trie.root().unwrap(), root);
+        trie.remove(&[7]).unwrap();
+        expected.remove(&[7]).unwrap();
+        assert_eq!(trie.peek_root().unwrap(), expected.root().unwrap());
     }
 
-    fn recover_from_db(&self, key: &[u8]) -> TrieResult<Node> {
-        match self.db.get(key).map_err(|e| TrieError::DB(e.to_string()))? {
-            Some(value) => Ok(self.decode_node(&value)?),
-            None => Ok(Node::Empty),
+    #[test]
+    fn test_pending_diff() {
+        let memdb = Arc::new(MemoryDB::new(true));
+        let mut trie = PatriciaTrie::new(Arc::clone(&memdb), Arc::new(HasherKeccak::new()))
+            .with_hash_only_commits();
+        for i in 0..100u8 {
+            trie.insert(vec![i], vec![i; 40]).unwrap();
         }
+        assert_eq!(trie.pending_diff().unwrap().len(), 100);
+        trie.root().unwrap();
+        assert!(trie.pending_diff().unwrap().is_empty());
+
+        trie.insert(vec![1], b"changed".to_vec()).unwrap();
+        trie.remove(&[2]).unwrap();
+        trie.insert(vec![2, 0], b"added".to_vec()).unwrap();
+        // Changes that cancel out are not listed.
+        trie.insert(vec![3], b"changed".to_vec()).unwrap();
+        trie.insert(vec![3], vec![3; 40]).unwrap();
+        trie.insert(vec![4, 4], b"added".to_vec()).unwrap();
+        trie.remove(&[4, 4]).unwrap();
+        let expected = vec![
+            (vec![1], Some(vec![1; 40]), Some(b"changed".to_vec())),
+            (vec![2], Some(vec![2; 40]), None),
+            (vec![2, 0], None, Some(b"added".to_vec())),
+        ];
+        assert_eq!(trie.pending_diff().unwrap(), expected);
+
+        // The diff is against the last root, written or not.
+        assert_eq!(memdb.len().unwrap(), 0);
+        trie.persist().unwrap();
+        assert!(trie.pending_diff().unwrap().is_empty());
+        trie.remove(&[1]).unwrap();
+        assert_eq!(
+            trie.pending_diff().unwrap(),
+            vec![(vec![1], Some(b"changed".to_vec()), None)]
+        );
     }
-}
-
-#[cfg(test)]
-mod tests {
-    use rand::distributions::Alphanumeric;
-    use rand::seq::SliceRandom;
-    use rand::{thread_rng, Rng};
-    use std::collections::{HashMap, HashSet};
-    use std::sync::Arc;
-
-    use ethereum_types;
-    use hasher::{Hasher, HasherKeccak};
-
-    use super::{PatriciaTrie, Trie};
-    use crate::db::{MemoryDB, DB};
 
     #[test]
-    fn test_trie_insert() {
+    fn test_apply() {
         let memdb = Arc::new(MemoryDB::new(true));
-        let mut trie = PatriciaTrie::new(memdb, Arc::new(HasherKeccak::new()));
-        trie.insert(b"test".to_vec(), b"test".to_vec()).unwrap();
+        let hasher = Arc::new(HasherKeccak::new());
+        let mut trie = PatriciaTrie::new(Arc::clone(&memdb), Arc::clone(&hasher));
+        let mut expected = PatriciaTrie::new(Arc::new(MemoryDB::new(true)), Arc::clone(&hasher));
+        let changes: Vec<(Vec<u8>, Option<Vec<u8>>)> =
+            (0..100u8).map(|i| (vec![i], Some(vec![i; 40]))).collect();
+        for (key, value) in changes.iter() {
+            expected
+                .insert(key.clone(), value.clone().unwrap())
+                .unwrap();
+        }
+        let root = trie.apply(changes).unwrap();
+        assert_eq!(root, expected.root().unwrap());
+
+        let changes = vec![
+            (vec![1], None),
+            (vec![2], Some(b"changed".to_vec())),
+            (vec![200], Some(b"added".to_vec())),
+        ];
+        expected.remove(&[1]).unwrap();
+        expected.insert(vec![2], b"changed".to_vec()).unwrap();
+        expected.insert(vec![200], b"added".to_vec()).unwrap();
+        let root = trie.apply(changes).unwrap();
+        assert_eq!(root, expected.root().unwrap());
+
+        // The leaf of [5] is corrupted, the changes before it are undone.
+        let leaf = trie.get_proof(&[5]).unwrap().pop().unwrap();
+        memdb.insert(hasher.digest(&leaf), vec![0xff]).unwrap();
+        let changes = vec![(vec![3], None), (vec![5], None)];
+        assert!(trie.apply(changes).is_err());
+        assert_eq!(trie.get(&[3]).unwrap(), Some(vec![3; 40]));
+        assert_eq!(trie.peek_root().unwrap(), root);
+
+        // With empty values, "None" still removes the key.
+        let codec = Arc::new(BinaryNodeCodec::new());
+        let mut trie = PatriciaTrie::new_with_codec(Arc::new(MemoryDB::new(true)), hasher, codec)
+            .with_empty_values()
+            .unwrap();
+        let changes = vec![(vec![1], Some(vec![1; 40])), (vec![2], Some(vec![]))];
+        let root = trie.apply(changes).unwrap();
+        let changes = vec![
+            (vec![3], Some(vec![3; 40])),
+            (vec![1], None),
+            (vec![3], None),
+        ];
+        trie.apply(changes).unwrap();
+        assert_eq!(trie.get(&[1]).unwrap(), None);
+        assert_eq!(trie.get(&[2]).unwrap(), Some(vec![]));
+        assert_eq!(trie.get(&[3]).unwrap(), None);
+        assert_ne!(trie.root().unwrap(), root);
+        trie.apply(vec![(vec![1], Some(vec![1; 40]))]).unwrap();
+        assert_eq!(trie.root().unwrap(), root);
     }
 
     #[test]
-    fn test_trie_get() {
+    fn test_discard() {
         let memdb = Arc::new(MemoryDB::new(true));
-        let mut trie = PatriciaTrie::new(memdb, Arc::new(HasherKeccak::new()));
+        let hasher = Arc::new(HasherKeccak::new());
+        let mut trie = PatriciaTrie::new(Arc::clone(&memdb), Arc::clone(&hasher));
+        let empty = trie.peek_root().unwrap();
         trie.insert(b"test".to_vec(), b"test".to_vec()).unwrap();
-        let v = trie.get(b"test").unwrap();
+        trie.discard().unwrap();
+        assert_eq!(trie.get(b"test").unwrap(), None);
+        assert_eq!(trie.root().unwrap(), empty);
 
-        assert_eq!(Some(b"test".to_vec()), v)
+        for i in 0..100u8 {
+            trie.insert(vec![i], vec![i; 40]).unwrap();
+        }
+        let root = trie.root().unwrap();
+        let nodes = memdb.len().unwrap();
+        trie.insert(vec![1], b"changed".to_vec()).unwrap();
+        trie.remove(&[2]).unwrap();
+        trie.get_proof(&[1]).unwrap();
+        trie.begin();
+        trie.discard().unwrap();
+        assert_eq!(trie.get(&[1]).unwrap(), Some(vec![1; 40]));
+        assert_eq!(trie.get(&[2]).unwrap(), Some(vec![2; 40]));
+        assert!(trie.rollback().is_err());
+        assert_eq!(trie.root().unwrap(), root);
+        assert_eq!(memdb.len().unwrap(), nodes);
+
+        // A candidate root is kept until it is written.
+        let memdb = Arc::new(MemoryDB::new(true));
+        let mut trie = PatriciaTrie::new(Arc::clone(&memdb), hasher).with_hash_only_commits();
+        for i in 0..100u8 {
+            trie.insert(vec![i], vec![i; 40]).unwrap();
+        }
+        trie.root().unwrap();
+        trie.remove(&[2]).unwrap();
+        trie.discard().unwrap();
+        assert_eq!(trie.get(&[2]).unwrap(), Some(vec![2; 40]));
+        assert!(trie.pending_diff().unwrap().is_empty());
+        assert_eq!(trie.persist().unwrap(), root);
+        assert_eq!(memdb.len().unwrap(), nodes);
     }
 
     #[test]
-    fn test_trie_random_insert() {
-        let memdb = Arc::new(MemoryDB::new(true));
-        let mut trie = PatriciaTrie::new(memdb, Arc::new(HasherKeccak::new()));
+    fn test_transaction_rollback() {
+        let mut trie =
+            PatriciaTrie::new(Arc::new(MemoryDB::new(true)), Arc::new(HasherKeccak::new()));
+        for i in 0..100u8 {
+            trie.insert(vec![i], vec![i; 40]).unwrap();
+        }
+        let root = trie.root().unwrap();
+        trie.insert(vec![200], b"kept".to_vec()).unwrap();
+        let pending = trie.peek_root().unwrap();
 
-        for _ in 0..1000 {
-            let rand_str: String = thread_rng().sample_iter(&Alphanumeric).take(30).collect();
-            let val = rand_str.as_bytes();
-            trie.insert(val.to_vec(), val.to_vec()).unwrap();
+        trie.begin();
+        for i in 0..50u8 {
+            trie.remove(&[i]).unwrap();
+        }
+        trie.insert(vec![7, 7], b"gone".to_vec()).unwrap();
+        assert!(trie.root().is_err());
+        trie.rollback().unwrap();
+        assert_eq!(trie.get(&[7]).unwrap(), Some(vec![7; 40]));
+        assert_eq!(trie.get(&[7, 7]).unwrap(), None);
+        assert_eq!(trie.peek_root().unwrap(), pending);
+
+        // The inner transaction is undone with the outer one.
+        trie.begin();
+        trie.insert(vec![201], b"outer".to_vec()).unwrap();
+        trie.begin();
+        trie.insert(vec![202], b"inner".to_vec()).unwrap();
+        trie.commit_tx().unwrap();
+        trie.begin();
+        trie.remove(&[201]).unwrap();
+        trie.rollback().unwrap();
+        assert_eq!(trie.get(&[201]).unwrap(), Some(b"outer".to_vec()));
+        assert_eq!(trie.get(&[202]).unwrap(), Some(b"inner".to_vec()));
+        trie.rollback().unwrap();
+        assert_eq!(trie.peek_root().unwrap(), pending);
+        assert!(trie.commit_tx().is_err() && trie.rollback().is_err());
+
+        trie.remove(&[200]).unwrap();
+        assert_eq!(trie.root().unwrap(), root);
+    }
 
-            let v = trie.get(val).unwrap();
-            assert_eq!(v.map(|v| v.to_vec()), Some(val.to_vec()));
+    #[test]
+    fn test_get_ref() {
+        let memdb = Arc::new(MemoryDB::new(true));
+        let hasher = Arc::new(HasherKeccak::new());
+        let mut trie = PatriciaTrie::new(Arc::clone(&memdb), Arc::clone(&hasher));
+        for i in 0..100u8 {
+            trie.insert(vec![i, i], vec![i; 40]).unwrap();
+        }
+        trie.insert(vec![1], b"branch value".to_vec()).unwrap();
+
+        // The values are shared with the trie, they do not borrow it, so the
+        // trie can be read and changed while they are held.
+        let leaf = trie.get_ref(&[7, 7]).unwrap();
+        let branch = trie.get_ref(&[1]).unwrap();
+        match (&leaf, &branch) {
+            (Some(ValueRef::Shared(leaf)), Some(ValueRef::Shared(branch))) => {
+                assert_eq!(&leaf[..], &[7; 40][..]);
+                assert_eq!(&branch[..], b"branch value");
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        assert_eq!(trie.get_ref(&[7, 8]).unwrap(), None);
+        assert_eq!(trie.get(&[8, 8]).unwrap(), Some(vec![8; 40]));
+        trie.insert(vec![7, 7], vec![0; 40]).unwrap();
+        assert_eq!(leaf.unwrap().into_owned(), vec![7; 40]);
+        assert_eq!(&*trie.get_ref(&[7, 7]).unwrap().unwrap(), &[0; 40][..]);
+
+        // After a commit the values are read from the DB, overlapping reads
+        // load nodes while the first value is held.
+        let root = trie.root().unwrap();
+        let trie = PatriciaTrie::from(memdb, hasher, &root).unwrap();
+        let first = trie.get_ref(&[7, 7]).unwrap();
+        let second = trie.get_ref(&[9, 9]).unwrap();
+        match (&first, &second) {
+            (Some(ValueRef::Owned(first)), Some(ValueRef::Owned(second))) => {
+                assert_eq!(first, &vec![0; 40]);
+                assert_eq!(second, &vec![9; 40]);
+            }
+            other => panic!("unexpected {:?}", other),
         }
+        assert!(!trie.get_proof(&[9, 9]).unwrap().is_empty());
+        assert_eq!(trie.get_ref(&[7, 8]).unwrap(), None);
+        assert_eq!(branch.unwrap().into_owned(), b"branch value".to_vec());
     }
 
     #[test]
-    fn test_trie_contains() {
+    fn test_memory_usage() {
         let memdb = Arc::new(MemoryDB::new(true));
-        let mut trie = PatriciaTrie::new(memdb, Arc::new(HasherKeccak::new()));
-        trie.insert(b"test".to_vec(), b"test".to_vec()).unwrap();
-        assert_eq!(true, trie.contains(b"test").unwrap());
-        assert_eq!(false, trie.contains(b"test2").unwrap());
+        let hasher = Arc::new(HasherKeccak::new());
+        let mut trie = PatriciaTrie::new(Arc::clone(&memdb), Arc::clone(&hasher));
+        let empty = trie.memory_usage();
+        for i in 0..100u8 {
+            trie.insert(vec![i], vec![i; 40]).unwrap();
+        }
+        let stats = trie.memory_usage();
+        assert!(stats.nodes > empty.nodes + 100 * 40);
+        assert_eq!(stats.total(), stats.nodes + stats.cache);
+
+        let root = trie.root().unwrap();
+        let mut trie = PatriciaTrie::from(memdb, hasher, &root).unwrap();
+        for i in 0..50u8 {
+            trie.insert(vec![i], vec![i + 1; 40]).unwrap();
+        }
+        trie.get_proof(&[99]).unwrap();
+        let stats = trie.memory_usage();
+        assert!(stats.cache > 0 && stats.journal > 0);
+
+        // Committing empties the cache and the key sets.
+        trie.root().unwrap();
+        let committed = trie.memory_usage();
+        assert!(committed.cache < stats.cache);
+        assert!(committed.journal < stats.journal);
     }
 
     #[test]
-    fn test_trie_remove() {
+    fn test_pending_changes() {
         let memdb = Arc::new(MemoryDB::new(true));
-        let mut trie = PatriciaTrie::new(memdb, Arc::new(HasherKeccak::new()));
-        trie.insert(b"test".to_vec(), b"test".to_vec()).unwrap();
-        let removed = trie.remove(b"test").unwrap();
-        assert_eq!(true, removed)
+        let hasher = Arc::new(HasherKeccak::new());
+        let mut trie = PatriciaTrie::new(Arc::clone(&memdb), Arc::clone(&hasher));
+        for i in 0..100u8 {
+            trie.insert(vec![i], vec![i; 40]).unwrap();
+        }
+        let stats = trie.pending_changes();
+        assert!(stats.dirty_nodes > 100 && stats.encoded_bytes > 100 * 40);
+        assert_eq!(stats.deletions, 0);
+
+        let root = trie.root().unwrap();
+        assert_eq!(trie.pending_changes(), PendingStats::default());
+        let opened = PatriciaTrie::from(Arc::clone(&memdb), hasher, &root).unwrap();
+        assert_eq!(opened.pending_changes(), PendingStats::default());
+
+        trie.insert(vec![7], vec![107; 40]).unwrap();
+        let stats = trie.pending_changes();
+        // The leaf and the branches above it.
+        assert_eq!(stats.dirty_nodes, stats.deletions);
+        assert!(stats.dirty_nodes >= 2 && stats.encoded_bytes > 40);
     }
 
     #[test]
-    fn test_trie_random_remove() {
+    fn test_last_changeset() {
         let memdb = Arc::new(MemoryDB::new(true));
-        let mut trie = PatriciaTrie::new(memdb, Arc::new(HasherKeccak::new()));
+        let mut trie = PatriciaTrie::new(Arc::clone(&memdb), Arc::new(HasherKeccak::new()));
+        for i in 0..100u8 {
+            trie.insert(vec![i], vec![i; 40]).unwrap();
+        }
+        trie.root().unwrap();
+        let changes = trie.last_changeset().clone();
+        assert_eq!(changes.inserted.len(), memdb.len().unwrap());
+        assert!(changes.removed.is_empty());
+
+        // Changing a value back cancels out.
+        trie.insert(vec![7], vec![107; 40]).unwrap();
+        trie.insert(vec![7], vec![7; 40]).unwrap();
+        trie.insert(vec![8], vec![108; 40]).unwrap();
+        trie.root().unwrap();
+        let changes = trie.last_changeset();
+        assert_eq!(changes.inserted.len(), changes.removed.len());
+        for hash in changes.inserted.iter() {
+            assert!(memdb.contains(hash).unwrap());
+        }
+        for hash in changes.removed.iter() {
+            assert!(!memdb.contains(hash).unwrap());
+        }
+    }
 
-        for _ in 0..1000 {
-            let rand_str: String = thread_rng().sample_iter(&Alphanumeric).take(30).collect();
-            let val = rand_str.as_bytes();
-            trie.insert(val.to_vec(), val.to_vec()).unwrap();
+    #[test]
+    fn test_encoded_hashes_are_kept_until_modified() {
+        let hasher = Arc::new(HasherKeccak::new());
+        let mut trie = PatriciaTrie::new(Arc::new(MemoryDB::new(true)), Arc::clone(&hasher));
+        for i in 0..100u8 {
+            trie.insert(vec![i], vec![i; 40]).unwrap();
+        }
+        trie.get_proof(&[0]).unwrap();
+        let arena = trie.arena.borrow();
+        match trie.root {
+            Node::Branch(id) => {
+                let hashed = arena
+                    .branch(id)
+                    .children
+                    .iter()
+                    .filter(|n| arena.node_hash(**n).is_some())
+                    .count();
+                assert_eq!(hashed, 7);
+            }
+            _ => panic!("the root should be a branch"),
+        }
+        drop(arena);
 
-            let removed = trie.remove(val).unwrap();
-            assert_eq!(true, removed);
+        for i in 0..50u8 {
+            trie.insert(vec![i], vec![i + 1; 40]).unwrap();
         }
+        trie.remove(&[99]).unwrap();
+        trie.get_proof(&[0]).unwrap();
+        trie.remove(&[98]).unwrap();
+
+        let mut expected = PatriciaTrie::new(Arc::new(MemoryDB::new(true)), hasher);
+        for i in 0..98u8 {
+            let value = if i < 50 { vec![i + 1; 40] } else { vec![i; 40] };
+            expected.insert(vec![i], value).unwrap();
+        }
+        assert_eq!(trie.root().unwrap(), expected.root().unwrap());
     }
 
     #[test]
-    fn test_trie_from_root() {
-        let memdb = Arc::new(MemoryDB::new(true));
+    fn test_node_cache() {
+        let memdb = Arc::new(MeteredDB::new(MemoryDB::new(true)));
+        let hasher = Arc::new(HasherKeccak::new());
         let root = {
-            let mut trie = PatriciaTrie::new(Arc::clone(&memdb), Arc::new(HasherKeccak::new()));
-            trie.insert(b"test".to_vec(), b"test".to_vec()).unwrap();
-            trie.insert(b"test1".to_vec(), b"test".to_vec()).unwrap();
-            trie.insert(b"test2".to_vec(), b"test".to_vec()).unwrap();
-            trie.insert(b"test23".to_vec(), b"test".to_vec()).unwrap();
-            trie.insert(b"test33".to_vec(), b"test".to_vec()).unwrap();
-            trie.insert(b"test44".to_vec(), b"test".to_vec()).unwrap();
+            let mut trie = PatriciaTrie::new(Arc::clone(&memdb), Arc::clone(&hasher));
+            for i in 0..=255u8 {
+                trie.insert(vec![i], vec![i; 40]).unwrap();
+            }
             trie.root().unwrap()
         };
 
-        let mut trie =
-            PatriciaTrie::from(Arc::clone(&memdb), Arc::new(HasherKeccak::new()), &root).unwrap();
-        let v1 = trie.get(b"test33").unwrap();
-        assert_eq!(Some(b"test".to_vec()), v1);
-        let v2 = trie.get(b"test44").unwrap();
-        assert_eq!(Some(b"test".to_vec()), v2);
-        let root2 = trie.root().unwrap();
-        assert_eq!(hex::encode(root), hex::encode(root2));
+        let mut trie = PatriciaTrie::from(Arc::clone(&memdb), hasher, &root)
+            .unwrap()
+            .with_node_cache(1000);
+        for i in 0..=255u8 {
+            assert_eq!(trie.get(&[i]).unwrap(), Some(vec![i; 40]));
+        }
+        memdb.reset();
+        for i in 0..=255u8 {
+            assert_eq!(trie.get(&[i]).unwrap(), Some(vec![i; 40]));
+        }
+        assert_eq!(memdb.metrics().gets, 0);
+
+        trie.insert(vec![7], b"new".to_vec()).unwrap();
+        trie.remove(&[8]).unwrap();
+        trie.root().unwrap();
+        assert_eq!(trie.get(&[7]).unwrap(), Some(b"new".to_vec()));
+        assert_eq!(trie.get(&[8]).unwrap(), None);
+        assert_eq!(trie.get(&[9]).unwrap(), Some(vec![9; 40]));
     }
 
     #[test]
-    fn test_trie_from_root_and_insert() {
-        let memdb = Arc::new(MemoryDB::new(true));
+    fn test_iterator_batches_child_reads() {
+        let memdb = Arc::new(MeteredDB::new(MemoryDB::new(true)));
         let root = {
             let mut trie = PatriciaTrie::new(Arc::clone(&memdb), Arc::new(HasherKeccak::new()));
-            trie.insert(b"test".to_vec(), b"test".to_vec()).unwrap();
-            trie.insert(b"test1".to_vec(), b"test".to_vec()).unwrap();
-            trie.insert(b"test2".to_vec(), b"test".to_vec()).unwrap();
-            trie.insert(b"test23".to_vec(), b"test".to_vec()).unwrap();
-            trie.insert(b"test33".to_vec(), b"test".to_vec()).unwrap();
-            trie.insert(b"test44".to_vec(), b"test".to_vec()).unwrap();
-            trie.commit().unwrap()
+            for i in 0..=255u8 {
+                trie.insert(vec![i], vec![i; 40]).unwrap();
+            }
+            trie.root().unwrap()
         };
 
-        let mut trie =
+        let trie =
             PatriciaTrie::from(Arc::clone(&memdb), Arc::new(HasherKeccak::new()), &root).unwrap();
-        trie.insert(b"test55".to_vec(), b"test55".to_vec()).unwrap();
-        trie.commit().unwrap();
-        let v = trie.get(b"test55").unwrap();
-        assert_eq!(Some(b"test55".to_vec()), v);
+        memdb.reset();
+        let kvs: Vec<(Vec<u8>, Vec<u8>)> = trie.iter().collect();
+        assert_eq!(kvs.len(), 256);
+        for (i, (k, v)) in kvs.into_iter().enumerate() {
+            assert_eq!(k, vec![i as u8]);
+            assert_eq!(v, vec![i as u8; 40]);
+        }
+
+        let metrics = memdb.metrics();
+        assert_eq!(metrics.get_batches, 17);
+        assert_eq!(metrics.gets, 256 + 16);
     }
 
     #[test]
-    fn test_trie_from_root_and_delete() {
-        let memdb = Arc::new(MemoryDB::new(true));
+    fn test_get_many() {
+        let memdb = Arc::new(MeteredDB::new(MemoryDB::new(true)));
         let root = {
             let mut trie = PatriciaTrie::new(Arc::clone(&memdb), Arc::new(HasherKeccak::new()));
-            trie.insert(b"test".to_vec(), b"test".to_vec()).unwrap();
-            trie.insert(b"test1".to_vec(), b"test".to_vec()).unwrap();
-            trie.insert(b"test2".to_vec(), b"test".to_vec()).unwrap();
-            trie.insert(b"test23".to_vec(), b"test".to_vec()).unwrap();
-            trie.insert(b"test33".to_vec(), b"test".to_vec()).unwrap();
-            trie.insert(b"test44".to_vec(), b"test".to_vec()).unwrap();
-            trie.commit().unwrap()
+            for i in 0..=255u8 {
+                trie.insert(vec![i, i], vec![i; 40]).unwrap();
+            }
+            trie.root().unwrap()
         };
 
         let mut trie =
             PatriciaTrie::from(Arc::clone(&memdb), Arc::new(HasherKeccak::new()), &root).unwrap();
-        let removed = trie.remove(b"test44").unwrap();
-        assert_eq!(true, removed);
-        let removed = trie.remove(b"test33").unwrap();
-        assert_eq!(true, removed);
-        let removed = trie.remove(b"test23").unwrap();
-        assert_eq!(true, removed);
-    }
-
-    #[test]
-    fn test_multiple_trie_roots() {
-        let k0: ethereum_types::H256 = 0.into();
-        let k1: ethereum_types::H256 = 1.into();
-        let v: ethereum_types::H256 = 0x1234.into();
-
-        let root1 = {
-            let memdb = Arc::new(MemoryDB::new(true));
-            let mut trie = PatriciaTrie::new(memdb, Arc::new(HasherKeccak::new()));
-            trie.insert(k0.as_bytes().to_vec(), v.as_bytes().to_vec())
-                .unwrap();
-            trie.root().unwrap()
-        };
-
-        let root2 = {
-            let memdb = Arc::new(MemoryDB::new(true));
-            let mut trie = PatriciaTrie::new(memdb, Arc::new(HasherKeccak::new()));
-            trie.insert(k0.as_bytes().to_vec(), v.as_bytes().to_vec())
-                .unwrap();
-            trie.insert(k1.as_bytes().to_vec(), v.as_bytes().to_vec())
-                .unwrap();
-            trie.root().unwrap();
-            trie.remove(k1.as_ref()).unwrap();
-            trie.root().unwrap()
-        };
-
-        let root3 = {
-            let memdb = Arc::new(MemoryDB::new(true));
-            let mut trie1 = PatriciaTrie::new(Arc::clone(&memdb), Arc::new(HasherKeccak::new()));
-            trie1
-                .insert(k0.as_bytes().to_vec(), v.as_bytes().to_vec())
-                .unwrap();
-            trie1
-                .insert(k1.as_bytes().to_vec(), v.as_bytes().to_vec())
-                .unwrap();
-            trie1.root().unwrap();
-            let root = trie1.root().unwrap();
-            let mut trie2 =
-                PatriciaTrie::from(Arc::clone(&memdb), Arc::new(HasherKeccak::new()), &root)
-                    .unwrap();
-            trie2.remove(&k1.as_bytes().to_vec()).unwrap();
-            trie2.root().unwrap()
-        };
-
-        assert_eq!(root1, root2);
-        assert_eq!(root2, root3);
+        trie.insert(vec![3, 3], b"new".to_vec()).unwrap();
+        // Unsorted, with repeated and absent keys.
+        let mut keys: Vec<Vec<u8>> = (0..=255u8).rev().map(|i| vec![i, i]).collect();
+        keys.extend(vec![vec![3, 3], vec![1, 1], vec![1], vec![1, 2], vec![]]);
+        memdb.reset();
+        let values = trie.get_many(&keys).unwrap();
+
+        // The branches below the root, then the leaves, except for the branch
+        // and the leaf that "insert" loaded.
+        let metrics = memdb.metrics();
+        assert_eq!((metrics.get_batches, metrics.gets), (2, 15 + 255));
+        assert_eq!(values.len(), keys.len());
+        for (key, value) in keys.iter().zip(values.into_iter()) {
+            assert_eq!(trie.get(key).unwrap(), value);
+        }
+        assert_eq!(
+            trie.get_many(&[vec![3, 3]]).unwrap(),
+            vec![Some(b"new".to_vec())]
+        );
     }
 
     #[test]
-    fn test_delete_stale_keys_with_random_insert_and_delete() {
+    fn test_trie_snapshot_to() {
+        let path = std::env::temp_dir().join(format!("cita-trie-snapshot-{}", Uuid::new_v4()));
         let memdb = Arc::new(MemoryDB::new(true));
-        let mut trie = PatriciaTrie::new(memdb, Arc::new(HasherKeccak::new()));
-
-        let mut rng = rand::thread_rng();
-        let mut keys = vec![];
-        for _ in 0..100 {
-            let random_bytes: Vec<u8> = (0..rng.gen_range(2, 30))
-                .map(|_| rand::random::<u8>())
-                .collect();
-            trie.insert(random_bytes.clone(), random_bytes.clone())
-                .unwrap();
-            keys.push(random_bytes.clone());
+        let mut trie = PatriciaTrie::new(Arc::clone(&memdb), Arc::new(HasherKeccak::new()));
+        for i in 0..100u8 {
+            trie.insert(vec![i], vec![i; 40]).unwrap();
         }
-        trie.commit().unwrap();
-        let slice = &mut keys;
-        slice.shuffle(&mut rng);
-
-        for key in slice.iter() {
-            trie.remove(key).unwrap();
+        let root = trie.snapshot_to(&path).unwrap();
+
+        let restored = Arc::new(MemoryDB::new(true));
+        assert!(restored.restore(&path).unwrap());
+        assert_eq!(restored.len().unwrap(), memdb.len().unwrap());
+        let trie = PatriciaTrie::from(restored, Arc::new(HasherKeccak::new()), &root).unwrap();
+        for i in 0..100u8 {
+            assert_eq!(trie.get(&[i]).unwrap(), Some(vec![i; 40]));
         }
-        trie.commit().unwrap();
-
-        let empty_node_key = HasherKeccak::new().digest(&rlp::NULL_RLP);
-        let value = trie.db.get(empty_node_key.as_ref()).unwrap().unwrap();
-        assert_eq!(value, &rlp::NULL_RLP)
+        std::fs::remove_file(&path).unwrap();
     }
 
     #[test]
@@ -0,0 +1,183 @@
+use std::marker::PhantomData;
+use std::sync::Arc;
+
+use hasher::{Hasher, HasherKeccak};
+use rlp::{Decodable, Encodable};
+
+use crate::db::DB;
+use crate::trie::{PatriciaTrie, Trie, TrieResult};
+
+/// "ValueCodec" turns the values of a "TypedTrie" into bytes and back.
+pub trait ValueCodec<T>: Send + Sync {
+    fn encode(&self, value: &T) -> Vec<u8>;
+
+    fn decode(&self, data: &[u8]) -> TrieResult<T>;
+}
+
+/// Encodes values with RLP.
+#[derive(Default, Debug, Clone, Copy)]
+pub struct RLPValueCodec;
+
+impl RLPValueCodec {
+    pub fn new() -> Self {
+        RLPValueCodec
+    }
+}
+
+impl<T: Encodable + Decodable> ValueCodec<T> for RLPValueCodec {
+    fn encode(&self, value: &T) -> Vec<u8> {
+        rlp::encode(value)
+    }
+
+    fn decode(&self, data: &[u8]) -> TrieResult<T> {
+        Ok(rlp::decode(data)?)
+    }
+}
+
+/// "TypedTrie" stores values of type "T", serialized by the value codec "C",
+/// so callers don't have to encode and decode at every call site.
+/// Proofs and iteration are available on the underlying trie through "trie".
+#[derive(Debug)]
+pub struct TypedTrie<T, C, D, H = HasherKeccak>
+where
+    C: ValueCodec<T>,
+    D: DB,
+    H: Hasher,
+{
+    trie: PatriciaTrie<D, H>,
+    codec: Arc<C>,
+    value: PhantomData<T>,
+}
+
+impl<T, C, D, H> TypedTrie<T, C, D, H>
+where
+    C: ValueCodec<T>,
+    D: DB,
+    H: Hasher,
+{
+    pub fn new(db: Arc<D>, hasher: Arc<H>, codec: Arc<C>) -> Self {
+        TypedTrie {
+            trie: PatriciaTrie::new(db, hasher),
+            codec,
+            value: PhantomData,
+        }
+    }
+
+    pub fn from(db: Arc<D>, hasher: Arc<H>, codec: Arc<C>, root: &[u8]) -> TrieResult<Self> {
+        Ok(TypedTrie {
+            trie: PatriciaTrie::from(db, hasher, root)?,
+            codec,
+            value: PhantomData,
+        })
+    }
+
+    pub fn trie(&self) -> &PatriciaTrie<D, H> {
+        &self.trie
+    }
+
+    pub fn get(&self, key: &[u8]) -> TrieResult<Option<T>> {
+        match self.trie.get(key)? {
+            Some(data) => Ok(Some(self.codec.decode(&data)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn contains(&self, key: &[u8]) -> TrieResult<bool> {
+        self.trie.contains(key)
+    }
+
+    pub fn insert(&mut self, key: Vec<u8>, value: &T) -> TrieResult<()> {
+        self.trie.insert(key, self.codec.encode(value))
+    }
+
+    pub fn remove(&mut self, key: &[u8]) -> TrieResult<bool> {
+        self.trie.remove(key)
+    }
+
+    pub fn root(&mut self) -> TrieResult<Vec<u8>> {
+        self.trie.root()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rlp::{DecoderError, Rlp, RlpStream};
+
+    use super::*;
+    use crate::db::MemoryDB;
+    use crate::errors::TrieError;
+
+    #[derive(Debug, Clone, PartialEq)]
+    struct Account {
+        nonce: u64,
+        balance: u64,
+        code: Vec<u8>,
+    }
+
+    impl Encodable for Account {
+        fn rlp_append(&self, s: &mut RlpStream) {
+            s.begin_list(3);
+            s.append(&self.nonce);
+            s.append(&self.balance);
+            s.append(&self.code);
+        }
+    }
+
+    impl Decodable for Account {
+        fn decode(rlp: &Rlp) -> Result<Self, DecoderError> {
+            Ok(Account {
+                nonce: rlp.val_at(0)?,
+                balance: rlp.val_at(1)?,
+                code: rlp.val_at(2)?,
+            })
+        }
+    }
+
+    #[test]
+    fn test_typed_trie() {
+        let memdb = Arc::new(MemoryDB::new(true));
+        let hasher = Arc::new(HasherKeccak::new());
+        let codec = Arc::new(RLPValueCodec::new());
+        let account = |i: u8| Account {
+            nonce: u64::from(i),
+            balance: u64::from(i) * 1000,
+            code: vec![i; i as usize],
+        };
+
+        let root = {
+            let mut trie =
+                TypedTrie::new(Arc::clone(&memdb), Arc::clone(&hasher), Arc::clone(&codec));
+            for i in 0..100u8 {
+                trie.insert(vec![i], &account(i)).unwrap();
+            }
+            assert_eq!(trie.remove(&[0]).unwrap(), true);
+            trie.root().unwrap()
+        };
+
+        let trie: TypedTrie<Account, _, _> = TypedTrie::from(memdb, hasher, codec, &root).unwrap();
+        assert_eq!(trie.get(&[0]).unwrap(), None);
+        for i in 1..100u8 {
+            assert_eq!(trie.get(&[i]).unwrap(), Some(account(i)));
+        }
+        let proof = trie.trie().get_proof(&[7]).unwrap();
+        let value = trie.trie().verify_proof(root, &[7], proof).unwrap();
+        assert_eq!(value, Some(rlp::encode(&account(7))));
+    }
+
+    #[test]
+    fn test_typed_trie_decode_error() {
+        let memdb = Arc::new(MemoryDB::new(true));
+        let hasher = Arc::new(HasherKeccak::new());
+        let mut raw = PatriciaTrie::new(Arc::clone(&memdb), Arc::clone(&hasher));
+        raw.insert(b"key".to_vec(), b"not an account".to_vec())
+            .unwrap();
+        let root = raw.root().unwrap();
+
+        let trie: TypedTrie<Account, _, _> =
+            TypedTrie::from(memdb, hasher, Arc::new(RLPValueCodec::new()), &root).unwrap();
+        match trie.get(b"key") {
+            Err(TrieError::Decoder(_)) => {}
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+}
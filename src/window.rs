@@ -0,0 +1,170 @@
+use std::collections::VecDeque;
+
+use hashbrown::HashMap;
+
+use crate::db::{read_bytes, write_bytes, DB};
+use crate::errors::TrieError;
+use crate::journal::ChangeSet;
+use crate::trie::TrieResult;
+
+// The window is kept under this key, so that a trie opened later on the DB
+// carries on with it: the generation, 8 bytes big endian, then every kept
+// generation, its number of stale nodes, 4 bytes little endian, and their
+// length prefixed hashes.
+pub(crate) const PRUNING_WINDOW_KEY: &[u8] = b"cita-trie:pruning-window";
+
+/// Keeps stale nodes for a number of commits, so that the roots of the last
+/// "size" commits stay readable, e.g. to serve the state of recent blocks.
+/// Every commit is a generation, a window of 1 only keeps the latest root.
+#[derive(Debug, Clone)]
+pub(crate) struct PruningWindow {
+    size: u64,
+    generation: u64,
+    // The generation in which each kept node was made stale. A node that was
+    // written again since is live and not in here.
+    stale_since: HashMap<Vec<u8>, u64>,
+    // The nodes made stale by each kept generation, the oldest first.
+    generations: VecDeque<(u64, Vec<Vec<u8>>)>,
+}
+
+impl PruningWindow {
+    pub fn new(size: u64) -> Self {
+        PruningWindow {
+            size,
+            generation: 0,
+            stale_since: HashMap::new(),
+            generations: VecDeque::new(),
+        }
+    }
+
+    /// Reads the window kept in "db", or starts a new one.
+    pub fn load<D: DB>(db: &D, size: u64) -> TrieResult<Self> {
+        let mut window = PruningWindow::new(size);
+        let data = match db
+            .get(PRUNING_WINDOW_KEY)
+            .map_err(|e| TrieError::DB(e.to_string()))?
+        {
+            Some(data) => data,
+            None => return Ok(window),
+        };
+        let mut rest = &data[..];
+        window.generation = read_u64(&mut rest)?;
+        while !rest.is_empty() {
+            let generation = read_u64(&mut rest)?;
+            if rest.len() < 4 {
+                return Err(TrieError::InvalidData);
+            }
+            let count = rest[..4]
+                .iter()
+                .rev()
+                .fold(0usize, |acc, b| (acc << 8) | *b as usize);
+            rest = &rest[4..];
+            let mut hashes = Vec::with_capacity(count);
+            for _ in 0..count {
+                let hash = read_bytes(&mut rest).ok_or(TrieError::InvalidData)?;
+                window.stale_since.insert(hash.clone(), generation);
+                hashes.push(hash);
+            }
+            window.generations.push_back((generation, hashes));
+        }
+        Ok(window)
+    }
+
+    /// Encodes the window to keep it under "PRUNING_WINDOW_KEY". Only the
+    /// nodes still stale since their generation are kept.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut data = self.generation.to_be_bytes().to_vec();
+        for (generation, hashes) in self.generations.iter() {
+            let stale: Vec<&Vec<u8>> = hashes
+                .iter()
+                .filter(|hash| self.stale_since.get(*hash) == Some(generation))
+                .collect();
+            data.extend_from_slice(&generation.to_be_bytes());
+            data.extend_from_slice(&(stale.len() as u32).to_le_bytes());
+            for hash in stale.into_iter() {
+                write_bytes(&mut data, hash);
+            }
+        }
+        data
+    }
+
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// Keeps "hashes" until they are made stale again, e.g. nodes that were
+    /// written back.
+    pub fn revive(&mut self, hashes: &[Vec<u8>]) {
+        for hash in hashes.iter() {
+            self.stale_since.remove(hash);
+        }
+    }
+
+    /// Records the changes of the next commit, returns the nodes that fell
+    /// out of the window and can be removed.
+    pub fn advance(&mut self, changes: &ChangeSet) -> Vec<Vec<u8>> {
+        self.generation += 1;
+        for hash in changes.inserted.iter() {
+            self.stale_since.remove(hash);
+        }
+        for hash in changes.removed.iter() {
+            self.stale_since.insert(hash.clone(), self.generation);
+        }
+        self.generations
+            .push_back((self.generation, changes.removed.clone()));
+
+        let mut expired = vec![];
+        while let Some((generation, _)) = self.generations.front() {
+            // The root the nodes belonged to is the one before "generation".
+            if generation + self.size > self.generation + 1 {
+                break;
+            }
+            let (generation, hashes) = self.generations.pop_front().unwrap();
+            for hash in hashes.into_iter() {
+                if self.stale_since.get(&hash) == Some(&generation) {
+                    self.stale_since.remove(&hash);
+                    expired.push(hash);
+                }
+            }
+        }
+        expired
+    }
+}
+
+fn read_u64(rest: &mut &[u8]) -> TrieResult<u64> {
+    if rest.len() < 8 {
+        return Err(TrieError::InvalidData);
+    }
+    let mut buf = [0u8; 8];
+    buf.copy_from_slice(&rest[..8]);
+    *rest = &rest[8..];
+    Ok(u64::from_be_bytes(buf))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PruningWindow;
+    use crate::journal::ChangeSet;
+
+    fn changes(inserted: &[&[u8]], removed: &[&[u8]]) -> ChangeSet {
+        ChangeSet {
+            inserted: inserted.iter().map(|h| h.to_vec()).collect(),
+            removed: removed.iter().map(|h| h.to_vec()).collect(),
+        }
+    }
+
+    #[test]
+    fn test_pruning_window() {
+        let mut window = PruningWindow::new(2);
+        assert!(window.advance(&changes(&[b"b"], &[b"a"])).is_empty());
+        assert_eq!(
+            window.advance(&changes(&[b"d"], &[b"b", b"c"])),
+            vec![b"a".to_vec()]
+        );
+        // "c" is written again, so it is live when it would expire.
+        assert_eq!(window.advance(&changes(&[b"c"], &[])), vec![b"b".to_vec()]);
+        assert!(window.advance(&changes(&[], &[b"d"])).is_empty());
+        assert_eq!(window.advance(&changes(&[], &[])), vec![b"d".to_vec()]);
+        assert_eq!(window.generation(), 5);
+    }
+}